@@ -0,0 +1,79 @@
+//! Apply of a wide compose, run with `cargo bench -p josh --bench parallel_compose`.
+//!
+//! A compose of one member per team of a monorepo applies its members in parallel.
+//! This compares that to applying the same members one after the other. Every apply
+//! uses a new transaction and a tree with different content, so none of the results
+//! are cached yet.
+
+const TEAMS: usize = 40;
+const FILES: usize = 50;
+const ITERATIONS: usize = 20;
+
+/// A tree with `FILES` files below `team<n>/src` for each team, with content depending
+/// on `salt`
+fn teams_tree(repo: &git2::Repository, salt: &str) -> git2::Oid {
+    let mut root = repo.treebuilder(None).unwrap();
+    for t in 0..TEAMS {
+        let mut src = repo.treebuilder(None).unwrap();
+        for f in 0..FILES {
+            let blob = repo
+                .blob(format!("{} {} {}", salt, t, f).as_bytes())
+                .unwrap();
+            src.insert(format!("f{}", f), blob, 0o100644).unwrap();
+        }
+        let mut team = repo.treebuilder(None).unwrap();
+        team.insert("src", src.write().unwrap(), 0o040000).unwrap();
+        root.insert(format!("team{}", t), team.write().unwrap(), 0o040000)
+            .unwrap();
+    }
+    root.write().unwrap()
+}
+
+fn members() -> Vec<josh::filter::Filter> {
+    (0..TEAMS)
+        .map(|t| {
+            let spec = match t % 3 {
+                0 => format!("out/team{0} = :/team{0}", t),
+                1 => format!("::team{}/src/f1*", t),
+                _ => format!(":/team{}", t),
+            };
+            josh::filter::parse(&spec).unwrap()
+        })
+        .collect()
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    josh::cache::load(&dir.path().join("cache")).unwrap();
+    let repo_path = dir.path().join("repo");
+    let repo = git2::Repository::init_bare(&repo_path).unwrap();
+
+    let members = members();
+    let specs: Vec<_> = members.iter().map(|f| josh::filter::spec(*f)).collect();
+    let compose = josh::filter::parse(&format!(":[{}]", specs.join(","))).unwrap();
+
+    for name in ["compose", "members"] {
+        let runs = (0..ITERATIONS)
+            .map(|i| {
+                let transaction = josh::cache::Transaction::open(&repo_path, None).unwrap();
+                (transaction, teams_tree(&repo, &format!("{} {}", name, i)))
+            })
+            .collect::<Vec<_>>();
+        let start = std::time::Instant::now();
+        for (transaction, tree) in &runs {
+            let tree = transaction.repo().find_tree(*tree).unwrap();
+            if name == "compose" {
+                josh::filter::apply(transaction, compose, tree).unwrap();
+            } else {
+                for f in &members {
+                    josh::filter::apply(transaction, *f, tree.clone()).unwrap();
+                }
+            }
+        }
+        println!(
+            "{:>7}: {:?} per apply",
+            name,
+            start.elapsed() / ITERATIONS as u32
+        );
+    }
+}
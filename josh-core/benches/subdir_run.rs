@@ -0,0 +1,57 @@
+//! Cold cache apply of a deep path, run with `cargo bench -p josh --bench subdir_run`.
+//!
+//! The optimizer splits `:/c0/c1/...` into one subdir per path component, which `apply`
+//! descends in one go. This compares that to applying the same path as a single subdir,
+//! like `optimize_for_size` produces it. Every apply uses a new transaction, so none of
+//! the results are cached yet.
+
+const DEPTH: usize = 32;
+const SIBLINGS: usize = 50;
+const ITERATIONS: u32 = 500;
+
+/// A tree with a file at `c0/c1/.../file`, and other entries next to every directory
+fn deep_tree(repo: &git2::Repository) -> git2::Oid {
+    let blob = repo.blob(b"content").unwrap();
+    let mut builder = repo.treebuilder(None).unwrap();
+    builder.insert("file", blob, 0o100644).unwrap();
+    let mut tree = builder.write().unwrap();
+    for level in (0..DEPTH).rev() {
+        let mut builder = repo.treebuilder(None).unwrap();
+        for i in 0..SIBLINGS {
+            builder
+                .insert(format!("sibling{}", i), blob, 0o100644)
+                .unwrap();
+        }
+        builder
+            .insert(format!("c{}", level), tree, 0o040000)
+            .unwrap();
+        tree = builder.write().unwrap();
+    }
+    tree
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    josh::cache::load(&dir.path().join("cache")).unwrap();
+    let repo_path = dir.path().join("repo");
+    let tree = deep_tree(&git2::Repository::init_bare(&repo_path).unwrap());
+
+    let path = (0..DEPTH)
+        .map(|i| format!("c{}", i))
+        .collect::<Vec<_>>()
+        .join("/");
+    let split = josh::filter::parse(&format!(":/{}", path)).unwrap();
+    let joined = josh::filter::optimize_for_size(split);
+
+    for (name, filter) in [("split", split), ("joined", joined)] {
+        let transactions = (0..ITERATIONS)
+            .map(|_| josh::cache::Transaction::open(&repo_path, None).unwrap())
+            .collect::<Vec<_>>();
+        let start = std::time::Instant::now();
+        for transaction in &transactions {
+            let tree = transaction.repo().find_tree(tree).unwrap();
+            josh::filter::apply(transaction, filter, tree).unwrap();
+        }
+        println!("{:>6}: {:?} per apply", name, start.elapsed() / ITERATIONS);
+    }
+}
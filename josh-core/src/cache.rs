@@ -1,10 +1,13 @@
 use super::*;
 use std::collections::HashMap;
 
-const CACHE_VERSION: u64 = 22;
+/// Part of the paths of the caches on disk. Has to be increased when a change of josh
+/// changes the results of filters, so entries written by older versions are not used.
+pub(crate) const CACHE_VERSION: u64 = 22;
 
 lazy_static! {
     static ref DB: std::sync::Mutex<Option<sled::Db>> = std::sync::Mutex::new(None);
+    static ref APPLY_DB: std::sync::Mutex<Option<sled::Db>> = std::sync::Mutex::new(None);
     static ref REF_CACHE: std::sync::Mutex<HashMap<git2::Oid, HashMap<git2::Oid, git2::Oid>>> =
         std::sync::Mutex::new(HashMap::new());
     static ref POPULATE_MAP: std::sync::Mutex<HashMap<(git2::Oid, git2::Oid), git2::Oid>> =
@@ -23,6 +26,47 @@ pub fn load(path: &std::path::Path) -> JoshResult<()> {
     Ok(())
 }
 
+/// Keep the trees produced by filters combining other filters, like compose or chain,
+/// in a cache at `path` that is kept across restarts. The entries are keyed by the
+/// filter and the input tree, so they never get stale, but the trees they refer to have
+/// to be in the repository used to look them up.
+/// Loading the cache again, also with a different `path`, replaces the loaded one.
+pub fn load_apply_cache(path: &std::path::Path) -> JoshResult<()> {
+    let mut db = APPLY_DB.lock()?;
+    // The old database has to be closed before the same path can be opened again
+    *db = None;
+    *db = Some(
+        sled::Config::default()
+            .path(path.join(format!("josh/{}/apply/", CACHE_VERSION)))
+            .flush_every_ms(Some(200))
+            .open()?,
+    );
+    Ok(())
+}
+
+/// Close the cache loaded with `load_apply_cache`, writing pending changes to disk.
+/// Results are only kept in memory afterwards.
+pub fn unload_apply_cache() -> JoshResult<()> {
+    let mut db = APPLY_DB.lock()?;
+    if let Some(db) = db.as_ref() {
+        db.flush()?;
+    }
+    *db = None;
+    Ok(())
+}
+
+/// Write pending changes of the cache to disk. They are otherwise only written
+/// periodically, so short lived processes can lose them when exiting.
+pub fn flush() -> JoshResult<()> {
+    if let Some(db) = DB.lock()?.as_ref() {
+        db.flush()?;
+    }
+    if let Some(db) = APPLY_DB.lock()?.as_ref() {
+        db.flush()?;
+    }
+    Ok(())
+}
+
 pub fn print_stats() {
     let d = DB.lock().unwrap();
     let db = d.as_ref().unwrap();
@@ -49,6 +93,10 @@ pub fn print_stats() {
     }
 }
 
+fn stored_apply_key(filter: filter::Filter, from: git2::Oid) -> Vec<u8> {
+    [filter.id().as_bytes(), from.as_bytes()].concat()
+}
+
 #[allow(unused)]
 struct Transaction2 {
     commit_map: HashMap<git2::Oid, HashMap<git2::Oid, git2::Oid>>,
@@ -65,10 +113,26 @@ struct Transaction2 {
     walks: usize,
 }
 
+pub(crate) struct ApplyScope<'a>(&'a Transaction);
+
+impl Drop for ApplyScope<'_> {
+    fn drop(&mut self) {
+        self.0.apply_depth.set(self.0.apply_depth.get() - 1);
+    }
+}
+
+type ProgressCallback = Box<dyn Fn(&history::Progress) + Send>;
+
 pub struct Transaction {
     t2: std::cell::RefCell<Transaction2>,
     repo: git2::Repository,
     ref_prefix: String,
+    progress: Option<ProgressCallback>,
+    shared: bool,
+    deadline: std::cell::Cell<Option<std::time::Instant>>,
+    log_conflicts: std::cell::Cell<bool>,
+    in_memory: std::cell::Cell<bool>,
+    apply_depth: std::cell::Cell<usize>,
 }
 
 impl Transaction {
@@ -142,13 +206,92 @@ impl Transaction {
             }),
             repo,
             ref_prefix: ref_prefix.unwrap_or("").to_string(),
+            progress: None,
+            shared: true,
+            deadline: std::cell::Cell::new(None),
+            log_conflicts: std::cell::Cell::new(false),
+            in_memory: std::cell::Cell::new(false),
+            apply_depth: std::cell::Cell::new(0),
+        }
+    }
+
+    /// Set a callback that is called after each commit filtered during history walks
+    pub fn set_progress(&mut self, progress: impl Fn(&history::Progress) + Send + 'static) {
+        self.progress = Some(Box::new(progress));
+    }
+
+    pub fn report_progress(&self, progress: &history::Progress) {
+        if let Some(callback) = &self.progress {
+            callback(progress);
         }
     }
 
+    /// Make filtering through this transaction fail with `timeout_error` once
+    /// `deadline` has passed, or never if it is `None`
+    pub fn set_deadline(&self, deadline: Option<std::time::Instant>) {
+        self.deadline.set(deadline);
+    }
+
+    pub fn deadline(&self) -> Option<std::time::Instant> {
+        self.deadline.get()
+    }
+
+    /// Called at tree walk boundaries while filtering
+    pub fn check_deadline(&self) -> JoshResult<()> {
+        match self.deadline.get() {
+            Some(deadline) if std::time::Instant::now() >= deadline => Err(timeout_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Log a warning whenever members of a compose produce different content at the same
+    /// path. Only compositions that are actually computed are checked, results found in
+    /// the cache are not logged again.
+    pub fn set_log_conflicts(&self, enabled: bool) {
+        self.log_conflicts.set(enabled);
+    }
+
+    pub fn log_conflicts(&self) -> bool {
+        self.log_conflicts.get()
+    }
+
+    /// Tell the transaction that the objects it writes go to a mempack backend of its
+    /// repo, where other transactions on the same repo can't read them
+    pub fn set_in_memory(&self, in_memory: bool) {
+        self.in_memory.set(in_memory);
+    }
+
+    /// Whether other transactions opened on the same repo can read the objects written
+    /// by this one and share its cached results
+    pub(crate) fn writes_shared(&self) -> bool {
+        self.shared && !self.in_memory.get()
+    }
+
+    /// Track a call of `filter::apply` until the returned scope is dropped. Also returns
+    /// whether no other call is running on this transaction.
+    pub(crate) fn enter_apply(&self) -> (bool, ApplyScope<'_>) {
+        let depth = self.apply_depth.get();
+        self.apply_depth.set(depth + 1);
+        (depth == 0, ApplyScope(self))
+    }
+
+    pub(crate) fn ref_prefix(&self) -> &str {
+        &self.ref_prefix
+    }
+
     pub fn try_clone(&self) -> JoshResult<Transaction> {
         Transaction::open(self.repo.path(), Some(&self.ref_prefix))
     }
 
+    /// Like `try_clone`, but results are only cached within the returned transaction.
+    /// For writing objects that may never end up in the repository, which must not
+    /// be referenced by the caches shared with other transactions.
+    pub fn try_clone_private(&self) -> JoshResult<Transaction> {
+        let mut transaction = self.try_clone()?;
+        transaction.shared = false;
+        Ok(transaction)
+    }
+
     pub fn repo(&self) -> &git2::Repository {
         &self.repo
     }
@@ -179,6 +322,12 @@ impl Transaction {
             .insert(from, to);
     }
 
+    /// Number of results in the memo of `apply`
+    #[cfg(test)]
+    pub(crate) fn apply_len(&self) -> usize {
+        self.t2.borrow().apply_map.values().map(|m| m.len()).sum()
+    }
+
     pub fn get_apply(&self, filter: filter::Filter, from: git2::Oid) -> Option<git2::Oid> {
         let t2 = self.t2.borrow_mut();
         if let Some(m) = t2.apply_map.get(&filter.id()) {
@@ -187,6 +336,34 @@ impl Transaction {
         None
     }
 
+    /// Add a result to the cache loaded with `load_apply_cache`, if there is one
+    pub fn insert_stored_apply(
+        &self,
+        filter: filter::Filter,
+        from: git2::Oid,
+        to: git2::Oid,
+    ) -> JoshResult<()> {
+        if !self.shared {
+            return Ok(());
+        }
+        if let Some(db) = APPLY_DB.lock()?.as_ref() {
+            db.insert(stored_apply_key(filter, from), to.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn get_stored_apply(
+        &self,
+        filter: filter::Filter,
+        from: git2::Oid,
+    ) -> JoshResult<Option<git2::Oid>> {
+        let db = APPLY_DB.lock()?;
+        let db = some_or!(db.as_ref(), { return Ok(None) });
+        Ok(db
+            .get(stored_apply_key(filter, from))?
+            .and_then(|oid| git2::Oid::from_bytes(&oid).ok()))
+    }
+
     pub fn insert_subtract(&self, from: (git2::Oid, git2::Oid), to: git2::Oid) {
         let mut t2 = self.t2.borrow_mut();
         t2.subtract_map.insert(from, to);
@@ -216,6 +393,9 @@ impl Transaction {
     }
 
     pub fn insert_paths(&self, tree: (git2::Oid, String), result: git2::Oid) {
+        if !self.shared {
+            return;
+        }
         let t2 = self.t2.borrow();
         let s = format!("{:?}", tree);
         let x = git2::Oid::hash_object(git2::ObjectType::Blob, s.as_bytes()).expect("hash_object");
@@ -236,6 +416,9 @@ impl Transaction {
     }
 
     pub fn insert_invert(&self, tree: (git2::Oid, String), result: git2::Oid) {
+        if !self.shared {
+            return;
+        }
         let t2 = self.t2.borrow();
         let s = format!("{:?}", tree);
         let x = git2::Oid::hash_object(git2::ObjectType::Blob, s.as_bytes()).expect("hash_object");
@@ -256,6 +439,9 @@ impl Transaction {
     }
 
     pub fn insert_trigram_index(&self, tree: git2::Oid, result: git2::Oid) {
+        if !self.shared {
+            return;
+        }
         let t2 = self.t2.borrow();
         t2.trigram_index_tree
             .insert(tree.as_bytes(), result.as_bytes())
@@ -272,6 +458,9 @@ impl Transaction {
     }
 
     pub fn insert_populate(&self, tree: (git2::Oid, git2::Oid), result: git2::Oid) {
+        if !self.shared {
+            return;
+        }
         POPULATE_MAP.lock().unwrap().entry(tree).or_insert(result);
     }
 
@@ -280,6 +469,9 @@ impl Transaction {
     }
 
     pub fn insert_glob(&self, tree: (git2::Oid, git2::Oid), result: git2::Oid) {
+        if !self.shared {
+            return;
+        }
         GLOB_MAP.lock().unwrap().entry(tree).or_insert(result);
     }
 
@@ -288,6 +480,9 @@ impl Transaction {
     }
 
     pub fn insert_ref(&self, filter: filter::Filter, from: git2::Oid, to: git2::Oid) {
+        if !self.shared {
+            return;
+        }
         REF_CACHE
             .lock()
             .unwrap()
@@ -325,7 +520,7 @@ impl Transaction {
         // In addition to commits that are explicitly requested to be stored, also store
         // random extra commits (probability 1/256) to avoid long searches for filters that reduce
         // the history length by a very large factor.
-        if store || from.as_bytes()[0] == 0 {
+        if self.shared && (store || from.as_bytes()[0] == 0) {
             let t = t2.sled_trees.entry(filter.id()).or_insert_with(|| {
                 DB.lock()
                     .unwrap()
@@ -0,0 +1,157 @@
+/*
+ * Deterministic archives of trees.
+ *
+ * The output only depends on the content of the tree: entries are written in the order
+ * of a pre-order walk of the (sorted) tree, and timestamps, owners and permissions are
+ * fixed. Archiving the same tree therefore always produces the same blob.
+ */
+
+use super::*;
+
+const BLOCK: usize = 512;
+
+/// Archive formats supported by `:archive`
+pub const FORMATS: &[&str] = &["tar"];
+
+/// Replace `input` by a tree containing only an archive of it, stored at `path`
+pub fn archive<'a>(
+    transaction: &'a cache::Transaction,
+    format: &str,
+    path: &Path,
+    input: git2::Oid,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    if let Some(cached) = transaction.get_glob((input, key)) {
+        return Ok(repo.find_tree(cached)?);
+    }
+
+    let result = if input == tree::empty_id() {
+        tree::empty(repo)
+    } else {
+        let data = match format {
+            "tar" => tar(repo, &repo.find_tree(input)?)?,
+            _ => {
+                return Err(josh_error(&format!(
+                    "Unsupported archive format: {}",
+                    format
+                )))
+            }
+        };
+        let blob = write_blob(repo, &data)?;
+        tree::insert(repo, &tree::empty(repo), path, blob, 0o0100644)?
+    };
+
+    transaction.insert_glob((input, key), result.id());
+    Ok(result)
+}
+
+/// Write `tree` as an ustar archive
+fn tar(repo: &git2::Repository, tree: &git2::Tree) -> JoshResult<Vec<u8>> {
+    let mut out = vec![];
+    let mut error = None;
+
+    tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+        let name = format!("{}{}", root, entry.name().unwrap_or(""));
+        let written = match (entry.kind(), entry.filemode()) {
+            (Some(git2::ObjectType::Tree), _) => {
+                tar_entry(&mut out, &format!("{}/", name), 0o755, b'5', "", &[])
+            }
+            (Some(git2::ObjectType::Blob), 0o0120000) => repo
+                .find_blob(entry.id())
+                .map_err(JoshError::from)
+                .and_then(|blob| {
+                    let target = std::str::from_utf8(blob.content())
+                        .map_err(|_| josh_error("Symlink target is not utf-8"))?;
+                    tar_entry(&mut out, &name, 0o777, b'2', target, &[])
+                }),
+            (Some(git2::ObjectType::Blob), mode) => repo
+                .find_blob(entry.id())
+                .map_err(JoshError::from)
+                .and_then(|blob| {
+                    let mode = if mode == 0o0100755 { 0o755 } else { 0o644 };
+                    tar_entry(&mut out, &name, mode, b'0', "", blob.content())
+                }),
+            // Submodules have no content that could be archived
+            _ => Ok(()),
+        };
+        match written {
+            Ok(()) => git2::TreeWalkResult::Ok,
+            Err(e) => {
+                error = Some(e);
+                git2::TreeWalkResult::Abort
+            }
+        }
+    })?;
+
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    out.resize(out.len() + 2 * BLOCK, 0);
+    Ok(out)
+}
+
+fn tar_entry(
+    out: &mut Vec<u8>,
+    path: &str,
+    mode: u32,
+    kind: u8,
+    link: &str,
+    content: &[u8],
+) -> JoshResult<()> {
+    let mut header = [0u8; BLOCK];
+    let (prefix, name) = split_tar_path(path)?;
+    if link.len() > 100 {
+        return Err(josh_error(&format!(
+            "Symlink target too long for tar: {}",
+            link
+        )));
+    }
+    if content.len() as u64 >= 1 << 33 {
+        return Err(josh_error(&format!("File too large for tar: {}", path)));
+    }
+
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], mode as u64);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], content.len() as u64);
+    octal(&mut header[136..148], 0);
+    header[148..156].fill(b' ');
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    octal(&mut header[329..337], 0);
+    octal(&mut header[337..345], 0);
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    let checksum: u64 = header.iter().map(|b| *b as u64).sum();
+    octal(&mut header[148..155], checksum);
+
+    out.extend_from_slice(&header);
+    out.extend_from_slice(content);
+    out.resize(out.len().div_ceil(BLOCK) * BLOCK, 0);
+    Ok(())
+}
+
+/// Split a path into the `prefix` and `name` fields of an ustar header
+fn split_tar_path(path: &str) -> JoshResult<(&str, &str)> {
+    if path.len() <= 100 {
+        return Ok(("", path));
+    }
+    let trimmed = path.trim_end_matches('/');
+    path.char_indices()
+        .filter(|(i, c)| *c == '/' && *i < trimmed.len())
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100)
+        .ok_or_else(|| josh_error(&format!("Path too long for tar: {}", path)))
+}
+
+/// Write `value` as zero padded octal number followed by a NUL byte
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
@@ -0,0 +1,211 @@
+/*
+ * Compact binary encoding of filters.
+ *
+ * The encoding starts with a version byte followed by the root node. Every node
+ * starts with a tag byte. The most common ops and all ops containing other filters
+ * have dedicated tags, everything else is stored as its spec.
+ * Strings and lists are prefixed with their length as unsigned LEB128.
+ */
+
+use super::*;
+
+const VERSION: u8 = 1;
+
+/// Limit for the nesting of decoded filters, so corrupt input can't overflow the stack
+const MAX_DEPTH: usize = 1000;
+
+const TAG_NOP: u8 = 0;
+const TAG_EMPTY: u8 = 1;
+const TAG_SUBDIR: u8 = 2;
+const TAG_PREFIX: u8 = 3;
+const TAG_FILE: u8 = 4;
+const TAG_GLOB: u8 = 5;
+const TAG_COMPOSE: u8 = 6;
+const TAG_CHAIN: u8 = 7;
+const TAG_SUBTRACT: u8 = 8;
+const TAG_EXCLUDE: u8 = 9;
+const TAG_INTERSECT: u8 = 10;
+const TAG_SPEC: u8 = 255;
+
+/// Encode a filter in the binary format understood by `from_bytes`
+pub fn to_bytes(filter: Filter) -> Vec<u8> {
+    let mut out = vec![VERSION];
+    write_filter(&mut out, filter);
+    out
+}
+
+/// Decode a filter encoded with `to_bytes`.
+/// Ops without a dedicated tag are parsed from their spec, so they are returned
+/// in optimized form.
+pub fn from_bytes(bytes: &[u8]) -> JoshResult<Filter> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(josh_error(&format!(
+            "Unsupported binary filter version: {}",
+            version
+        )));
+    }
+    let filter = reader.filter(0)?;
+    if reader.pos != bytes.len() {
+        return Err(josh_error("Invalid binary filter: trailing bytes"));
+    }
+    Ok(filter)
+}
+
+fn write_len(out: &mut Vec<u8>, mut n: usize) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_len(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_path(out: &mut Vec<u8>, path: &Path) {
+    write_str(out, &path.to_string_lossy());
+}
+
+fn write_filter(out: &mut Vec<u8>, filter: Filter) {
+    match to_op(filter) {
+        Op::Nop => out.push(TAG_NOP),
+        Op::Empty => out.push(TAG_EMPTY),
+        Op::Subdir(path) => {
+            out.push(TAG_SUBDIR);
+            write_path(out, &path);
+        }
+        Op::Prefix(path) => {
+            out.push(TAG_PREFIX);
+            write_path(out, &path);
+        }
+        Op::File(path) => {
+            out.push(TAG_FILE);
+            write_path(out, &path);
+        }
+        Op::Glob(pattern) => {
+            out.push(TAG_GLOB);
+            write_str(out, &pattern);
+        }
+        Op::Compose(filters) => {
+            out.push(TAG_COMPOSE);
+            write_len(out, filters.len());
+            for f in filters {
+                write_filter(out, f);
+            }
+        }
+        Op::Chain(a, b) => {
+            out.push(TAG_CHAIN);
+            write_filter(out, a);
+            write_filter(out, b);
+        }
+        Op::Subtract(a, b) => {
+            out.push(TAG_SUBTRACT);
+            write_filter(out, a);
+            write_filter(out, b);
+        }
+        Op::Exclude(b) => {
+            out.push(TAG_EXCLUDE);
+            write_filter(out, b);
+        }
+        Op::Intersect(a, b) => {
+            out.push(TAG_INTERSECT);
+            write_filter(out, a);
+            write_filter(out, b);
+        }
+        op => {
+            out.push(TAG_SPEC);
+            write_str(out, &spec2(&op));
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> JoshResult<u8> {
+        let b = *self
+            .bytes
+            .get(self.pos)
+            .ok_or_else(|| josh_error("Invalid binary filter: truncated"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn len(&mut self) -> JoshResult<usize> {
+        let mut n: usize = 0;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            if shift >= usize::BITS {
+                return Err(josh_error("Invalid binary filter: length overflow"));
+            }
+            n |= ((b & 0x7f) as usize) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+            shift += 7;
+        }
+    }
+
+    fn str(&mut self) -> JoshResult<String> {
+        let len = self.len()?;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| josh_error("Invalid binary filter: truncated"))?;
+        let s = std::str::from_utf8(&self.bytes[self.pos..end])
+            .map_err(|_| josh_error("Invalid binary filter: string is not utf-8"))?;
+        self.pos = end;
+        Ok(s.to_string())
+    }
+
+    fn path(&mut self) -> JoshResult<std::path::PathBuf> {
+        Ok(std::path::PathBuf::from(self.str()?))
+    }
+
+    fn filter(&mut self, depth: usize) -> JoshResult<Filter> {
+        if depth > MAX_DEPTH {
+            return Err(josh_error("Invalid binary filter: nested too deeply"));
+        }
+        let op = match self.byte()? {
+            TAG_NOP => Op::Nop,
+            TAG_EMPTY => Op::Empty,
+            TAG_SUBDIR => Op::Subdir(self.path()?),
+            TAG_PREFIX => Op::Prefix(self.path()?),
+            TAG_FILE => Op::File(self.path()?),
+            TAG_GLOB => Op::Glob(self.str()?),
+            TAG_COMPOSE => {
+                let n = self.len()?;
+                let mut filters = vec![];
+                for _ in 0..n {
+                    filters.push(self.filter(depth + 1)?);
+                }
+                Op::Compose(filters)
+            }
+            TAG_CHAIN => Op::Chain(self.filter(depth + 1)?, self.filter(depth + 1)?),
+            TAG_SUBTRACT => Op::Subtract(self.filter(depth + 1)?, self.filter(depth + 1)?),
+            TAG_EXCLUDE => Op::Exclude(self.filter(depth + 1)?),
+            TAG_INTERSECT => Op::Intersect(self.filter(depth + 1)?, self.filter(depth + 1)?),
+            TAG_SPEC => return parse::parse(&self.str()?),
+            tag => {
+                return Err(josh_error(&format!(
+                    "Invalid binary filter: unknown tag {}",
+                    tag
+                )))
+            }
+        };
+        Ok(to_filter(op))
+    }
+}
@@ -0,0 +1,101 @@
+/*
+ * Ownership of paths as declared by a CODEOWNERS file, for `:owned`.
+ *
+ * Patterns follow the rules of gitignore: a pattern without a slash matches at any
+ * depth, a leading slash anchors it at the root, and a pattern matching a directory
+ * matches everything below it. Of all the lines matching a path, the last one wins.
+ */
+
+use super::*;
+
+/// Location of the CODEOWNERS file used by `:owned` unless another one is given
+pub const DEFAULT_PATH: &str = ".github/CODEOWNERS";
+
+const MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+struct Rule {
+    /// Alternative globs, the rule matches if any of them does
+    patterns: Vec<glob::Pattern>,
+    owners: Vec<String>,
+}
+
+fn parse_rules(content: &str) -> Vec<Rule> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace().take_while(|w| !w.starts_with('#'));
+            let pattern = words.next()?;
+            Some(Rule {
+                patterns: globs(pattern),
+                owners: words.map(str::to_string).collect(),
+            })
+        })
+        .collect()
+}
+
+fn globs(pattern: &str) -> Vec<glob::Pattern> {
+    let dir_only = pattern.ends_with('/');
+    let trimmed = pattern.trim_end_matches('/');
+    let anchored = trimmed.contains('/');
+    let trimmed = trimmed.trim_start_matches('/');
+    let base = if anchored || trimmed.starts_with("**") {
+        trimmed.to_string()
+    } else {
+        format!("**/{}", trimmed)
+    };
+
+    let mut alternatives = vec![];
+    // Like on GitHub, `docs/*` only matches the files directly inside of `docs`
+    if dir_only || !base.rsplit('/').next().unwrap_or_default().contains('*') {
+        alternatives.push(format!("{}/**", base));
+    }
+    if !dir_only {
+        alternatives.push(base);
+    }
+    // Invalid patterns can't match anything, like they are ignored by git
+    alternatives
+        .iter()
+        .filter_map(|p| glob::Pattern::new(p).ok())
+        .collect()
+}
+
+/// Returns whether `owner` is one of the owners of `path`. Owners are compared ignoring
+/// case, paths without a matching line, or whose last matching line lists no owners, are
+/// not owned by anyone.
+fn is_owned_by(rules: &[Rule], path: &Path, owner: &str) -> bool {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| {
+            rule.patterns
+                .iter()
+                .any(|p| p.matches_path_with(path, MATCH_OPTIONS))
+        })
+        .is_some_and(|rule| rule.owners.iter().any(|o| o.eq_ignore_ascii_case(owner)))
+}
+
+/// Keep only the files of `input` that are owned by `owner` according to the CODEOWNERS
+/// file at `path` of `input`. Without such a file nothing is kept.
+pub fn owned<'a>(
+    transaction: &'a cache::Transaction,
+    owner: &str,
+    path: &Path,
+    input: git2::Oid,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    let tree = repo.find_tree(input)?;
+    let content = match tree.get_path(path) {
+        Ok(entry) if entry.kind() == Some(git2::ObjectType::Blob) => {
+            String::from_utf8_lossy(repo.find_blob(entry.id())?.content()).into_owned()
+        }
+        _ => return Ok(tree::empty(repo)),
+    };
+    let rules = parse_rules(&content);
+
+    tree::keep_paths(transaction, input, &|p| is_owned_by(&rules, p, owner), key)
+}
@@ -0,0 +1,60 @@
+/*
+ * Static estimate of how expensive a filter is to apply, for rejecting or
+ * deprioritizing filters before running them.
+ */
+
+use super::*;
+
+/// Weight of ops that walk the full trees of both of their sides
+const FULL_WALK: u64 = 8;
+
+/// Estimated cost of a filter, as returned by `estimate_cost`
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct FilterCost {
+    /// Total score, the sum of `by_kind`
+    pub total: u64,
+    /// Score attributed to each kind of op, using the kind names of `explain`
+    pub by_kind: std::collections::BTreeMap<String, u64>,
+}
+
+/// Estimate the cost of applying the optimized form of `filter`.
+/// The score has no unit, it is only meant to be compared against other scores
+/// or a configured ceiling.
+pub fn estimate_cost(filter: Filter) -> FilterCost {
+    cost_of(optimize(filter))
+}
+
+/// Estimate the cost of `filter` as it is, without optimizing it first
+pub(crate) fn cost_of(filter: Filter) -> FilterCost {
+    let mut cost = FilterCost::default();
+    add(&explain(filter), &mut cost);
+    cost
+}
+
+fn add(node: &FilterExplanation, cost: &mut FilterCost) {
+    let components = || {
+        node.args
+            .iter()
+            .map(|p| Path::new(p).components().count() as u64)
+            .sum::<u64>()
+    };
+    let own = match node.kind.as_str() {
+        "nop" | "empty" => 0,
+        "chain" => 1,
+        // Every member is applied to the full input and the results merged
+        "compose" => 2 * node.children.len() as u64,
+        "subtract" | "intersect" | "exclude" => FULL_WALK,
+        "subdir" | "prefix" | "file" => components(),
+        "workspace" => 4 + components(),
+        "glob" => 3,
+        "rev" | "join" => 4,
+        _ => 2,
+    };
+    if own > 0 {
+        *cost.by_kind.entry(node.kind.clone()).or_insert(0) += own;
+        cost.total += own;
+    }
+    for child in &node.children {
+        add(child, cost);
+    }
+}
@@ -0,0 +1,168 @@
+/*
+ * Structured description of filters, for tools that render them as a tree.
+ */
+
+use super::*;
+
+/// One node of a filter, as returned by `explain`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct FilterExplanation {
+    /// Name of the op, like `subdir` or `compose`. Ops without structure that is
+    /// relevant for a tree view are reported as `other`.
+    pub kind: String,
+    /// Paths, patterns or refs the op takes
+    pub args: Vec<String>,
+    /// Explanations of the filters the op contains, in the order they are applied
+    /// (chain) or listed (compose). For `rev` and `join` they match `args`.
+    pub children: Vec<FilterExplanation>,
+    /// Spec of the whole node
+    pub spec: String,
+}
+
+/// Describe `filter` as a tree of its ops.
+/// The filter is described as it is, so callers that want to show what is actually
+/// applied need to pass an optimized filter.
+pub fn explain(filter: Filter) -> FilterExplanation {
+    let path = |p: &Path| vec![p.to_string_lossy().to_string()];
+    let (kind, args, children) = match to_op(filter) {
+        Op::Nop => ("nop", vec![], vec![]),
+        Op::Empty => ("empty", vec![], vec![]),
+        Op::Subdir(p) => ("subdir", path(&p), vec![]),
+        Op::Prefix(p) => ("prefix", path(&p), vec![]),
+        Op::File(p) => ("file", path(&p), vec![]),
+        Op::Workspace(p) => ("workspace", path(&p), vec![]),
+        Op::Glob(pattern) => ("glob", vec![pattern], vec![]),
+        Op::Compose(filters) => ("compose", vec![], filters),
+        Op::Chain(a, b) => ("chain", vec![], vec![a, b]),
+        Op::Subtract(a, b) => ("subtract", vec![], vec![a, b]),
+        Op::Intersect(a, b) => ("intersect", vec![], vec![a, b]),
+        Op::Exclude(b) => ("exclude", vec![], vec![b]),
+        Op::Rev(filters) => (
+            "rev",
+            filters.keys().map(LazyRef::to_string).collect(),
+            filters.into_values().collect(),
+        ),
+        Op::Join(filters) => (
+            "join",
+            filters.keys().map(LazyRef::to_string).collect(),
+            filters.into_values().collect(),
+        ),
+        _ => ("other", vec![], vec![]),
+    };
+
+    FilterExplanation {
+        kind: kind.to_string(),
+        args,
+        children: children.into_iter().map(explain).collect(),
+        spec: spec(filter),
+    }
+}
+
+/// Set of paths selected in place by a simple filter, as returned by `include_exclude`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathPattern {
+    /// Everything below a directory, the whole tree for the empty path
+    Dir(std::path::PathBuf),
+    File(std::path::PathBuf),
+    Glob(String),
+}
+
+impl std::fmt::Display for PathPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathPattern::Dir(path) if path.as_os_str().is_empty() => write!(f, "/"),
+            PathPattern::Dir(path) => write!(f, "{}/", path.display()),
+            PathPattern::File(path) => write!(f, "{}", path.display()),
+            PathPattern::Glob(pattern) => write!(f, "{}", pattern),
+        }
+    }
+}
+
+/// Describe `filter` as the paths it includes minus the paths it excludes, if it only
+/// keeps paths of its input in place. Composes on either side are flattened.
+/// Returns `None` for filters that move, rewrite or generate paths, which can only be
+/// described by their spec. Like `explain`, this looks at the filter as it is, so it is
+/// meant to be called with an optimized filter.
+pub fn include_exclude(filter: Filter) -> Option<(Vec<PathPattern>, Vec<PathPattern>)> {
+    let only_includes = |f| match include_exclude(f)? {
+        (include, exclude) if exclude.is_empty() => Some(include),
+        _ => None,
+    };
+    match to_op(filter) {
+        Op::Empty => Some((vec![], vec![])),
+        Op::Subtract(a, b) => {
+            let (include, mut exclude) = include_exclude(a)?;
+            exclude.extend(only_includes(b)?);
+            Some((include, exclude))
+        }
+        Op::Exclude(b) => Some((
+            vec![PathPattern::Dir(std::path::PathBuf::new())],
+            only_includes(b)?,
+        )),
+        Op::Chain(_, _) => {
+            let mut parts = as_chain_vec(filter);
+            let excluded = match to_op(*parts.last()?) {
+                Op::Exclude(b) => only_includes(b)?,
+                _ => return Some((vec![path_pattern(filter)?], vec![])),
+            };
+            parts.pop();
+            let rest = parts
+                .into_iter()
+                .reduce(|a, b| to_filter(Op::Chain(a, b)))?;
+            let (include, mut exclude) = include_exclude(rest)?;
+            exclude.extend(excluded);
+            Some((include, exclude))
+        }
+        Op::Compose(filters) => {
+            let include = filters
+                .into_iter()
+                .map(only_includes)
+                .collect::<Option<Vec<_>>>()?;
+            Some((include.concat(), vec![]))
+        }
+        Op::Select(include, exclude) => Some((
+            include.into_iter().map(PathPattern::Glob).collect(),
+            exclude.into_iter().map(PathPattern::Glob).collect(),
+        )),
+        _ => Some((vec![path_pattern(filter)?], vec![])),
+    }
+}
+
+/// Pattern of a filter like `::dir/`, `::dir/file` or `::dir/*.rs`, also in the form
+/// `optimize` gives them, where the directory is taken with subdirs and restored by
+/// prefixes around the rest
+fn path_pattern(filter: Filter) -> Option<PathPattern> {
+    let parts: Vec<_> = as_chain_vec(filter).into_iter().map(to_op).collect();
+    let subdirs = parts
+        .iter()
+        .take_while(|op| matches!(op, Op::Subdir(_)))
+        .count();
+    let mut dir = std::path::PathBuf::new();
+    for op in &parts[..subdirs] {
+        if let Op::Subdir(p) = op {
+            dir.push(p);
+        }
+    }
+    let (inner, prefixes) = match parts.get(subdirs) {
+        Some(Op::Nop) => (Op::Nop, &parts[subdirs + 1..]),
+        Some(Op::Prefix(_)) | None => (Op::Nop, &parts[subdirs..]),
+        Some(inner) => (inner.clone(), &parts[subdirs + 1..]),
+    };
+    let mut prefix = std::path::PathBuf::new();
+    for op in prefixes.iter().rev() {
+        match op {
+            Op::Prefix(p) => prefix.push(p),
+            _ => return None,
+        }
+    }
+    if prefix != dir {
+        return None;
+    }
+    match inner {
+        Op::Nop => Some(PathPattern::Dir(dir)),
+        Op::File(path) => Some(PathPattern::File(dir.join(path))),
+        Op::Glob(pattern) if subdirs == 0 => Some(PathPattern::Glob(pattern)),
+        Op::Glob(pattern) => Some(PathPattern::Glob(format!("{}/{}", dir.display(), pattern))),
+        _ => None,
+    }
+}
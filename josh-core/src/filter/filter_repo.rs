@@ -0,0 +1,181 @@
+/*
+ * Translation of simple filters into arguments of `git filter-repo`, for users moving
+ * a history rewrite to a one-off run of that tool.
+ */
+
+use super::*;
+
+/// Arguments for `git filter-repo` that rewrite the trees of a history the same way as
+/// `filter`, or `None` if it does more than selecting paths and moving them into or out
+/// of a directory. Globs are not translated, as `--path-glob` lets `*` match slashes.
+/// Only trees are covered, filter-repo keeps or prunes commits by its own rules.
+pub fn to_filter_repo(filter: Filter) -> Option<Vec<String>> {
+    let filter = optimize(filter);
+    match to_op(filter) {
+        Op::Nop => return Some(vec![]),
+        Op::Empty => return None,
+        _ => {}
+    }
+    if let Some((include, exclude)) = include_exclude(filter) {
+        return selection_args(&include, &exclude);
+    }
+
+    let parts = as_chain_vec(filter);
+    let subdirs = parts
+        .iter()
+        .take_while(|f| matches!(to_op(**f), Op::Subdir(_)))
+        .count();
+    let prefixes = parts
+        .iter()
+        .rev()
+        .take_while(|f| matches!(to_op(**f), Op::Prefix(_)))
+        .count();
+
+    // The optimized form of in place selections also starts with subdirs and ends
+    // with prefixes, so try leaving some of them to the selection in the middle
+    for s in (0..=subdirs).rev() {
+        for p in (0..=prefixes.min(parts.len() - s)).rev() {
+            if let Some(args) = split_args(&parts, s, p) {
+                return Some(args);
+            }
+        }
+    }
+    None
+}
+
+/// Arguments for the first `s` parts being subdirs, the last `p` parts being prefixes
+/// and the parts in between selecting paths in place
+fn split_args(parts: &[Filter], s: usize, p: usize) -> Option<Vec<String>> {
+    let mut dir = std::path::PathBuf::new();
+    for f in &parts[..s] {
+        if let Op::Subdir(d) = to_op(*f) {
+            dir.push(d);
+        }
+    }
+    let mut prefix = std::path::PathBuf::new();
+    for f in parts[parts.len() - p..].iter().rev() {
+        if let Op::Prefix(d) = to_op(*f) {
+            prefix.push(d);
+        }
+    }
+
+    let middle = &parts[s..parts.len() - p];
+    let (include, exclude) = match middle
+        .iter()
+        .copied()
+        .reduce(|a, b| to_filter(Op::Chain(a, b)))
+    {
+        Some(f) => include_exclude(f)?,
+        None => (vec![PathPattern::Dir(std::path::PathBuf::new())], vec![]),
+    };
+    // `join` would add a trailing slash for the empty path
+    let join = |p: std::path::PathBuf| {
+        if p.as_os_str().is_empty() {
+            dir.clone()
+        } else {
+            dir.join(p)
+        }
+    };
+    let below = |patterns: Vec<PathPattern>| {
+        patterns
+            .into_iter()
+            .map(|pattern| match pattern {
+                PathPattern::Dir(d) => Some(PathPattern::Dir(join(d))),
+                PathPattern::File(f) => Some(PathPattern::File(join(f))),
+                PathPattern::Glob(_) => None,
+            })
+            .collect::<Option<Vec<_>>>()
+    };
+    let (include, exclude) = (below(include)?, below(exclude)?);
+
+    let everything = include == [PathPattern::Dir(dir.clone())] && exclude.is_empty();
+    let [dir, prefix] = [&dir, &prefix].map(|p| p.to_string_lossy().to_string());
+    Some(match (dir.is_empty(), prefix.is_empty()) {
+        (false, true) if everything => vec!["--subdirectory-filter".to_string(), dir],
+        (true, false) if everything => vec!["--to-subdirectory-filter".to_string(), prefix],
+        _ => {
+            let mut args = selection_args(&include, &exclude)?;
+            if !dir.is_empty() || !prefix.is_empty() {
+                let to = if prefix.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}/", prefix)
+                };
+                let from = if dir.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}/", dir)
+                };
+                args.extend(["--path-rename".to_string(), format!("{}:{}", from, to)]);
+            }
+            args
+        }
+    })
+}
+
+/// Arguments keeping the paths matching `include` but not `exclude`, in place
+fn selection_args(include: &[PathPattern], exclude: &[PathPattern]) -> Option<Vec<String>> {
+    if include.is_empty() {
+        return None;
+    }
+    let whole_tree = include
+        .iter()
+        .any(|p| matches!(p, PathPattern::Dir(d) if d.as_os_str().is_empty()));
+
+    let mut args = vec![];
+    if exclude.is_empty() && !whole_tree {
+        for pattern in include {
+            args.extend(path_args(pattern)?);
+        }
+    } else if whole_tree {
+        for pattern in exclude {
+            args.extend(path_args(pattern)?);
+        }
+        if !args.is_empty() {
+            args.push("--invert-paths".to_string());
+        }
+    } else {
+        // `--invert-paths` applies to all of the paths given, so a selection with
+        // exclusions becomes a single regex with a negative lookahead
+        let alternatives = |patterns: &[PathPattern]| {
+            patterns
+                .iter()
+                .map(path_regex)
+                .collect::<Option<Vec<_>>>()
+                .map(|v| v.join("|"))
+        };
+        args.extend([
+            "--path-regex".to_string(),
+            format!(
+                "^(?!{})(?:{})",
+                alternatives(exclude)?,
+                alternatives(include)?
+            ),
+        ]);
+    }
+    Some(args)
+}
+
+fn path_args(pattern: &PathPattern) -> Option<Vec<String>> {
+    match pattern {
+        PathPattern::Dir(d) => Some(vec![
+            "--path".to_string(),
+            format!("{}/", d.to_string_lossy()),
+        ]),
+        // `--path` would also match a directory of that name
+        PathPattern::File(_) => Some(vec![
+            "--path-regex".to_string(),
+            format!("^{}", path_regex(pattern)?),
+        ]),
+        PathPattern::Glob(_) => None,
+    }
+}
+
+fn path_regex(pattern: &PathPattern) -> Option<String> {
+    match pattern {
+        PathPattern::Dir(d) if d.as_os_str().is_empty() => Some(String::new()),
+        PathPattern::Dir(d) => Some(format!("{}/", regex::escape(&d.to_string_lossy()))),
+        PathPattern::File(f) => Some(format!("{}$", regex::escape(&f.to_string_lossy()))),
+        PathPattern::Glob(_) => None,
+    }
+}
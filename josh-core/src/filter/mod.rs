@@ -1,14 +1,44 @@
 use super::*;
 use history::RewriteData;
 use pest::Parser;
+use rayon::prelude::*;
 use std::path::Path;
+mod archive;
+mod binary;
+mod codeowners;
+mod cost;
+mod explain;
+mod filter_repo;
 mod opt;
 mod parse;
+mod portability;
+mod sink;
 pub mod tree;
+mod visitor;
 
+pub use binary::{from_bytes, to_bytes};
+pub use cost::{estimate_cost, FilterCost};
+pub use explain::{explain, include_exclude, FilterExplanation, PathPattern};
+pub use filter_repo::to_filter_repo;
 pub use opt::invert;
+pub use opt::optimize;
+pub use opt::optimize_interleaved;
+pub use opt::optimize_with_limit;
+pub use opt::set_opt_cache_capacity;
+pub use opt::{
+    clear_optimization_caches, optimization_cache_stats, register_fixpoints, CacheStats,
+};
+pub use opt::{denormalize, optimize_for_size};
+pub use opt::{optimize_traced, optimize_with_stats, rule_names, OptimizationStep, RuleStats};
 pub use parse::get_comments;
 pub use parse::parse;
+pub use parse::parse_with_delimiter;
+pub use parse::parse_workspace_toml;
+pub use portability::{
+    validate_output_portability, Platform, PortabilityIssue, PortabilityProblem,
+};
+pub use sink::{apply_with_sink, MemorySink, NullSink, ObjectSink, OdbSink};
+pub use visitor::{walk, FilterVisitor};
 
 lazy_static! {
     static ref FILTERS: std::sync::Mutex<std::collections::HashMap<Filter, Op>> =
@@ -17,8 +47,31 @@ lazy_static! {
         std::sync::Mutex::new(std::collections::HashMap::new());
     static ref ANCESTORS: std::sync::Mutex<std::collections::HashMap<git2::Oid, std::collections::HashSet<git2::Oid>>> =
         std::sync::Mutex::new(std::collections::HashMap::new());
+    static ref SPEC_CACHE: opt::OptCache<String> = opt::opt_cache();
 }
 
+const GLOB_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: true,
+};
+
+/// Directory that `:notes` stores the notes of commits in, one file per commit
+const NOTES_DIR: &str = ".notes";
+
+/// Patterns of the files selected by `:docs`
+pub const DOCS_PATTERNS: &[&str] = &[
+    "**/README*",
+    "**/*.md",
+    "**/*.rst",
+    "**/*.adoc",
+    "**/docs/**",
+    "**/doc/**",
+];
+
+/// File read by `:self` to find the filter a tree wants to be viewed through
+const SELF_VIEW_FILE: &str = ".josh-view";
+
 /// Filters are represented as `git2::Oid`, however they are not ever stored
 /// inside the repo.
 #[derive(
@@ -145,6 +198,12 @@ enum Op {
     Join(std::collections::BTreeMap<LazyRef, Filter>),
     Linear,
     Unsign,
+    OriginTrailer,
+    RewriteMessage(Vec<(regex::Regex, String)>),
+    Take(usize),
+    KeepVersions(usize),
+    Notes(String),
+    LastAuthor(String),
 
     RegexReplace(Vec<(regex::Regex, String)>),
 
@@ -155,14 +214,38 @@ enum Op {
     Prefix(std::path::PathBuf),
     Subdir(std::path::PathBuf),
     Workspace(std::path::PathBuf),
+    SelfView,
 
     Glob(String),
+    PathRegex(regex::Regex),
+    ContentGrep(regex::Regex),
+    BinaryOnly,
+    TextOnly,
+    LfsOnly,
+    Owned(String, std::path::PathBuf),
+    TouchedBy(git2::Oid),
+    DiffRange(git2::Oid, git2::Oid),
+    AddedSince(git2::Oid),
+    IntersectRefs(Vec<git2::Oid>, bool),
+    LowercasePaths,
+    FlattenSingleChild,
+    Explode(String),
+    StripExtension(String),
+    FlattenWithPathNames(String),
+    DedupBlobs,
+    ContentToHash,
+    Archive(String, std::path::PathBuf),
+    Select(Vec<String>, Vec<String>),
+    Docs,
     Message(String),
 
     Compose(Vec<Filter>),
     Chain(Filter, Filter),
     Subtract(Filter, Filter),
+    Intersect(Filter, Filter),
     Exclude(Filter),
+    /// Apply the first filter if the path exists in the input tree, the second otherwise
+    IfExists(std::path::PathBuf, Filter, Filter),
 }
 
 /// Pretty print the filter on multiple lines with initial indentation level.
@@ -204,14 +287,13 @@ fn pretty2(op: &Op, indent: usize, compose: bool) -> String {
     match op {
         Op::Compose(filters) => ff(filters, "", indent),
         Op::Subtract(af, bf) => ff(&vec![*af, *bf], "subtract", indent + 4),
+        Op::Intersect(af, bf) => ff(&vec![*af, *bf], "intersect", indent + 4),
         Op::Exclude(bf) => match to_op(*bf) {
             Op::Compose(filters) => ff(&filters, "exclude", indent),
             b => format!(":exclude[{}]", pretty2(&b, indent, false)),
         },
         Op::Chain(a, b) => match (to_op(*a), to_op(*b)) {
-            (Op::Subdir(p1), Op::Prefix(p2)) if p1 == p2 => {
-                format!("::{}/", parse::quote_if(&p1.to_string_lossy()))
-            }
+            (Op::Subdir(p1), Op::Prefix(p2)) if p1 == p2 => format!("::{}", quote_dir(&p1)),
             (a, Op::Prefix(p)) if compose => {
                 format!(
                     "{} = {}",
@@ -239,6 +321,20 @@ fn pretty2(op: &Op, indent: usize, compose: bool) -> String {
                 .collect::<Vec<_>>();
             format!(":replace(\n{}\n)", v.join("\n"))
         }
+        Op::RewriteMessage(rules) => {
+            let v = rules
+                .iter()
+                .map(|(regex, r)| {
+                    format!(
+                        "{}{}:{}",
+                        " ".repeat(indent),
+                        parse::quote(&regex.to_string()),
+                        parse::quote(r)
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!(":rewrite-message(\n{}\n)", v.join("\n"))
+        }
         Op::Squash(Some(ids)) => {
             let mut v = ids
                 .iter()
@@ -251,6 +347,101 @@ fn pretty2(op: &Op, indent: usize, compose: bool) -> String {
     }
 }
 
+/// Split a chain into its segments in application order.
+/// Chains are flattened regardless of how they are nested, any other filter
+/// is returned as a single segment.
+pub fn as_chain_vec(filter: Filter) -> Vec<Filter> {
+    match to_op(filter) {
+        Op::Chain(a, b) => {
+            let mut v = as_chain_vec(a);
+            v.append(&mut as_chain_vec(b));
+            v
+        }
+        _ => vec![filter],
+    }
+}
+
+/// Split a filter into `(tree_part, history_part)`, such that chaining them gives
+/// back the original filter. `tree_part` is the longest leading part of the chain that
+/// only transforms trees, so it can be applied to each commit's tree independently of
+/// history. Commit metadata is considered part of the history, so filters like
+/// `:author` or `:linear` and anything after them end up in `history_part`.
+/// Either part may be `:/` if there is nothing to split off.
+pub fn split_history_boundary(filter: Filter) -> (Filter, Filter) {
+    let segments = as_chain_vec(filter);
+    let n = segments
+        .iter()
+        .take_while(|f| is_tree_op(&to_op(**f)))
+        .count();
+    let join = |segments: &[Filter]| {
+        segments
+            .iter()
+            .rev()
+            .copied()
+            .reduce(|b, a| to_filter(Op::Chain(a, b)))
+            .unwrap_or_else(|| to_filter(Op::Nop))
+    };
+    (join(&segments[..n]), join(&segments[n..]))
+}
+
+/// Path of a `::dir/` filter. The trailing slash has to be inside of the quotes,
+/// as the parser only looks for it after unquoting.
+fn quote_dir(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if parse::quote_if(&path) == path {
+        format!("{}/", path)
+    } else {
+        parse::quote(&format!("{}/", path))
+    }
+}
+
+/// Whether `filter` only changes the trees of commits, not the history
+pub(crate) fn is_tree_filter(filter: Filter) -> bool {
+    is_tree_op(&to_op(filter))
+}
+
+fn is_tree_op(op: &Op) -> bool {
+    match op {
+        Op::Nop
+        | Op::Empty
+        | Op::Paths
+        | Op::Index
+        | Op::Invert
+        | Op::RegexReplace(_)
+        | Op::File(_)
+        | Op::Prefix(_)
+        | Op::Subdir(_)
+        | Op::Glob(_)
+        | Op::PathRegex(_)
+        | Op::ContentGrep(_)
+        | Op::BinaryOnly
+        | Op::TextOnly
+        | Op::LfsOnly
+        | Op::Owned(_, _)
+        | Op::TouchedBy(_)
+        | Op::DiffRange(_, _)
+        | Op::AddedSince(_)
+        | Op::IntersectRefs(_, _)
+        | Op::LowercasePaths
+        | Op::FlattenSingleChild
+        | Op::Explode(_)
+        | Op::StripExtension(_)
+        | Op::FlattenWithPathNames(_)
+        | Op::DedupBlobs
+        | Op::ContentToHash
+        | Op::Archive(_, _)
+        | Op::Select(_, _)
+        | Op::Docs => true,
+        Op::Compose(filters) => filters.iter().all(|f| is_tree_op(&to_op(*f))),
+        Op::Chain(a, b) | Op::Subtract(a, b) | Op::Intersect(a, b) => {
+            is_tree_op(&to_op(*a)) && is_tree_op(&to_op(*b))
+        }
+        Op::Exclude(a) => is_tree_op(&to_op(*a)),
+        Op::IfExists(_, a, b) => is_tree_op(&to_op(*a)) && is_tree_op(&to_op(*b)),
+        _ => false,
+    }
+}
+
 pub fn nesting(filter: Filter) -> usize {
     nesting2(&to_op(filter))
 }
@@ -261,7 +452,9 @@ fn nesting2(op: &Op) -> usize {
         Op::Exclude(filter) => 1 + nesting(*filter),
         Op::Workspace(_) => usize::MAX / 2, // divide by 2 to make sure there is enough headroom to avoid overflows
         Op::Chain(a, b) => 1 + nesting(*a).max(nesting(*b)),
-        Op::Subtract(a, b) => 1 + nesting(*a).max(nesting(*b)),
+        Op::Subtract(a, b) | Op::Intersect(a, b) | Op::IfExists(_, a, b) => {
+            1 + nesting(*a).max(nesting(*b))
+        }
         Op::Rev(filters) => {
             1 + filters
                 .values()
@@ -301,7 +494,7 @@ fn lazy_refs2(op: &Op) -> Vec<String> {
             av.append(&mut lazy_refs(*b));
             av
         }
-        Op::Subtract(a, b) => {
+        Op::Subtract(a, b) | Op::Intersect(a, b) | Op::IfExists(_, a, b) => {
             let mut av = lazy_refs(*a);
             av.append(&mut lazy_refs(*b));
             av
@@ -351,6 +544,10 @@ fn resolve_refs2(refs: &std::collections::HashMap<String, git2::Oid>, op: &Op) -
         Op::Exclude(filter) => Op::Exclude(resolve_refs(refs, *filter)),
         Op::Chain(a, b) => Op::Chain(resolve_refs(refs, *a), resolve_refs(refs, *b)),
         Op::Subtract(a, b) => Op::Subtract(resolve_refs(refs, *a), resolve_refs(refs, *b)),
+        Op::Intersect(a, b) => Op::Intersect(resolve_refs(refs, *a), resolve_refs(refs, *b)),
+        Op::IfExists(path, a, b) => {
+            Op::IfExists(path.clone(), resolve_refs(refs, *a), resolve_refs(refs, *b))
+        }
         Op::Rev(filters) => {
             let lr = filters
                 .into_iter()
@@ -411,8 +608,190 @@ fn resolve_refs2(refs: &std::collections::HashMap<String, git2::Oid>, op: &Op) -
 /// Compact, single line string representation of a filter so that `parse(spec(F)) == F`
 /// Note that this is will not be the best human readable representation. For that see `pretty(...)`
 pub fn spec(filter: Filter) -> String {
-    let filter = opt::simplify(filter);
-    spec2(&to_op(filter))
+    if let Some(s) = SPEC_CACHE.lock().unwrap().get(&filter) {
+        return s.clone();
+    }
+    let s = spec2(&to_op(opt::simplify(filter)));
+    SPEC_CACHE.lock().unwrap().put(filter, s.clone());
+    s
+}
+
+/// Like `spec`, but with filters started by `delimiter` instead of `:`, to be read
+/// back with `parse_with_delimiter`. Fails if `delimiter` has a meaning in the filter
+/// language.
+pub fn spec_with_delimiter(filter: Filter, delimiter: char) -> JoshResult<String> {
+    parse::swap_delimiter(&spec(filter), ':', delimiter)
+}
+
+/// Specs of several filters with the fragments they share factored out into
+/// definitions, as returned by `spec_batch_shared`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SharedSpecs {
+    /// Labels like `$0` together with the fragment they stand for. A fragment can
+    /// refer to labels defined after it.
+    pub definitions: Vec<(String, String)>,
+    pub specs: Vec<String>,
+}
+
+impl SharedSpecs {
+    /// Replace all labels with their fragments, giving back the `spec` of each filter
+    pub fn expand(&self) -> Vec<String> {
+        let mut resolved: Vec<(String, String)> = vec![];
+        for (label, fragment) in self.definitions.iter().rev() {
+            resolved.push((label.clone(), substitute(fragment, &resolved)));
+        }
+        // Later labels are longer or equally long, so `$10` is replaced before `$1`
+        fn substitute(s: &str, resolved: &[(String, String)]) -> String {
+            resolved.iter().fold(s.to_string(), |s, (label, fragment)| {
+                s.replace(label, fragment)
+            })
+        }
+        self.specs
+            .iter()
+            .map(|s| substitute(s, &resolved))
+            .collect()
+    }
+}
+
+/// Specs of several filters at once, with fragments of at least `min_len` characters
+/// that occur more than once replaced by labels and listed once in the definitions.
+/// The specs of subfilters are cached, so filters sharing parts, like the views of a
+/// workspace, only render those parts once.
+/// This is meant for displaying or storing large sets of related filters, the result
+/// can't be parsed without calling `SharedSpecs::expand` first.
+pub fn spec_batch_shared(filters: &[Filter], min_len: usize) -> SharedSpecs {
+    fn count(filter: Filter, counts: &mut std::collections::HashMap<Filter, usize>) {
+        *counts.entry(filter).or_default() += 1;
+        match to_op(filter) {
+            Op::Compose(filters) => filters.into_iter().for_each(|f| count(f, counts)),
+            Op::Chain(a, b) | Op::Subtract(a, b) | Op::Intersect(a, b) => {
+                count(a, counts);
+                count(b, counts);
+            }
+            Op::Exclude(b) => count(b, counts),
+            _ => {}
+        }
+    }
+
+    let mut shared = SharedSpecs {
+        definitions: vec![],
+        specs: filters.iter().map(|f| spec(*f)).collect(),
+    };
+    // Labels would be ambiguous with specs that contain them already
+    if shared.specs.iter().any(|s| s.contains('$')) {
+        return shared;
+    }
+
+    let mut counts = std::collections::HashMap::new();
+    for filter in filters {
+        count(*filter, &mut counts);
+    }
+    let mut candidates: Vec<_> = counts
+        .into_iter()
+        .filter(|(_, n)| *n > 1)
+        .map(|(f, _)| spec(f))
+        .filter(|s| s.len() >= min_len)
+        .collect();
+    // Longest first, so large fragments aren't split up by the smaller ones inside them
+    candidates.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+    candidates.dedup();
+
+    for fragment in candidates {
+        let occurrences: usize = shared
+            .specs
+            .iter()
+            .chain(shared.definitions.iter().map(|(_, d)| d))
+            .map(|s| s.matches(fragment.as_str()).count())
+            .sum();
+        if occurrences < 2 {
+            continue;
+        }
+        let label = format!("${}", shared.definitions.len());
+        for s in shared.specs.iter_mut() {
+            *s = s.replace(&fragment, &label);
+        }
+        for (_, d) in shared.definitions.iter_mut() {
+            *d = d.replace(&fragment, &label);
+        }
+        shared.definitions.push((label, fragment));
+    }
+    shared
+}
+
+/// Same as `optimize`, but runs on tokio's blocking thread pool, so optimizing large
+/// filters does not stall the async executor while waiting for the optimizer caches.
+pub async fn optimize_async(filter: Filter) -> Filter {
+    tokio::task::spawn_blocking(move || opt::optimize(filter))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+/// Spec of the optimized form of a filter. Filters that are equivalent after
+/// optimization have the same canonical spec regardless of how they were written.
+pub fn canonical_spec(filter: Filter) -> String {
+    spec(opt::optimize(filter))
+}
+
+/// Check that `input` optimizes to the same filter as `expected`, for tables of
+/// known optimizations. On mismatch the panic message shows both filters pretty printed.
+#[cfg(any(test, feature = "testing"))]
+pub fn assert_optimizes_to(input: &str, expected: &str) {
+    let parse = |s| parse(s).unwrap_or_else(|e| panic!("invalid filter {:?}: {}", s, e.0));
+    let (actual, expected) = (opt::optimize(parse(input)), opt::optimize(parse(expected)));
+    if canonical_spec(actual) != canonical_spec(expected) {
+        panic!(
+            "{:?} did not optimize as expected\n--- expected\n{}\n--- actual\n{}\n",
+            input,
+            pretty(expected, 4),
+            pretty(actual, 4)
+        );
+    }
+}
+
+/// Hash of the canonical spec of a filter. Unlike `Filter::id()` this only depends on
+/// the textual representation, so it is suitable for keys that are persisted outside of josh.
+pub fn stable_hash(filter: Filter) -> u64 {
+    hash_u64(canonical_spec(filter).as_bytes())
+}
+
+/// Order independent hash over a set of named views, for example the views of a
+/// workspace manifest. The fingerprint changes only when a view is added, removed,
+/// renamed or its canonical form changes.
+pub fn workspace_fingerprint(views: &std::collections::HashMap<String, Filter>) -> u64 {
+    let mut lines = views
+        .iter()
+        .map(|(name, filter)| format!("{} = {}\n", parse::quote(name), canonical_spec(*filter)))
+        .collect::<Vec<_>>();
+    lines.sort();
+    hash_u64(lines.concat().as_bytes())
+}
+
+/// Names of the views, for example of a workspace manifest, whose output can change
+/// when `path` changes. Views for which the read paths can't be determined, like
+/// globs or subtractions, are always included. The names are returned sorted.
+pub fn views_affected_by(
+    views: &std::collections::HashMap<String, Filter>,
+    path: &Path,
+) -> Vec<String> {
+    let mut names = views
+        .iter()
+        .filter(|(_, filter)| match read_roots(**filter) {
+            Some(roots) => roots
+                .iter()
+                .any(|root| path.starts_with(root) || root.starts_with(path)),
+            None => true,
+        })
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+fn hash_u64(data: &[u8]) -> u64 {
+    let oid = git2::Oid::hash_object(git2::ObjectType::Blob, data).expect("hash_object");
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&oid.as_bytes()[..8]);
+    u64::from_be_bytes(bytes)
 }
 
 fn spec2(op: &Op) -> String {
@@ -430,6 +809,15 @@ fn spec2(op: &Op) -> String {
         Op::Subtract(a, b) => {
             format!(":subtract[{},{}]", spec(*a), spec(*b))
         }
+        Op::Intersect(a, b) => {
+            format!(":intersect[{},{}]", spec(*a), spec(*b))
+        }
+        Op::IfExists(path, a, b) => format!(
+            ":if-exists={}?({}):({})",
+            parse::quote_if(&path.to_string_lossy()),
+            spec(*a),
+            spec(*b)
+        ),
         Op::Exclude(b) => {
             format!(":exclude[{}]", spec(*b))
         }
@@ -461,11 +849,18 @@ fn spec2(op: &Op) -> String {
                 .collect::<Vec<_>>();
             format!(":replace({})", v.join(","))
         }
+        Op::RewriteMessage(rules) => {
+            let v = rules
+                .iter()
+                .map(|(regex, r)| {
+                    format!("{}:{}", parse::quote(&regex.to_string()), parse::quote(r))
+                })
+                .collect::<Vec<_>>();
+            format!(":rewrite-message({})", v.join(","))
+        }
 
         Op::Chain(a, b) => match (to_op(*a), to_op(*b)) {
-            (Op::Subdir(p1), Op::Prefix(p2)) if p1 == p2 => {
-                format!("::{}/", parse::quote_if(&p1.to_string_lossy()))
-            }
+            (Op::Subdir(p1), Op::Prefix(p2)) if p1 == p2 => format!("::{}", quote_dir(&p1)),
             (a, b) => format!("{}{}", spec2(&a), spec2(&b)),
         },
 
@@ -485,11 +880,66 @@ fn spec2(op: &Op) -> String {
             format!(":squash({})", v.join(","))
         }
         Op::Linear => ":linear".to_string(),
+        Op::Take(n) => format!(":take={}", n),
+        Op::KeepVersions(k) => format!(":keep-versions={}", k),
+        Op::Notes(notes_ref) => format!(":notes={}", parse::quote_if(notes_ref)),
+        Op::LastAuthor(pattern) => format!(":last-author={}", parse::quote_if(pattern)),
         Op::Unsign => ":unsign".to_string(),
+        Op::OriginTrailer => ":origin-trailer".to_string(),
+        Op::SelfView => ":self".to_string(),
         Op::Subdir(path) => format!(":/{}", parse::quote_if(&path.to_string_lossy())),
         Op::File(path) => format!("::{}", parse::quote_if(&path.to_string_lossy())),
         Op::Prefix(path) => format!(":prefix={}", parse::quote_if(&path.to_string_lossy())),
         Op::Glob(pattern) => format!("::{}", parse::quote_if(pattern)),
+        Op::PathRegex(regex) => format!(":regex={}", parse::quote_if(regex.as_str())),
+        Op::ContentGrep(regex) => format!(":grep={}", parse::quote_if(regex.as_str())),
+        Op::BinaryOnly => ":binary".to_string(),
+        Op::TextOnly => ":text".to_string(),
+        Op::LfsOnly => ":lfs".to_string(),
+        Op::Owned(owner, path) if path == Path::new(codeowners::DEFAULT_PATH) => {
+            format!(":owned={}", parse::quote_if(owner))
+        }
+        Op::Owned(owner, path) => format!(
+            ":owned={};{}",
+            parse::quote_if(owner),
+            parse::quote_if(&path.to_string_lossy())
+        ),
+        Op::TouchedBy(id) => format!(":touched={}", id),
+        Op::DiffRange(from, to) => format!(":diff={}..{}", from, to),
+        Op::AddedSince(base) => format!(":added-since={}", base),
+        Op::IntersectRefs(ids, content) => format!(
+            ":{}={}",
+            if *content {
+                "intersect-content"
+            } else {
+                "intersect"
+            },
+            ids.iter()
+                .map(git2::Oid::to_string)
+                .collect::<Vec<_>>()
+                .join(";")
+        ),
+        Op::LowercasePaths => ":lowercase".to_string(),
+        Op::FlattenSingleChild => ":collapse".to_string(),
+        Op::Explode(child) => format!(":explode={}", parse::quote_if(child)),
+        Op::StripExtension(ext) => format!(":strip-ext={}", parse::quote_if(ext)),
+        Op::FlattenWithPathNames(sep) => format!(":flatten={}", parse::quote_if(sep)),
+        Op::DedupBlobs => ":dedup".to_string(),
+        Op::ContentToHash => ":content-hash".to_string(),
+        Op::Archive(format, path) => format!(
+            ":archive={};{}",
+            parse::quote_if(format),
+            parse::quote_if(&path.to_string_lossy())
+        ),
+        Op::Select(include, exclude) => {
+            let v = include
+                .iter()
+                .map(|p| format!("+{}", parse::quote_if(p)))
+                .chain(exclude.iter().map(|p| format!("-{}", parse::quote_if(p))))
+                .collect::<Vec<_>>();
+            format!(":select[{}]", v.join(","))
+        }
+        Op::Docs => ":docs".to_string(),
         Op::Author(author, email) => {
             format!(":author={};{}", parse::quote(author), parse::quote(email))
         }
@@ -532,6 +982,142 @@ fn dst_path2(op: &Op) -> std::path::PathBuf {
     })
 }
 
+/// Append a `key: value` trailer to a commit message. If the message already ends with a
+/// block of trailers the new one is added to it, otherwise a new paragraph is started.
+fn add_trailer(message: &str, key: &str, value: &str) -> String {
+    let message = message.trim_end();
+    let last_paragraph = message.rsplit("\n\n").next().unwrap_or("");
+    let is_trailer = |line: &str| {
+        line.split_once(": ").is_some_and(|(k, _)| {
+            !k.is_empty() && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+    };
+    let separator = if message.contains("\n\n") && last_paragraph.lines().all(is_trailer) {
+        "\n"
+    } else {
+        "\n\n"
+    };
+    format!("{}{}{}: {}\n", message, separator, key, value)
+}
+
+/// Check if `filter` leaves every tree and history unchanged, i.e. if it optimizes to `:/`.
+/// The result of `optimize` is cached, so this is cheap to call repeatedly.
+pub fn is_nop_equivalent(filter: Filter) -> bool {
+    opt::optimize(filter) == nop()
+}
+
+/// Check if `filter` is a compose whose members read from and write to pairwise disjoint
+/// directories. Members of such a compose can't interact, so their outputs can be merged
+/// without checking which paths were already taken by other members.
+pub fn is_disjoint_compose(filter: Filter) -> bool {
+    match to_op(filter) {
+        Op::Compose(filters) => disjoint_members(&filters),
+        _ => false,
+    }
+}
+
+fn disjoint_members(filters: &[Filter]) -> bool {
+    let roots = filters
+        .iter()
+        .map(|f| roots(*f))
+        .collect::<Option<Vec<_>>>();
+    let roots = some_or!(roots, { return false });
+    roots
+        .iter()
+        .enumerate()
+        .all(|(i, a)| roots[i + 1..].iter().all(|b| roots_disjoint(a, b)))
+}
+
+/// Check if `a` and `b` read from and write to disjoint directories
+fn disjoint(a: Filter, b: Filter) -> bool {
+    match (roots(a), roots(b)) {
+        (Some(a), Some(b)) => roots_disjoint(&a, &b),
+        _ => false,
+    }
+}
+
+type Roots = (Vec<std::path::PathBuf>, Vec<std::path::PathBuf>);
+
+fn roots(filter: Filter) -> Option<Roots> {
+    Some((read_roots(filter)?, write_roots(filter)?))
+}
+
+fn roots_disjoint(a: &Roots, b: &Roots) -> bool {
+    let overlap = |a: &[std::path::PathBuf], b: &[std::path::PathBuf]| {
+        a.iter()
+            .any(|a| b.iter().any(|b| a.starts_with(b) || b.starts_with(a)))
+    };
+    !overlap(&a.0, &b.0) && !overlap(&a.1, &b.1)
+}
+
+/// Directories outside of which a filter does not look at its input
+fn read_roots(filter: Filter) -> Option<Vec<std::path::PathBuf>> {
+    match to_op(filter) {
+        Op::Subdir(path) | Op::File(path) if path != Path::new("") => Some(vec![path]),
+        Op::Chain(a, b) => match to_op(a) {
+            Op::Subdir(path) if path != Path::new("") => Some(
+                read_roots(b)
+                    .map(|roots| roots.iter().map(|r| path.join(r)).collect())
+                    .unwrap_or_else(|| vec![path]),
+            ),
+            _ => read_roots(a),
+        },
+        Op::Compose(filters) => filters
+            .into_iter()
+            .map(read_roots)
+            .collect::<Option<Vec<_>>>()
+            .map(|roots| roots.concat()),
+        _ => None,
+    }
+}
+
+/// Directories outside of which a filter does not produce any output
+fn write_roots(filter: Filter) -> Option<Vec<std::path::PathBuf>> {
+    match to_op(filter) {
+        Op::Prefix(path) | Op::File(path) if path != Path::new("") => Some(vec![path]),
+        Op::Chain(a, b) => match prefix_path(b) {
+            Some(path) if path != Path::new("") => Some(
+                write_roots(a)
+                    .map(|roots| roots.iter().map(|r| path.join(r)).collect())
+                    .unwrap_or_else(|| vec![path]),
+            ),
+            _ => write_roots(b),
+        },
+        Op::Compose(filters) => filters
+            .into_iter()
+            .map(write_roots)
+            .collect::<Option<Vec<_>>>()
+            .map(|roots| roots.concat()),
+        _ => None,
+    }
+}
+
+/// Path that a filter made up only of prefixes moves its input to
+fn prefix_path(filter: Filter) -> Option<std::path::PathBuf> {
+    match to_op(filter) {
+        Op::Prefix(path) => Some(path),
+        Op::Chain(a, b) => Some(prefix_path(b)?.join(prefix_path(a)?)),
+        _ => None,
+    }
+}
+
+/// Like `apply_to_commit`, but give up with an error for which `JoshError::is_timeout`
+/// is true once `deadline` has passed. The deadline is checked whenever a tree is
+/// about to be filtered. Only completed results are cached, so calling this again,
+/// with a later deadline or without one, continues where the previous call stopped.
+pub fn apply_with_deadline(
+    filter: Filter,
+    commit: &git2::Commit,
+    transaction: &cache::Transaction,
+    deadline: std::time::Instant,
+) -> JoshResult<git2::Oid> {
+    let previous = transaction.deadline();
+    transaction.set_deadline(Some(deadline));
+    let result = apply_to_commit(filter, commit, transaction);
+    transaction.set_deadline(previous);
+    result
+}
+
 /// Calculate the filtered commit for `commit`. This can take some time if done
 /// for the first time and thus should generally be done asynchronously.
 pub fn apply_to_commit(
@@ -585,6 +1171,65 @@ fn resolve_workspace_redirect<'a>(
     }
 }
 
+/// Check that following the workspaces that the workspace at `path` refers to, and the
+/// ones those refer to, never leads back to a workspace already on the way.
+/// Such cycles are cut off when the workspace is applied, so the result would silently
+/// miss files. Only references that see the whole tree, like `:workspace=a` or
+/// `x = :workspace=a`, are followed.
+pub fn check_workspace_cycles(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    path: &Path,
+) -> JoshResult<()> {
+    struct References(Vec<std::path::PathBuf>);
+
+    impl FilterVisitor for References {
+        fn visit_workspace(&mut self, path: &Path) {
+            self.0.push(normalize_path(path));
+        }
+        // Only the first filter of a chain gets the whole tree
+        fn visit_chain(&mut self, first: Filter, _second: Filter) {
+            walk(first, self);
+        }
+    }
+
+    fn visit(
+        repo: &git2::Repository,
+        tree: &git2::Tree,
+        stack: &mut Vec<std::path::PathBuf>,
+        checked: &mut std::collections::HashSet<std::path::PathBuf>,
+    ) -> JoshResult<()> {
+        let path = stack.last().unwrap().clone();
+        let content = tree::get_blob(repo, tree, &path.join("workspace.josh"));
+        let mut references = References(vec![]);
+        walk(
+            parse::parse(&content).unwrap_or_else(|_| to_filter(Op::Empty)),
+            &mut references,
+        );
+
+        for next in references.0 {
+            if let Some(start) = stack.iter().position(|p| *p == next) {
+                let names: Vec<_> = stack[start..]
+                    .iter()
+                    .chain(std::iter::once(&next))
+                    .map(|p| spec(to_filter(Op::Workspace(p.clone()))))
+                    .collect();
+                return Err(filter_cycle_error(&names));
+            }
+            if checked.insert(next.clone()) {
+                stack.push(next);
+                visit(repo, tree, stack, checked)?;
+                stack.pop();
+            }
+        }
+        Ok(())
+    }
+
+    let path = normalize_path(path);
+    let mut checked = std::collections::HashSet::from([path.clone()]);
+    visit(repo, tree, &mut vec![path], &mut checked)
+}
+
 fn get_workspace<'a>(repo: &'a git2::Repository, tree: &'a git2::Tree<'a>, path: &Path) -> Filter {
     let ws_path = normalize_path(&path.join("workspace.josh"));
     let ws_id = ok_or!(tree.get_path(&ws_path), {
@@ -655,6 +1300,16 @@ fn apply_to_commit2(
             ))
             .transpose()
         }
+        // With history filters in the branches each commit is filtered with the branch
+        // matching its own tree
+        Op::IfExists(path, a, b) if !is_tree_op(op) => {
+            let branch = if commit.tree()?.get_path(path).is_ok() {
+                a
+            } else {
+                b
+            };
+            return apply_to_commit2(&to_op(*branch), commit, transaction);
+        }
         Op::Join(refs) => {
             // First loop to populate missing list
             for (&_, f) in refs.iter() {
@@ -816,6 +1471,52 @@ fn apply_to_commit2(
             ))
             .transpose();
         }
+        Op::Take(n) => {
+            if transaction.known(filter, commit.id()) {
+                return Ok(transaction.get(filter, commit.id()));
+            }
+
+            // Which commits are kept depends on their distance to the commit being filtered,
+            // so the result can't be derived from the filtered parents like for other filters.
+            let mut kept = vec![commit.clone()];
+            while kept.len() < *n {
+                let parent = some_or!(kept[kept.len() - 1].parents().next(), { break });
+                kept.push(parent);
+            }
+
+            let mut filtered: Option<git2::Commit> = None;
+            for c in kept.iter().rev() {
+                let parents: Vec<_> = filtered.iter().collect();
+                let id = history::rewrite_commit(
+                    repo,
+                    c,
+                    &parents,
+                    RewriteData {
+                        tree: c.tree()?,
+                        author: None,
+                        committer: None,
+                        message: None,
+                    },
+                    false,
+                )?;
+                filtered = Some(repo.find_commit(id)?);
+            }
+
+            let filtered = filtered.map(|c| c.id()).unwrap_or_else(git2::Oid::zero);
+            transaction.insert(filter, commit.id(), filtered, true);
+            return Ok(Some(filtered));
+        }
+        Op::KeepVersions(k) => {
+            if transaction.known(filter, commit.id()) {
+                return Ok(transaction.get(filter, commit.id()));
+            }
+
+            // Like for `:take`, which versions are the most recent ones depends on the
+            // commit being filtered
+            let filtered = history::keep_versions(transaction, commit, *k)?;
+            transaction.insert(filter, commit.id(), filtered, true);
+            return Ok(Some(filtered));
+        }
         Op::Unsign => {
             let parents: Vec<_> = commit.parent_ids().collect();
 
@@ -931,6 +1632,106 @@ fn apply_to_commit2(
                 message: None,
             }
         }
+        Op::Notes(notes_ref) => {
+            let filtered_parent_ids = commit
+                .parents()
+                .map(|x| transaction.get(filter, x.id()))
+                .collect::<Option<Vec<_>>>();
+
+            let filtered_parent_ids = some_or!(filtered_parent_ids, { return Ok(None) });
+
+            // The notes of all ancestors are kept, so the notes directory of a
+            // commit is the union of those of its parents plus its own note
+            let mut notes = tree::empty(repo);
+            for id in filtered_parent_ids {
+                if let Ok(entry) = repo.find_commit(id)?.tree()?.get_path(Path::new(NOTES_DIR)) {
+                    notes = repo.find_tree(tree::overlay(transaction, notes.id(), entry.id())?)?;
+                }
+            }
+            if let Ok(note) = repo.find_note(Some(notes_ref), commit.id()) {
+                let blob = write_blob(repo, note.message_bytes())?;
+                notes = tree::insert(
+                    repo,
+                    &notes,
+                    Path::new(&commit.id().to_string()),
+                    blob,
+                    0o0100644,
+                )?;
+            }
+
+            let filtered_tree = if notes.id() == tree::empty_id() {
+                commit.tree()?
+            } else {
+                tree::insert(
+                    repo,
+                    &commit.tree()?,
+                    Path::new(NOTES_DIR),
+                    notes.id(),
+                    0o0040000,
+                )?
+            };
+            RewriteData {
+                tree: filtered_tree,
+                author: None,
+                committer: None,
+                message: None,
+            }
+        }
+        Op::LastAuthor(pattern) => {
+            let filtered_parent_ids = commit
+                .parents()
+                .map(|x| transaction.get(filter, x.id()))
+                .collect::<Option<Vec<_>>>();
+
+            let filtered_parent_ids = some_or!(filtered_parent_ids, { return Ok(None) });
+
+            // A file that is the same as in one of the parents was last changed by the
+            // same commit as there, so it is kept if the filtered parent has it
+            let parents = commit
+                .parents()
+                .map(|parent| Ok(parent.tree()?))
+                .collect::<JoshResult<Vec<_>>>()?;
+            let filtered_parents = filtered_parent_ids
+                .iter()
+                .map(|id| match repo.find_commit(*id) {
+                    Ok(c) => Ok(c.tree()?),
+                    Err(_) => Ok(tree::empty(repo)),
+                })
+                .collect::<JoshResult<Vec<_>>>()?;
+            let by_author =
+                glob::Pattern::new(pattern)?.matches(commit.author().email().unwrap_or_default());
+
+            let tree = commit.tree()?;
+            let key = git2::Oid::hash_object(
+                git2::ObjectType::Blob,
+                format!("{}{}", filter.id(), commit.id()).as_bytes(),
+            )?;
+            let filtered_tree = tree::remove_pred(
+                transaction,
+                "",
+                tree.id(),
+                &|path, isblob| {
+                    if !isblob {
+                        return false;
+                    }
+                    let id = tree.get_path(path).map(|e| e.id()).ok();
+                    let same = parents
+                        .iter()
+                        .position(|p| p.get_path(path).map(|e| e.id()).ok() == id);
+                    match same {
+                        Some(i) => filtered_parents[i].get_path(path).is_ok(),
+                        None => by_author,
+                    }
+                },
+                key,
+            )?;
+            RewriteData {
+                tree: filtered_tree,
+                author: None,
+                committer: None,
+                message: None,
+            }
+        }
         Op::Author(author, email) => RewriteData {
             tree: commit.tree()?,
             author: Some((author.clone(), email.clone())),
@@ -953,6 +1754,27 @@ fn apply_to_commit2(
                 &std::collections::HashMap::<String, &dyn strfmt::DisplayStr>::new(),
             )?),
         },
+        Op::OriginTrailer => RewriteData {
+            tree: commit.tree()?,
+            author: None,
+            committer: None,
+            message: Some(add_trailer(
+                commit.message_raw().unwrap_or("no message"),
+                "Josh-Original",
+                &commit.id().to_string(),
+            )),
+        },
+        Op::RewriteMessage(rules) => RewriteData {
+            tree: commit.tree()?,
+            author: None,
+            committer: None,
+            message: Some(rules.iter().fold(
+                commit.message_raw().unwrap_or("no message").to_string(),
+                |message, (regex, replacement)| {
+                    regex.replace_all(&message, replacement).into_owned()
+                },
+            )),
+        },
         _ => RewriteData {
             tree: apply(transaction, filter, commit.tree()?)?,
             message: None,
@@ -981,374 +1803,4458 @@ fn apply_to_commit2(
     .transpose()
 }
 
-/// Filter a single tree. This does not involve walking history and is thus fast in most cases.
-pub fn apply<'a>(
-    transaction: &'a cache::Transaction,
+/// Filter a tree contained in a packfile, for example as received over the wire.
+/// The pack is indexed into the object database of the transaction as is, without
+/// unpacking it into loose objects. Returns the id of the filtered tree together with a
+/// new packfile containing all objects reachable from it.
+pub fn apply_to_pack(
+    transaction: &cache::Transaction,
     filter: Filter,
-    tree: git2::Tree<'a>,
-) -> JoshResult<git2::Tree<'a>> {
-    apply2(transaction, &to_op(filter), tree)
+    pack: &[u8],
+    tree: git2::Oid,
+) -> JoshResult<(git2::Oid, Vec<u8>)> {
+    let repo = transaction.repo();
+    {
+        let odb = repo.odb()?;
+        let mut writer = odb.packwriter()?;
+        std::io::Write::write_all(&mut writer, pack)?;
+        writer.commit()?;
+    }
+
+    let filtered = apply(transaction, filter, repo.find_tree(tree)?)?;
+
+    let mut builder = repo.packbuilder()?;
+    builder.insert_tree(filtered.id())?;
+    let mut buf = git2::Buf::new();
+    builder.write_buf(&mut buf)?;
+
+    Ok((filtered.id(), buf.to_vec()))
 }
 
-fn apply2<'a>(
-    transaction: &'a cache::Transaction,
-    op: &Op,
-    tree: git2::Tree<'a>,
-) -> JoshResult<git2::Tree<'a>> {
+/// Filter what is staged in the index of the repository of the transaction, to preview
+/// the view of work that was not committed yet. Changes of the worktree that are not
+/// staged are not included. Returns the id of the filtered tree.
+pub fn apply_to_index(transaction: &cache::Transaction, filter: Filter) -> JoshResult<git2::Oid> {
     let repo = transaction.repo();
-    match op {
-        Op::Nop => Ok(tree),
-        Op::Empty => return Ok(tree::empty(repo)),
-        Op::Fold => Ok(tree),
-        Op::Squash(None) => Ok(tree),
-        Op::Message(_) => Ok(tree),
-        Op::Author(_, _) => Ok(tree),
-        Op::Committer(_, _) => Ok(tree),
-        Op::Squash(Some(_)) => Err(josh_error("not applicable to tree")),
-        Op::Linear => Ok(tree),
-        Op::Unsign => Ok(tree),
-        Op::Rev(_) => Err(josh_error("not applicable to tree")),
-        Op::Join(_) => Err(josh_error("not applicable to tree")),
-        Op::RegexReplace(replacements) => {
-            let mut t = tree;
-            for (regex, replacement) in replacements {
-                t = tree::regex_replace(t.id(), regex, replacement, transaction)?;
-            }
-            Ok(t)
-        }
-
-        Op::Glob(pattern) => {
-            let pattern = glob::Pattern::new(pattern)?;
-            let options = glob::MatchOptions {
-                case_sensitive: true,
-                require_literal_separator: true,
-                require_literal_leading_dot: true,
-            };
-            tree::remove_pred(
-                transaction,
-                "",
-                tree.id(),
-                &|path, isblob| isblob && (pattern.matches_path_with(path, options)),
-                to_filter(op.clone()).id(),
-            )
-        }
-        Op::File(path) => {
-            let (file, mode) = tree
-                .get_path(path)
-                .map(|x| (x.id(), x.filemode()))
-                .unwrap_or((git2::Oid::zero(), 0o0100644));
-            tree::insert(repo, &tree::empty(repo), path, file, mode)
-        }
-
-        Op::Subdir(path) => {
-            return Ok(tree
-                .get_path(path)
-                .and_then(|x| repo.find_tree(x.id()))
-                .unwrap_or_else(|_| tree::empty(repo)));
-        }
-        Op::Prefix(path) => tree::insert(repo, &tree::empty(repo), path, tree.id(), 0o0040000),
+    let mut index = repo.index()?;
+    if index.has_conflicts() {
+        return Err(josh_error("index has conflicts"));
+    }
+    let tree = repo.find_tree(index.write_tree()?)?;
+    Ok(apply(transaction, filter, tree)?.id())
+}
 
-        Op::Subtract(a, b) => {
-            let af = apply(transaction, *a, tree.clone())?;
-            let bf = apply(transaction, *b, tree.clone())?;
-            let bu = apply(transaction, invert(*b)?, bf)?;
-            let ba = apply(transaction, *a, bu)?.id();
-            Ok(repo.find_tree(tree::subtract(transaction, af.id(), ba)?)?)
-        }
-        Op::Exclude(b) => {
-            let bf = apply(transaction, *b, tree.clone())?.id();
-            Ok(repo.find_tree(tree::subtract(transaction, tree.id(), bf)?)?)
-        }
+/// Check if applying `filter` to a particular tree returns the tree unchanged.
+/// Unlike optimization this can also recognize filters that only happen to be the
+/// identity for this tree, like a compose whose members cover all of its entries.
+pub fn is_identity_on(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree,
+) -> JoshResult<bool> {
+    let id = tree.id();
+    Ok(apply(transaction, filter, tree)?.id() == id)
+}
 
-        Op::Paths => tree::pathstree("", tree.id(), transaction),
-        Op::Index => tree::trigram_index(transaction, tree),
+/// Optimize `filter` for being applied to `tree` only. A compose at the start of the
+/// filter that only lists top level directories, like `:[::a/,::b/]`, is replaced by
+/// `:/` if applying it to `tree` gives back `tree` unchanged, because the directories
+/// cover all of its entries. The result is not equivalent to `filter` on other trees.
+pub fn optimize_on(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree,
+) -> JoshResult<Filter> {
+    fn top_level_dirs(filter: Filter) -> bool {
+        let Op::Compose(members) = to_op(filter) else {
+            return false;
+        };
+        members.iter().all(|m| match to_op(*m) {
+            Op::Chain(a, b) => match (to_op(a), to_op(b)) {
+                (Op::Subdir(s), Op::Prefix(p)) => s == p && s.components().count() == 1,
+                _ => false,
+            },
+            _ => false,
+        })
+    }
 
-        Op::Invert => tree::invert_paths(transaction, "", tree),
+    let filter = opt::optimize(filter);
+    let mut parts = as_chain_vec(filter);
+    if top_level_dirs(parts[0]) && is_identity_on(transaction, parts[0], tree)? {
+        parts[0] = nop();
+        let rest = parts
+            .into_iter()
+            .reduce(|a, b| to_filter(Op::Chain(a, b)))
+            .unwrap_or_else(nop);
+        return Ok(opt::optimize(rest));
+    }
+    Ok(filter)
+}
 
-        Op::Workspace(path) => {
-            let wsj_file = to_filter(Op::File(Path::new("workspace.josh").to_owned()));
-            let base = to_filter(Op::Subdir(path.to_owned()));
-            let wsj_file = chain(base, wsj_file);
+/// Mapping from original to filtered commits
+pub type CommitMap = std::collections::HashMap<git2::Oid, git2::Oid>;
 
-            if let Some((redirect, _)) = resolve_workspace_redirect(repo, &tree, path) {
-                return apply(transaction, redirect, tree);
-            }
+/// Filter `new_tip`, reusing the filtered commits in `known` instead of filtering them again.
+/// Only commits reachable from `new_tip` but not from any commit in `known` are filtered.
+/// Returns the filtered tip together with `known` extended by the newly filtered commits.
+pub fn filter_incremental(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    known: &CommitMap,
+    new_tip: git2::Oid,
+) -> JoshResult<(git2::Oid, CommitMap)> {
+    let repo = transaction.repo();
+    let filter = opt::optimize(filter);
+    for (original, filtered) in known.iter() {
+        transaction.insert(filter, *original, *filtered, false);
+    }
 
-            apply(
-                transaction,
-                compose(wsj_file, compose(get_workspace(repo, &tree, path), base)),
-                tree,
-            )
-        }
+    let filtered_tip = apply_to_commit(filter, &repo.find_commit(new_tip)?, transaction)?;
 
-        Op::Compose(filters) => {
-            let filtered: Vec<_> = filters
-                .iter()
-                .map(|f| apply(transaction, *f, tree.clone()))
-                .collect::<JoshResult<_>>()?;
-            let filtered: Vec<_> = filters.iter().zip(filtered.into_iter()).collect();
-            tree::compose(transaction, filtered)
-        }
+    let mut walk = repo.revwalk()?;
+    walk.push(new_tip)?;
+    for original in known.keys() {
+        walk.hide(*original)?;
+    }
 
-        Op::Chain(a, b) => {
-            return apply(transaction, *b, apply(transaction, *a, tree)?);
+    let mut mapping = known.clone();
+    for id in walk {
+        let id = id?;
+        if transaction.known(filter, id) {
+            if let Some(filtered) = transaction.get(filter, id) {
+                mapping.insert(id, filtered);
+            }
         }
     }
+    Ok((filtered_tip, mapping))
 }
 
-/// Calculate a tree with minimal differences from `parent_tree`
-/// such that `apply(unapply(tree, parent_tree)) == tree`
-pub fn unapply<'a>(
-    transaction: &'a cache::Transaction,
+/// Split a tree into the output of `filter` and the complement of the paths it was
+/// computed from. This is not a single walk over the tree: the filter is applied first,
+/// and its output is then mapped back to the input paths it came from, to remove those
+/// from the input. If `filter` can be inverted, applying the inverse to the matched tree
+/// and overlaying the complement gives back the input.
+pub fn partition(
+    transaction: &cache::Transaction,
     filter: Filter,
-    tree: git2::Tree<'a>,
-    parent_tree: git2::Tree<'a>,
-) -> JoshResult<git2::Tree<'a>> {
+    tree: git2::Tree,
+) -> JoshResult<(git2::Oid, git2::Oid)> {
+    let matched = apply(transaction, filter, tree.clone())?;
     if let Ok(inverted) = invert(filter) {
-        let matching = apply(
-            transaction,
-            chain(invert(inverted)?, inverted),
-            parent_tree.clone(),
-        )?;
-        let stripped = tree::subtract(transaction, parent_tree.id(), matching.id())?;
-        let new_tree = apply(transaction, inverted, tree)?;
-
-        return Ok(transaction.repo().find_tree(tree::overlay(
-            transaction,
-            new_tree.id(),
-            stripped,
-        )?)?);
+        let used = apply(transaction, inverted, matched.clone())?;
+        let complement = tree::subtract(transaction, tree.id(), used.id())?;
+        return Ok((matched.id(), complement));
     }
 
-    if let Some(ws) = unapply_workspace(
+    // Trace the files of the output back to their input paths instead. That does not
+    // work for filters that look at the content of files, but those can all be inverted.
+    let repo = transaction.repo();
+    let paths = apply(
         transaction,
-        &to_op(filter),
+        chain(to_filter(Op::Paths), filter),
         tree.clone(),
-        parent_tree.clone(),
-    )? {
-        return Ok(ws);
+    )?;
+    let mut used = std::collections::HashSet::new();
+    let mut ids = vec![];
+    paths.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
+        if entry.kind() == Some(git2::ObjectType::Blob) {
+            ids.push(entry.id());
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+    for id in ids {
+        let blob = repo.find_blob(id)?;
+        let source = tree::pathline(std::str::from_utf8(blob.content())?)?;
+        used.insert(std::path::PathBuf::from(source));
     }
+    let complement = tree::keep_files(transaction, tree.id(), &|path, _| !used.contains(path))?;
+    Ok((matched.id(), complement))
+}
 
-    if let Op::Chain(a, b) = to_op(filter) {
-        // If filter "a" is invertable, use "invert(invert(a))" version of it, otherwise use as is
-        let a_normalized = if let Ok(a_inverted) = invert(a) {
-            invert(a_inverted)?
-        } else {
-            a
-        };
-        let filtered_parent_tree = apply(transaction, a_normalized, parent_tree.clone())?;
+/// Compare the outputs of two filters applied to the same tree. Returns the paths only
+/// `a` produces and the paths only `b` produces, as trees with the content of the filter
+/// that produced them. Paths both filters produce are in neither of the trees, even if
+/// their content differs.
+pub fn symmetric_diff(
+    transaction: &cache::Transaction,
+    a: Filter,
+    b: Filter,
+    tree: git2::Tree,
+) -> JoshResult<(git2::Oid, git2::Oid)> {
+    let a = apply(transaction, a, tree.clone())?.id();
+    let b = apply(transaction, b, tree)?.id();
+    Ok((
+        tree::subtract(transaction, a, b)?,
+        tree::subtract(transaction, b, a)?,
+    ))
+}
 
-        return unapply(
-            transaction,
-            a,
-            unapply(transaction, b, tree, filtered_parent_tree)?,
-            parent_tree,
-        );
+/// Find the members of a compose filter whose output contains `path`, in the order they
+/// appear in the filter. When several members produce the path, the content is taken from
+/// the last one. Filters other than compose are treated as a compose with one member.
+/// Note that `parse` optimizes filters, which can regroup the members of a compose.
+pub fn contributing_members(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree,
+    path: &Path,
+) -> JoshResult<Vec<Filter>> {
+    let members = match to_op(filter) {
+        Op::Compose(filters) => filters,
+        _ => vec![filter],
+    };
+    let mut contributing = vec![];
+    for member in members {
+        if apply(transaction, member, tree.clone())?
+            .get_path(path)
+            .is_ok()
+        {
+            contributing.push(member);
+        }
     }
+    Ok(contributing)
+}
 
-    Err(josh_error("filter cannot be unapplied"))
+/// How a compose resolves members producing different files at the same path, for
+/// `apply_with_collision_strategy`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Collision {
+    /// Fail with an error naming the path
+    Error,
+    /// Keep the file of the first member producing it
+    FirstWins,
+    /// Keep the file of the last member producing it, like `apply` does
+    #[default]
+    LastWins,
+    /// Keep the file whose content was added to the history of the commit last, at the
+    /// path it has in the commit. If the contents were added by the same commit, or are
+    /// not part of the commit at all, the last member wins.
+    Newest,
 }
 
-fn unapply_workspace<'a>(
+/// Apply `filter` to the tree of `commit` like `apply`, but resolve the paths at which
+/// members of a compose produce different files according to `strategy`. This is done
+/// for the composes reached through chains and other composes, inside of other ops the
+/// last member wins as usual. Unless `strategy` is `LastWins` the composes are computed
+/// again on every call, as their cached results could be resolved differently.
+pub fn apply_with_collision_strategy(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    commit: &git2::Commit,
+    strategy: Collision,
+) -> JoshResult<git2::Oid> {
+    let tree = commit.tree()?;
+    if strategy == Collision::LastWins {
+        return Ok(apply(transaction, filter, tree)?.id());
+    }
+    let history = ContentHistory::new(transaction.repo(), commit);
+    Ok(apply_colliding(transaction, filter, tree, &history, strategy)?.id())
+}
+
+fn apply_colliding<'a>(
     transaction: &'a cache::Transaction,
-    op: &Op,
+    filter: Filter,
     tree: git2::Tree<'a>,
-    parent_tree: git2::Tree<'a>,
-) -> JoshResult<Option<git2::Tree<'a>>> {
-    return match op {
-        Op::Workspace(path) => {
-            let tree = pre_process_tree(transaction.repo(), tree)?;
-            let workspace = get_workspace(transaction.repo(), &tree, Path::new(""));
-            let original_workspace = get_workspace(transaction.repo(), &parent_tree, path);
-
-            let root = to_filter(Op::Subdir(path.to_owned()));
-            let wsj_file = to_filter(Op::File(Path::new("workspace.josh").to_owned()));
-            let wsj_file = chain(root, wsj_file);
-            let filter = compose(wsj_file, compose(workspace, root));
-            let original_filter = compose(wsj_file, compose(original_workspace, root));
-            let matching = apply(
-                transaction,
-                chain(original_filter, invert(original_filter)?),
-                parent_tree.clone(),
-            )?;
-            let stripped = tree::subtract(transaction, parent_tree.id(), matching.id())?;
-            let new_tree = apply(transaction, invert(filter)?, tree)?;
-
-            let result = transaction.repo().find_tree(tree::overlay(
-                transaction,
-                new_tree.id(),
-                stripped,
-            )?)?;
+    history: &ContentHistory,
+    strategy: Collision,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    let members = match to_op(filter) {
+        Op::Chain(a, b) => {
+            let first = apply_colliding(transaction, a, tree, history, strategy)?;
+            return apply_colliding(transaction, b, first, history, strategy);
+        }
+        Op::Compose(members) => members,
+        _ => return apply(transaction, filter, tree),
+    };
 
-            return Ok(Some(result));
+    let filtered = members
+        .iter()
+        .map(|m| apply_colliding(transaction, *m, tree.clone(), history, strategy))
+        .collect::<JoshResult<Vec<_>>>()?;
+    let merge = |member: git2::Oid, result: git2::Oid| {
+        let conflicts = tree::conflicts(repo, member, result, "")?;
+        match strategy {
+            _ if conflicts.is_empty() => tree::overlay(transaction, member, result),
+            Collision::Error => Err(josh_error(&format!(
+                "Path collision in compose: {:?}",
+                conflicts[0]
+            ))),
+            Collision::FirstWins => tree::overlay(transaction, result, member),
+            Collision::LastWins => tree::overlay(transaction, member, result),
+            Collision::Newest => {
+                let (member, result) = (repo.find_tree(member)?, repo.find_tree(result)?);
+                let mut merged =
+                    repo.find_tree(tree::overlay(transaction, member.id(), result.id())?)?;
+                for path in conflicts {
+                    let path = Path::new(&path);
+                    let (Ok(new), Ok(old)) = (member.get_path(path), result.get_path(path)) else {
+                        continue;
+                    };
+                    if history.newest(old.id(), new.id())? == old.id() {
+                        merged = tree::insert(repo, &merged, path, old.id(), old.filemode())?;
+                    }
+                }
+                Ok(merged.id())
+            }
         }
-        _ => Ok(None),
     };
+    tree::compose_with(transaction, members.iter().zip(filtered).collect(), &merge)
 }
 
-fn pre_process_tree<'a>(
+/// Finds out which of two contents was added to the history of a commit last, for
+/// `Collision::Newest`. Contents are looked for at the paths they have in the tree of
+/// the commit, and the entries at those paths are remembered for the trees of its
+/// history, so resolving several collisions does not look at the same tree twice.
+struct ContentHistory<'a> {
     repo: &'a git2::Repository,
-    tree: git2::Tree<'a>,
-) -> JoshResult<git2::Tree<'a>> {
-    let path = Path::new("workspace.josh");
-    let ws_file = tree::get_blob(repo, &tree, path);
-    let parsed = filter::parse(&ws_file)?;
+    commit: git2::Oid,
+    /// Paths of each object in the tree of `commit`, found on first use
+    paths: std::cell::RefCell<Option<std::collections::HashMap<git2::Oid, Vec<String>>>>,
+    entries: std::cell::RefCell<std::collections::HashMap<(git2::Oid, String), Option<git2::Oid>>>,
+}
 
-    if invert(parsed).is_err() {
-        return Err(josh_error("Invalid workspace: not reversible"));
+impl<'a> ContentHistory<'a> {
+    fn new(repo: &'a git2::Repository, commit: &git2::Commit) -> Self {
+        ContentHistory {
+            repo,
+            commit: commit.id(),
+            paths: Default::default(),
+            entries: Default::default(),
+        }
     }
 
-    let mut blob = String::new();
-    if let Ok(c) = get_comments(&ws_file) {
-        if !c.is_empty() {
-            blob = c;
+    fn paths(&self, id: git2::Oid) -> JoshResult<Vec<String>> {
+        let mut paths = self.paths.borrow_mut();
+        if paths.is_none() {
+            let mut found = std::collections::HashMap::<_, Vec<_>>::new();
+            let tree = self.repo.find_commit(self.commit)?.tree()?;
+            tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+                if let Some(name) = entry.name() {
+                    found
+                        .entry(entry.id())
+                        .or_default()
+                        .push(format!("{}{}", root, name));
+                }
+                git2::TreeWalkResult::Ok
+            })?;
+            *paths = Some(found);
         }
+        Ok(paths
+            .as_ref()
+            .and_then(|p| p.get(&id))
+            .cloned()
+            .unwrap_or_default())
     }
-    let blob = &format!("{}{}\n", &blob, pretty(parsed, 0));
 
-    let tree = tree::insert(
-        repo,
-        &tree,
-        path,
-        repo.blob(blob.as_bytes())?,
-        0o0100644, // Should this handle filemode?
-    )?;
+    fn contains(&self, tree: &git2::Tree, id: git2::Oid) -> JoshResult<bool> {
+        for path in self.paths(id)? {
+            let key = (tree.id(), path);
+            let entry = *self
+                .entries
+                .borrow_mut()
+                .entry(key.clone())
+                .or_insert_with(|| tree.get_path(Path::new(&key.1)).ok().map(|e| e.id()));
+            if entry == Some(id) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
 
-    Ok(tree)
+    /// Of the objects `old` and `new`, the one that was added to the history last. The
+    /// history is walked back until a commit is found that contains only one of them.
+    fn newest(&self, old: git2::Oid, new: git2::Oid) -> JoshResult<git2::Oid> {
+        let mut walk = self.repo.revwalk()?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+        walk.push(self.commit)?;
+        for id in walk {
+            let tree = self.repo.find_commit(id?)?.tree()?;
+            match (self.contains(&tree, old)?, self.contains(&tree, new)?) {
+                (true, true) => continue,
+                (true, false) => return Ok(new),
+                (false, true) => return Ok(old),
+                (false, false) => break,
+            }
+        }
+        Ok(new)
+    }
 }
 
-/// Create a filter that is the result of feeding the output of `first` into `second`
-pub fn chain(first: Filter, second: Filter) -> Filter {
-    opt::optimize(to_filter(Op::Chain(first, second)))
+/// Where a file in the output of `apply_with_provenance` comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    /// Path of the file in the input tree, `None` if the filter moves files in a way
+    /// that can't be traced back, like `:collapse`
+    pub source: Option<std::path::PathBuf>,
+    /// The innermost compose member the file was taken from, or the whole filter if it
+    /// contains no compose
+    pub filter: Filter,
 }
 
-/// Create a filter that is the result of overlaying the output of `first` onto `second`
-pub fn compose(first: Filter, second: Filter) -> Filter {
-    opt::optimize(to_filter(Op::Compose(vec![first, second])))
+type ProvenanceMap =
+    std::collections::HashMap<std::path::PathBuf, (Option<std::path::PathBuf>, Option<Filter>)>;
+
+/// Apply `filter` to `tree` like `apply`, and also find out where each file of the result
+/// comes from. Of the members of a compose that produce the same file, it is attributed
+/// to the one whose output ends up in the result.
+pub fn apply_with_provenance(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree,
+) -> JoshResult<(
+    git2::Oid,
+    std::collections::HashMap<std::path::PathBuf, Provenance>,
+)> {
+    let (result, map) = provenance(transaction, filter, tree)?;
+    let map = map
+        .into_iter()
+        .map(|(path, (source, by))| {
+            let filter = by.unwrap_or(filter);
+            (path, Provenance { source, filter })
+        })
+        .collect();
+    Ok((result.id(), map))
 }
 
-/// Compute the warnings (filters not matching anything) for the filter applied to the tree
-pub fn compute_warnings<'a>(
+fn provenance<'a>(
     transaction: &'a cache::Transaction,
     filter: Filter,
     tree: git2::Tree<'a>,
-) -> Vec<String> {
-    let mut warnings = Vec::new();
-    let mut filter = filter;
+) -> JoshResult<(git2::Tree<'a>, ProvenanceMap)> {
+    let files = |tree: &git2::Tree| -> JoshResult<Vec<(std::path::PathBuf, git2::Oid)>> {
+        let mut files = vec![];
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() != Some(git2::ObjectType::Tree) {
+                let name = String::from_utf8_lossy(entry.name_bytes()).to_string();
+                files.push((Path::new(root).join(name), entry.id()));
+            }
+            git2::TreeWalkResult::Ok
+        })?;
+        Ok(files)
+    };
 
-    if let Op::Workspace(path) = to_op(filter) {
-        let workspace_filter = &tree::get_blob(
-            transaction.repo(),
-            &tree,
-            &path.join(Path::new("workspace.josh")),
-        );
-        if let Ok(res) = parse(workspace_filter) {
-            filter = res;
-        } else {
+    let result = apply(transaction, filter, tree.clone())?;
+    let map: ProvenanceMap = match to_op(filter) {
+        Op::Subdir(path) => files(&result)?
+            .into_iter()
+            .map(|(p, _)| (p.clone(), (Some(path.join(p)), None)))
+            .collect(),
+        Op::Prefix(path) => files(&tree)?
+            .into_iter()
+            .map(|(p, _)| (path.join(&p), (Some(p), None)))
+            .collect(),
+        Op::Chain(a, b) => {
+            let (first, first_map) = provenance(transaction, a, tree)?;
+            let (_, second_map) = provenance(transaction, b, first)?;
+            second_map
+                .into_iter()
+                .map(|(p, (source, by))| {
+                    let before = source.and_then(|s| first_map.get(&s).cloned());
+                    let source = before.as_ref().and_then(|(s, _)| s.clone());
+                    (p, (source, by.or(before.and_then(|(_, by)| by))))
+                })
+                .collect()
+        }
+        Op::Compose(members) => {
+            // Like in `tree::compose`, a member only gets the files that no member
+            // before it was applied to, and overrides the output of those
+            let mut map = ProvenanceMap::new();
+            let mut taken = std::collections::HashSet::new();
+            for member in members {
+                let (_, member_map) = provenance(transaction, member, tree.clone())?;
+                let mut sources = vec![];
+                for (p, (source, by)) in member_map {
+                    if source.as_ref().is_some_and(|s| taken.contains(s)) {
+                        continue;
+                    }
+                    sources.extend(source.clone());
+                    map.insert(p, (source, by.or(Some(member))));
+                }
+                taken.extend(sources);
+            }
+            let kept: std::collections::HashSet<_> =
+                files(&result)?.into_iter().map(|(p, _)| p).collect();
+            map.retain(|p, _| kept.contains(p));
+            map
+        }
+        Op::Subtract(a, _) => {
+            let (_, mut map) = provenance(transaction, a, tree)?;
+            let kept: std::collections::HashSet<_> =
+                files(&result)?.into_iter().map(|(p, _)| p).collect();
+            map.retain(|p, _| kept.contains(p));
+            map
+        }
+        // All other filters keep the files they don't drop in place, or move them
+        // without a way to find the original path from the new one
+        _ => files(&result)?
+            .into_iter()
+            .map(|(p, _)| {
+                let source = tree.get_path(&p).is_ok().then(|| p.clone());
+                (p, (source, None))
+            })
+            .collect(),
+    };
+    Ok((result, map))
+}
+
+/// Inputs and output of applying a filter to a commit, for external build caches.
+/// `key` only depends on the inputs, so it can be computed with `recipe_key` before
+/// filtering, to look up the `output` of an earlier run.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Recipe {
+    /// Version of the filter semantics, the same inputs may give different outputs
+    /// across versions
+    pub version: u64,
+    pub commit: String,
+    /// Spec of the optimized filter, so equivalent filters give the same recipe
+    pub filter: String,
+    pub key: String,
+    /// Filtered commit, all zeros if nothing was left of the history
+    pub output: String,
+}
+
+/// Key identifying the application of `filter` to `commit`, as used in `Recipe`
+pub fn recipe_key(filter: Filter, commit: git2::Oid) -> String {
+    let inputs = format!(
+        "josh-recipe {}\ncommit {}\nfilter {}\n",
+        cache::CACHE_VERSION,
+        commit,
+        spec(opt::optimize(filter))
+    );
+    git2::Oid::hash_object(git2::ObjectType::Blob, inputs.as_bytes())
+        .expect("hash_object recipe")
+        .to_string()
+}
+
+/// Filter `commit` and describe what was done as a `Recipe`
+pub fn apply_recipe(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    commit: &git2::Commit,
+) -> JoshResult<Recipe> {
+    let output = apply_to_commit(filter, commit, transaction)?;
+    Ok(Recipe {
+        version: cache::CACHE_VERSION,
+        commit: commit.id().to_string(),
+        filter: spec(opt::optimize(filter)),
+        key: recipe_key(filter, commit.id()),
+        output: output.to_string(),
+    })
+}
+
+/// Filter several branches at once, each with its own filter. Returns the filtered head
+/// of each branch, keyed by the same ref names as `mapping`. All branches are filtered
+/// in the same transaction, so work is shared between filters with common parts.
+pub fn apply_branches(
+    transaction: &cache::Transaction,
+    mapping: &std::collections::HashMap<String, Filter>,
+) -> JoshResult<std::collections::HashMap<String, git2::Oid>> {
+    let repo = transaction.repo();
+    mapping
+        .iter()
+        .map(|(refname, filter)| {
+            let commit = repo
+                .find_reference(refname)
+                .and_then(|r| r.peel_to_commit())
+                .map_err(|_| josh_error(&format!("Reference not found: {}", refname)))?;
+            Ok((
+                refname.clone(),
+                apply_to_commit(*filter, &commit, transaction)?,
+            ))
+        })
+        .collect()
+}
+
+/// Filter the history of `refname` and write it as a `git fast-import` stream to `writer`
+/// instead of storing the filtered commits under a ref. The stream updates `refname`
+/// when imported.
+pub fn export_fast_import(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    refname: &str,
+    writer: &mut dyn std::io::Write,
+) -> JoshResult<()> {
+    let repo = transaction.repo();
+    let commit = repo.find_reference(refname)?.peel_to_commit()?;
+    let filtered = apply_to_commit(filter, &commit, transaction)?;
+    if filtered == git2::Oid::zero() {
+        return Ok(());
+    }
+
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    walk.push(filtered)?;
+
+    let mut marks = std::collections::HashMap::<git2::Oid, usize>::new();
+    let mut next_mark = 1;
+
+    for id in walk {
+        let commit = repo.find_commit(id?)?;
+
+        let mut entries = vec![];
+        commit
+            .tree()?
+            .walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+                if entry.kind() != Some(git2::ObjectType::Tree) {
+                    let path = format!("{}{}", root, String::from_utf8_lossy(entry.name_bytes()));
+                    entries.push((entry.filemode(), entry.id(), entry.kind(), path));
+                }
+                git2::TreeWalkResult::Ok
+            })?;
+
+        for (_, id, kind, _) in entries.iter() {
+            if *kind != Some(git2::ObjectType::Blob) || marks.contains_key(id) {
+                continue;
+            }
+            let blob = repo.find_blob(*id)?;
+            write!(
+                writer,
+                "blob\nmark :{}\ndata {}\n",
+                next_mark,
+                blob.content().len()
+            )?;
+            writer.write_all(blob.content())?;
+            writeln!(writer)?;
+            marks.insert(*id, next_mark);
+            next_mark += 1;
+        }
+
+        if commit.parent_count() == 0 {
+            writeln!(writer, "reset {}", refname)?;
+        }
+        writeln!(writer, "commit {}\nmark :{}", refname, next_mark)?;
+        marks.insert(commit.id(), next_mark);
+        next_mark += 1;
+
+        for (name, signature) in [
+            ("author", commit.author()),
+            ("committer", commit.committer()),
+        ] {
+            write!(writer, "{} ", name)?;
+            writer.write_all(signature.name_bytes())?;
+            write!(writer, " <")?;
+            writer.write_all(signature.email_bytes())?;
+            let offset = signature.when().offset_minutes();
+            writeln!(
+                writer,
+                "> {} {}{:02}{:02}",
+                signature.when().seconds(),
+                if offset < 0 { '-' } else { '+' },
+                offset.abs() / 60,
+                offset.abs() % 60
+            )?;
+        }
+
+        let message = commit.message_bytes();
+        writeln!(writer, "data {}", message.len())?;
+        writer.write_all(message)?;
+        writeln!(writer)?;
+
+        for (i, parent) in commit.parent_ids().enumerate() {
+            let mark = marks
+                .get(&parent)
+                .ok_or_else(|| josh_error("parent commit not exported"))?;
+            writeln!(
+                writer,
+                "{} :{}",
+                if i == 0 { "from" } else { "merge" },
+                mark
+            )?;
+        }
+
+        writeln!(writer, "deleteall")?;
+        for (mode, id, kind, path) in entries.iter() {
+            let path = fast_import_path(path);
+            if *kind == Some(git2::ObjectType::Blob) {
+                writeln!(writer, "M {:o} :{} {}", mode, marks[id], path)?;
+            } else {
+                writeln!(writer, "M {:o} {} {}", mode, id, path)?;
+            }
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Paths in a fast-import stream need C style quoting if they could be mistaken
+/// for the end of the line or a quoted path
+fn fast_import_path(path: &str) -> String {
+    if !path.starts_with('"') && !path.contains('\n') {
+        return path.to_string();
+    }
+    format!(
+        "\"{}\"",
+        path.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+static TREES_VISITED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+static BLOBS_WRITTEN: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+static APPLY_CACHE_HITS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Work done by `apply` in this process, as returned by `global_apply_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct GlobalStats {
+    /// Trees that filters were applied to, including those of nested filters
+    pub trees_visited: u64,
+    /// Blobs created by filters that produce new content, like `:content-hash`.
+    /// Files that are only moved keep their blobs and are not counted.
+    pub blobs_written: u64,
+    /// Trees whose filtered result was found in a cache
+    pub cache_hits: u64,
+}
+
+/// Counters are shared by all threads and transactions, and only reset by
+/// `reset_global_apply_stats`
+pub fn global_apply_stats() -> GlobalStats {
+    GlobalStats {
+        trees_visited: TREES_VISITED.load(std::sync::atomic::Ordering::Relaxed),
+        blobs_written: BLOBS_WRITTEN.load(std::sync::atomic::Ordering::Relaxed),
+        cache_hits: APPLY_CACHE_HITS.load(std::sync::atomic::Ordering::Relaxed),
+    }
+}
+
+pub fn reset_global_apply_stats() {
+    TREES_VISITED.store(0, std::sync::atomic::Ordering::Relaxed);
+    BLOBS_WRITTEN.store(0, std::sync::atomic::Ordering::Relaxed);
+    APPLY_CACHE_HITS.store(0, std::sync::atomic::Ordering::Relaxed);
+}
+
+fn write_blob(repo: &git2::Repository, data: &[u8]) -> JoshResult<git2::Oid> {
+    BLOBS_WRITTEN.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    Ok(repo.blob(data)?)
+}
+
+/// Filter a single tree. This does not involve walking history and is thus fast in most cases.
+pub fn apply<'a>(
+    transaction: &'a cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree<'a>,
+) -> JoshResult<git2::Tree<'a>> {
+    transaction.check_deadline()?;
+    TREES_VISITED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let (outermost, _scope) = transaction.enter_apply();
+    let op = to_op(filter);
+    // Results are memoized per transaction, so identical subtrees reached by the same
+    // filter, for example in consecutive commits, are only processed once.
+    // The other ops are either cheap or cache their results themselves.
+    let memoized = matches!(
+        op,
+        Op::Compose(_)
+            | Op::Subtract(_, _)
+            | Op::Intersect(_, _)
+            | Op::Exclude(_)
+            | Op::Workspace(_)
+    );
+    // The results of outermost applies of these and of chains are also kept in memory and
+    // on disk, if `cache::load_apply_cache` was called. Going to disk for every nested
+    // apply would cost more than it saves. Stored trees may be missing from the repo,
+    // those get computed again.
+    let stored = outermost && (memoized || matches!(op, Op::Chain(_, _)));
+    let memoized = memoized || stored;
+    if let Op::Subdir(path) = op {
+        return apply_subdir_run(transaction, path, nop(), tree);
+    }
+    if !stored {
+        return apply2(transaction, &op, tree);
+    }
+    if memoized {
+        if let Some(cached) = transaction.get_apply(filter, tree.id()) {
+            APPLY_CACHE_HITS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            return Ok(transaction.repo().find_tree(cached)?);
+        }
+    }
+    if let Some(cached) = transaction
+        .get_stored_apply(filter, tree.id())?
+        .and_then(|id| transaction.repo().find_tree(id).ok())
+    {
+        APPLY_CACHE_HITS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if memoized {
+            transaction.insert_apply(filter, tree.id(), cached.id());
+        }
+        return Ok(cached);
+    }
+    let result = apply2(transaction, &op, tree.clone())?;
+    if memoized {
+        transaction.insert_apply(filter, tree.id(), result.id());
+    }
+    transaction.insert_stored_apply(filter, tree.id(), result.id())?;
+    Ok(result)
+}
+
+/// Composes with at least this many members apply them in parallel
+const PARALLEL_COMPOSE_MIN: usize = 8;
+
+/// Apply each of `filters` to `tree`, in parallel if `parallel` is set, `transaction` writes
+/// to the repo on disk and there are enough of them to make up for the overhead.
+/// git2 objects can't be passed between threads, so every worker opens its own transaction
+/// on the repo and only the ids of the results are returned. The caches of those transactions
+/// are dropped afterwards, so the results are also added to the cache of `transaction`.
+fn apply_members(
+    transaction: &cache::Transaction,
+    filters: &[Filter],
+    tree: &git2::Tree,
+    parallel: bool,
+) -> JoshResult<Vec<git2::Oid>> {
+    // The workers only see what the transaction wrote if it is on disk, and they add
+    // their results to the shared caches
+    if !parallel || filters.len() < PARALLEL_COMPOSE_MIN || !transaction.writes_shared() {
+        return filters
+            .iter()
+            .map(|f| Ok(apply(transaction, *f, tree.clone())?.id()))
+            .collect();
+    }
+
+    let path = transaction.repo().path();
+    let ref_prefix = transaction.ref_prefix();
+    let tree_id = tree.id();
+    let deadline = transaction.deadline();
+    let log_conflicts = transaction.log_conflicts();
+    let filtered = filters
+        .par_iter()
+        .map_init(
+            || {
+                let worker = cache::Transaction::open(path, Some(ref_prefix))?;
+                worker.set_deadline(deadline);
+                worker.set_log_conflicts(log_conflicts);
+                JoshResult::Ok(worker)
+            },
+            |worker, f| {
+                let worker = worker.as_ref().map_err(|e| e.clone())?;
+                let tree = worker.repo().find_tree(tree_id)?;
+                Ok(apply(worker, *f, tree)?.id())
+            },
+        )
+        .collect::<JoshResult<Vec<_>>>()?;
+
+    for (f, id) in filters.iter().zip(filtered.iter()) {
+        transaction.insert_apply(*f, tree_id, *id);
+    }
+    Ok(filtered)
+}
+
+fn apply2<'a>(
+    transaction: &'a cache::Transaction,
+    op: &Op,
+    tree: git2::Tree<'a>,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    match op {
+        Op::Nop => Ok(tree),
+        Op::Empty => return Ok(tree::empty(repo)),
+        Op::Fold => Ok(tree),
+        Op::Squash(None) => Ok(tree),
+        Op::Message(_) => Ok(tree),
+        Op::Author(_, _) => Ok(tree),
+        Op::Committer(_, _) => Ok(tree),
+        Op::Squash(Some(_)) => Err(josh_error("not applicable to tree")),
+        Op::Linear => Ok(tree),
+        Op::Take(_) => Ok(tree),
+        Op::KeepVersions(_) => Ok(tree),
+        Op::Notes(_) => Ok(tree),
+        Op::LastAuthor(_) => Err(josh_error("not applicable to tree")),
+        Op::Unsign => Ok(tree),
+        Op::OriginTrailer => Ok(tree),
+        Op::RewriteMessage(_) => Ok(tree),
+        Op::Rev(_) => Err(josh_error("not applicable to tree")),
+        Op::Join(_) => Err(josh_error("not applicable to tree")),
+        Op::RegexReplace(replacements) => {
+            let mut t = tree;
+            for (regex, replacement) in replacements {
+                t = tree::regex_replace(t.id(), regex, replacement, transaction)?;
+            }
+            Ok(t)
+        }
+
+        Op::Glob(pattern) => {
+            let pattern = glob::Pattern::new(pattern)?;
+            tree::remove_pred(
+                transaction,
+                "",
+                tree.id(),
+                &|path, isblob| isblob && (pattern.matches_path_with(path, GLOB_OPTIONS)),
+                to_filter(op.clone()).id(),
+            )
+        }
+        Op::Select(include, exclude) => {
+            let compile = |patterns: &Vec<String>| {
+                patterns
+                    .iter()
+                    .map(|p| glob::Pattern::new(p))
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            let (include, exclude) = (compile(include)?, compile(exclude)?);
+            let matches = |patterns: &Vec<glob::Pattern>, path: &Path| {
+                patterns
+                    .iter()
+                    .any(|p| p.matches_path_with(path, GLOB_OPTIONS))
+            };
+            tree::remove_pred(
+                transaction,
+                "",
+                tree.id(),
+                &|path, isblob| isblob && matches(&include, path) && !matches(&exclude, path),
+                to_filter(op.clone()).id(),
+            )
+        }
+        Op::Docs => apply2(
+            transaction,
+            &Op::Select(
+                DOCS_PATTERNS.iter().map(|p| p.to_string()).collect(),
+                vec![],
+            ),
+            tree,
+        ),
+        Op::LowercasePaths => {
+            tree::lowercase(transaction, "", tree.id(), to_filter(op.clone()).id())
+        }
+        Op::FlattenSingleChild => {
+            tree::collapse(transaction, "", tree.id(), to_filter(op.clone()).id())
+        }
+        Op::Explode(child) => {
+            tree::explode(transaction, child, tree.id(), to_filter(op.clone()).id())
+        }
+        Op::StripExtension(ext) => {
+            tree::strip_extension(transaction, "", ext, tree.id(), to_filter(op.clone()).id())
+        }
+        Op::FlattenWithPathNames(sep) => {
+            tree::flatten(transaction, sep, tree.id(), to_filter(op.clone()).id())
+        }
+        Op::DedupBlobs => tree::dedup_blobs(transaction, tree.id(), to_filter(op.clone()).id()),
+        Op::Archive(format, path) => archive::archive(
+            transaction,
+            format,
+            path,
+            tree.id(),
+            to_filter(op.clone()).id(),
+        ),
+        Op::ContentToHash => tree::content_hash(transaction, tree.id(), to_filter(op.clone()).id()),
+        Op::TouchedBy(id) => {
+            let commit = repo.find_commit(*id)?;
+            let parent_tree = commit
+                .parents()
+                .next()
+                .map(|parent| parent.tree_id())
+                .unwrap_or_else(tree::empty_id);
+            let touched = tree::diff_paths(repo, parent_tree, commit.tree_id(), "")?
+                .into_iter()
+                .map(|(path, _)| std::path::PathBuf::from(path))
+                .collect::<std::collections::HashSet<_>>();
+            tree::remove_pred(
+                transaction,
+                "",
+                tree.id(),
+                &|path, isblob| isblob && touched.contains(path),
+                to_filter(op.clone()).id(),
+            )
+        }
+        Op::DiffRange(from, to) => {
+            let from = repo.find_commit(*from)?.tree_id();
+            let to = repo.find_commit(*to)?.tree_id();
+            let changed = tree::diff_paths(repo, from, to, "")?
+                .into_iter()
+                .filter(|(_, kind)| *kind >= 0)
+                .map(|(path, _)| std::path::PathBuf::from(path))
+                .collect::<std::collections::HashSet<_>>();
+            tree::remove_pred(
+                transaction,
+                "",
+                tree.id(),
+                &|path, isblob| isblob && changed.contains(path),
+                to_filter(op.clone()).id(),
+            )
+        }
+        Op::AddedSince(base) => {
+            let base = repo.find_commit(*base)?.tree_id();
+            tree::added_since(transaction, tree.id(), base, to_filter(op.clone()).id())
+        }
+        Op::IntersectRefs(ids, content) => {
+            let blobs = |tree: &git2::Tree| -> JoshResult<
+                std::collections::HashMap<std::path::PathBuf, git2::Oid>,
+            > {
+                let mut blobs = std::collections::HashMap::new();
+                tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+                    if entry.kind() == Some(git2::ObjectType::Blob) {
+                        let path = Path::new(root).join(entry.name().unwrap_or_default());
+                        blobs.insert(path, entry.id());
+                    }
+                    git2::TreeWalkResult::Ok
+                })?;
+                Ok(blobs)
+            };
+            let others = ids
+                .iter()
+                .map(|id| blobs(&repo.find_commit(*id)?.tree()?))
+                .collect::<JoshResult<Vec<_>>>()?;
+            let input = if *content {
+                blobs(&tree)?
+            } else {
+                std::collections::HashMap::new()
+            };
+            tree::remove_pred(
+                transaction,
+                "",
+                tree.id(),
+                &|path, isblob| {
+                    isblob
+                        && others.iter().all(|other| match other.get(path) {
+                            Some(id) => !*content || input.get(path) == Some(id),
+                            None => false,
+                        })
+                },
+                to_filter(op.clone()).id(),
+            )
+        }
+        Op::PathRegex(regex) => tree::remove_pred(
+            transaction,
+            "",
+            tree.id(),
+            &|path, isblob| isblob && path.to_str().is_some_and(|p| regex.is_match(p)),
+            to_filter(op.clone()).id(),
+        ),
+        Op::ContentGrep(regex) => tree::keep_blobs(
+            transaction,
+            tree.id(),
+            &|blob| !blob.is_binary() && regex.is_match(&String::from_utf8_lossy(blob.content())),
+            to_filter(op.clone()).id(),
+        ),
+        Op::BinaryOnly => tree::keep_blobs(
+            transaction,
+            tree.id(),
+            &|blob| blob.is_binary(),
+            to_filter(op.clone()).id(),
+        ),
+        Op::TextOnly => tree::keep_blobs(
+            transaction,
+            tree.id(),
+            &|blob| !blob.is_binary(),
+            to_filter(op.clone()).id(),
+        ),
+        Op::LfsOnly => tree::keep_blobs(
+            transaction,
+            tree.id(),
+            &|blob| tree::is_lfs_pointer(blob.content()),
+            to_filter(op.clone()).id(),
+        ),
+        Op::Owned(owner, path) => codeowners::owned(
+            transaction,
+            owner,
+            path,
+            tree.id(),
+            to_filter(op.clone()).id(),
+        ),
+        Op::File(path) => {
+            let (file, mode) = tree
+                .get_path(path)
+                .map(|x| (x.id(), x.filemode()))
+                .unwrap_or((git2::Oid::zero(), 0o0100644));
+            tree::insert(repo, &tree::empty(repo), path, file, mode)
+        }
+
+        Op::Subdir(path) => {
+            return Ok(tree
+                .get_path(path)
+                .and_then(|x| repo.find_tree(x.id()))
+                .unwrap_or_else(|_| tree::empty(repo)));
+        }
+        Op::Prefix(path) => tree::insert(repo, &tree::empty(repo), path, tree.id(), 0o0040000),
+
+        Op::Subtract(a, b) => {
+            let af = apply(transaction, *a, tree.clone())?;
+            let bf = apply(transaction, *b, tree.clone())?;
+            let bu = apply(transaction, invert(*b)?, bf)?;
+            let ba = apply(transaction, *a, bu)?.id();
+            Ok(repo.find_tree(tree::subtract(transaction, af.id(), ba)?)?)
+        }
+        Op::Intersect(a, b) => {
+            let af = apply(transaction, *a, tree.clone())?;
+            let bf = apply(transaction, *b, tree.clone())?;
+            let bu = apply(transaction, invert(*b)?, bf)?;
+            let ba = apply(transaction, *a, bu)?.id();
+            let outside = tree::subtract(transaction, af.id(), ba)?;
+            Ok(repo.find_tree(tree::subtract(transaction, af.id(), outside)?)?)
+        }
+        Op::Exclude(b) => {
+            let bf = apply(transaction, *b, tree.clone())?.id();
+            Ok(repo.find_tree(tree::subtract(transaction, tree.id(), bf)?)?)
+        }
+        Op::IfExists(path, a, b) => {
+            let branch = if tree.get_path(path).is_ok() { a } else { b };
+            apply(transaction, *branch, tree)
+        }
+
+        Op::Paths => tree::pathstree("", tree.id(), transaction),
+        Op::Index => tree::trigram_index(transaction, tree),
+
+        Op::Invert => tree::invert_paths(transaction, "", tree),
+
+        Op::Workspace(path) => {
+            let wsj_file = to_filter(Op::File(Path::new("workspace.josh").to_owned()));
+            let base = to_filter(Op::Subdir(path.to_owned()));
+            let wsj_file = chain(base, wsj_file);
+
+            if let Some((redirect, _)) = resolve_workspace_redirect(repo, &tree, path) {
+                return apply(transaction, redirect, tree);
+            }
+
+            apply(
+                transaction,
+                compose(wsj_file, compose(get_workspace(repo, &tree, path), base)),
+                tree,
+            )
+        }
+
+        Op::SelfView => {
+            let view_path = Path::new(SELF_VIEW_FILE);
+            if tree.get_path(view_path).is_err() {
+                return Ok(tree);
+            }
+            let view = tree::get_blob(repo, &tree, view_path);
+            let f = parse::parse(&view).map_err(|e| {
+                josh_error(&format!("Invalid filter in {}: {}", SELF_VIEW_FILE, e.0))
+            })?;
+            if uses_self_view(f) {
+                return Err(josh_error(&format!(
+                    "Filter in {} must not use \":self\"",
+                    SELF_VIEW_FILE
+                )));
+            }
+            apply(transaction, f, tree)
+        }
+
+        Op::Compose(filters) => {
+            let filtered = apply_members(transaction, filters, &tree, true)?;
+            if disjoint_members(filters) {
+                return tree::compose_fast(transaction, filtered);
+            }
+            let filtered = filtered
+                .into_iter()
+                .map(|id| repo.find_tree(id))
+                .collect::<Result<Vec<_>, _>>()?;
+            let filtered: Vec<_> = filters.iter().zip(filtered.into_iter()).collect();
+            tree::compose(transaction, filtered)
+        }
+
+        Op::Chain(a, b) => {
+            if let Op::Subdir(path) = to_op(*a) {
+                return apply_subdir_run(transaction, path, *b, tree);
+            }
+            return apply(transaction, *b, apply(transaction, *a, tree)?);
+        }
+    }
+}
+
+/// The optimizer splits subdir filters into chains of single components so that
+/// partial results can be shared. When applying such a chain to a tree the run of
+/// components is descended in one go, instead of applying each part on its own.
+/// The result of the whole run is recorded, and so is that of each component for the
+/// tree it was applied to. A later `:/a` or `:/a/b` reuses those of the descent done
+/// for `:/a/b/c`, and so do other runs that start with the same components.
+fn apply_subdir_run<'a>(
+    transaction: &'a cache::Transaction,
+    path: std::path::PathBuf,
+    mut rest: Filter,
+    tree: git2::Tree<'a>,
+) -> JoshResult<git2::Tree<'a>> {
+    let mut paths = vec![path];
+    loop {
+        let (p, next) = match to_op(rest) {
+            Op::Subdir(p) => (p, nop()),
+            Op::Chain(x, y) => match to_op(x) {
+                Op::Subdir(p) => (p, y),
+                _ => break,
+            },
+            _ => break,
+        };
+        paths.push(p);
+        rest = next;
+    }
+
+    let subdirs: Vec<_> = paths
+        .into_iter()
+        .map(|p| to_filter(Op::Subdir(p)))
+        .collect();
+    // Built the way the optimizer chains them, so it is the same filter as the run
+    let run = subdirs
+        .iter()
+        .rev()
+        .copied()
+        .reduce(|run, s| to_filter(Op::Chain(s, run)))
+        .unwrap();
+
+    let repo = transaction.repo();
+    let subdir = match transaction.get_apply(run, tree.id()) {
+        Some(id) => repo.find_tree(id)?,
+        None => {
+            let mut subdir = tree.clone();
+            for s in &subdirs {
+                let parent = subdir.id();
+                subdir = match transaction.get_apply(*s, parent) {
+                    Some(id) => repo.find_tree(id)?,
+                    None => apply2(transaction, &to_op(*s), subdir)?,
+                };
+                transaction.insert_apply(*s, parent, subdir.id());
+            }
+            transaction.insert_apply(run, tree.id(), subdir.id());
+            subdir
+        }
+    };
+
+    if rest == nop() {
+        return Ok(subdir);
+    }
+    apply(transaction, rest, subdir)
+}
+
+/// Apply `filter` to `tree` and also return the path in `tree` that each file of the result
+/// comes from, for example to place edits of the result in the input.
+/// The filter has to be invertible and it must not select files based on their content.
+pub fn apply_with_reverse_map(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree,
+) -> JoshResult<(
+    git2::Oid,
+    std::collections::HashMap<std::path::PathBuf, std::path::PathBuf>,
+)> {
+    invert(filter)?;
+    let repo = transaction.repo();
+    let paths = apply(
+        transaction,
+        chain(to_filter(Op::Paths), filter),
+        tree.clone(),
+    )?;
+    let filtered = apply(transaction, filter, tree)?;
+
+    let blobs = |tree: &git2::Tree| -> JoshResult<Vec<(std::path::PathBuf, git2::Oid)>> {
+        let mut blobs = vec![];
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() == Some(git2::ObjectType::Blob) {
+                let path = Path::new(root).join(entry.name().unwrap_or_default());
+                blobs.push((path, entry.id()));
+            }
+            git2::TreeWalkResult::Ok
+        })?;
+        Ok(blobs)
+    };
+
+    let mut map = std::collections::HashMap::new();
+    for (path, id) in blobs(&paths)? {
+        let blob = repo.find_blob(id)?;
+        let source = tree::pathline(std::str::from_utf8(blob.content())?)?;
+        map.insert(path, std::path::PathBuf::from(source));
+    }
+
+    let files = blobs(&filtered)?;
+    if files.len() != map.len() || files.iter().any(|(path, _)| !map.contains_key(path)) {
+        return Err(josh_error(&format!(
+            "{} depends on file content, can't map its output paths",
+            spec(filter)
+        )));
+    }
+    Ok((filtered.id(), map))
+}
+
+/// Try to find a filter made of subdir, prefix and compose operations that produces `output`
+/// when applied to `input`. Every directory or file of `output` must appear somewhere in
+/// `input` with identical content; files also need to keep their name.
+/// Returns `None` when the output can't be explained by such a rearrangement.
+pub fn infer_filter(
+    transaction: &cache::Transaction,
+    input: &git2::Tree,
+    output: &git2::Tree,
+) -> Option<Filter> {
+    if input.id() == output.id() {
+        return Some(nop());
+    }
+
+    let mut index = std::collections::HashMap::<git2::Oid, std::path::PathBuf>::new();
+    input
+        .walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            let path = Path::new(root).join(entry.name().unwrap_or_default());
+            let known = index.entry(entry.id()).or_insert_with(|| path.clone());
+            if known.components().count() > path.components().count() {
+                *known = path;
+            }
+            git2::TreeWalkResult::Ok
+        })
+        .ok()?;
+    index.insert(input.id(), std::path::PathBuf::new());
+
+    let members = infer_members(transaction.repo(), &index, output, Path::new(""))?;
+    let filter = opt::optimize(to_filter(Op::Compose(members)));
+
+    let filtered = apply(transaction, filter, input.clone()).ok()?;
+    (filtered.id() == output.id()).then_some(filter)
+}
+
+fn infer_members(
+    repo: &git2::Repository,
+    index: &std::collections::HashMap<git2::Oid, std::path::PathBuf>,
+    output: &git2::Tree,
+    dst: &Path,
+) -> Option<Vec<Filter>> {
+    let place = |filter: Filter| {
+        if dst == Path::new("") {
+            filter
+        } else {
+            to_filter(Op::Chain(filter, to_filter(Op::Prefix(dst.to_owned()))))
+        }
+    };
+
+    if let Some(src) = index.get(&output.id()) {
+        return Some(vec![place(to_filter(Op::Subdir(src.clone())))]);
+    }
+
+    let mut members = vec![];
+    for entry in output.iter() {
+        let name = entry.name()?;
+        if entry.kind() == Some(git2::ObjectType::Tree) {
+            let tree = repo.find_tree(entry.id()).ok()?;
+            members.append(&mut infer_members(repo, index, &tree, &dst.join(name))?);
+        } else {
+            let src = index.get(&entry.id())?;
+            if src.file_name()? != name {
+                return None;
+            }
+            let file = to_filter(Op::File(Path::new(name).to_owned()));
+            let file = match src.parent() {
+                Some(parent) if parent != Path::new("") => {
+                    to_filter(Op::Chain(to_filter(Op::Subdir(parent.to_owned())), file))
+                }
+                _ => file,
+            };
+            members.push(place(file));
+        }
+    }
+    Some(members)
+}
+
+/// Calculate a tree with minimal differences from `parent_tree`
+/// such that `apply(unapply(tree, parent_tree)) == tree`
+pub fn unapply<'a>(
+    transaction: &'a cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree<'a>,
+    parent_tree: git2::Tree<'a>,
+) -> JoshResult<git2::Tree<'a>> {
+    if let Ok(inverted) = invert(filter) {
+        let matching = apply(
+            transaction,
+            chain(invert(inverted)?, inverted),
+            parent_tree.clone(),
+        )?;
+        let stripped = tree::subtract(transaction, parent_tree.id(), matching.id())?;
+        let new_tree = apply(transaction, inverted, tree)?;
+
+        return Ok(transaction.repo().find_tree(tree::overlay(
+            transaction,
+            new_tree.id(),
+            stripped,
+        )?)?);
+    }
+
+    if let Op::Intersect(_, _) = to_op(filter) {
+        // Report why the intersection can't be inverted
+        invert(filter)?;
+    }
+
+    if let Some(ws) = unapply_workspace(
+        transaction,
+        &to_op(filter),
+        tree.clone(),
+        parent_tree.clone(),
+    )? {
+        return Ok(ws);
+    }
+
+    if let Op::Chain(a, b) = to_op(filter) {
+        // If filter "a" is invertable, use "invert(invert(a))" version of it, otherwise use as is
+        let a_normalized = if let Ok(a_inverted) = invert(a) {
+            invert(a_inverted)?
+        } else {
+            a
+        };
+        let filtered_parent_tree = apply(transaction, a_normalized, parent_tree.clone())?;
+
+        return unapply(
+            transaction,
+            a,
+            unapply(transaction, b, tree, filtered_parent_tree)?,
+            parent_tree,
+        );
+    }
+
+    Err(josh_error("filter cannot be unapplied"))
+}
+
+/// Files of a filtered tree that are lost when it is unapplied, because the filter
+/// can't produce them at their path or with their content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedPaths {
+    /// Spec of the filter that was unapplied
+    pub filter: String,
+    /// Paths of the files in the filtered tree, sorted
+    pub paths: Vec<std::path::PathBuf>,
+}
+
+impl std::fmt::Display for RejectedPaths {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Paths outside of the filter {}:", self.filter)?;
+        for path in &self.paths {
+            writeln!(f, "  {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the files of `tree` that `unapply` drops or changes.
+/// Unapplied trees are filtered again and compared with `tree`, so this covers any
+/// nesting of filters. The paths are those of `tree`, as files that can't be mapped
+/// back have no path in the unfiltered tree.
+pub fn rejected_paths(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree,
+    parent_tree: git2::Tree,
+) -> JoshResult<Option<RejectedPaths>> {
+    let unapplied = unapply(transaction, filter, tree.clone(), parent_tree)?;
+    let reapplied = apply(transaction, filter, unapplied)?;
+    if reapplied.id() == tree.id() {
+        return Ok(None);
+    }
+
+    let mut paths = vec![];
+    tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() == Some(git2::ObjectType::Blob) {
+            let path = Path::new(root).join(entry.name().unwrap_or_default());
+            if reapplied.get_path(&path).map(|e| e.id()).ok() != Some(entry.id()) {
+                paths.push(path);
+            }
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+    paths.sort();
+
+    Ok((!paths.is_empty()).then(|| RejectedPaths {
+        filter: spec(filter),
+        paths,
+    }))
+}
+
+/// Like `unapply`, but fail listing the rejected paths instead of dropping files
+/// that can't be mapped back
+pub fn unapply_strict<'a>(
+    transaction: &'a cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree<'a>,
+    parent_tree: git2::Tree<'a>,
+) -> JoshResult<git2::Tree<'a>> {
+    if let Some(rejected) = rejected_paths(transaction, filter, tree.clone(), parent_tree.clone())?
+    {
+        return Err(josh_error(&rejected.to_string()));
+    }
+    unapply(transaction, filter, tree, parent_tree)
+}
+
+fn unapply_workspace<'a>(
+    transaction: &'a cache::Transaction,
+    op: &Op,
+    tree: git2::Tree<'a>,
+    parent_tree: git2::Tree<'a>,
+) -> JoshResult<Option<git2::Tree<'a>>> {
+    return match op {
+        Op::Workspace(path) => {
+            let tree = pre_process_tree(transaction.repo(), tree)?;
+            let workspace = get_workspace(transaction.repo(), &tree, Path::new(""));
+            let original_workspace = get_workspace(transaction.repo(), &parent_tree, path);
+
+            let root = to_filter(Op::Subdir(path.to_owned()));
+            let wsj_file = to_filter(Op::File(Path::new("workspace.josh").to_owned()));
+            let wsj_file = chain(root, wsj_file);
+            let filter = compose(wsj_file, compose(workspace, root));
+            let original_filter = compose(wsj_file, compose(original_workspace, root));
+            let matching = apply(
+                transaction,
+                chain(original_filter, invert(original_filter)?),
+                parent_tree.clone(),
+            )?;
+            let stripped = tree::subtract(transaction, parent_tree.id(), matching.id())?;
+            let new_tree = apply(transaction, invert(filter)?, tree)?;
+
+            let result = transaction.repo().find_tree(tree::overlay(
+                transaction,
+                new_tree.id(),
+                stripped,
+            )?)?;
+
+            return Ok(Some(result));
+        }
+        _ => Ok(None),
+    };
+}
+
+fn pre_process_tree<'a>(
+    repo: &'a git2::Repository,
+    tree: git2::Tree<'a>,
+) -> JoshResult<git2::Tree<'a>> {
+    let path = Path::new("workspace.josh");
+    let ws_file = tree::get_blob(repo, &tree, path);
+    let parsed = filter::parse(&ws_file)?;
+
+    if invert(parsed).is_err() {
+        return Err(josh_error("Invalid workspace: not reversible"));
+    }
+
+    let mut blob = String::new();
+    if let Ok(c) = get_comments(&ws_file) {
+        if !c.is_empty() {
+            blob = c;
+        }
+    }
+    let blob = &format!("{}{}\n", &blob, pretty(parsed, 0));
+
+    let tree = tree::insert(
+        repo,
+        &tree,
+        path,
+        repo.blob(blob.as_bytes())?,
+        0o0100644, // Should this handle filemode?
+    )?;
+
+    Ok(tree)
+}
+
+/// Create a filter that is the result of feeding the output of `first` into `second`
+pub fn chain(first: Filter, second: Filter) -> Filter {
+    opt::optimize(to_filter(Op::Chain(first, second)))
+}
+
+/// Create a filter that is the result of overlaying the output of `first` onto `second`
+pub fn compose(first: Filter, second: Filter) -> Filter {
+    opt::optimize(to_filter(Op::Compose(vec![first, second])))
+}
+
+/// Compute the warnings (filters not matching anything) for the filter applied to the tree
+pub fn compute_warnings<'a>(
+    transaction: &'a cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree<'a>,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut filter = filter;
+
+    if let Op::Workspace(path) = to_op(filter) {
+        let workspace_filter = &tree::get_blob(
+            transaction.repo(),
+            &tree,
+            &path.join(Path::new("workspace.josh")),
+        );
+        if let Ok(res) = parse(workspace_filter) {
+            filter = res;
+        } else {
             warnings.push("couldn't parse workspace\n".to_string());
             return warnings;
         }
     }
 
-    let filter = opt::flatten(filter);
-    if let Op::Compose(filters) = to_op(filter) {
-        for f in filters {
-            let tree = transaction.repo().find_tree(tree.id());
-            if let Ok(tree) = tree {
-                warnings.append(&mut compute_warnings2(transaction, f, tree));
+    let filter = opt::flatten(filter);
+    if let Op::Compose(filters) = to_op(filter) {
+        for f in filters {
+            let tree = transaction.repo().find_tree(tree.id());
+            if let Ok(tree) = tree {
+                warnings.append(&mut compute_warnings2(transaction, f, tree));
+            }
+        }
+    } else {
+        warnings.append(&mut compute_warnings2(transaction, filter, tree));
+    }
+    warnings
+}
+
+fn compute_warnings2<'a>(
+    transaction: &'a cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree<'a>,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    let tree = apply(transaction, filter, tree);
+    if let Ok(tree) = tree {
+        if tree.is_empty() {
+            warnings.push(format!("No match for \"{}\"", pretty(filter, 2)));
+        }
+    }
+    warnings
+}
+
+/// Find parts of the filter that are likely mistakes, without applying it.
+/// These are advisory, the filter works as written:
+/// * Members of a compose that are the same filter except for the prefix they are
+///   placed at last, which usually means a copied line was not fully adjusted.
+pub fn lint(filter: Filter) -> Vec<String> {
+    struct Lint(Vec<String>);
+
+    impl FilterVisitor for Lint {
+        fn visit_compose(&mut self, members: &[Filter]) {
+            let prefixed: Vec<_> = members
+                .iter()
+                .filter_map(|m| outer_prefix(*m).map(|(rest, p)| (*m, opt::optimize(rest), p)))
+                .collect();
+            for (i, (a, rest_a, prefix_a)) in prefixed.iter().enumerate() {
+                for (b, rest_b, prefix_b) in &prefixed[i + 1..] {
+                    if rest_a == rest_b && prefix_a != prefix_b {
+                        self.0.push(format!(
+                            "\"{}\" and \"{}\" only differ in their prefix",
+                            spec(*a),
+                            spec(*b)
+                        ));
+                    }
+                }
+            }
+            for m in members {
+                walk(*m, self);
+            }
+        }
+    }
+
+    fn outer_prefix(filter: Filter) -> Option<(Filter, std::path::PathBuf)> {
+        match to_op(filter) {
+            Op::Prefix(p) => Some((nop(), p)),
+            Op::Chain(a, b) => outer_prefix(b).map(|(rest, p)| (to_filter(Op::Chain(a, rest)), p)),
+            _ => None,
+        }
+    }
+
+    let mut lint = Lint(vec![]);
+    walk(opt::flatten(filter), &mut lint);
+    lint.0
+}
+
+pub fn make_permissions_filter(filter: Filter, whitelist: Filter, blacklist: Filter) -> Filter {
+    rs_tracing::trace_scoped!("make_permissions_filter");
+
+    let filter = chain(to_filter(Op::Paths), filter);
+    let filter = chain(filter, to_filter(Op::Invert));
+    let filter = chain(
+        filter,
+        compose(blacklist, to_filter(Op::Subtract(nop(), whitelist))),
+    );
+    opt::optimize(filter)
+}
+
+/// Check if `commit` is an ancestor of `tip`.
+///
+/// Creates a cache for a given `tip` so repeated queries with the same `tip` are more efficient.
+fn is_ancestor_of(
+    repo: &git2::Repository,
+    commit: git2::Oid,
+    tip: git2::Oid,
+) -> Result<bool, git2::Error> {
+    let mut ancestor_cache = ANCESTORS.lock().unwrap();
+    let ancestors = match ancestor_cache.entry(tip) {
+        std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+        std::collections::hash_map::Entry::Vacant(entry) => {
+            tracing::trace!("is_ancestor_of tip={tip}");
+            // Recursively compute all ancestors of `tip`.
+            // Invariant: Everything in `todo` is also in `ancestors`.
+            let mut todo = vec![tip];
+            let mut ancestors = std::collections::HashSet::from_iter(todo.iter().copied());
+            while let Some(commit) = todo.pop() {
+                for parent in repo.find_commit(commit)?.parent_ids() {
+                    if ancestors.insert(parent) {
+                        // Newly inserted! Also handle its parents.
+                        todo.push(parent);
+                    }
+                }
+            }
+            entry.insert(ancestors)
+        }
+    };
+    Ok(ancestors.contains(&commit))
+}
+
+/// Whether the result of `filter` for a commit is computed from the whole history of the
+/// commit, like for `:keep-versions`, instead of from the results for its parents
+pub(crate) fn filters_from_tip(filter: Filter) -> bool {
+    match to_op(filter) {
+        Op::Take(_) | Op::KeepVersions(_) => true,
+        Op::Chain(_, b) => filters_from_tip(b),
+        _ => false,
+    }
+}
+
+fn uses_self_view(filter: Filter) -> bool {
+    struct SelfView(bool);
+
+    impl FilterVisitor for SelfView {
+        fn visit_other(&mut self, filter: Filter) {
+            self.0 |= matches!(to_op(filter), Op::SelfView);
+        }
+    }
+
+    let mut found = SelfView(false);
+    walk(filter, &mut found);
+    found.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    lazy_static! {
+        static ref CACHE_DIR: tempfile::TempDir = {
+            let dir = tempfile::tempdir().unwrap();
+            cache::load(dir.path()).unwrap();
+            dir
+        };
+    }
+
+    fn test_transaction() -> (tempfile::TempDir, cache::Transaction) {
+        lazy_static::initialize(&CACHE_DIR);
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(dir.path()).unwrap();
+        repo.treebuilder(None).unwrap().write().unwrap();
+        let transaction = cache::Transaction::open(dir.path(), None).unwrap();
+        (dir, transaction)
+    }
+
+    fn make_tree<'a>(repo: &'a git2::Repository, files: &[(&str, &str)]) -> git2::Tree<'a> {
+        let mut tree = tree::empty(repo);
+        for (path, content) in files {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            tree = tree::insert(repo, &tree, Path::new(path), blob, 0o0100644).unwrap();
+        }
+        tree
+    }
+
+    /// Commit a tree made of `files` on top of `parents`
+    fn make_commit(
+        repo: &git2::Repository,
+        files: &[(&str, &str)],
+        parents: &[git2::Oid],
+    ) -> git2::Oid {
+        let signature = git2::Signature::now("Josh", "josh@example.com").unwrap();
+        make_signed_commit(repo, &signature, &signature, "commit", files, parents)
+    }
+
+    fn make_signed_commit(
+        repo: &git2::Repository,
+        author: &git2::Signature,
+        committer: &git2::Signature,
+        message: &str,
+        files: &[(&str, &str)],
+        parents: &[git2::Oid],
+    ) -> git2::Oid {
+        let parents: Vec<_> = parents
+            .iter()
+            .map(|id| repo.find_commit(*id).unwrap())
+            .collect();
+        let tree = make_tree(repo, files);
+        repo.commit(
+            None,
+            author,
+            committer,
+            message,
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    fn tree_files(repo: &git2::Repository, tree: &git2::Tree) -> Vec<String> {
+        let mut files = vec![];
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() == Some(git2::ObjectType::Blob) {
+                let blob = repo.find_blob(entry.id()).unwrap();
+                files.push(format!(
+                    "{}{} {}",
+                    root,
+                    entry.name().unwrap(),
+                    std::str::from_utf8(blob.content()).unwrap()
+                ));
+            }
+            git2::TreeWalkResult::Ok
+        })
+        .unwrap();
+        files
+    }
+
+    #[test]
+    fn src_path_test() {
+        assert_eq!(PathBuf::from("x"), src_path(parse(":/x").unwrap()));
+        assert_eq!(PathBuf::from("x/y"), src_path(parse(":/x/y").unwrap()));
+        assert_eq!(PathBuf::from("x/y"), src_path(parse(":/x::y").unwrap()));
+    }
+
+    #[test]
+    fn dst_path_test() {
+        assert_eq!(PathBuf::from(""), dst_path(parse(":/x").unwrap()));
+        assert_eq!(PathBuf::from(""), dst_path(parse(":/x/y").unwrap()));
+        assert_eq!(PathBuf::from("y"), dst_path(parse(":/x::y").unwrap()));
+        assert_eq!(
+            PathBuf::from("a/y"),
+            dst_path(parse(":[a=:/x::y/]").unwrap())
+        );
+
+        assert_eq!(
+            PathBuf::from("c/a"),
+            dst_path(parse(":[a=:/x::y/,a/b=:/i]:prefix=c").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn optimize_async_test() {
+        let filter = to_filter(Op::Compose(vec![
+            parse(":/optimize_async/a").unwrap(),
+            parse(":/optimize_async/b").unwrap(),
+        ]));
+        assert_eq!(optimize_async(filter).await, optimize(filter));
+    }
+
+    #[test]
+    fn optimization_golden_test() {
+        for (input, expected) in [
+            (":/a:/b", ":/a/b"),
+            (":prefix=a:/a", ":/"),
+            (":prefix=a:/b", ":empty"),
+            (":[:subtract[:/,::a/],:subtract[:/,::b/]]", ":/"),
+            (":[::*.rs,:empty]", "::*.rs"),
+        ] {
+            assert_optimizes_to(input, expected);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "did not optimize as expected")]
+    fn optimization_golden_mismatch_test() {
+        assert_optimizes_to(":/a:/b", ":/a");
+    }
+
+    #[test]
+    fn spec_cache_test() {
+        let filter = parse(":[a = :/spec_cache/a, :/spec_cache/b]").unwrap();
+        assert!(SPEC_CACHE.lock().unwrap().peek(&filter).is_none());
+
+        let s = spec(filter);
+        assert_eq!(spec(filter), s);
+
+        // Cached strings are returned as they are
+        SPEC_CACHE.lock().unwrap().put(filter, "cached".to_string());
+        assert_eq!(spec(filter), "cached");
+        SPEC_CACHE.lock().unwrap().pop(&filter);
+        assert_eq!(spec(filter), s);
+    }
+
+    #[test]
+    fn delimiter_test() {
+        for s in [
+            ":/src:prefix=x",
+            ":[a = :/a::*.rs,::b/]",
+            r#"::"h:i/[j]/*.rs""#,
+            r#":subtract[:/a,:exclude[::"x|y"]]"#,
+            r#":replace("a:b":"c")"#,
+        ] {
+            let filter = parse(s).unwrap();
+            for delimiter in [':', '|', '!'] {
+                let alt = spec_with_delimiter(filter, delimiter).unwrap();
+                assert_eq!(
+                    parse_with_delimiter(&alt, delimiter).unwrap(),
+                    filter,
+                    "{}",
+                    alt
+                );
+            }
+        }
+
+        assert_eq!(
+            parse_with_delimiter("|/src|prefix=x", '|').unwrap(),
+            parse(":/src:prefix=x").unwrap()
+        );
+        assert_eq!(
+            spec_with_delimiter(parse(r#"::"h:i/*.rs""#).unwrap(), '|').unwrap(),
+            r#"||"h:i/*.rs""#
+        );
+        assert!(parse_with_delimiter("|/src:/x", '|').is_err());
+        assert!(parse_with_delimiter(":/src", '/').is_err());
+        assert!(spec_with_delimiter(parse(":/src").unwrap(), 'x').is_err());
+    }
+
+    #[test]
+    fn spec_batch_shared_test() {
+        let filters: Vec<_> = [
+            ":[a = :/shared/lib::*.rs,b = :/shared/doc::*.md]:prefix=x",
+            ":[:/y,:[a = :/shared/lib::*.rs,b = :/shared/doc::*.md]:prefix=x]",
+            ":/z",
+        ]
+        .iter()
+        .map(|s| parse(s).unwrap())
+        .collect();
+        let specs: Vec<_> = filters.iter().map(|f| spec(*f)).collect();
+
+        let shared = spec_batch_shared(&filters, 10);
+        assert_eq!(shared.definitions.len(), 1);
+        assert_eq!(shared.definitions[0].1, specs[0]);
+        assert_eq!(shared.specs[0], "$0");
+        assert!(shared.specs[1].contains("$0"), "{}", shared.specs[1]);
+        assert_eq!(shared.specs[2], ":/z");
+        assert_eq!(shared.expand(), specs);
+
+        // Fragments shared inside of definitions get their own definitions
+        let filters: Vec<_> = [
+            ":[a = :/lib/one::*.rs,b = :/lib/two::*.md]",
+            ":[:[a = :/lib/one::*.rs,b = :/lib/two::*.md]:prefix=x,c = :/other]",
+            ":[a = :/lib/one::*.rs,d = :/x]",
+        ]
+        .iter()
+        .map(|s| parse(s).unwrap())
+        .collect();
+        let specs: Vec<_> = filters.iter().map(|f| spec(*f)).collect();
+        let shared = spec_batch_shared(&filters, 8);
+        assert!(shared.definitions.len() > 1, "{:?}", shared);
+        assert_eq!(shared.expand(), specs);
+
+        assert_eq!(spec_batch_shared(&filters, 1000).specs, specs);
+    }
+
+    #[test]
+    fn parse_workspace_toml_test() {
+        let views = parse_workspace_toml(indoc::indoc! {r#"
+            name = "monorepo"
+
+            [views]
+            docs = ":/docs"
+            api = ":[proto = :/api/proto, ::api/README.md]"
+        "#})
+        .unwrap();
+
+        assert_eq!(views.len(), 2);
+        assert_eq!(views["docs"], parse(":/docs").unwrap());
+        assert_eq!(
+            views["api"],
+            parse(":[proto = :/api/proto, ::api/README.md]").unwrap()
+        );
+
+        assert!(parse_workspace_toml("name = \"monorepo\"")
+            .unwrap()
+            .is_empty());
+        assert!(parse_workspace_toml("[views]\ndocs = 1").is_err());
+        assert!(parse_workspace_toml("[views]\ndocs = \":/docs:[\"").is_err());
+        assert!(parse_workspace_toml("views = \":/docs\"").is_err());
+    }
+
+    #[test]
+    fn common_subtract_test() {
+        assert_eq!(
+            parse(":[:subtract[::*.rs,::gen*],:subtract[::*.md,::gen*]]").unwrap(),
+            parse(":subtract[:[::*.rs,::*.md],::gen*]").unwrap()
+        );
+        assert_eq!(
+            parse(":[:subtract[::*.rs,::gen*],:subtract[::*.md,::gen*],::x/]").unwrap(),
+            parse(":[:subtract[:[::*.rs,::*.md],::gen*],::x/]").unwrap()
+        );
+
+        // Minuends that move files are left alone
+        let f = parse(":[:subtract[:/a,::gen*],:subtract[:/b,::gen*]]").unwrap();
+        assert!(matches!(to_op(f), Op::Compose(_)));
+
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("a.rs", "1"),
+                ("gen.rs", "2"),
+                ("b.md", "3"),
+                ("gen.md", "4"),
+            ],
+        );
+        let filter = parse(":[:subtract[::*.rs,::gen*],:subtract[::*.md,::gen*]]").unwrap();
+        let filtered = apply(&transaction, filter, tree).unwrap();
+        assert_eq!(tree_files(repo, &filtered), vec!["a.rs 1", "b.md 3"]);
+    }
+
+    #[test]
+    fn common_exclusion_test() {
+        assert_eq!(
+            parse(":[:subtract[:/,::a/],:subtract[:/,::a/b/]]").unwrap(),
+            parse(":subtract[:/,::a/b/]").unwrap()
+        );
+        assert_eq!(
+            parse(":[:subtract[:/,::a/],:subtract[:/,::b/]]").unwrap(),
+            to_filter(Op::Nop)
+        );
+
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(repo, &[("a/b/file", "1"), ("a/file", "2"), ("b/file", "3")]);
+        // Without the rewrite this filter could not be applied at all, because
+        // composing subtracts requires inverting them
+        let filter = parse(":[:subtract[:/,::a/],:subtract[:/,::a/b/]]").unwrap();
+        let filtered = apply(&transaction, filter, tree).unwrap();
+        assert_eq!(tree_files(repo, &filtered), vec!["a/file 2", "b/file 3"]);
+    }
+
+    #[test]
+    fn views_affected_by_test() {
+        let views = parse_workspace_toml(indoc::indoc! {r#"
+            [views]
+            lib = ":/src/lib"
+            src = ":[code = :/src, ::README.md]"
+            docs = ":/docs"
+            rust = "::**/*.rs"
+            tools = ":subtract[:/tools,::tools/tmp/]"
+        "#})
+        .unwrap();
+        let affected = |path: &str| views_affected_by(&views, Path::new(path));
+
+        assert_eq!(affected("src/main.rs"), vec!["rust", "src", "tools"]);
+        assert_eq!(
+            affected("src/lib/mod.rs"),
+            vec!["lib", "rust", "src", "tools"]
+        );
+        assert_eq!(affected("src"), vec!["lib", "rust", "src", "tools"]);
+        assert_eq!(affected("README.md"), vec!["rust", "src", "tools"]);
+        assert_eq!(affected("docs/index.md"), vec!["docs", "rust", "tools"]);
+        assert_eq!(affected("other/file"), vec!["rust", "tools"]);
+    }
+
+    #[test]
+    fn workspace_fingerprint_test() {
+        let a = parse_workspace_toml(indoc::indoc! {r#"
+            [views]
+            docs = ":/docs"
+            api = ":[proto = :/api/proto, ::api/README.md]"
+        "#})
+        .unwrap();
+        let b = parse_workspace_toml(indoc::indoc! {r#"
+            [views]
+            api = ":[::api/README.md,proto=:/api/proto ]"
+            docs = ":/docs:/"
+        "#})
+        .unwrap();
+        assert_eq!(workspace_fingerprint(&a), workspace_fingerprint(&b));
+        assert_eq!(
+            stable_hash(a["docs"]),
+            stable_hash(parse(":/docs").unwrap())
+        );
+
+        let mut c = a.clone();
+        c.insert("docs".to_string(), parse(":/doc").unwrap());
+        assert_ne!(workspace_fingerprint(&a), workspace_fingerprint(&c));
+
+        let mut d = a.clone();
+        let docs = d.remove("docs").unwrap();
+        d.insert("documentation".to_string(), docs);
+        assert_ne!(workspace_fingerprint(&a), workspace_fingerprint(&d));
+    }
+
+    #[test]
+    fn progress_test() {
+        let (_dir, mut transaction) = test_transaction();
+        let reported = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let r = reported.clone();
+        transaction.set_progress(move |p| r.lock().unwrap().push((p.walked, p.total)));
+
+        let repo = transaction.repo();
+        let mut parents = vec![];
+        for i in 0..5 {
+            parents = vec![make_commit(
+                repo,
+                &[("progress/file", &i.to_string())],
+                &parents,
+            )];
+        }
+
+        let filter = parse(":/progress").unwrap();
+        history::walk2(filter, parents[0], &transaction).unwrap();
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![(1, 5), (2, 5), (3, 5), (4, 5), (5, 5)]
+        );
+    }
+
+    #[test]
+    fn filter_incremental_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let commit =
+            |files: &[(&str, &str)], parents: &[git2::Oid]| make_commit(repo, files, parents);
+
+        let filter = parse(":/filter_incremental").unwrap();
+        let a = commit(&[("filter_incremental/file", "1")], &[]);
+        let b = commit(&[("filter_incremental/file", "2")], &[a]);
+
+        let (filtered_b, mapping) =
+            filter_incremental(&transaction, filter, &CommitMap::new(), b).unwrap();
+        assert_eq!(mapping.len(), 2);
+        assert_eq!(mapping[&b], filtered_b);
+
+        // Point the known commit at a marker, so it shows if it got filtered again
+        let marker = commit(&[("marker", "1")], &[]);
+        let known = CommitMap::from([(b, marker)]);
+        let c = commit(&[("filter_incremental/file", "3")], &[b]);
+
+        let (filtered_c, mapping) = filter_incremental(&transaction, filter, &known, c).unwrap();
+        assert_eq!(mapping, CommitMap::from([(b, marker), (c, filtered_c)]));
+        assert_eq!(
+            repo.find_commit(filtered_c)
+                .unwrap()
+                .parent_ids()
+                .collect::<Vec<_>>(),
+            vec![marker]
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn log_conflicts_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("a/file", "1"),
+                ("a/same", "2"),
+                ("b/file", "3"),
+                ("b/same", "2"),
+            ],
+        );
+        let a = tree.get_path(Path::new("a")).unwrap().id();
+        let b = tree.get_path(Path::new("b")).unwrap().id();
+        assert_eq!(tree::conflicts(repo, a, b, "").unwrap(), vec!["file"]);
+        assert!(tree::conflicts(repo, a, a, "").unwrap().is_empty());
+
+        let log = LogBuffer::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+
+        let filter = to_filter(Op::Compose(vec![
+            parse(":/a").unwrap(),
+            parse(":/b").unwrap(),
+        ]));
+        transaction.set_log_conflicts(true);
+        let filtered = tracing::subscriber::with_default(subscriber, || {
+            apply(&transaction, filter, tree.clone()).unwrap()
+        });
+
+        assert_eq!(tree_files(repo, &filtered), vec!["file 3", "same 2"]);
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            log.contains("compose conflict at \"file\", using output of :/b"),
+            "{}",
+            log
+        );
+        assert!(!log.contains("\"same\""));
+    }
+
+    #[test]
+    fn is_identity_on_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(repo, &[("a/file", "1"), ("b/c/file", "2")]);
+
+        let covering = parse(":[::a/,::b/]").unwrap();
+        assert_ne!(covering, to_filter(Op::Nop));
+        assert!(is_identity_on(&transaction, covering, tree.clone()).unwrap());
+        assert!(!is_identity_on(&transaction, parse("::a/").unwrap(), tree.clone()).unwrap());
+
+        let other = make_tree(repo, &[("a/file", "1"), ("d", "3")]);
+        assert!(!is_identity_on(&transaction, covering, other).unwrap());
+    }
+
+    #[test]
+    fn collision_strategy_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let base = make_commit(repo, &[("a/f", "from a"), ("b/g", "b")], &[]);
+        let tip = make_commit(
+            repo,
+            &[("a/f", "from a"), ("b/f", "from b"), ("b/g", "b")],
+            &[base],
+        );
+        let tip = repo.find_commit(tip).unwrap();
+
+        let content = |filter: &str, strategy| {
+            let filter = parse(filter).unwrap();
+            apply_with_collision_strategy(&transaction, filter, &tip, strategy).map(|id| {
+                let tree = repo.find_tree(id).unwrap();
+                tree::get_blob(repo, &tree, Path::new("f"))
+            })
+        };
+
+        for (first, last) in [("a", "b"), ("b", "a")] {
+            let filter = format!(":[:/{},:/{}]", first, last);
+            let of = |dir| format!("from {}", dir);
+            assert_eq!(content(&filter, Collision::FirstWins).unwrap(), of(first));
+            assert_eq!(content(&filter, Collision::LastWins).unwrap(), of(last));
+            // `b/f` was added after `a/f`, regardless of the order of the members
+            assert_eq!(content(&filter, Collision::Newest).unwrap(), "from b");
+            assert_eq!(
+                content(&filter, Collision::Error).unwrap_err().0,
+                "Path collision in compose: \"f\""
+            );
+        }
+
+        // Members that don't collide are composed as usual
+        let filter = ":[:/a,:/b:prefix=b]";
+        let expected = apply(&transaction, parse(filter).unwrap(), tip.tree().unwrap()).unwrap();
+        let applied = apply_with_collision_strategy(
+            &transaction,
+            parse(filter).unwrap(),
+            &tip,
+            Collision::Error,
+        )
+        .unwrap();
+        assert_eq!(applied, expected.id());
+
+        // The entries looked up in the history are remembered
+        let history = ContentHistory::new(repo, &tip);
+        let blob = |path| tip.tree().unwrap().get_path(Path::new(path)).unwrap().id();
+        let (a, b) = (blob("a/f"), blob("b/f"));
+        assert_eq!(history.newest(a, b).unwrap(), b);
+        let looked_up = history.entries.borrow().len();
+        assert_eq!(history.newest(b, a).unwrap(), b);
+        assert_eq!(history.entries.borrow().len(), looked_up);
+    }
+
+    #[test]
+    fn filters_from_tip_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let mut commits: Vec<git2::Oid> = vec![];
+        for i in 0..5 {
+            let parents: Vec<_> = commits.last().copied().into_iter().collect();
+            commits.push(make_commit(repo, &[("a/file", &i.to_string())], &parents));
+        }
+        let tip = commits[4];
+
+        // Only the tip is filtered, the versions kept for it don't depend on the results
+        // for its ancestors
+        for spec in [":keep-versions=2", ":take=2"] {
+            let filter = parse(spec).unwrap();
+            assert!(filters_from_tip(filter), "{}", spec);
+            history::walk2(filter, tip, &transaction).unwrap();
+            assert!(transaction.known(filter, tip), "{}", spec);
+            assert!(!transaction.known(filter, commits[3]), "{}", spec);
+        }
+        assert!(filters_from_tip(parse(":/a:keep-versions=2").unwrap()));
+        assert!(!filters_from_tip(parse(":keep-versions=2:/a").unwrap()));
+    }
+
+    #[test]
+    fn filtered_commit_count_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let commit =
+            |files: &[(&str, &str)], parents: &[git2::Oid]| make_commit(repo, files, parents);
+
+        let c1 = commit(&[("a/x", "1"), ("b/y", "1")], &[]);
+        let c2 = commit(&[("a/x", "1"), ("b/y", "2")], &[c1]);
+        let c3 = commit(&[("a/x", "2"), ("b/y", "1")], &[c1]);
+        let c4 = commit(&[("a/x", "2"), ("b/y", "2")], &[c2, c3]);
+        let c5 = commit(&[("a/x", "2"), ("b/y", "2"), ("c/z", "1")], &[c4]);
+        let c6 = commit(&[("a/x", "2"), ("b/y", "2"), ("c/z", "1")], &[c5]);
+        let other_root = commit(&[("d/w", "1")], &[]);
+        let tip = commit(
+            &[("a/x", "2"), ("b/y", "2"), ("c/z", "1"), ("d/w", "1")],
+            &[c6, other_root],
+        );
+
+        for s in [
+            ":/",
+            ":/a",
+            ":/a:prefix=x",
+            "::b/",
+            ":[::a/,::c/]",
+            ":exclude[::a/]",
+            ":/d",
+            ":/nope",
+            ":linear",
+            ":linear:/a",
+        ] {
+            let filter = parse(s).unwrap();
+            let filtered =
+                apply_to_commit(filter, &repo.find_commit(tip).unwrap(), &transaction).unwrap();
+            let materialized = if filtered == git2::Oid::zero() {
+                0
+            } else {
+                let mut walk = repo.revwalk().unwrap();
+                walk.push(filtered).unwrap();
+                walk.count()
+            };
+            assert_eq!(
+                crate::history::filtered_commit_count(&transaction, filter, tip).unwrap(),
+                materialized,
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn if_exists_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let filter = parse(":if-exists=old/layout/?(:/old/layout/src):(:/src)").unwrap();
+        assert_eq!(
+            spec(filter),
+            ":if-exists=old/layout?(:/old/layout/src):(:/src)"
+        );
+        assert_eq!(parse(&spec(filter)).unwrap(), filter);
+
+        let files = |tree| tree_files(repo, &apply(&transaction, filter, tree).unwrap());
+        let old = make_tree(repo, &[("old/layout/src/a", "1"), ("src/b", "2")]);
+        assert_eq!(files(old), vec!["a 1"]);
+        let new = make_tree(repo, &[("old/other", "1"), ("src/b", "2")]);
+        assert_eq!(files(new), vec!["b 2"]);
+    }
+
+    #[test]
+    fn optimize_on_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(repo, &[("a/file", "1"), ("b/c/file", "2")]);
+
+        let covering = parse(":[::a/,::b/]").unwrap();
+        assert_eq!(
+            optimize_on(&transaction, covering, tree.clone()).unwrap(),
+            nop()
+        );
+
+        let chained = parse(":[::a/,::b/]:prefix=x").unwrap();
+        let optimized = optimize_on(&transaction, chained, tree.clone()).unwrap();
+        assert_eq!(optimized, parse(":prefix=x").unwrap());
+        assert_eq!(
+            apply(&transaction, optimized, tree.clone()).unwrap().id(),
+            apply(&transaction, chained, tree.clone()).unwrap().id()
+        );
+
+        // Only trees that are fully covered get the compose replaced
+        let other = make_tree(repo, &[("a/file", "1"), ("d", "3")]);
+        assert_eq!(
+            optimize_on(&transaction, covering, other).unwrap(),
+            covering
+        );
+
+        let not_top_level = parse(":[::a/,::b/c/]").unwrap();
+        let tree = make_tree(repo, &[("a/file", "1"), ("b/c/file", "2")]);
+        assert_eq!(
+            optimize_on(&transaction, not_top_level, tree).unwrap(),
+            not_top_level
+        );
+    }
+
+    #[test]
+    fn subtract_in_compose_roundtrip_test() {
+        for s in [
+            ":[:/a,:subtract[::b/,::b/c/]]",
+            ":[:subtract[:[::a/,::b/],::a/x/]:prefix=p,::c/]",
+            ":[::x/,:subtract[::y/,:[::y/z/,:subtract[::y/w/,::y/w/v]]]:/y]",
+            ":subtract[:[:/a,:subtract[::b/,::b/c]],::d/]",
+        ] {
+            let f = parse(s).unwrap();
+            assert_eq!(parse(&spec(f)).unwrap(), f, "{}", spec(f));
+        }
+    }
+
+    #[test]
+    fn multiline_spec_test() {
+        for s in [
+            ":[a=:/x,b=:[::c/,::d/:exclude[::*.md]],:subtract[::e/,::e/f/]]",
+            ":/a:[::b/,::c/]:prefix=x",
+            ":[::a/,:exclude[::a/b/,::a/c]]",
+        ] {
+            let f = parse(s).unwrap();
+            assert_eq!(parse(&pretty(f, 0)).unwrap(), f, "{}", pretty(f, 0));
+            assert_eq!(parse(&pretty(f, 4)).unwrap(), f, "{}", pretty(f, 4));
+        }
+
+        let single = parse(":[a=:/x,:[::b/,::c/],:exclude[::*.md]]").unwrap();
+        let formatted = indoc::indoc! {"
+            :[
+            \ta = :/x,
+            \t:[
+            \t\t::b/
+            \t\t::c/,
+            \t]
+            \t:exclude[ ::*.md ]
+            ]"};
+        assert_eq!(parse(formatted).unwrap(), single);
+        assert_eq!(
+            parse(":/a :prefix=b").unwrap(),
+            parse(":/a:prefix=b").unwrap()
+        );
+    }
+
+    #[test]
+    fn subtract_overlapping_member_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(repo, &[("a.rs", "1"), ("b.rs", "2")]);
+        let filter = parse(":subtract[:[::a.rs,::*.rs],::a.rs]").unwrap();
+        let filtered = apply(&transaction, filter, tree).unwrap();
+        assert_eq!(tree_files(repo, &filtered), vec!["b.rs 2"]);
+    }
+
+    #[test]
+    fn subtract_compose_rewrite_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[("a.rs", "1"), ("b.rs", "2"), ("c/d", "3"), ("e/f", "4")],
+        );
+        let files =
+            |filter: Filter| tree_files(repo, &apply(&transaction, filter, tree.clone()).unwrap());
+        let subtract = |a: &str, b: &str| -> Filter {
+            to_filter(Op::Subtract(parse(a).unwrap(), parse(b).unwrap()))
+        };
+
+        for (filter, expected) in [
+            (
+                subtract(":[::c/,::e/,::a.rs]", "::c/"),
+                vec!["a.rs 1", "e/f 4"],
+            ),
+            (
+                subtract(":[::c/,::e/,::a.rs]", ":[::c/,::a.rs]"),
+                vec!["e/f 4"],
+            ),
+            (subtract(":[::c/,::e/,::c/]", ":[::c/]"), vec!["e/f 4"]),
+            (subtract(":[::a.rs,::*.rs]", "::a.rs"), vec!["b.rs 2"]),
+            (
+                subtract(":[::a.rs,::*.rs,::c/]", ":[::a.rs,::e/]"),
+                vec!["b.rs 2", "c/d 3"],
+            ),
+        ] {
+            assert_eq!(files(filter), expected);
+            assert_eq!(files(opt::optimize(filter)), expected);
+        }
+        assert_eq!(
+            opt::optimize(subtract(":[::c/,::e/,::a.rs]", ":[::c/,::a.rs]")),
+            parse("::e/").unwrap()
+        );
+    }
+
+    #[test]
+    fn contributing_members_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(repo, &[("a/b/f", "1"), ("a/g", "2"), ("c/h", "3")]);
+        let (outer, inner, moved) = (
+            parse("::a/").unwrap(),
+            parse("::a/b/").unwrap(),
+            parse("x=:/c").unwrap(),
+        );
+        let filter = to_filter(Op::Compose(vec![outer, inner, moved]));
+        let members = |path: &str| {
+            contributing_members(&transaction, filter, tree.clone(), Path::new(path)).unwrap()
+        };
+
+        assert_eq!(members("a/b/f"), vec![outer, inner]);
+        assert_eq!(members("a/b"), vec![outer, inner]);
+        assert_eq!(members("a/g"), vec![outer]);
+        assert_eq!(members("x/h"), vec![moved]);
+        assert_eq!(members("c/h"), vec![]);
+
+        assert_eq!(
+            contributing_members(&transaction, moved, tree.clone(), Path::new("x/h")).unwrap(),
+            vec![moved]
+        );
+    }
+
+    #[test]
+    fn apply_with_provenance_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[("a/b/f", "1"), ("a/g", "2"), ("c/h", "3"), ("d/k", "4")],
+        );
+        let (outer, inner, moved, nested) = (
+            parse("::a/").unwrap(),
+            parse("::a/b/").unwrap(),
+            parse("x = :/c").unwrap(),
+            parse("y = :/d:prefix=z").unwrap(),
+        );
+        let filter = to_filter(Op::Compose(vec![outer, inner, moved, nested]));
+
+        let (result, map) = apply_with_provenance(&transaction, filter, tree.clone()).unwrap();
+        assert_eq!(
+            result,
+            apply(&transaction, filter, tree.clone()).unwrap().id()
+        );
+
+        let mut found: Vec<_> = map
+            .iter()
+            .map(|(path, p)| {
+                (
+                    path.display().to_string(),
+                    p.source.as_ref().map(|s| s.display().to_string()),
+                    p.filter,
+                )
+            })
+            .collect();
+        found.sort();
+        let source = |s: &str| Some(s.to_string());
+        assert_eq!(
+            found,
+            vec![
+                ("a/b/f".to_string(), source("a/b/f"), outer),
+                ("a/g".to_string(), source("a/g"), outer),
+                ("x/h".to_string(), source("c/h"), moved),
+                ("y/z/k".to_string(), source("d/k"), nested),
+            ]
+        );
+
+        // Files already taken by `outer` are not attributed to `inner`
+        assert!(map.values().all(|p| p.filter != inner));
+
+        // Without a compose everything is attributed to the filter itself
+        let (_, map) = apply_with_provenance(&transaction, moved, tree.clone()).unwrap();
+        assert_eq!(
+            map.get(Path::new("x/h")),
+            Some(&Provenance {
+                source: Some("c/h".into()),
+                filter: moved
+            })
+        );
+
+        // Paths changed beyond recognition have no source
+        let lowercase = to_filter(Op::LowercasePaths);
+        let tree = make_tree(repo, &[("README", "1")]);
+        let (_, map) = apply_with_provenance(&transaction, lowercase, tree).unwrap();
+        assert_eq!(map.get(Path::new("readme")).unwrap().source, None);
+    }
+
+    #[test]
+    fn optimize_with_limit_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("a/x.rs", "1"),
+                ("a/b/y.md", "2"),
+                ("c/z.rs", "3"),
+                ("d", "4"),
+            ],
+        );
+        let filter = to_filter(Op::Chain(
+            to_filter(Op::Compose(vec![
+                parse(":/a:prefix=a").unwrap(),
+                parse(":/c:prefix=c").unwrap(),
+                parse(":/a/b:prefix=a/b").unwrap(),
+            ])),
+            parse(":exclude[::**/*.md]:/a").unwrap(),
+        ));
+
+        let expected = tree_files(repo, &apply(&transaction, filter, tree.clone()).unwrap());
+        for rounds in [0, 1, 2, 3, 1000] {
+            let limited = optimize_with_limit(filter, rounds);
+            let result = apply(&transaction, limited, tree.clone()).unwrap();
+            assert_eq!(tree_files(repo, &result), expected);
+        }
+        assert_eq!(expected, vec!["x.rs 1".to_string()]);
+    }
+
+    #[test]
+    fn apply_memo_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(repo, &[("a/x.rs", "1"), ("a/y.md", "2"), ("b", "3")]);
+        let exclude = parse(":exclude[::*.md]").unwrap();
+        let subdir = parse(":/a").unwrap();
+
+        let filtered = apply(&transaction, exclude, tree.clone()).unwrap();
+        assert_eq!(
+            transaction.get_apply(exclude, tree.id()),
+            Some(filtered.id())
+        );
+        assert_eq!(
+            apply(&transaction, exclude, tree.clone()).unwrap().id(),
+            filtered.id()
+        );
+
+        // Subdirs record their results themselves, see `apply_subdir_run`
+        let filtered = apply(&transaction, subdir, tree.clone()).unwrap();
+        assert_eq!(
+            transaction.get_apply(subdir, tree.id()),
+            Some(filtered.id())
+        );
+    }
+
+    #[test]
+    fn stored_apply_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(repo, &[("a/x.rs", "1"), ("a/y.md", "2"), ("b", "3")]);
+        let filter = parse(":[x = :/a:exclude[::*.md],::b]").unwrap();
+
+        // The cache is global, so it must not stay loaded for the other tests
+        struct Unload;
+        impl Drop for Unload {
+            fn drop(&mut self) {
+                cache::unload_apply_cache().unwrap();
+            }
+        }
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        cache::load_apply_cache(cache_dir.path()).unwrap();
+        let _unload = Unload;
+        let filtered = apply(&transaction, filter, tree.clone()).unwrap();
+        cache::flush().unwrap();
+
+        // A new transaction on the reopened cache, like after a restart
+        cache::load_apply_cache(cache_dir.path()).unwrap();
+        let restarted = cache::Transaction::open(repo.path(), None).unwrap();
+        assert_eq!(restarted.get_apply(filter, tree.id()), None);
+        assert_eq!(
+            restarted.get_stored_apply(filter, tree.id()).unwrap(),
+            Some(filtered.id())
+        );
+
+        // A stored result is used as it is, without applying the filter again
+        let other = make_tree(repo, &[("c", "4")]);
+        restarted
+            .insert_stored_apply(filter, tree.id(), other.id())
+            .unwrap();
+        let tree = restarted.repo().find_tree(tree.id()).unwrap();
+        assert_eq!(
+            apply(&restarted, filter, tree.clone()).unwrap().id(),
+            other.id()
+        );
+
+        // Only the results of outermost applies are stored
+        let Op::Compose(members) = to_op(filter) else {
+            unreachable!()
+        };
+        assert_eq!(
+            restarted.get_stored_apply(members[0], tree.id()).unwrap(),
+            None
+        );
+
+        // Results missing from the repo are computed again
+        let missing = git2::Oid::hash_object(git2::ObjectType::Tree, b"missing").unwrap();
+        let filter = parse(":[::a/,::b]").unwrap();
+        restarted
+            .insert_stored_apply(filter, tree.id(), missing)
+            .unwrap();
+        let result = apply(&restarted, filter, tree.clone()).unwrap();
+        assert_eq!(tree_files(restarted.repo(), &result).len(), 3);
+    }
+
+    #[test]
+    fn global_apply_stats_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(repo, &[("a/x", "stats1"), ("a/y", "stats2"), ("b", "3")]);
+        let filter = parse(":[::a/,::b]").unwrap();
+        let hashed = parse(":/a:content-hash").unwrap();
+
+        // Other tests apply filters at the same time, so the counters can only be
+        // checked for lower bounds
+        let before = global_apply_stats();
+        apply(&transaction, filter, tree.clone()).unwrap();
+        apply(&transaction, hashed, tree.clone()).unwrap();
+        let first = global_apply_stats();
+        assert!(first.trees_visited >= before.trees_visited + 2);
+        assert!(first.blobs_written >= before.blobs_written + 2);
+
+        apply(&transaction, filter, tree.clone()).unwrap();
+        let second = global_apply_stats();
+        assert!(second.trees_visited > first.trees_visited);
+        assert!(second.cache_hits > first.cache_hits);
+    }
+
+    #[test]
+    fn archive_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let dir = format!("{}/{}", "d".repeat(60), "e".repeat(60));
+        let long = format!("{}/{}", dir, "f".repeat(90));
+        let tree = make_tree(repo, &[("a", "1"), (&long, "2")]);
+        let filter = parse(":archive=tar;out/x.tar").unwrap();
+
+        let filtered = apply(&transaction, filter, tree.clone()).unwrap();
+        let entry = filtered.get_path(Path::new("out/x.tar")).unwrap();
+        let data = repo.find_blob(entry.id()).unwrap().content().to_vec();
+        assert_eq!(data.len() % 512, 0);
+        assert_eq!(&data[..2], b"a\0");
+        assert_eq!(&data[257..263], b"ustar\0");
+
+        // The long path is split into the prefix and name fields
+        let header = &data[4 * 512..5 * 512];
+        assert_eq!(&header[..90], "f".repeat(90).as_bytes());
+        assert_eq!(&header[345..466], dir.as_bytes());
+
+        // Pin the exact bytes, so any change to the output is noticed
+        assert_eq!(
+            entry.id().to_string(),
+            "9f1c8cec62a3cf4be4f94d9721fa3c5061d863f3"
+        );
+
+        assert_eq!(
+            apply(&transaction, filter, tree::empty(repo)).unwrap().id(),
+            tree::empty_id()
+        );
+    }
+
+    #[test]
+    fn rejected_paths_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("a/f.rs", "1"),
+                ("b/g.rs", "2"),
+                ("b/h.md", "3"),
+                ("c/i", "4"),
+            ],
+        );
+        let filter = parse(":[:/a:prefix=x,:/b:subtract[::*,::*.md]:prefix=y]").unwrap();
+        let filtered = apply(&transaction, filter, tree.clone()).unwrap();
+        assert_eq!(tree_files(repo, &filtered), vec!["x/f.rs 1", "y/g.rs 2"]);
+
+        // Unchanged trees and changes inside of the view map back
+        assert_eq!(
+            rejected_paths(&transaction, filter, filtered.clone(), tree.clone()).unwrap(),
+            None
+        );
+        let changed = make_tree(
+            repo,
+            &[("x/f.rs", "changed"), ("x/new.rs", "5"), ("y/g.rs", "2")],
+        );
+        assert_eq!(
+            rejected_paths(&transaction, filter, changed.clone(), tree.clone()).unwrap(),
+            None
+        );
+        assert_eq!(
+            unapply_strict(&transaction, filter, changed.clone(), tree.clone())
+                .unwrap()
+                .id(),
+            unapply(&transaction, filter, changed, tree.clone())
+                .unwrap()
+                .id()
+        );
+
+        // Files the filter can't produce, including ones inside of a prefix
+        let changed = make_tree(
+            repo,
+            &[
+                ("x/f.rs", "changed"),
+                ("y/g.rs", "2"),
+                ("y/doc.md", "6"),
+                ("z/j", "7"),
+            ],
+        );
+        let rejected = rejected_paths(&transaction, filter, changed.clone(), tree.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(rejected.filter, spec(filter));
+        assert_eq!(
+            rejected.paths,
+            vec![
+                Path::new("y/doc.md").to_owned(),
+                Path::new("z/j").to_owned()
+            ]
+        );
+        let err = unapply_strict(&transaction, filter, changed, tree).unwrap_err();
+        assert_eq!(err.0, rejected.to_string());
+        assert!(err.0.ends_with("\n  y/doc.md\n  z/j\n"), "{}", err.0);
+    }
+
+    #[test]
+    fn check_workspace_cycles_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let check = |files: &[(&str, &str)], path: &str| {
+            check_workspace_cycles(repo, &make_tree(repo, files), Path::new(path))
+        };
+
+        let err = check(&[("a/workspace.josh", ":workspace=a")], "a").unwrap_err();
+        assert!(err.is_filter_cycle());
+        assert_eq!(err.0, "filter cycle: :workspace=a -> :workspace=a");
+
+        let err = check(
+            &[
+                ("a/workspace.josh", "x = :workspace=b\n::c/"),
+                ("b/workspace.josh", ":workspace=c"),
+                ("c/workspace.josh", ":[y = :workspace=b,:/d]"),
+            ],
+            "a",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.0,
+            "filter cycle: :workspace=b -> :workspace=c -> :workspace=b"
+        );
+
+        // Workspaces that are referred to more than once, or inside of another directory
+        assert!(check(
+            &[
+                ("a/workspace.josh", ":[x = :workspace=b,y = :workspace=c]"),
+                ("b/workspace.josh", ":workspace=c"),
+                ("c/workspace.josh", ":/sub:workspace=a"),
+            ],
+            "a",
+        )
+        .is_ok());
+
+        // Deep chains without a cycle
+        let files: Vec<_> = (0..200)
+            .map(|i| {
+                (
+                    format!("w{}/workspace.josh", i),
+                    format!(":workspace=w{}", i + 1),
+                )
+            })
+            .collect();
+        let files: Vec<_> = files
+            .iter()
+            .map(|(p, c)| (p.as_str(), c.as_str()))
+            .collect();
+        assert!(check(&files, "w0").is_ok());
+        assert!(check(&[], "missing").is_ok());
+    }
+
+    #[test]
+    fn validate_output_portability_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let long = format!("src/{}", "x".repeat(300));
+        let tree = make_tree(
+            repo,
+            &[
+                ("src/CON", "1"),
+                ("src/lpt1.txt", "2"),
+                ("src/console.rs", "3"),
+                ("src/a:b", "4"),
+                ("src/trailing.", "5"),
+                ("src/Readme.md", "6"),
+                ("src/README.md", "7"),
+                (&long, "8"),
+                ("other/nul", "9"),
+            ],
+        );
+        let issues = |filter: &str| {
+            let filter = parse(filter).unwrap();
+            validate_output_portability(&transaction, filter, tree.clone(), Platform::Windows)
+                .unwrap()
+                .into_iter()
+                .map(|issue| issue.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            issues(":/src"),
+            vec![
+                "CON: reserved name".to_string(),
+                "Readme.md: differs only in case from README.md".to_string(),
+                "a:b: invalid character ':'".to_string(),
+                "lpt1.txt: reserved name".to_string(),
+                "trailing.: ends with a dot or space".to_string(),
+                format!(
+                    "{}: 300 characters, at most 260 are supported",
+                    "x".repeat(300)
+                ),
+            ]
+        );
+        // Only the paths of the output are checked
+        assert_eq!(issues("::src/console.rs"), Vec::<String>::new());
+        assert_eq!(issues(":/other:prefix=a"), vec!["a/nul: reserved name"]);
+    }
+
+    #[test]
+    fn intersect_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("team/a.rs", "1"),
+                ("team/b.md", "2"),
+                ("team/sub/c.rs", "3"),
+                ("other/d.rs", "4"),
+            ],
+        );
+        let filter = parse(":intersect[:/team,::**/*.rs]").unwrap();
+        assert_eq!(spec(filter), ":intersect[:/team,::**/*.rs]");
+        assert_eq!(from_bytes(&to_bytes(filter)).unwrap(), filter);
+
+        let filtered = apply(&transaction, filter, tree.clone()).unwrap();
+        assert_eq!(tree_files(repo, &filtered), vec!["a.rs 1", "sub/c.rs 3"]);
+
+        let changed = make_tree(repo, &[("a.rs", "changed"), ("e.rs", "5")]);
+        let unapplied = unapply(&transaction, filter, changed.clone(), tree.clone()).unwrap();
+        assert_eq!(
+            tree_files(repo, &unapplied),
+            vec![
+                "other/d.rs 4",
+                "team/a.rs changed",
+                "team/b.md 2",
+                "team/e.rs 5"
+            ]
+        );
+        assert_eq!(
+            apply(&transaction, filter, unapplied).unwrap().id(),
+            changed.id()
+        );
+
+        let not_invertible = parse(":intersect[:/team,:subtract[::**/*.rs,::a.rs]]").unwrap();
+        let err = unapply(&transaction, not_invertible, changed, tree).unwrap_err();
+        assert!(err.0.starts_with("no invert: :subtract["), "{}", err.0);
+
+        assert_eq!(
+            parse(":intersect[::a/,::a/]").unwrap(),
+            parse("::a/").unwrap()
+        );
+        assert_eq!(
+            parse(":intersect[:/a:/b,:/a:/c]").unwrap(),
+            parse(":/a:intersect[:/b,:/c]").unwrap()
+        );
+        assert_eq!(parse(":intersect[::a/,:empty]").unwrap(), empty());
+    }
+
+    #[test]
+    fn is_nop_equivalent_test() {
+        assert!(is_nop_equivalent(nop()));
+        assert!(is_nop_equivalent(to_filter(Op::Chain(nop(), nop()))));
+        assert!(is_nop_equivalent(to_filter(Op::Compose(vec![nop()]))));
+        assert!(is_nop_equivalent(parse(":prefix=x:/x").unwrap()));
+        assert!(is_nop_equivalent(parse(":prefix=x/y:/x:/y").unwrap()));
+        assert!(is_nop_equivalent(parse(":[:/]").unwrap()));
+
+        assert!(!is_nop_equivalent(empty()));
+        assert!(!is_nop_equivalent(to_filter(Op::Compose(vec![]))));
+        assert!(!is_nop_equivalent(parse(":/x:prefix=x").unwrap()));
+        assert!(!is_nop_equivalent(parse(":prefix=x:/y").unwrap()));
+        assert!(!is_nop_equivalent(parse(":linear").unwrap()));
+    }
+
+    #[test]
+    fn is_disjoint_compose_test() {
+        let compose = |members: &[&str]| {
+            to_filter(Op::Compose(
+                members.iter().map(|m| parse(m).unwrap()).collect(),
+            ))
+        };
+        let disjoint = compose(&[":/a:prefix=x", "::b/", ":/c/d:prefix=y/z", "::e"]);
+        assert!(is_disjoint_compose(disjoint));
+        // Members that are themselves composes are looked into
+        assert!(is_disjoint_compose(compose(&[
+            ":/a:[x=:/b,y=:/c]",
+            ":/a/d:prefix=z",
+            "p/q=:[::e/,::f/]",
+        ])));
+
+        for overlapping in [
+            compose(&[":/a:prefix=x", ":/b:prefix=x/y"]),
+            compose(&[":/a:prefix=x", ":/a/b:prefix=y"]),
+            compose(&["::a/", "::**/*.rs"]),
+            compose(&["::a/", ":/b"]),
+            compose(&[":/a:[x=:/b,y=:/c]", ":/a/b:prefix=z"]),
+            compose(&[":/a:[x=:/b,y=:/c]", ":/d:prefix=y/w"]),
+        ] {
+            assert!(!is_disjoint_compose(overlapping), "{}", spec(overlapping));
+        }
+        assert!(!is_disjoint_compose(parse("::a/").unwrap()));
+
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("a/1", "1"),
+                ("b/2", "2"),
+                ("c/d/3", "3"),
+                ("e", "4"),
+                ("f", "5"),
+            ],
+        );
+        let filtered = apply(&transaction, disjoint, tree).unwrap();
+        assert_eq!(
+            tree_files(repo, &filtered),
+            vec!["b/2 2", "e 4", "x/1 1", "y/z/3 3"]
+        );
+    }
+
+    #[test]
+    fn symmetric_diff_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("a/b.rs", "1"),
+                ("a/c.md", "2"),
+                ("d.rs", "3"),
+                ("e/f.rs", "4"),
+                ("e/g", "5"),
+            ],
+        );
+        let a = parse(":[:/a:prefix=a,::e/]").unwrap();
+        let b = parse("::**/*.rs").unwrap();
+
+        let (only_a, only_b) = symmetric_diff(&transaction, a, b, tree.clone()).unwrap();
+        let files = |id| tree_files(repo, &repo.find_tree(id).unwrap());
+        assert_eq!(files(only_a), vec!["a/c.md 2", "e/g 5"]);
+        assert_eq!(files(only_b), vec!["d.rs 3"]);
+
+        // Together they are the paths produced by exactly one of the filters
+        let paths = |id| -> std::collections::BTreeSet<_> { files(id).into_iter().collect() };
+        let output_a = paths(apply(&transaction, a, tree.clone()).unwrap().id());
+        let output_b = paths(apply(&transaction, b, tree.clone()).unwrap().id());
+        assert!(paths(only_a).is_disjoint(&paths(only_b)));
+        assert_eq!(
+            paths(only_a)
+                .union(&paths(only_b))
+                .cloned()
+                .collect::<Vec<_>>(),
+            output_a
+                .symmetric_difference(&output_b)
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+
+        let (only_a, only_b) = symmetric_diff(&transaction, a, a, tree).unwrap();
+        assert_eq!((only_a, only_b), (tree::empty_id(), tree::empty_id()));
+    }
+
+    #[test]
+    fn partition_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("a/b.rs", "1"),
+                ("a/c.md", "2"),
+                ("d.rs", "3"),
+                ("e/f", "4"),
+            ],
+        );
+
+        let filter = parse("::**/*.rs").unwrap();
+        let (matched, complement) = partition(&transaction, filter, tree.clone()).unwrap();
+        let files = |id| tree_files(repo, &repo.find_tree(id).unwrap());
+        assert_eq!(files(matched), vec!["a/b.rs 1", "d.rs 3"]);
+        assert_eq!(files(complement), vec!["a/c.md 2", "e/f 4"]);
+        assert_eq!(
+            tree::overlay(&transaction, matched, complement).unwrap(),
+            tree.id()
+        );
+
+        let filter = parse(":/a").unwrap();
+        let (matched, complement) = partition(&transaction, filter, tree.clone()).unwrap();
+        assert_eq!(files(matched), vec!["b.rs 1", "c.md 2"]);
+        assert_eq!(files(complement), vec!["d.rs 3", "e/f 4"]);
+        let unmapped = apply(
+            &transaction,
+            invert(filter).unwrap(),
+            repo.find_tree(matched).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            tree::overlay(&transaction, unmapped.id(), complement).unwrap(),
+            tree.id()
+        );
+
+        // Filters without an inverse are traced back to the input paths
+        let tree = make_tree(repo, &[("A/B.rs", "1"), ("A/c.md", "2"), ("d.rs", "3")]);
+        let filter = parse(":/A:lowercase").unwrap();
+        assert!(invert(filter).is_err());
+        let (matched, complement) = partition(&transaction, filter, tree).unwrap();
+        assert_eq!(files(matched), vec!["b.rs 1", "c.md 2"]);
+        assert_eq!(files(complement), vec!["d.rs 3"]);
+    }
+
+    #[test]
+    fn excluded_prefix_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(repo, &[("a/x", "1"), ("b/y", "2"), ("z", "3")]);
+        let excluding = |prefix: &str, dir: &str| {
+            to_filter(Op::Chain(
+                to_filter(Op::Prefix(prefix.into())),
+                to_filter(Op::Subtract(nop(), to_filter(Op::Subdir(dir.into())))),
+            ))
+        };
+        let selecting = |prefix: &str, dir: &str| {
+            to_filter(Op::Chain(
+                to_filter(Op::Prefix(prefix.into())),
+                to_filter(Op::Select(vec!["**".into()], vec![format!("{}/**", dir)])),
+            ))
+        };
+
+        for (prefix, dir, nothing_left) in
+            [("p", "p", true), ("p/q", "p", true), ("p", "p/q", false)]
+        {
+            for filter in [excluding(prefix, dir), selecting(prefix, dir)] {
+                assert_eq!(
+                    opt::optimize(filter) == empty(),
+                    nothing_left,
+                    "{}",
+                    spec(filter)
+                );
+                let filtered = apply(&transaction, filter, tree.clone()).unwrap();
+                assert_eq!(
+                    apply(&transaction, opt::optimize(filter), tree.clone())
+                        .unwrap()
+                        .id(),
+                    filtered.id(),
+                    "{}",
+                    spec(filter)
+                );
+                assert_eq!(filtered.is_empty(), nothing_left, "{}", spec(filter));
+            }
+        }
+    }
+
+    #[test]
+    fn owned_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let codeowners = indoc::indoc!(
+            "
+            # Default owners
+            *                 @everyone
+            *.js              @frontend
+            /docs/            @docs @Frontend
+            /docs/internal/
+            src/*             @backend
+            **/generated      @bots
+            "
+        );
+        let tree = make_tree(
+            repo,
+            &[
+                (".github/CODEOWNERS", codeowners),
+                ("app.js", "1"),
+                ("README.md", "2"),
+                ("docs/guide.md", "3"),
+                ("docs/internal/notes.md", "4"),
+                ("src/main.rs", "5"),
+                ("src/ui/button.js", "6"),
+                ("src/lib/util.rs", "7"),
+                ("a/generated/x.rs", "8"),
+            ],
+        );
+        let owned = |owner: &str| {
+            let filter = parse(&format!(":owned={}", owner)).unwrap();
+            let filtered = apply(&transaction, filter, tree.clone()).unwrap();
+            tree_files(repo, &filtered)
+                .iter()
+                .map(|f| f.split(' ').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            owned("@frontend"),
+            vec!["app.js", "docs/guide.md", "src/ui/button.js"]
+        );
+        assert_eq!(owned("@docs"), vec!["docs/guide.md"]);
+        assert_eq!(owned("@backend"), vec!["src/main.rs"]);
+        assert_eq!(owned("@bots"), vec!["a/generated/x.rs"]);
+        assert_eq!(
+            owned("@everyone"),
+            vec![".github/CODEOWNERS", "README.md", "src/lib/util.rs"]
+        );
+
+        let elsewhere = parse(":owned=@frontend;CODEOWNERS").unwrap();
+        assert_eq!(spec(elsewhere), ":owned=@frontend;CODEOWNERS");
+        assert!(apply(&transaction, elsewhere, tree.clone())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn apply_to_index_test() {
+        lazy_static::initialize(&CACHE_DIR);
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let write = |path: &str, content: &str| {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        write("a/committed", "1");
+        write("b/other", "2");
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("a/committed")).unwrap();
+        index.add_path(Path::new("b/other")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("josh", "josh@example.com").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+
+        write("a/committed", "changed");
+        write("a/staged", "3");
+        write("a/unstaged", "4");
+        index.add_path(Path::new("a/committed")).unwrap();
+        index.add_path(Path::new("a/staged")).unwrap();
+        index.write().unwrap();
+
+        let transaction = cache::Transaction::open(dir.path(), None).unwrap();
+        let filtered = apply_to_index(&transaction, parse(":/a").unwrap()).unwrap();
+        let repo = transaction.repo();
+        assert_eq!(
+            tree_files(repo, &repo.find_tree(filtered).unwrap()),
+            vec!["committed changed", "staged 3"]
+        );
+    }
+
+    #[test]
+    fn apply_recipe_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(repo, &[("a/x", "1"), ("b", "2")]);
+        let signature = git2::Signature::now("josh", "josh@example.com").unwrap();
+        let commit = repo
+            .commit(None, &signature, &signature, "add files", &tree, &[])
+            .unwrap();
+        let commit = repo.find_commit(commit).unwrap();
+
+        let filter = parse(":/a:prefix=c").unwrap();
+        let recipe = apply_recipe(&transaction, filter, &commit).unwrap();
+        assert_eq!(recipe.key, recipe_key(filter, commit.id()));
+        assert_eq!(recipe.commit, commit.id().to_string());
+        assert_eq!(recipe.filter, ":/a:prefix=c");
+        let output = repo
+            .find_commit(git2::Oid::from_str(&recipe.output).unwrap())
+            .unwrap();
+        assert_eq!(tree_files(repo, &output.tree().unwrap()), vec!["c/x 1"]);
+
+        let json = serde_json::to_string(&recipe).unwrap();
+        assert_eq!(serde_json::from_str::<Recipe>(&json).unwrap(), recipe);
+
+        // The key only depends on the inputs, equivalent filters get the same key
+        let unoptimized = to_filter(Op::Chain(
+            to_filter(Op::Subdir("a".into())),
+            to_filter(Op::Prefix("c".into())),
+        ));
+        assert_eq!(recipe_key(unoptimized, commit.id()), recipe.key);
+        assert_ne!(recipe_key(parse(":/a").unwrap(), commit.id()), recipe.key);
+        assert_ne!(recipe_key(filter, tree.id()), recipe.key);
+    }
+
+    #[test]
+    fn apply_branches_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let signature = git2::Signature::now("Josh", "josh@example.com").unwrap();
+        for (branch, files) in [
+            ("refs/heads/a", vec![("sub/file", "1"), ("other", "2")]),
+            ("refs/heads/b", vec![("sub/file", "1"), ("other", "3")]),
+        ] {
+            let tree = make_tree(repo, &files);
+            repo.commit(Some(branch), &signature, &signature, "commit", &tree, &[])
+                .unwrap();
+        }
+
+        let sub = parse(":/sub").unwrap();
+        let with_other = parse(":[:/sub,::other]").unwrap();
+        let mapping = std::collections::HashMap::from([
+            ("refs/heads/a".to_string(), sub),
+            ("refs/heads/b".to_string(), with_other),
+        ]);
+        let heads = apply_branches(&transaction, &mapping).unwrap();
+
+        assert_eq!(heads.len(), 2);
+        for (branch, filter) in mapping.iter() {
+            let commit = repo
+                .find_reference(branch)
+                .unwrap()
+                .peel_to_commit()
+                .unwrap();
+            assert_eq!(
+                heads[branch],
+                apply_to_commit(*filter, &commit, &transaction).unwrap()
+            );
+        }
+        let b = repo.find_commit(heads["refs/heads/b"]).unwrap();
+        assert_eq!(
+            tree_files(repo, &b.tree().unwrap()),
+            vec!["file 1", "other 3"]
+        );
+
+        let mapping = std::collections::HashMap::from([("refs/heads/c".to_string(), sub)]);
+        assert!(apply_branches(&transaction, &mapping).is_err());
+    }
+
+    #[test]
+    fn apply_with_deadline_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let files: Vec<_> = (0..20)
+            .map(|i| (format!("d{}/f", i), format!("deadline {}", i)))
+            .collect();
+        let files: Vec<_> = files
+            .iter()
+            .map(|(p, c)| (p.as_str(), c.as_str()))
+            .collect();
+        let tree = make_tree(repo, &files);
+        let signature = git2::Signature::now("Josh", "josh@example.com").unwrap();
+        let commit = repo
+            .find_commit(
+                repo.commit(None, &signature, &signature, "commit", &tree, &[])
+                    .unwrap(),
+            )
+            .unwrap();
+        let filter = parse(&format!(
+            ":[{}]",
+            (0..20)
+                .map(|i| format!(":/d{}:prefix=e{}", i, i))
+                .collect::<Vec<_>>()
+                .join(",")
+        ))
+        .unwrap();
+
+        let past = std::time::Instant::now();
+        for _ in 0..2 {
+            let err = apply_with_deadline(filter, &commit, &transaction, past).unwrap_err();
+            assert!(err.is_timeout());
+        }
+        assert_eq!(transaction.deadline(), None);
+        // Only the kind of the error counts, not its message
+        assert!(!josh_error(&timeout_error().0).is_timeout());
+
+        let later = std::time::Instant::now() + std::time::Duration::from_secs(600);
+        let filtered = apply_with_deadline(filter, &commit, &transaction, later).unwrap();
+        assert_eq!(
+            repo.find_commit(filtered).unwrap().tree_id(),
+            apply(&transaction, filter, tree).unwrap().id()
+        );
+        assert_eq!(
+            apply_to_commit(filter, &commit, &transaction).unwrap(),
+            filtered
+        );
+    }
+
+    #[test]
+    fn commit_dates_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+
+        let mut parents = vec![];
+        for (i, files) in [
+            vec![("sub/a", "dates 1"), ("other", "2")],
+            vec![("sub/a", "dates 1"), ("sub/b", "3"), ("other", "4")],
+        ]
+        .iter()
+        .enumerate()
+        {
+            let offset = 3600 * i as i64;
+            let author = git2::Signature::new(
+                "Author",
+                "author@example.com",
+                &git2::Time::new(1112911993 + offset, 120),
+            )
+            .unwrap();
+            let committer = git2::Signature::new(
+                "Committer",
+                "committer@example.com",
+                &git2::Time::new(1112999999 + offset, -300),
+            )
+            .unwrap();
+            let message = format!("commit {}\n", i);
+            parents = vec![make_signed_commit(
+                repo, &author, &committer, &message, files, &parents,
+            )];
+        }
+        let head = repo.find_commit(parents[0]).unwrap();
+
+        for spec in [
+            ":/sub",
+            ":prefix=x",
+            ":[:/sub:prefix=s,::other]",
+            ":author=\"Someone\";\"someone@example.com\"",
+            ":committer=\"Someone\";\"someone@example.com\"",
+            ":\"rewritten\"",
+            ":linear",
+        ] {
+            let filter = parse(spec).unwrap();
+            let filtered = apply_to_commit(filter, &head, &transaction).unwrap();
+            let filtered = repo.find_commit(filtered).unwrap();
+            for (original, filtered) in [
+                (head.clone(), filtered.clone()),
+                (head.parent(0).unwrap(), filtered.parent(0).unwrap()),
+            ] {
+                assert_eq!(
+                    original.author().when(),
+                    filtered.author().when(),
+                    "{}",
+                    spec
+                );
+                assert_eq!(
+                    original.committer().when(),
+                    filtered.committer().when(),
+                    "{}",
+                    spec
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn export_fast_import_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let signature = git2::Signature::new(
+            "Josh",
+            "josh@example.com",
+            &git2::Time::new(1112911993, -90),
+        )
+        .unwrap();
+
+        let mut parents = vec![];
+        for files in [
+            vec![("sub/a", "1"), ("other", "2")],
+            vec![("sub/a", "1"), ("sub/\"b", "3"), ("other", "4")],
+            vec![("sub/b", "3")],
+        ] {
+            let message = format!("commit {}\n", parents.len());
+            parents = vec![make_signed_commit(
+                repo, &signature, &signature, &message, &files, &parents,
+            )];
+        }
+        repo.reference("refs/heads/master", parents[0], true, "")
+            .unwrap();
+
+        let filter = parse(":/sub").unwrap();
+        let mut stream = vec![];
+        export_fast_import(&transaction, filter, "refs/heads/master", &mut stream).unwrap();
+        let filtered =
+            apply_to_commit(filter, &repo.find_commit(parents[0]).unwrap(), &transaction).unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        git2::Repository::init_bare(target.path()).unwrap();
+        let mut import = std::process::Command::new("git")
+            .arg("fast-import")
+            .arg("--quiet")
+            .current_dir(target.path())
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(&mut import.stdin.take().unwrap(), &stream).unwrap();
+        assert!(import.wait().unwrap().success());
+
+        let imported = git2::Repository::open(target.path()).unwrap();
+        let head = imported.refname_to_id("refs/heads/master").unwrap();
+
+        // Identical commits have identical ids, so the whole history was reproduced
+        assert_eq!(head, filtered);
+        assert_eq!(imported.find_commit(head).unwrap().parent_count(), 1);
+    }
+
+    #[test]
+    fn split_history_boundary_test() {
+        let f = |op: Op| to_filter(op);
+        let subdir = f(Op::Subdir(Path::new("a").to_owned()));
+        let prefix = f(Op::Prefix(Path::new("x").to_owned()));
+        let rest = f(Op::Chain(f(Op::Linear), prefix));
+        let filter = f(Op::Chain(subdir, rest));
+
+        assert_eq!(split_history_boundary(filter), (subdir, rest));
+
+        let (tree_part, history_part) = split_history_boundary(filter);
+        assert_eq!(
+            as_chain_vec(f(Op::Chain(tree_part, history_part))),
+            as_chain_vec(filter)
+        );
+
+        let tree_only = parse(":[a = :/a, ::b/]:prefix=x").unwrap();
+        assert_eq!(split_history_boundary(tree_only), (tree_only, f(Op::Nop)));
+        assert_eq!(split_history_boundary(rest), (f(Op::Nop), rest));
+    }
+
+    #[test]
+    fn glob_exclusion_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("src/a.rs", "1"),
+                ("src/target/b.rs", "2"),
+                ("target/c.rs", "3"),
+                ("lib/target/debug/d", "4"),
+                ("lib/e.md", "5"),
+                (".github/target/f", "6"),
+            ],
+        );
+        let glob = |p: &str| to_filter(Op::Glob(p.to_string()));
+        let subtract = |a, b| to_filter(Op::Subtract(a, b));
+        let exclude = |a, b| to_filter(Op::Chain(a, to_filter(Op::Exclude(b))));
+
+        for (filter, expected) in [
+            (
+                subtract(glob("**"), glob("**/target/**")),
+                ":select[+**,-**/target/**]",
+            ),
+            (
+                exclude(glob("**"), glob("**/target/**")),
+                ":select[+**,-**/target/**]",
+            ),
+            (
+                exclude(
+                    subtract(glob("**/*.rs"), glob("target/**")),
+                    glob("src/target/**"),
+                ),
+                ":select[+**/*.rs,-target/**,-src/target/**]",
+            ),
+            // The excluded glob matches everything the first one does
+            (subtract(glob("**/*.rs"), glob("**")), ":empty"),
+            (subtract(glob("src/target/**"), glob("src/**")), ":empty"),
+            (exclude(glob("lib/**"), glob("lib/**")), ":empty"),
+            // `**` doesn't match hidden paths
+            (
+                subtract(glob(".github/**"), glob("**")),
+                ":select[+.github/**,-**]",
+            ),
+        ] {
+            let optimized = opt::optimize(filter);
+            assert_eq!(spec(optimized), expected);
+            assert_eq!(
+                apply(&transaction, optimized, tree.clone()).unwrap().id(),
+                apply(&transaction, filter, tree.clone()).unwrap().id(),
+                "{}",
+                expected
+            );
+        }
+
+        // Parsed globs with literal directories are split before the subtract is seen
+        assert_eq!(
+            spec(parse(":subtract[::src/**,::src/target/**]").unwrap()),
+            ":select[+src/**,-src/target/**]"
+        );
+        assert_eq!(
+            parse(":subtract[::src/target/**,::src/**]").unwrap(),
+            to_filter(Op::Empty)
+        );
+    }
+
+    #[test]
+    fn subtract_subset_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("src/core/a.rs", "1"),
+                ("src/b.rs", "2"),
+                ("a/c", "3"),
+                ("a/a/e", "5"),
+                ("b/d", "4"),
+            ],
+        );
+        let subtract =
+            |a: &str, b: &str| to_filter(Op::Subtract(parse(a).unwrap(), parse(b).unwrap()));
+
+        for (filter, expected) in [
+            // Everything in `src/core` is also in `src`
+            (subtract("::src/core/", "::src/"), ":empty"),
+            (subtract("::src/core/", ":/"), ":empty"),
+            (subtract("::src/core/a.rs", "::src/"), ":empty"),
+            (subtract("::src/**/*.rs", "::src/"), ":empty"),
+            (subtract(":[::src/core/,::a/]", ":[::src/,::a/]"), ":empty"),
+            // The members before `:/` take some of its input away
+            (subtract(":/", ":[:/a/a,:/]"), ":subtract[:/,:[:/a/a,:/]]"),
+            // `src/core` is taken by the first member, so `::src/` doesn't keep it
+            (
+                subtract("::src/core/", ":[:/src/core:prefix=x,::src/]"),
+                ":/src:subtract[:/core,:[:/core:prefix=x,:prefix=src]]:prefix=src/core",
+            ),
+            // Nothing written by one side is written by the other
+            (subtract("::a/", "::b/"), "::a/"),
+            (subtract("::src/core/", "::src/b.rs"), "::src/core/"),
+            // Only part of `src` is in `src/core`
+            (
+                subtract("::src/", "::src/core/"),
+                ":/src:subtract[:/,:/core]:prefix=src",
+            ),
+            (
+                subtract(":[::src/core/,::a/]", "::src/"),
+                ":subtract[:[::a/,::src/core/],:/src]",
+            ),
+        ] {
+            let optimized = opt::optimize(filter);
+            assert_eq!(spec(optimized), expected);
+            assert_eq!(
+                apply(&transaction, optimized, tree.clone()).unwrap().id(),
+                apply(&transaction, filter, tree.clone()).unwrap().id(),
+                "{}",
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn glob_literal_dir_test() {
+        let glob = to_filter(Op::Glob("src/**".to_string()));
+        let optimized = opt::optimize(glob);
+        assert_eq!(
+            as_chain_vec(optimized),
+            vec![
+                to_filter(Op::Subdir(Path::new("src").to_owned())),
+                to_filter(Op::Glob("**".to_string())),
+                to_filter(Op::Prefix(Path::new("src").to_owned())),
+            ]
+        );
+        assert_eq!(spec(optimized), "::src/**");
+
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("src/a/b.rs", "1"),
+                ("src/a/c.md", "2"),
+                ("src/.hidden", "3"),
+                ("srcx/d.rs", "4"),
+                ("e.rs", "5"),
+            ],
+        );
+        for pattern in ["src/**", "src/a/*.rs", "src/*", "*/a/*"] {
+            let glob = to_filter(Op::Glob(pattern.to_string()));
+            assert_eq!(spec(opt::optimize(glob)), format!("::{}", pattern));
+            assert_eq!(
+                apply(&transaction, opt::optimize(glob), tree.clone())
+                    .unwrap()
+                    .id(),
+                apply(&transaction, glob, tree.clone()).unwrap().id(),
+                "{}",
+                pattern
+            );
+        }
+        for (a, b) in [
+            ("src/**", "src/a/**"),
+            ("src/a/**", "src/**"),
+            ("src/**", "e/**"),
+        ] {
+            let chain = to_filter(Op::Chain(
+                to_filter(Op::Glob(a.to_string())),
+                to_filter(Op::Glob(b.to_string())),
+            ));
+            assert_eq!(
+                apply(&transaction, opt::optimize(chain), tree.clone())
+                    .unwrap()
+                    .id(),
+                apply(&transaction, chain, tree.clone()).unwrap().id(),
+                "{}",
+                spec(chain)
+            );
+        }
+        let filtered = apply(&transaction, optimized, tree).unwrap();
+        assert_eq!(
+            tree_files(repo, &filtered),
+            vec!["src/a/b.rs 1", "src/a/c.md 2"]
+        );
+
+        // The glob is applied below `a`, where the same subtree ends up at other paths
+        let tree = make_tree(repo, &[("x.rs", "1")]);
+        let filter = to_filter(Op::Chain(
+            to_filter(Op::Compose(vec![
+                to_filter(Op::Prefix(Path::new("a/b/c").to_owned())),
+                to_filter(Op::Exclude(to_filter(Op::Glob("*.rs".to_string())))),
+            ])),
+            to_filter(Op::Glob("a/*.rs".to_string())),
+        ));
+        for f in [opt::optimize(filter), filter] {
+            let filtered = apply(&transaction, f, tree.clone()).unwrap();
+            assert_eq!(tree_files(repo, &filtered), Vec::<String>::new());
+        }
+    }
+
+    fn teams_tree<'a>(
+        repo: &'a git2::Repository,
+        teams: usize,
+        files: usize,
+        salt: &str,
+    ) -> git2::Tree<'a> {
+        let paths: Vec<_> = (0..teams)
+            .flat_map(|t| (0..files).map(move |f| (t, f)))
+            .map(|(t, f)| {
+                (
+                    format!("team{}/src/f{}", t, f),
+                    format!("{}{}", salt, t + f),
+                )
+            })
+            .collect();
+        let files: Vec<_> = paths
+            .iter()
+            .map(|(p, c)| (p.as_str(), c.as_str()))
+            .collect();
+        make_tree(repo, &files)
+    }
+
+    fn teams_members(teams: usize) -> Vec<Filter> {
+        (0..teams)
+            .map(|t| {
+                let team = Path::new(&format!("team{}", t)).to_owned();
+                match t % 3 {
+                    0 => to_filter(Op::Chain(
+                        to_filter(Op::Subdir(team.clone())),
+                        to_filter(Op::Prefix(Path::new("out").join(&team))),
+                    )),
+                    1 => to_filter(Op::Glob(format!("team{}/src/f1*", t))),
+                    _ => to_filter(Op::Subdir(team)),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parallel_compose_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = teams_tree(repo, 40, 12, "parallel_compose_test");
+        let members = teams_members(40);
+
+        // Separate transaction, so the results of the parallel apply are not cached in it
+        let sequential_transaction = transaction.try_clone().unwrap();
+        let sequential_tree = sequential_transaction.repo().find_tree(tree.id()).unwrap();
+        let sequential =
+            apply_members(&sequential_transaction, &members, &sequential_tree, false).unwrap();
+        let parallel = apply_members(&transaction, &members, &tree, true).unwrap();
+        assert_eq!(parallel, sequential);
+
+        // The results written by the workers are visible to the transaction and cached in it
+        for (f, id) in members.iter().zip(parallel.iter()) {
+            repo.find_tree(*id).unwrap();
+            assert_eq!(transaction.get_apply(*f, tree.id()), Some(*id));
+        }
+
+        // Overlapping members are applied in parallel, but combined in order
+        assert!(!disjoint_members(&members));
+        let composed = apply(&transaction, to_filter(Op::Compose(members.clone())), tree).unwrap();
+        let expected = tree::compose(
+            &sequential_transaction,
+            members
+                .iter()
+                .zip(sequential.iter())
+                .map(|(f, id)| (f, sequential_transaction.repo().find_tree(*id).unwrap()))
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(composed.id(), expected.id());
+    }
+
+    /// Path components used by `RandomFilters`, including characters the filter
+    /// language reserves
+    const RANDOM_NAMES: &[&str] = &[
+        "a", "b", "c", "a b", "x.rs", "d,e", "f=g", "h:i", "[j]", "k'l", "m\"n",
+    ];
+
+    /// Deterministic generator of random filters, so failures can be reproduced
+    struct RandomFilters(u64);
+
+    impl RandomFilters {
+        fn next(&mut self, n: u64) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+
+        fn path(&mut self) -> std::path::PathBuf {
+            let depth = 1 + self.next(2);
+            (0..depth)
+                .map(|_| RANDOM_NAMES[self.next(RANDOM_NAMES.len() as u64) as usize])
+                .collect()
+        }
+
+        fn filter(&mut self, depth: usize) -> Filter {
+            let leaves = 6;
+            let kinds = if depth == 0 { leaves } else { leaves + 5 };
+            to_filter(match self.next(kinds) {
+                0 => Op::Nop,
+                1 => Op::Empty,
+                2 => Op::Subdir(self.path()),
+                3 => Op::Prefix(self.path()),
+                4 => Op::File(self.path()),
+                5 => Op::Glob(format!("{}/*.rs", self.path().display())),
+                6 => Op::Compose((0..self.next(4)).map(|_| self.filter(depth - 1)).collect()),
+                7 => Op::Chain(self.filter(depth - 1), self.filter(depth - 1)),
+                8 => Op::Subtract(self.filter(depth - 1), self.filter(depth - 1)),
+                9 => Op::Intersect(self.filter(depth - 1), self.filter(depth - 1)),
+                _ => Op::Exclude(self.filter(depth - 1)),
+            })
+        }
+    }
+
+    #[test]
+    fn compose_order_test() {
+        let mut members: Vec<_> = [
+            "::a/",
+            "::b/c/",
+            "::b/d/e.txt",
+            "x = :/src",
+            "y/z = :/lib:[::f/,::g/]",
+            "docs = :/doc::**/*.md",
+            ":/h:prefix=i",
+            ":[::j/,k = :/l]",
+        ]
+        .iter()
+        .map(|s| parse(s).unwrap())
+        .collect();
+        let canonical = opt::optimize(to_filter(Op::Compose(members.clone())));
+
+        let mut random = RandomFilters(7);
+        for _ in 0..200 {
+            for i in (1..members.len()).rev() {
+                members.swap(i, random.next(i as u64 + 1) as usize);
             }
+            let shuffled = to_filter(Op::Compose(members.clone()));
+            assert_eq!(opt::optimize(shuffled), canonical, "{}", spec(shuffled));
+            assert_eq!(
+                opt::simplify(shuffled),
+                opt::simplify(to_filter(Op::Compose(
+                    members.iter().rev().copied().collect()
+                )))
+            );
         }
-    } else {
-        warnings.append(&mut compute_warnings2(transaction, filter, tree));
     }
-    warnings
-}
 
-fn compute_warnings2<'a>(
-    transaction: &'a cache::Transaction,
-    filter: Filter,
-    tree: git2::Tree<'a>,
-) -> Vec<String> {
-    let mut warnings = Vec::new();
+    #[test]
+    fn spec_roundtrip_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let paths: Vec<_> = RANDOM_NAMES
+            .iter()
+            .flat_map(|x| {
+                RANDOM_NAMES
+                    .iter()
+                    .map(move |y| format!("{}/{}/{}.rs", x, y, y))
+            })
+            .chain(RANDOM_NAMES.iter().map(|x| x.to_string()))
+            .collect();
+        let files: Vec<_> = paths.iter().map(|p| (p.as_str(), p.as_str())).collect();
+        let tree = make_tree(repo, &files);
 
-    let tree = apply(transaction, filter, tree);
-    if let Ok(tree) = tree {
-        if tree.is_empty() {
-            warnings.push(format!("No match for \"{}\"", pretty(filter, 2)));
+        let mut random = RandomFilters(0x6a6f7368);
+        for _ in 0..5000 {
+            let filter = random.filter(4);
+            let spec = spec(filter);
+            let parsed = parse(&spec).unwrap_or_else(|e| panic!("{}: {}", spec, e.0));
+
+            // The optimizer does not always reach the same form from different starting
+            // points, so the filters are compared by what they produce
+            let apply = |f| apply(&transaction, f, tree.clone()).map(|t| t.id());
+            if let Ok(id) = apply(opt::optimize(filter)) {
+                assert_eq!(
+                    apply(parsed).ok(),
+                    Some(id),
+                    "{}\n{:?}",
+                    spec,
+                    to_op(filter)
+                );
+                // The filter as it was generated has to produce the same, unless it can't be
+                // applied before optimizing, like a subtract of something not invertible
+                // that the optimizer removes
+                if let Ok(unoptimized) = apply(filter) {
+                    assert_eq!(unoptimized, id, "unoptimized {}\n{:?}", spec, to_op(filter));
+                }
+            }
         }
     }
-    warnings
-}
 
-pub fn make_permissions_filter(filter: Filter, whitelist: Filter, blacklist: Filter) -> Filter {
-    rs_tracing::trace_scoped!("make_permissions_filter");
+    #[test]
+    fn binary_roundtrip_test() {
+        for spec in [
+            ":/",
+            ":empty",
+            ":/a/b:prefix=c",
+            "::a/",
+            ":[a = :/x, ::b/**/*.rs, :subtract[::c/,::c/d], :exclude[::e]]",
+            ":[:linear, :unsign, :take=3, :replace(\"a\":\"b\")]",
+            ":select[+**/*.rs,-**/target/**]",
+        ] {
+            let filter = parse(spec).unwrap();
+            assert_eq!(from_bytes(&to_bytes(filter)).unwrap(), filter, "{}", spec);
+        }
+    }
 
-    let filter = chain(to_filter(Op::Paths), filter);
-    let filter = chain(filter, to_filter(Op::Invert));
-    let filter = chain(
-        filter,
-        compose(blacklist, to_filter(Op::Subtract(nop(), whitelist))),
-    );
-    opt::optimize(filter)
-}
+    #[test]
+    fn estimate_cost_test() {
+        let subdir = estimate_cost(parse(":/a").unwrap());
+        assert_eq!(subdir.total, 1);
 
-/// Check if `commit` is an ancestor of `tip`.
-///
-/// Creates a cache for a given `tip` so repeated queries with the same `tip` are more efficient.
-fn is_ancestor_of(
-    repo: &git2::Repository,
-    commit: git2::Oid,
-    tip: git2::Oid,
-) -> Result<bool, git2::Error> {
-    let mut ancestor_cache = ANCESTORS.lock().unwrap();
-    let ancestors = match ancestor_cache.entry(tip) {
-        std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
-        std::collections::hash_map::Entry::Vacant(entry) => {
-            tracing::trace!("is_ancestor_of tip={tip}");
-            // Recursively compute all ancestors of `tip`.
-            // Invariant: Everything in `todo` is also in `ancestors`.
-            let mut todo = vec![tip];
-            let mut ancestors = std::collections::HashSet::from_iter(todo.iter().copied());
-            while let Some(commit) = todo.pop() {
-                for parent in repo.find_commit(commit)?.parent_ids() {
-                    if ancestors.insert(parent) {
-                        // Newly inserted! Also handle its parents.
-                        todo.push(parent);
-                    }
+        let deep = estimate_cost(
+            parse(":[:/a:[::x/,::y/:exclude[::*.md]],:/b:prefix=c/d,::*.rs]").unwrap(),
+        );
+        assert!(deep.total > subdir.total);
+        assert_eq!(deep.by_kind.values().sum::<u64>(), deep.total);
+        assert!(deep.by_kind["compose"] > 0);
+        assert!(deep.by_kind["exclude"] > 0);
+
+        // Duplicate members and chains that cancel out are removed by the optimizer.
+        // The filter is built directly, as `parse` already returns optimized filters.
+        let p = |s: &str| Path::new(s).to_owned();
+        let a = to_filter(Op::Subdir(p("a")));
+        let cancelled = to_filter(Op::Chain(
+            to_filter(Op::Chain(
+                to_filter(Op::Prefix(p("x"))),
+                to_filter(Op::Subdir(p("x"))),
+            )),
+            a,
+        ));
+        let redundant = to_filter(Op::Compose(vec![a, a, cancelled]));
+        assert!(estimate_cost(redundant).total < cost::cost_of(redundant).total);
+        assert_eq!(estimate_cost(redundant), subdir);
+    }
+
+    #[test]
+    fn explain_test() {
+        let p = |s: &str| Path::new(s).to_owned();
+        let compose = to_filter(Op::Compose(vec![
+            to_filter(Op::Prefix(p("x"))),
+            to_filter(Op::Subtract(
+                to_filter(Op::Glob("*.rs".to_string())),
+                to_filter(Op::Linear),
+            )),
+        ]));
+        let filter = to_filter(Op::Chain(to_filter(Op::Subdir(p("a/b"))), compose));
+        let node = |kind: &str, args: &[&str], children: Vec<serde_json::Value>, spec: &str| serde_json::json!({"kind": kind, "args": args, "children": children, "spec": spec});
+        let expected = node(
+            "chain",
+            &[],
+            vec![
+                node("subdir", &["a/b"], vec![], ":/a/b"),
+                node(
+                    "compose",
+                    &[],
+                    vec![
+                        node("prefix", &["x"], vec![], ":prefix=x"),
+                        node(
+                            "subtract",
+                            &[],
+                            vec![
+                                node("glob", &["*.rs"], vec![], "::*.rs"),
+                                node("other", &[], vec![], ":linear"),
+                            ],
+                            ":subtract[::*.rs,:linear]",
+                        ),
+                    ],
+                    &spec(compose),
+                ),
+            ],
+            &spec(filter),
+        );
+        let json = serde_json::to_string(&explain(filter)).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            expected
+        );
+
+        // The filter is not optimized before explaining it
+        assert_eq!(explain(filter).children[0].kind, "subdir");
+        assert_eq!(explain(opt::optimize(filter)).kind, "chain");
+        assert_eq!(
+            explain(opt::optimize(filter)).children[0],
+            explain(to_filter(Op::Subdir(p("a"))))
+        );
+    }
+
+    #[test]
+    fn lint_test() {
+        let lint = |spec: &str| lint(parse(spec).unwrap());
+
+        assert_eq!(
+            lint("a = :/x\nb = :/x\nc = :/y"),
+            vec!["\":/x:prefix=a\" and \":/x:prefix=b\" only differ in their prefix"]
+        );
+        assert_eq!(
+            lint(":[a = :/lib:exclude[::test/],::docs/,b = :/lib:exclude[::test/]]").len(),
+            1
+        );
+        // Nested composes are checked as well
+        assert_eq!(lint(":/sub:[a = :/x,b = :/x]").len(), 1);
+
+        assert!(lint("a = :/x\nb = :/y").is_empty());
+        assert!(lint("a = :/x\nb = :/x:exclude[::test/]").is_empty());
+        assert!(lint(":[::a/,::b/]").is_empty());
+        assert!(lint(":/x").is_empty());
+    }
+
+    #[test]
+    fn to_filter_repo_test() {
+        let args = |f: &str| to_filter_repo(parse(f).unwrap()).map(|args| args.join(" "));
+
+        assert_eq!(args(":/lib").as_deref(), Some("--subdirectory-filter lib"));
+        assert_eq!(args(":/a/b").as_deref(), Some("--subdirectory-filter a/b"));
+        assert_eq!(
+            args(":prefix=p").as_deref(),
+            Some("--to-subdirectory-filter p")
+        );
+        assert_eq!(
+            args(":/lib:prefix=x").as_deref(),
+            Some("--path lib/ --path-rename lib/:x/")
+        );
+        assert_eq!(
+            args(":[::a/,::b/x]").as_deref(),
+            Some("--path a/ --path-regex ^b/x$")
+        );
+        assert_eq!(
+            args(":exclude[::a/,::b/x]").as_deref(),
+            Some("--path a/ --path-regex ^b/x$ --invert-paths")
+        );
+        assert_eq!(
+            args(":/lib:exclude[::tests/]").as_deref(),
+            Some("--path-regex ^(?!lib/tests/)(?:lib/) --path-rename lib/:")
+        );
+        assert_eq!(
+            args("::a/:exclude[::a/t]:prefix=q").as_deref(),
+            Some("--path-regex ^(?!a/t$)(?:a/) --path-rename :q/")
+        );
+        assert_eq!(args(":/").as_deref(), Some(""));
+
+        assert_eq!(args("::*.rs"), None);
+        assert_eq!(args(":/a:prefix=b:/c"), None);
+        assert_eq!(args(":empty"), None);
+    }
+
+    #[test]
+    fn include_exclude_test() {
+        let describe = |spec: &str| {
+            include_exclude(parse(spec).unwrap()).map(|(include, exclude)| {
+                let show = |p: Vec<PathPattern>| p.iter().map(|p| p.to_string()).collect();
+                (show(include), show(exclude))
+            })
+        };
+        let lists = |include: &[&str], exclude: &[&str]| -> Option<(Vec<String>, Vec<String>)> {
+            Some((
+                include.iter().map(|s| s.to_string()).collect(),
+                exclude.iter().map(|s| s.to_string()).collect(),
+            ))
+        };
+
+        assert_eq!(
+            describe(":[::src/,::docs/**/*.md,::lib/a.rs]"),
+            lists(&["docs/**/*.md", "lib/a.rs", "src/"], &[])
+        );
+        assert_eq!(describe(":/"), lists(&["/"], &[]));
+        assert_eq!(
+            describe(":subtract[:[::src/,::lib/],:[::src/gen/,::lib/**/*.bin]]"),
+            lists(&["lib/", "src/"], &["lib/**/*.bin", "src/gen/"])
+        );
+        assert_eq!(
+            describe(":[::src/,::lib/]:exclude[::src/gen/]"),
+            lists(&["lib/", "src/"], &["src/gen/"])
+        );
+        assert_eq!(describe(":exclude[::target/]"), lists(&["/"], &["target/"]));
+        assert_eq!(
+            describe(":select[+**/*.rs,-target/**]"),
+            lists(&["**/*.rs"], &["target/**"])
+        );
+
+        // Paths are moved or the excluded part has excludes itself
+        assert_eq!(describe(":/src"), None);
+        assert_eq!(describe("x = ::src/"), None);
+        assert_eq!(describe(":[::a/,x = :/b]"), None);
+        assert_eq!(describe(":subtract[::a/,:subtract[::a/b/,::a/b/c/]]"), None);
+    }
+    #[test]
+    fn binary_invalid_test() {
+        let bytes = to_bytes(parse(":[a = :/x, ::b/**/*.rs, :linear]").unwrap());
+        for n in 0..bytes.len() {
+            assert!(from_bytes(&bytes[..n]).is_err(), "{}", n);
+        }
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = 2;
+        assert!(from_bytes(&wrong_version).is_err());
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(from_bytes(&trailing).is_err());
+
+        assert!(from_bytes(&[1, 42]).is_err());
+    }
+
+    #[test]
+    fn file_of_dir_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(repo, &[("a/b/c.rs", "1"), ("a/d.rs", "2"), ("e", "3")]);
+        // `::a` takes the directory `a`, so the subdir and glob after it find files
+        for spec in ["::a:/a/b", "::a::a/*.rs", "::a:/a"] {
+            let filter = parse(spec).unwrap();
+            let optimized = apply(&transaction, opt::optimize(filter), tree.clone()).unwrap();
+            assert_eq!(
+                optimized.id(),
+                apply(&transaction, filter, tree.clone()).unwrap().id(),
+                "{}",
+                spec
+            );
+            assert!(!optimized.is_empty(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn glob_subdir_commute_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[
+                ("s/a.rs", "1"),
+                ("s/a.md", "2"),
+                ("s/x/b.rs", "3"),
+                ("s/.x/c.rs", "4"),
+                ("s/s/d.rs", "5"),
+                ("e.rs", "6"),
+                (".s/f.rs", "7"),
+            ],
+        );
+        for (pattern, subdir) in [
+            ("s/*.rs", "s"),
+            ("s/**/*.rs", "s"),
+            ("**/*.rs", "s"),
+            ("**/*.rs", ".s"),
+            ("**/s/*.rs", "s"),
+        ] {
+            let filter = to_filter(Op::Chain(
+                to_filter(Op::Glob(pattern.to_string())),
+                to_filter(Op::Subdir(Path::new(subdir).to_owned())),
+            ));
+            assert_eq!(
+                apply(&transaction, opt::optimize(filter), tree.clone())
+                    .unwrap()
+                    .id(),
+                apply(&transaction, filter, tree.clone()).unwrap().id(),
+                "{}",
+                spec(filter)
+            );
+        }
+
+        // Identical directories are still told apart by their path
+        let tree = make_tree(repo, &[("s/a.rs", "1"), ("t/a.rs", "1")]);
+        let filter = to_filter(Op::Chain(
+            to_filter(Op::Glob("s/*.rs".to_string())),
+            to_filter(Op::Subdir(Path::new("t").to_owned())),
+        ));
+        for f in [opt::optimize(filter), filter] {
+            let filtered = apply(&transaction, f, tree.clone()).unwrap();
+            assert_eq!(tree_files(repo, &filtered), Vec::<String>::new());
+        }
+    }
+
+    #[test]
+    fn visitor_nesting_test() {
+        #[derive(Default)]
+        struct Nesting {
+            depth: usize,
+            max: usize,
+        }
+
+        impl Nesting {
+            fn nested(&mut self, filters: &[Filter]) {
+                self.depth += 1;
+                self.max = self.max.max(self.depth);
+                for f in filters {
+                    walk(*f, self);
                 }
+                self.depth -= 1;
             }
-            entry.insert(ancestors)
         }
-    };
-    Ok(ancestors.contains(&commit))
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::path::PathBuf;
+        impl FilterVisitor for Nesting {
+            fn visit_workspace(&mut self, _path: &Path) {
+                self.max = self.max.max(self.depth + usize::MAX / 2);
+            }
+            fn visit_compose(&mut self, members: &[Filter]) {
+                self.nested(members);
+            }
+            fn visit_chain(&mut self, first: Filter, second: Filter) {
+                self.nested(&[first, second]);
+            }
+            fn visit_subtract(&mut self, a: Filter, b: Filter) {
+                self.nested(&[a, b]);
+            }
+            fn visit_exclude(&mut self, filter: Filter) {
+                self.nested(&[filter]);
+            }
+            fn visit_rev(&mut self, filters: &[Filter]) {
+                self.nested(filters);
+            }
+            fn visit_join(&mut self, filters: &[Filter]) {
+                self.nested(filters);
+            }
+        }
+
+        for spec in [
+            ":/",
+            ":/a",
+            ":/a/b:prefix=c",
+            ":[a = :/x, ::b/**/*.rs, :subtract[::c/,::c/d], :exclude[::e:linear]]",
+            ":rev(0000000000000000000000000000000000000000:[:/a,:/b])",
+            ":workspace=ws",
+        ] {
+            let filter = parse(spec).unwrap();
+            let mut v = Nesting::default();
+            walk(filter, &mut v);
+            assert_eq!(v.max, nesting(filter), "{}", spec);
+        }
+    }
 
     #[test]
-    fn src_path_test() {
-        assert_eq!(PathBuf::from("x"), src_path(parse(":/x").unwrap()));
-        assert_eq!(PathBuf::from("x/y"), src_path(parse(":/x/y").unwrap()));
-        assert_eq!(PathBuf::from("x/y"), src_path(parse(":/x::y").unwrap()));
+    fn apply_to_pack_test() {
+        let (_src_dir, src) = test_transaction();
+        let tree = make_tree(
+            src.repo(),
+            &[("a/b/file", "x"), ("a/other", "y"), ("c/file", "z")],
+        );
+        let mut builder = src.repo().packbuilder().unwrap();
+        builder.insert_tree(tree.id()).unwrap();
+        let mut pack = git2::Buf::new();
+        builder.write_buf(&mut pack).unwrap();
+
+        let (_dir, transaction) = test_transaction();
+        let (filtered, filtered_pack) =
+            apply_to_pack(&transaction, parse(":/a").unwrap(), &pack, tree.id()).unwrap();
+
+        let pack_dir = transaction.repo().path().join("objects/pack");
+        assert_eq!(
+            std::fs::read_dir(pack_dir)
+                .unwrap()
+                .filter(|e| e.as_ref().unwrap().path().extension() == Some("pack".as_ref()))
+                .count(),
+            1
+        );
+
+        let (_dst_dir, dst) = test_transaction();
+        {
+            let odb = dst.repo().odb().unwrap();
+            let mut writer = odb.packwriter().unwrap();
+            std::io::Write::write_all(&mut writer, &filtered_pack).unwrap();
+            writer.commit().unwrap();
+        }
+        let result = dst.repo().find_tree(filtered).unwrap();
+        assert_eq!(tree_files(dst.repo(), &result), vec!["b/file x", "other y"]);
+        assert!(dst.repo().find_tree(tree.id()).is_err());
     }
 
     #[test]
-    fn dst_path_test() {
-        assert_eq!(PathBuf::from(""), dst_path(parse(":/x").unwrap()));
-        assert_eq!(PathBuf::from(""), dst_path(parse(":/x/y").unwrap()));
-        assert_eq!(PathBuf::from("y"), dst_path(parse(":/x::y").unwrap()));
+    fn as_chain_vec_test() {
+        let a = to_filter(Op::Subdir(PathBuf::from("a")));
+        let b = to_filter(Op::Glob("*.rs".to_string()));
+        let c = to_filter(Op::Prefix(PathBuf::from("c")));
+
+        let left = to_filter(Op::Chain(to_filter(Op::Chain(a, b)), c));
+        let right = to_filter(Op::Chain(a, to_filter(Op::Chain(b, c))));
+        assert_eq!(as_chain_vec(left), vec![a, b, c]);
+        assert_eq!(as_chain_vec(right), vec![a, b, c]);
+
+        let compose = parse(":[::a/,::b/]").unwrap();
+        assert_eq!(as_chain_vec(compose), vec![compose]);
+        assert_eq!(as_chain_vec(nop()), vec![nop()]);
+    }
+
+    #[test]
+    fn apply_with_reverse_map_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[("lib/a/b.rs", "1"), ("lib/c.rs", "2"), ("docs/d.md", "3")],
+        );
+
+        let filter = parse(":[:/lib:prefix=src/lib,:/docs:prefix=doc]").unwrap();
+        let (filtered, map) = apply_with_reverse_map(&transaction, filter, tree.clone()).unwrap();
         assert_eq!(
-            PathBuf::from("a/y"),
-            dst_path(parse(":[a=:/x::y/]").unwrap())
+            filtered,
+            apply(&transaction, filter, tree.clone()).unwrap().id()
+        );
+        let mut map: Vec<_> = map.into_iter().collect();
+        map.sort();
+        let p = |s: &str| Path::new(s).to_owned();
+        assert_eq!(
+            map,
+            vec![
+                (p("doc/d.md"), p("docs/d.md")),
+                (p("src/lib/a/b.rs"), p("lib/a/b.rs")),
+                (p("src/lib/c.rs"), p("lib/c.rs")),
+            ]
+        );
+
+        // Not invertible, or the selected files depend on their content
+        for spec in [":lowercase", ":grep=1"] {
+            let filter = parse(spec).unwrap();
+            assert!(apply_with_reverse_map(&transaction, filter, tree.clone()).is_err());
+        }
+    }
+
+    #[test]
+    fn apply_with_sink_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[("src/a.rs", "sink 1"), ("src/b/c.rs", "sink 2"), ("d", "3")],
         );
+        let filter = parse(":[:/src:prefix=lib,::d:prefix=x]").unwrap();
+        let odb = repo.odb().unwrap();
 
+        let filtered = apply_with_sink(&transaction, filter, tree.clone(), &mut NullSink).unwrap();
+        assert!(!odb.exists(filtered));
+
+        let mut memory = MemorySink::default();
         assert_eq!(
-            PathBuf::from("c/a"),
-            dst_path(parse(":[a=:/x::y/,a/b=:/i]:prefix=c").unwrap())
+            apply_with_sink(&transaction, filter, tree.clone(), &mut memory).unwrap(),
+            filtered
+        );
+        assert!(!odb.exists(filtered));
+        // Only the root and `x`, the blobs and `lib` are already in the repository
+        assert!(memory
+            .objects
+            .values()
+            .all(|(kind, _)| *kind == git2::ObjectType::Tree));
+        assert_eq!(memory.objects.len(), 2);
+
+        assert_eq!(
+            apply_with_sink(&transaction, filter, tree.clone(), &mut OdbSink(repo)).unwrap(),
+            filtered
+        );
+        assert!(odb.exists(filtered));
+        assert_eq!(apply(&transaction, filter, tree).unwrap().id(), filtered);
+    }
+
+    #[test]
+    fn apply_with_sink_parallel_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let files: Vec<_> = (0..9)
+            .map(|i| (format!("d{}/f", i), format!("sink parallel {}", i)))
+            .collect();
+        let files: Vec<_> = files
+            .iter()
+            .map(|(p, c)| (p.as_str(), c.as_str()))
+            .collect();
+        let tree = make_tree(repo, &files);
+        let members: Vec<_> = (0..9)
+            .map(|i| parse(&format!(":/d{}:prefix=e{}", i, i)).unwrap())
+            .collect();
+        let filter = to_filter(Op::Compose(members.clone()));
+        let odb = repo.odb().unwrap();
+
+        // Enough members to be applied in parallel, but the objects of a sink must not
+        // be written to the repository by other transactions
+        let filtered = apply_with_sink(&transaction, filter, tree.clone(), &mut NullSink).unwrap();
+        assert!(!odb.exists(filtered));
+        for member in members {
+            let id = apply_with_sink(&transaction, member, tree.clone(), &mut NullSink).unwrap();
+            assert!(!odb.exists(id), "{}", spec(member));
+        }
+        assert_eq!(apply(&transaction, filter, tree).unwrap().id(), filtered);
+    }
+
+    #[test]
+    fn infer_filter_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let input = make_tree(
+            repo,
+            &[
+                ("a/b/x", "1"),
+                ("a/b/y", "2"),
+                ("c/z", "3"),
+                ("c/README", "4"),
+            ],
+        );
+
+        for (files, expected) in [
+            (vec![("lib/x", "1"), ("lib/y", "2")], ":/a/b:prefix=lib"),
+            (
+                vec![("lib/x", "1"), ("lib/y", "2"), ("docs/README", "4")],
+                ":[docs=:/c::README,lib=:/a/b]",
+            ),
+        ] {
+            let output = make_tree(repo, &files);
+            let filter = infer_filter(&transaction, &input, &output).unwrap();
+            assert_eq!(spec(filter), spec(parse(expected).unwrap()));
+            assert_eq!(
+                apply(&transaction, filter, input.clone()).unwrap().id(),
+                output.id()
+            );
+        }
+
+        assert_eq!(infer_filter(&transaction, &input, &input), Some(nop()));
+        assert_eq!(
+            infer_filter(&transaction, &input, &make_tree(repo, &[("x", "5")])),
+            None
+        );
+        assert_eq!(
+            infer_filter(&transaction, &input, &make_tree(repo, &[("renamed", "1")])),
+            None
+        );
+    }
+
+    #[test]
+    fn apply_subdir_run_test() {
+        let (_dir, transaction) = test_transaction();
+        let repo = transaction.repo();
+        let tree = make_tree(
+            repo,
+            &[("a/b/c/d/file", "x"), ("a/b/other", "y"), ("a/file", "z")],
         );
+
+        let filter = parse(":/a/b/c:prefix=p").unwrap();
+        let filtered = apply(&transaction, filter, tree.clone()).unwrap();
+        assert_eq!(tree_files(repo, &filtered), vec!["p/d/file x"]);
+
+        let filtered = apply(&transaction, parse(":/a/b").unwrap(), tree.clone()).unwrap();
+        assert_eq!(tree_files(repo, &filtered), vec!["c/d/file x", "other y"]);
+
+        let filtered = apply(&transaction, parse(":/a/x/y").unwrap(), tree.clone()).unwrap();
+        assert_eq!(filtered.id(), tree::empty_id());
+
+        // The descent for `:/a/b/c` is recorded for the whole run and each component
+        let transaction = cache::Transaction::open(repo.path(), None).unwrap();
+        let tree = transaction.repo().find_tree(tree.id()).unwrap();
+        let id = |path: &str| tree.get_path(Path::new(path)).unwrap().id();
+        apply(&transaction, parse(":/a/b/c").unwrap(), tree.clone()).unwrap();
+        assert_eq!(transaction.apply_len(), 4);
+        let subdir = |p: &str| to_filter(Op::Subdir(p.into()));
+        assert_eq!(transaction.get_apply(subdir("a"), tree.id()), Some(id("a")));
+        assert_eq!(transaction.get_apply(subdir("b"), id("a")), Some(id("a/b")));
+        assert_eq!(
+            transaction.get_apply(subdir("c"), id("a/b")),
+            Some(id("a/b/c"))
+        );
+
+        // Runs sharing leading components only add what they don't share
+        apply(&transaction, parse(":/a/b").unwrap(), tree.clone()).unwrap();
+        assert_eq!(transaction.apply_len(), 5);
+        apply(&transaction, parse(":/a/b/c").unwrap(), tree.clone()).unwrap();
+        assert_eq!(transaction.apply_len(), 5);
+
+        // Known components are not descended again
+        let fake = make_tree(transaction.repo(), &[("fake", "1")]);
+        transaction.insert_apply(subdir("a"), tree.id(), fake.id());
+        let filtered = apply(&transaction, parse(":/a").unwrap(), tree.clone()).unwrap();
+        assert_eq!(filtered.id(), fake.id());
     }
 }
 
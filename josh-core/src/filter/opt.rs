@@ -5,38 +5,387 @@
 
 use super::*;
 
+/// Number of entries kept in each of the `optimize`, `simplify` and `spec` caches by default
+const DEFAULT_OPT_CACHE_CAPACITY: usize = 50_000;
+
+// Filters are keyed by their interned id, which is the hash of their structure, so
+// equal filters built in different ways share their entries.
+pub(crate) type OptCache<V = Filter> = std::sync::Mutex<clru::CLruCache<Filter, V>>;
+
+pub(crate) fn opt_cache<V>() -> OptCache<V> {
+    std::sync::Mutex::new(clru::CLruCache::new(
+        std::num::NonZeroUsize::new(DEFAULT_OPT_CACHE_CAPACITY).unwrap(),
+    ))
+}
+
 lazy_static! {
-    static ref OPTIMIZED: std::sync::Mutex<std::collections::HashMap<Filter, Filter>> =
-        std::sync::Mutex::new(std::collections::HashMap::new());
+    static ref OPTIMIZED: OptCache = opt_cache();
+    // Results of single `step`s. These are kept apart from `OPTIMIZED`, as they are
+    // not fixpoints and `optimize` must not return them. The name of the applied rule
+    // is kept as well, so traces also cover cached steps.
+    static ref STEPPED: OptCache<(Filter, &'static str)> = opt_cache();
     static ref INVERTED: std::sync::Mutex<std::collections::HashMap<Filter, Filter>> =
         std::sync::Mutex::new(std::collections::HashMap::new());
-    static ref SIMPLIFIED: std::sync::Mutex<std::collections::HashMap<Filter, Filter>> =
-        std::sync::Mutex::new(std::collections::HashMap::new());
+    static ref SIMPLIFIED: OptCache = opt_cache();
+}
+
+static CACHE_HITS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+static CACHE_MISSES: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Usage of the `optimize`, `step` and `simplify` caches, as returned by
+/// `optimization_cache_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of entries currently kept in all of the caches
+    pub entries: usize,
+    /// Lookups that found a result since the caches were last cleared
+    pub hits: u64,
+    /// Lookups that had to compute the result since the caches were last cleared
+    pub misses: u64,
+}
+
+/// Counters are shared by all threads, so they include lookups of concurrent callers
+pub fn optimization_cache_stats() -> CacheStats {
+    CacheStats {
+        entries: OPTIMIZED.lock().unwrap().len()
+            + STEPPED.lock().unwrap().len()
+            + SIMPLIFIED.lock().unwrap().len(),
+        hits: CACHE_HITS.load(std::sync::atomic::Ordering::Relaxed),
+        misses: CACHE_MISSES.load(std::sync::atomic::Ordering::Relaxed),
+    }
+}
+
+/// Drop all cached results of `optimize`, `step`, `simplify`, `invert` and `spec`, and
+/// reset the counters of `optimization_cache_stats`
+pub fn clear_optimization_caches() {
+    OPTIMIZED.lock().unwrap().clear();
+    SPEC_CACHE.lock().unwrap().clear();
+    STEPPED.lock().unwrap().clear();
+    SIMPLIFIED.lock().unwrap().clear();
+    INVERTED.lock().unwrap().clear();
+    CACHE_HITS.store(0, std::sync::atomic::Ordering::Relaxed);
+    CACHE_MISSES.store(0, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Results of `step` and `simplify` computed during the outermost `optimize` call of
+/// the current thread. Lookups check these first, so optimizing a big filter does not
+/// lock the shared caches again for every node it has already seen. The results are
+/// moved to the shared caches when the call returns.
+#[derive(Default)]
+struct LocalMemo {
+    stepped: std::collections::HashMap<Filter, (Filter, &'static str)>,
+    simplified: std::collections::HashMap<Filter, Filter>,
 }
 
+thread_local! {
+    static MEMO: std::cell::RefCell<Option<LocalMemo>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Moves the local memo to the shared caches when dropped, also on panic
+struct MemoFlush;
+
+impl Drop for MemoFlush {
+    fn drop(&mut self) {
+        let Some(memo) = MEMO.with(|memo| memo.borrow_mut().take()) else {
+            return;
+        };
+        let mut stepped = STEPPED.lock().unwrap();
+        for (filter, result) in memo.stepped {
+            stepped.put(filter, result);
+        }
+        drop(stepped);
+        let mut simplified = SIMPLIFIED.lock().unwrap();
+        for (filter, result) in memo.simplified {
+            simplified.put(filter, result);
+        }
+    }
+}
+
+/// Run `f` with a local memo, unless one is active already for an outer call
+fn with_local_memo<R>(f: impl FnOnce() -> R) -> R {
+    let outermost = MEMO.with(|memo| {
+        let mut memo = memo.borrow_mut();
+        let outermost = memo.is_none();
+        if outermost {
+            *memo = Some(LocalMemo::default());
+        }
+        outermost
+    });
+    // Not `then_some`, that would drop a guard right away for inner calls and
+    // flush the memo of the outer one
+    let _flush = if outermost { Some(MemoFlush) } else { None };
+    f()
+}
+
+fn memo_get<V: Copy>(
+    local: fn(&mut LocalMemo) -> &mut std::collections::HashMap<Filter, V>,
+    shared: &OptCache<V>,
+    filter: Filter,
+) -> Option<V> {
+    let found = MEMO.with(|memo| {
+        memo.borrow_mut()
+            .as_mut()
+            .map(|m| local(m).get(&filter).copied())
+    });
+    match found {
+        Some(Some(result)) => {
+            CACHE_HITS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Some(result)
+        }
+        Some(None) => {
+            let result = cached(shared, filter)?;
+            MEMO.with(|memo| {
+                if let Some(m) = memo.borrow_mut().as_mut() {
+                    local(m).insert(filter, result);
+                }
+            });
+            Some(result)
+        }
+        None => cached(shared, filter),
+    }
+}
+
+fn memo_put<V: Copy>(
+    local: fn(&mut LocalMemo) -> &mut std::collections::HashMap<Filter, V>,
+    shared: &OptCache<V>,
+    filter: Filter,
+    result: V,
+) {
+    let kept = MEMO.with(|memo| {
+        memo.borrow_mut()
+            .as_mut()
+            .map(|m| local(m).insert(filter, result))
+            .is_some()
+    });
+    if !kept {
+        shared.lock().unwrap().put(filter, result);
+    }
+}
+
+fn cached<V: Copy>(cache: &OptCache<V>, filter: Filter) -> Option<V> {
+    let result = cache.lock().unwrap().get(&filter).copied();
+    let counter = if result.is_some() {
+        &CACHE_HITS
+    } else {
+        &CACHE_MISSES
+    };
+    counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    result
+}
+
+/// Limit the number of entries kept in each of the `optimize`, `step`, `simplify` and
+/// `spec` caches.
+/// The least recently used entries are evicted first and get recomputed when needed,
+/// so this only trades memory for time.
+pub fn set_opt_cache_capacity(n: usize) {
+    let n = std::num::NonZeroUsize::new(n.max(1)).unwrap();
+    OPTIMIZED.lock().unwrap().resize(n);
+    STEPPED.lock().unwrap().resize(n);
+    SIMPLIFIED.lock().unwrap().resize(n);
+    SPEC_CACHE.lock().unwrap().resize(n);
+}
+
+/// Make `optimize` return `filters` as they are, without running the optimizer on them.
+/// This is meant for frequently used filters that are known to be optimized already, to
+/// save the work of confirming that at startup. The filters are not checked, a filter
+/// that is not actually a fixpoint will just stay less optimized. Registered filters are
+/// kept like any other cached result, so they can be evicted or cleared again.
+pub fn register_fixpoints(filters: &[Filter]) {
+    register_fixpoints_in(&OPTIMIZED, filters)
+}
+
+fn register_fixpoints_in(cache: &OptCache, filters: &[Filter]) {
+    let mut optimized = cache.lock().unwrap();
+    for filter in filters {
+        optimized.put(*filter, *filter);
+    }
+}
+
+/// Number of rounds after which `optimize` gives up looking for a fixpoint
+const MAX_ROUNDS: usize = 1000;
+
 /*
  * Attempt to create an alternative representation of a filter AST that is most
  * suitable for fast evaluation and cache reuse.
  */
 pub fn optimize(filter: Filter) -> Filter {
-    if let Some(f) = OPTIMIZED.lock().unwrap().get(&filter) {
-        return *f;
+    optimize_with_limit(filter, MAX_ROUNDS)
+}
+
+/*
+ * Like `optimize`, but stop after `max_rounds` rounds of rewriting even when no fixpoint
+ * has been reached. In that case the best result so far is returned, which is still
+ * equivalent to the input, but it is not cached.
+ */
+pub fn optimize_with_limit(filter: Filter, max_rounds: usize) -> Filter {
+    optimize_in(&OPTIMIZED, filter, max_rounds)
+}
+
+/// `optimize_with_limit` with the results kept in `cache`
+fn optimize_in(cache: &OptCache, filter: Filter, max_rounds: usize) -> Filter {
+    if let Some(f) = cached(cache, filter) {
+        return f;
     }
-    let original = filter;
 
+    let (optimized, converged) = with_local_memo(|| optimize_rounds(filter, max_rounds));
+    if !converged {
+        tracing::warn!(
+            "optimize: no fixpoint after {} rounds: {}",
+            max_rounds,
+            spec(filter)
+        );
+        return optimized;
+    }
+
+    cache.lock().unwrap().put(filter, optimized);
+    optimized
+}
+
+fn optimize_rounds(filter: Filter, max_rounds: usize) -> (Filter, bool) {
     let mut filter = flatten(filter);
-    let result = loop {
+    for _ in 0..max_rounds {
         let pretty = simplify(filter);
-        let optimized = iterate(filter);
+        let (optimized, _) = iterate(filter, max_rounds, false);
         filter = simplify(optimized);
 
         if filter == pretty {
-            break iterate(filter);
+            return iterate(filter, max_rounds, false);
         }
+    }
+    (filter, false)
+}
+
+/// One rewrite done by `step` while optimizing a filter, as returned by `optimize_traced`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptimizationStep {
+    /// One of `rule_names`
+    pub rule: &'static str,
+    pub before: Filter,
+    pub after: Filter,
+}
+
+thread_local! {
+    static TRACE: std::cell::RefCell<Option<Vec<OptimizationStep>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Optimize `filter` like `optimize`, and return the rewrites that were done to get
+/// the result. Previous results of `optimize` are not used, so all rewrites are listed.
+pub fn optimize_traced(filter: Filter) -> (Filter, Vec<OptimizationStep>) {
+    TRACE.with(|trace| *trace.borrow_mut() = Some(vec![]));
+    let (optimized, _) = optimize_rounds(filter, MAX_ROUNDS);
+    let steps = TRACE
+        .with(|trace| trace.borrow_mut().take())
+        .unwrap_or_default();
+    (optimized, steps)
+}
+
+/// Effect of one rule while optimizing a filter, as returned by `optimize_with_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RuleStats {
+    /// Number of times the rule was applied
+    pub applied: usize,
+    /// Sum of the changes in the number of nodes caused by the rule, negative if it
+    /// made the filter smaller
+    pub size_delta: isize,
+}
+
+/// Optimize `filter` like `optimize_traced`, and sum up the effect of each rule on the
+/// size of the filter. Rewrites of the parts of a filter are counted for the rules that
+/// did them instead of `descend`. The changes `simplify` makes between the rounds of
+/// rewriting are not included, so the deltas don't add up to the total change.
+pub fn optimize_with_stats(
+    filter: Filter,
+) -> (Filter, std::collections::BTreeMap<&'static str, RuleStats>) {
+    let (optimized, steps) = optimize_traced(filter);
+    let mut stats = std::collections::BTreeMap::new();
+    for s in steps {
+        attribute(s.before, s.after, &mut stats);
+    }
+    (optimized, stats)
+}
+
+fn attribute(
+    before: Filter,
+    after: Filter,
+    stats: &mut std::collections::BTreeMap<&'static str, RuleStats>,
+) {
+    let (stepped, rule) = step_rule(before);
+    let children = match (to_op(before), to_op(after)) {
+        _ if rule != "descend" || stepped != after => None,
+        (Op::Compose(a), Op::Compose(b)) => Some((prefix_sort(&a), b)),
+        (Op::Chain(a, b), Op::Chain(x, y))
+        | (Op::Subtract(a, b), Op::Subtract(x, y))
+        | (Op::Intersect(a, b), Op::Intersect(x, y)) => Some((vec![a, b], vec![x, y])),
+        (Op::Exclude(a), Op::Exclude(x)) => Some((vec![a], vec![x])),
+        (Op::IfExists(_, a, b), Op::IfExists(_, x, y)) => Some((vec![a, b], vec![x, y])),
+        (Op::Rev(a), Op::Rev(x)) => Some((a.into_values().collect(), x.into_values().collect())),
+        _ => None,
     };
+    match children {
+        Some((a, b)) if a.len() == b.len() => {
+            for (a, b) in a.into_iter().zip(b) {
+                if a != b {
+                    attribute(a, b, stats);
+                }
+            }
+        }
+        _ => {
+            let entry = stats.entry(rule).or_default();
+            entry.applied += 1;
+            entry.size_delta += size(after) as isize - size(before) as isize;
+        }
+    }
+}
 
-    OPTIMIZED.lock().unwrap().insert(original, result);
-    result
+/*
+ * Like `optimize`, but simplify and step the result again until both leave it as it is.
+ * The result has at most as many nodes as the one of `optimize`. It is not cached, as
+ * this is meant for filters that are optimized once and then stored.
+ */
+pub fn optimize_interleaved(filter: Filter) -> Filter {
+    iterate(optimize(filter), MAX_ROUNDS, true).0
+}
+
+/*
+ * Like `optimize_interleaved`, and then merge the runs of single component `:/x` and
+ * `:prefix=x` that `step` splits paths into with `denormalize`. The split form lets
+ * filters share cached results of common path components, for a filter that is only
+ * used once the merged form is smaller and faster to apply.
+ * Optimizing the result again splits the paths again.
+ */
+pub fn optimize_for_size(filter: Filter) -> Filter {
+    denormalize(optimize_interleaved(filter))
+}
+
+/// Merge consecutive subdirs of a chain into one subdir, and consecutive prefixes into
+/// one prefix, so `:/a:/b:prefix=c:prefix=d` becomes `:/a/b:prefix=d/c`
+pub fn denormalize(filter: Filter) -> Filter {
+    let result = match to_op(filter) {
+        Op::Chain(_, _) => {
+            let mut merged: Vec<Op> = vec![];
+            for part in as_chain_vec(filter) {
+                let part = to_op(denormalize(part));
+                match (merged.last_mut(), part) {
+                    (Some(Op::Subdir(x)), Op::Subdir(y)) => *x = x.join(y),
+                    (Some(Op::Prefix(x)), Op::Prefix(y)) => *x = y.join(&x),
+                    (_, part) => merged.push(part),
+                }
+            }
+            return merged
+                .into_iter()
+                .map(to_filter)
+                .rev()
+                .reduce(|b, a| to_filter(Op::Chain(a, b)))
+                .unwrap_or_else(|| to_filter(Op::Nop));
+        }
+        Op::Compose(filters) => Op::Compose(filters.into_iter().map(denormalize).collect()),
+        Op::Subtract(a, b) => Op::Subtract(denormalize(a), denormalize(b)),
+        Op::Intersect(a, b) => Op::Intersect(denormalize(a), denormalize(b)),
+        Op::Exclude(b) => Op::Exclude(denormalize(b)),
+        op => op,
+    };
+    to_filter(result)
 }
 
 /*
@@ -45,8 +394,8 @@ pub fn optimize(filter: Filter) -> Filter {
  * Useful as a pre-processing step for pretty printing and also during filter optimization.
  */
 pub fn simplify(filter: Filter) -> Filter {
-    if let Some(f) = SIMPLIFIED.lock().unwrap().get(&filter) {
-        return *f;
+    if let Some(f) = memo_get(|m| &mut m.simplified, &SIMPLIFIED, filter) {
+        return f;
     }
     rs_tracing::trace_scoped!("simplify", "spec": spec2(&to_op(filter)));
     let original = filter;
@@ -60,7 +409,8 @@ pub fn simplify(filter: Filter) -> Filter {
                     out.push(f);
                 }
             }
-            Op::Compose(out.drain(..).map(simplify).collect())
+            let out: Vec<_> = out.drain(..).map(simplify).collect();
+            Op::Compose(prefix_sort(&out))
         }
         Op::Chain(a, b) => match (to_op(a), to_op(b)) {
             (a, Op::Chain(x, y)) => Op::Chain(to_filter(Op::Chain(to_filter(a), x)), y),
@@ -70,6 +420,10 @@ pub fn simplify(filter: Filter) -> Filter {
                 (x, Op::Prefix(p1), Op::Prefix(p2)) => {
                     Op::Chain(simplify(to_filter(x)), to_filter(Op::Prefix(p2.join(p1))))
                 }
+                // Undo the expansion of globs with a literal directory done by `step`
+                (Op::Subdir(s), Op::Glob(g), Op::Prefix(p)) if s == p => {
+                    Op::Glob(format!("{}/{}", s.to_string_lossy(), g))
+                }
                 _ => Op::Chain(simplify(a), simplify(to_filter(b))),
             },
             (a, b) => Op::Chain(simplify(to_filter(a)), simplify(to_filter(b))),
@@ -78,7 +432,9 @@ pub fn simplify(filter: Filter) -> Filter {
             let (a, b) = (to_op(a), to_op(b));
             Op::Subtract(simplify(to_filter(a)), simplify(to_filter(b)))
         }
+        Op::Intersect(a, b) => Op::Intersect(simplify(a), simplify(b)),
         Op::Exclude(b) => Op::Exclude(simplify(b)),
+        Op::IfExists(path, a, b) => Op::IfExists(path, simplify(a), simplify(b)),
         _ => to_op(filter),
     });
 
@@ -88,7 +444,7 @@ pub fn simplify(filter: Filter) -> Filter {
         simplify(result)
     };
 
-    SIMPLIFIED.lock().unwrap().insert(original, r);
+    memo_put(|m| &mut m.simplified, &SIMPLIFIED, original, r);
     r
 }
 
@@ -133,7 +489,9 @@ pub fn flatten(filter: Filter) -> Filter {
             let (a, b) = (to_op(a), to_op(b));
             Op::Subtract(flatten(to_filter(a)), flatten(to_filter(b)))
         }
+        Op::Intersect(a, b) => Op::Intersect(flatten(a), flatten(b)),
         Op::Exclude(b) => Op::Exclude(flatten(b)),
+        Op::IfExists(path, a, b) => Op::IfExists(path, flatten(a), flatten(b)),
         _ => to_op(filter),
     });
 
@@ -187,6 +545,24 @@ fn group(filters: &Vec<Filter>) -> Vec<Vec<Filter>> {
         }
         res.push(vec![*f]);
     }
+    if res.len() != filters.len() {
+        return res;
+    }
+
+    // Subtract is not a chain, so members sharing the same subtrahend
+    // need to be grouped separately
+    let mut res: Vec<Vec<Filter>> = vec![];
+    for f in filters {
+        if let (Op::Subtract(_, a), Some(last)) = (to_op(*f), res.last_mut()) {
+            if let Op::Subtract(_, x) = to_op(last[0]) {
+                if a == x {
+                    last.push(*f);
+                    continue;
+                }
+            }
+        }
+        res.push(vec![*f]);
+    }
     res
 }
 
@@ -197,20 +573,40 @@ fn last_chain(rest: Filter, filter: Filter) -> (Filter, Filter) {
     }
 }
 
+/// Order the members of a compose by their source and destination paths, so equivalent
+/// composes get the same representation. This is the canonical form of workspaces: the
+/// members of a `workspace.josh` file end up in the same order however they are written.
+/// Members that read or write overlapping paths are never swapped, because their order
+/// decides which of them gets a path. Members that keep paths where they are, like
+/// `::docs/` or `::**/*.md`, are the exception: a path they both produce has the same
+/// content either way. Of the members that have no such member in front of them the
+/// smallest one is taken first, which gives the same order for every permutation of
+/// independent members. Members with the same paths are ordered by their spec, so this
+/// does not depend on the ids of the filters.
 fn prefix_sort(filters: &[Filter]) -> Vec<Filter> {
-    let mut sorted = filters.to_owned();
-    sorted.sort_by(|a, b| {
-        let (src_a, src_b) = (src_path(*a), src_path(*b));
-        if src_a.starts_with(&src_b) || src_b.starts_with(&src_a) {
-            return std::cmp::Ordering::Equal;
-        }
-        let (dst_a, dst_b) = (dst_path(*a), dst_path(*b));
-        if dst_a.starts_with(&dst_b) || dst_b.starts_with(&dst_a) {
-            return std::cmp::Ordering::Equal;
-        }
+    let overlap = |a: &Path, b: &Path| a.starts_with(b) || b.starts_with(a);
+    let keys: Vec<_> = filters
+        .iter()
+        .map(|f| (src_path(*f), dst_path(*f), spec(*f)))
+        .collect();
+    let in_place: Vec<_> = filters
+        .iter()
+        .map(|f| in_place_roots(*f).is_some())
+        .collect();
+    let depends = |i: usize, j: usize| {
+        !(in_place[i] && in_place[j])
+            && (overlap(&keys[i].0, &keys[j].0) || overlap(&keys[i].1, &keys[j].1))
+    };
 
-        (&src_a, &dst_a).partial_cmp(&(&src_b, &dst_b)).unwrap()
-    });
+    let mut left: Vec<usize> = (0..filters.len()).collect();
+    let mut sorted = vec![];
+    while !left.is_empty() {
+        let next = (0..left.len())
+            .filter(|n| left[..*n].iter().all(|i| !depends(*i, left[*n])))
+            .min_by(|a, b| keys[left[*a]].cmp(&keys[left[*b]]))
+            .unwrap();
+        sorted.push(filters[left.remove(next)]);
+    }
     sorted
 }
 
@@ -233,24 +629,161 @@ fn common_pre(filters: &Vec<Filter>) -> Option<(Filter, Vec<Filter>)> {
     c.map(|c| (c, rest))
 }
 
-fn common_post(filters: &Vec<Filter>) -> Option<(Filter, Vec<Filter>)> {
+/// Find a subtrahend shared by all filters, so that
+/// `:[:subtract[a,x],:subtract[b,x]]` can become `:subtract[:[a,b],x]`.
+/// This is only done when all minuends keep paths where they are, otherwise
+/// the output of one member could be removed by the subtrahend of another.
+fn common_subtract(filters: &Vec<Filter>) -> Option<(Filter, Vec<Filter>)> {
     let mut rest = vec![];
-    let mut common_post: Option<Filter> = None;
+    let mut common: Option<Filter> = None;
     for f in filters {
-        let (a, b) = last_chain(to_filter(Op::Nop), *f);
-        {
-            rest.push(a);
-            if common_post.is_none() {
-                common_post = Some(b);
+        if let Op::Subtract(a, b) = to_op(*f) {
+            if *common.get_or_insert(b) != b || invert(a).ok() != Some(a) {
+                return None;
             }
-            if common_post != Some(b) {
+            rest.push(a);
+        } else {
+            return None;
+        }
+    }
+    common.map(|c| (c, rest))
+}
+
+/// Find a subtract of everything selected by `x` that all filters end with, so that
+/// `:[a:SUBTRACT[x],b:SUBTRACT[x]]` can become `:[a,b]:SUBTRACT[x]`. `group` puts such
+/// members next to each other, as they share their last chain link.
+/// This is only done when the rest of the members read and write disjoint paths, so
+/// removing a path from one of them can't leave it to another one, and when `x` only
+/// selects paths of its input, so it removes the same paths from the combined output.
+fn common_trailing_subtract(filters: &[Filter]) -> Option<(Filter, Vec<Filter>)> {
+    let mut rest = vec![];
+    let mut common: Option<Filter> = None;
+    for f in filters {
+        let parts = as_chain_vec(*f);
+        let (last, parts) = parts.split_last()?;
+        match to_op(*last) {
+            Op::Subtract(a, x) if a == to_filter(Op::Nop) && in_place_roots(x).is_some() => {}
+            _ => return None,
+        }
+        if *common.get_or_insert(*last) != *last {
+            return None;
+        }
+        rest.push(
+            parts
+                .iter()
+                .rev()
+                .copied()
+                .reduce(|b, a| to_filter(Op::Chain(a, b)))
+                .unwrap_or_else(|| to_filter(Op::Nop)),
+        );
+    }
+    if !disjoint_members(&rest) {
+        return None;
+    }
+    common.map(|c| (c, rest))
+}
+
+/// Path selected by a filter made up only of subdir ops
+fn subdir_path(filter: Filter) -> Option<std::path::PathBuf> {
+    match to_op(filter) {
+        Op::Subdir(p) => Some(p),
+        Op::Chain(a, b) => Some(subdir_path(a)?.join(subdir_path(b)?)),
+        _ => None,
+    }
+}
+
+/// A compose of exclusions keeps everything except the intersection of what is
+/// excluded. For subdirs that intersection is either the deepest of the paths, if
+/// they are all nested in each other, or nothing:
+/// `:[:exclude[:/a],:exclude[:/a/b]]` can become `:exclude[:/a/b]`
+/// and `:[:exclude[:/a],:exclude[:/b]]` can become `:/`.
+fn common_exclusion(filters: &Vec<Filter>) -> Option<Op> {
+    if filters.len() < 2 {
+        return None;
+    }
+    let mut paths = vec![];
+    for f in filters {
+        if let Op::Subtract(a, b) = to_op(*f) {
+            if a != to_filter(Op::Nop) {
                 return None;
             }
+            paths.push((subdir_path(b)?, b));
+        } else {
+            return None;
+        }
+    }
+    paths.sort_by_key(|(p, _)| p.components().count());
+    let (deepest, b) = paths.last()?.clone();
+    if paths.iter().all(|(p, _)| deepest.starts_with(p)) {
+        Some(Op::Subtract(to_filter(Op::Nop), b))
+    } else {
+        Some(Op::Nop)
+    }
+}
+
+/// Filters like `::a/` that keep a directory where it is. Applying those more
+/// than once in a row has no further effect.
+fn is_dir_restriction(filter: Filter) -> bool {
+    if let Op::Chain(a, b) = to_op(filter) {
+        if let (Op::Subdir(s), Op::Prefix(p)) = (to_op(a), to_op(b)) {
+            return s == p;
+        }
+    }
+    false
+}
+
+fn starts_with(filter: Filter, first: Filter) -> bool {
+    filter == first || matches!(to_op(filter), Op::Chain(x, _) if x == first)
+}
+
+/// Make the prefix a file below a directory is written with explicit, so `::a/f`
+/// becomes `:/a::f` followed by `:prefix=a`
+fn split_file(rest: Filter, last: Filter) -> (Filter, Filter) {
+    match to_op(last) {
+        Op::File(path) if path.components().count() > 1 => {
+            let mut components = path.components();
+            let dir = std::path::PathBuf::from(&components.next().unwrap());
+            let file = to_filter(Op::Chain(
+                to_filter(Op::Subdir(dir.clone())),
+                to_filter(Op::File(components.as_path().to_owned())),
+            ));
+            (to_filter(Op::Chain(rest, file)), to_filter(Op::Prefix(dir)))
+        }
+        _ => (rest, last),
+    }
+}
+
+fn common_post(filters: &[Filter]) -> Option<(Filter, Vec<Filter>)> {
+    let mut tails: Vec<_> = filters
+        .iter()
+        .map(|f| last_chain(to_filter(Op::Nop), *f))
+        .collect();
+    // Files only share a prefix with members that end with one, composes of only
+    // files are left as they are
+    if tails
+        .iter()
+        .any(|(_, b)| matches!(to_op(*b), Op::Prefix(_)))
+    {
+        tails = tails.into_iter().map(|(a, b)| split_file(a, b)).collect();
+    }
+
+    let mut rest = vec![];
+    let mut common_post: Option<Filter> = None;
+    for (a, b) in tails {
+        rest.push(a);
+        if common_post.is_none() {
+            common_post = Some(b);
+        }
+        if common_post != Some(b) {
+            return None;
         }
     }
 
     if let Some(c) = common_post {
-        if invert(c).ok() == common_post {
+        // Taking out a `Nop` doesn't simplify anything, it only adds a chain with it
+        if c == to_filter(Op::Nop) {
+            None
+        } else if invert(c).ok() == common_post {
             common_post.map(|c| (c, rest))
         } else if let Op::Prefix(_) = to_op(c) {
             common_post.map(|c| (c, rest))
@@ -262,29 +795,93 @@ fn common_post(filters: &Vec<Filter>) -> Option<(Filter, Vec<Filter>)> {
     }
 }
 
+/*
+ * Push `prefix` into the members of a compose it is chained with, in front of them
+ * if `before` is set. This is only done if at least one member then cancels with
+ * the prefix, because otherwise `common_pre` or `common_post` would just undo it.
+ */
+fn distribute(prefix: Filter, filters: &[Filter], before: bool) -> Option<Op> {
+    let mut cancelled = false;
+    let distributed = filters
+        .iter()
+        .map(|f| {
+            let chain = if before {
+                Op::Chain(prefix, *f)
+            } else {
+                Op::Chain(*f, prefix)
+            };
+            let stepped = step(to_filter(chain));
+            // A `Nop` member just turns into the prefix itself, which doesn't count
+            cancelled |= stepped != prefix && size(stepped) <= size(*f);
+            stepped
+        })
+        .collect();
+    cancelled.then_some(Op::Compose(distributed))
+}
+
+/// Number of nodes of the filter AST
+fn size(filter: Filter) -> usize {
+    match to_op(filter) {
+        Op::Compose(filters) => 1 + filters.into_iter().map(size).sum::<usize>(),
+        Op::Chain(a, b) | Op::Subtract(a, b) | Op::Intersect(a, b) => 1 + size(a) + size(b),
+        Op::Exclude(b) => 1 + size(b),
+        Op::IfExists(_, a, b) => 1 + size(a) + size(b),
+        _ => 1,
+    }
+}
+
 /*
  * Apply optimization steps to a filter until it converges (no rules apply anymore)
  */
-fn iterate(filter: Filter) -> Filter {
+/// Apply `step` until the filter does not change anymore, but at most `max_rounds` times.
+/// With `interleave`, every fixpoint is simplified and stepped again until `simplify` does
+/// not change it either, and the smallest fixpoint found is returned. As `simplify` undoes
+/// some of the rewrites of `step`, this stops as soon as a fixpoint repeats.
+/// Returns the result and whether a fixpoint was reached.
+fn iterate(filter: Filter, max_rounds: usize, interleave: bool) -> (Filter, bool) {
     let mut filter = filter;
+    let mut best: Option<Filter> = None;
+    let mut seen = std::collections::HashSet::new();
     log::debug!("opt::iterate:\n{}\n", pretty(filter, 0));
-    for i in 0..1000 {
-        let optimized = step(filter);
+    for i in 0..max_rounds {
+        let (optimized, rule) = step_rule(filter);
         if filter == optimized {
-            break;
+            if !interleave {
+                return (filter, true);
+            }
+            if best.is_none_or(|b| size(filter) < size(b)) {
+                best = Some(filter);
+            }
+            let simplified = simplify(filter);
+            if simplified == filter || !seen.insert(filter) {
+                return (best.unwrap(), true);
+            }
+            filter = simplified;
+            continue;
         }
 
         if log::log_enabled!(log::Level::Debug) {
             log::debug!(
-                "stepop {}:\n{:?}\n->\n{:?}\n",
+                "stepop {} ({}):\n{:?}\n->\n{:?}\n",
                 i,
+                rule,
                 to_op(filter),
                 to_op(optimized)
             );
         }
+        TRACE.with(|trace| {
+            if let Some(steps) = trace.borrow_mut().as_mut() {
+                steps.push(OptimizationStep {
+                    rule,
+                    before: filter,
+                    after: optimized,
+                });
+            }
+        });
         filter = optimized;
     }
-    filter
+    tracing::warn!("iterate: no fixpoint after {} rounds", max_rounds);
+    (best.unwrap_or(filter), false)
 }
 
 fn is_prefix(op: Op) -> bool {
@@ -300,141 +897,808 @@ fn prefix_of(op: Op) -> Filter {
     })
 }
 
+/// Split a glob pattern into its leading directory, if that is a literal name
+/// rather than a pattern, and the pattern to match inside of that directory
+fn glob_literal_dir(pattern: &str) -> Option<(&str, &str)> {
+    let (dir, rest) = pattern.split_once('/')?;
+    if dir.is_empty()
+        || dir == "."
+        || dir == ".."
+        || rest.is_empty()
+        || dir.contains(['*', '?', '[', ']'])
+    {
+        return None;
+    }
+    Some((dir, rest))
+}
+
+/// Pattern that selects the same files inside of `subdir` as `pattern` does before
+/// entering it, if there is one. This allows applying the subdir first, so that the
+/// glob only needs to walk the subdirectory.
+/// Only two cases are handled: patterns starting with `subdir` as a literal directory,
+/// and patterns that match a file name in any directory. For the latter the subdir
+/// must not contain hidden directories, because `**` does not match those.
+fn glob_in_subdir(pattern: &str, subdir: &std::path::Path) -> Option<String> {
+    if subdir.components().count() == 1 {
+        if let Some((dir, rest)) = glob_literal_dir(pattern) {
+            if std::path::Path::new(dir) == subdir {
+                return Some(rest.to_string());
+            }
+        }
+    }
+    let (head, name) = pattern.split_at(pattern.rfind('/')? + 1);
+    let hidden = subdir
+        .to_str()?
+        .split('/')
+        .any(|c| c.starts_with('.') || c.is_empty());
+    if head == "**/" && !name.contains("**") && !hidden {
+        return Some(pattern.to_string());
+    }
+    None
+}
+
+/// Whether the subdir or file that `b` starts with can never exist in the output of `a`.
+/// That is the case if everything `a` writes is below paths that are neither a parent
+/// nor a child of that path.
+fn contradicts(a: Filter, b: Filter) -> bool {
+    let first = match to_op(b) {
+        Op::Chain(x, _) => to_op(x),
+        op => op,
+    };
+    let path = match &first {
+        Op::Subdir(p) | Op::File(p) if p != std::path::Path::new("") => p,
+        _ => return false,
+    };
+    write_roots(a).is_some_and(|roots| {
+        roots
+            .iter()
+            .all(|r| !r.starts_with(path) && !path.starts_with(r))
+    })
+}
+
+/// Paths that a filter keeps in place together with everything below them, so its
+/// output contains every path of its input that is at or below one of them
+fn kept_roots(filter: Filter) -> Option<Vec<std::path::PathBuf>> {
+    match to_op(filter) {
+        Op::Nop => Some(vec![std::path::PathBuf::new()]),
+        Op::Chain(_, _) if dir_restriction_path(filter).is_some() => {
+            dir_restriction_path(filter).map(|p| vec![p])
+        }
+        // Earlier members of a compose take the input paths they read away from later
+        // ones, so only paths that no member before it reads are kept by a member
+        Op::Compose(filters) => {
+            let mut roots = vec![];
+            let mut read: Vec<std::path::PathBuf> = vec![];
+            for f in filters {
+                roots.extend(
+                    kept_roots(f)
+                        .into_iter()
+                        .flatten()
+                        .filter(|k| read.iter().all(|r| !r.starts_with(k) && !k.starts_with(r))),
+                );
+                match read_roots(f) {
+                    Some(r) => read.extend(r),
+                    None => break,
+                }
+            }
+            (!roots.is_empty()).then_some(roots)
+        }
+        _ => None,
+    }
+}
+
+/// Whether `member` is one of `filters` and no member before it reads any of the input
+/// of `member`, so composing `filters` produces every path that `member` produces
+fn produced_by_member(filters: &[Filter], member: Filter) -> bool {
+    let Some(i) = filters.iter().position(|f| *f == member) else {
+        return false;
+    };
+    let Some(roots) = read_roots(member) else {
+        return i == 0;
+    };
+    filters[..i].iter().all(|f| {
+        read_roots(*f).is_some_and(|read| {
+            read.iter()
+                .all(|r| roots.iter().all(|k| !r.starts_with(k) && !k.starts_with(r)))
+        })
+    })
+}
+
+/// Directories outside of which a filter produces no output, for filters that only
+/// produce paths of their input
+fn in_place_roots(filter: Filter) -> Option<Vec<std::path::PathBuf>> {
+    let root = || Some(vec![std::path::PathBuf::new()]);
+    match to_op(filter) {
+        Op::Empty => Some(vec![]),
+        Op::Nop | Op::Exclude(_) => root(),
+        Op::Glob(_) | Op::Select(_, _) | Op::Docs | Op::PathRegex(_) | Op::ContentGrep(_) => root(),
+        Op::BinaryOnly | Op::TextOnly | Op::LfsOnly => root(),
+        Op::File(path) => Some(vec![path]),
+        Op::Subtract(a, _) => in_place_roots(a),
+        Op::Compose(filters) => filters
+            .into_iter()
+            .map(in_place_roots)
+            .collect::<Option<Vec<_>>>()
+            .map(|roots| roots.concat()),
+        Op::Chain(a, b) => {
+            if let Some(dir) = dir_restriction_path(filter) {
+                return Some(vec![dir]);
+            }
+            if let (Some(ra), Some(rb)) = (in_place_roots(a), in_place_roots(b)) {
+                return Some(if ra == vec![std::path::PathBuf::new()] {
+                    rb
+                } else {
+                    ra
+                });
+            }
+            // A part that is moved into a subdir and back, like `:/src::*.rs:prefix=src`.
+            // Subtracting from the part only removes paths, so only what it starts
+            // with matters.
+            let mut parts = as_chain_vec(filter);
+            if let Op::Subtract(x, _) = to_op(parts[0]) {
+                parts.splice(..1, as_chain_vec(x));
+            }
+            let subdirs = parts
+                .iter()
+                .take_while(|f| matches!(to_op(**f), Op::Subdir(_)));
+            let n = subdirs.count();
+            let prefixes = parts[n..]
+                .iter()
+                .rev()
+                .take_while(|f| matches!(to_op(**f), Op::Prefix(_)))
+                .count();
+            if n == 0 || n + prefixes >= parts.len() {
+                return None;
+            }
+            let dir = subdir_path(chain_of(&parts[..n]))?;
+            let moved_back = prefix_path(chain_of(&parts[parts.len() - prefixes..]));
+            if moved_back.as_ref() != Some(&dir) {
+                return None;
+            }
+            let inner = in_place_roots(chain_of(&parts[n..parts.len() - prefixes]))?;
+            Some(inner.iter().map(|r| dir.join(r)).collect())
+        }
+        _ => None,
+    }
+}
+
+/// Whether `filter`, or the first part of it if it is a chain, removes everything
+/// inside of directory `dir`, like `:exclude[::a/]` or `:select[+**,-a/**]` do for `a/b`
+fn removes_dir(filter: Filter, dir: &std::path::Path) -> bool {
+    let first = match to_op(filter) {
+        Op::Chain(x, _) => x,
+        _ => filter,
+    };
+    let covers = |roots: Vec<std::path::PathBuf>| roots.iter().any(|r| dir.starts_with(r));
+    match to_op(first) {
+        Op::Subtract(x, y) if x == to_filter(Op::Nop) => {
+            subdir_path(y).is_some_and(|q| dir.starts_with(q))
+        }
+        Op::Exclude(x) => kept_roots(x).is_some_and(covers),
+        Op::Select(include, exclude) => {
+            // Patterns like `-a/**` don't match hidden files, so the included files
+            // must not be hidden either
+            let excluded = exclude
+                .iter()
+                .filter_map(|e| glob_confining_dir(e))
+                .any(|d| dir.starts_with(d));
+            let visible = include
+                .iter()
+                .all(|i| i.split('/').all(|c| !c.starts_with(['.', '['])));
+            excluded && visible
+        }
+        _ => false,
+    }
+}
+
+/// Remove all `:/` parts of a chain, keeping how the other parts are nested
+fn without_nop(filter: Filter) -> Filter {
+    match to_op(filter) {
+        Op::Chain(a, b) if a == to_filter(Op::Nop) => without_nop(b),
+        Op::Chain(a, b) if b == to_filter(Op::Nop) => without_nop(a),
+        Op::Chain(a, b) => {
+            let (x, y) = (without_nop(a), without_nop(b));
+            if (x, y) == (a, b) {
+                filter
+            } else {
+                to_filter(Op::Chain(x, y))
+            }
+        }
+        _ => filter,
+    }
+}
+
+/// Whether `filter` is `base` followed by filters that only keep some of the paths
+/// of their input in place, like `:/a::*.rs` compared to `:/a`
+fn restricts(filter: Filter, base: Filter) -> bool {
+    let parts = as_chain_vec(filter);
+    let base_parts = if base == to_filter(Op::Nop) {
+        vec![]
+    } else {
+        as_chain_vec(base)
+    };
+    parts.len() > base_parts.len()
+        && parts.starts_with(&base_parts)
+        && in_place_roots(chain_of(&parts[base_parts.len()..])).is_some()
+}
+
+/// Drop members of a compose that restrict an earlier member. Everything they
+/// produce comes from inputs the earlier member has taken already, so they don't
+/// contribute to the result.
+fn drop_restricted_members(filters: &[Filter]) -> Option<Vec<Filter>> {
+    let kept: Vec<_> = filters
+        .iter()
+        .enumerate()
+        .filter(|(i, f)| !filters[..*i].iter().any(|base| restricts(**f, *base)))
+        .map(|(_, f)| *f)
+        .collect();
+    (kept.len() != filters.len()).then_some(kept)
+}
+
+/// Directory kept by a filter like `::dir/`, which is a chain of subdirs followed
+/// by prefixes moving the result back to where it was
+fn dir_restriction_path(filter: Filter) -> Option<std::path::PathBuf> {
+    let parts = as_chain_vec(filter);
+    let n = parts
+        .iter()
+        .take_while(|f| matches!(to_op(**f), Op::Subdir(_)))
+        .count();
+    if n == 0 || n == parts.len() {
+        return None;
+    }
+    let dir = subdir_path(chain_of(&parts[..n]))?;
+    (prefix_path(chain_of(&parts[n..])) == Some(dir.clone())).then_some(dir)
+}
+
+fn chain_of(filters: &[Filter]) -> Filter {
+    filters
+        .iter()
+        .copied()
+        .reduce(|a, b| to_filter(Op::Chain(a, b)))
+        .unwrap_or_else(|| to_filter(Op::Nop))
+}
+
+/// Whether every path in the output of `a` is also in the output of `b`, so nothing
+/// is left of `a` after subtracting `b`. This is the case if `a` only selects paths of
+/// its input inside of directories that `b` keeps in place completely.
+fn is_subset(a: Filter, b: Filter) -> bool {
+    match (in_place_roots(a), kept_roots(b)) {
+        (Some(a), Some(b)) => a.iter().all(|r| b.iter().any(|k| r.starts_with(k))),
+        _ => false,
+    }
+}
+
+/// Directory `dir` of a pattern `dir/**` that selects everything below a literal directory
+fn glob_confining_dir(pattern: &str) -> Option<&std::path::Path> {
+    let dir = pattern.strip_suffix("/**")?;
+    let literal = dir
+        .split('/')
+        .all(|c| !c.is_empty() && c != "." && c != ".." && !c.contains(['*', '?', '[', ']']));
+    literal.then(|| std::path::Path::new(dir))
+}
+
+/// Single filter selecting the files matched by both of two directory confining globs.
+/// That is the glob of the inner directory, unless the directories are unrelated, in
+/// which case nothing matches both. If the inner directory is hidden relative to the
+/// outer one the outer glob doesn't match anything in it, so that case is left alone.
+fn glob_intersection(a: &str, b: &str) -> Option<Op> {
+    let (da, db) = (glob_confining_dir(a)?, glob_confining_dir(b)?);
+    let inner = |outer: &std::path::Path, inner: &std::path::Path, pattern: &str| {
+        let hidden = inner
+            .strip_prefix(outer)
+            .ok()?
+            .to_str()?
+            .split('/')
+            .any(|c| c.starts_with('.'));
+        (!hidden).then(|| Op::Glob(pattern.to_string()))
+    };
+    if db.starts_with(da) {
+        inner(da, db, b)
+    } else if da.starts_with(db) {
+        inner(db, da, a)
+    } else {
+        Some(Op::Empty)
+    }
+}
+
+/// Whether every file matched by glob `a` is also matched by glob `b`.
+/// Only the cases that are easy to prove are recognized: equal patterns, `**`, which
+/// matches every path without hidden components, and nested directories.
+fn glob_covers(b: &str, a: &str) -> bool {
+    let visible = a.split('/').all(|c| !c.starts_with(['.', '[']));
+    a == b
+        || (b == "**" && visible)
+        || matches!(glob_intersection(a, b), Some(Op::Glob(inner)) if inner == a)
+}
+
+/// Pattern of a glob, also after `step` has split off its literal directories
+fn glob_pattern(op: &Op) -> Option<String> {
+    if let Op::Glob(pattern) = op {
+        return Some(pattern.clone());
+    }
+    let parts: Vec<_> = as_chain_vec(to_filter(op.clone()))
+        .into_iter()
+        .map(to_op)
+        .collect();
+    let glob = parts.iter().position(|x| matches!(x, Op::Glob(_)))?;
+    let mut subdir = std::path::PathBuf::new();
+    for x in &parts[..glob] {
+        match x {
+            Op::Subdir(p) => subdir.push(p),
+            _ => return None,
+        }
+    }
+    let mut prefix = std::path::PathBuf::new();
+    for x in parts[glob + 1..].iter().rev() {
+        match x {
+            Op::Prefix(p) => prefix.push(p),
+            _ => return None,
+        }
+    }
+    match &parts[glob] {
+        Op::Glob(rest) if glob > 0 && subdir == prefix => {
+            Some(format!("{}/{}", subdir.to_str()?, rest))
+        }
+        _ => None,
+    }
+}
+
+/// Include and exclude patterns of a `Select` matching the same files as `op`, which
+/// also exist for globs and composes of globs
+fn select_patterns(op: &Op) -> Option<(Vec<String>, Vec<String>)> {
+    match op {
+        Op::Select(include, exclude) => Some((include.clone(), exclude.clone())),
+        Op::Compose(filters) if !filters.is_empty() => {
+            let include = filters
+                .iter()
+                .map(|f| glob_pattern(&to_op(*f)))
+                .collect::<Option<Vec<_>>>()?;
+            Some((include, vec![]))
+        }
+        _ => Some((vec![glob_pattern(op)?], vec![])),
+    }
+}
+
+/// Single matcher for the files matched by `a` but not by glob `b`, when `a` is made
+/// up of globs
+fn glob_exclusion(a: &Op, b: &Op) -> Option<Op> {
+    let (include, mut exclude) = select_patterns(a)?;
+    let b = glob_pattern(b)?;
+    if include.iter().all(|a| glob_covers(&b, a)) {
+        return Some(Op::Empty);
+    }
+    if !exclude.contains(&b) {
+        exclude.push(b);
+    }
+    Some(Op::Select(include, exclude))
+}
+
+/// Merge neighbouring members of a compose that are `Select`s with the same excluded
+/// patterns. Members are only merged with their neighbours, so no member can take a
+/// path from a filter listed between them.
+fn merge_selects(filters: &[Filter]) -> Option<Vec<Filter>> {
+    let mut merged: Vec<Filter> = vec![];
+    for f in filters {
+        if let (Some(last), Op::Select(include, exclude)) = (merged.last_mut(), to_op(*f)) {
+            if let Op::Select(mut prev, prev_exclude) = to_op(*last) {
+                if prev_exclude == exclude {
+                    prev.extend(
+                        include
+                            .into_iter()
+                            .filter(|p| !prev.contains(p))
+                            .collect::<Vec<_>>(),
+                    );
+                    *last = to_filter(Op::Select(prev, exclude));
+                    continue;
+                }
+            }
+        }
+        merged.push(*f);
+    }
+    (merged.len() != filters.len()).then_some(merged)
+}
+
+/// Names of the rules `step` rewrites filters with, as reported by `optimize_traced`.
+/// The names are stable, so tools can refer to them. `descend` is reported when rules were
+/// applied to the parts of a filter instead of the filter itself.
+pub fn rule_names() -> &'static [&'static str] {
+    RULES
+}
+
+const RULES: &[&str] = &[
+    "split-subdir",
+    "split-prefix",
+    "glob-literal-dir",
+    "empty-compose",
+    "single-member-compose",
+    "dedup-members",
+    "sort-members",
+    "restricted-member",
+    "common-exclusion",
+    "merge-selects",
+    "common-pre",
+    "common-post",
+    "common-subtract",
+    "common-trailing-subtract",
+    "group-members",
+    "cancel-restriction-subdir",
+    "restriction-in-compose",
+    "associate-right",
+    "distribute-prefix",
+    "cancel-prefix-subdir",
+    "shorten-prefix-subdir",
+    "contradicting-prefix-subdir",
+    "cancel-prefix-restriction",
+    "excluded-prefix",
+    "linear-linear",
+    "contradiction",
+    "glob-exclusion",
+    "glob-intersection",
+    "glob-into-subdir",
+    "drop-nop",
+    "empty-chain",
+    "exclude-nop",
+    "exclude-empty",
+    "subtract-self",
+    "empty-subtract",
+    "subtract-superset",
+    "subtract-disjoint",
+    "subtract-nop",
+    "subtract-empty",
+    "subtract-common-pre",
+    "subtract-strip-prefix",
+    "subtract-hoist-prefix",
+    "subtract-prefix",
+    "subtract-common-post",
+    "subtract-member",
+    "subtract-common-members",
+    "intersect-self",
+    "empty-intersect",
+    "intersect-nop",
+    "intersect-common-pre",
+    "descend",
+];
+
 /*
  * Attempt to apply one optimization rule to a filter. If no rule applies the input
  * is returned.
  */
 fn step(filter: Filter) -> Filter {
-    if let Some(f) = OPTIMIZED.lock().unwrap().get(&filter) {
-        return *f;
+    step_rule(filter).0
+}
+
+/// Like `step`, but also return the name of the rule that was applied, or `none`
+fn step_rule(filter: Filter) -> (Filter, &'static str) {
+    if let Some(f) = memo_get(|m| &mut m.stepped, &STEPPED, filter) {
+        return f;
     }
     rs_tracing::trace_scoped!("step", "spec": spec(filter));
     let original = filter;
-    let result = to_filter(match to_op(filter) {
-        Op::Subdir(path) => {
-            if path.components().count() > 1 {
-                let mut components = path.components();
-                let a = components.next().unwrap();
+    let (rule, result) = match to_op(filter) {
+        Op::Subdir(path) if path.components().count() > 1 => {
+            let mut components = path.components();
+            let a = components.next().unwrap();
+            (
+                "split-subdir",
                 Op::Chain(
                     to_filter(Op::Subdir(std::path::PathBuf::from(&a))),
                     to_filter(Op::Subdir(components.as_path().to_owned())),
-                )
-            } else {
-                Op::Subdir(path)
-            }
+                ),
+            )
         }
-        Op::Prefix(path) => {
-            if path.components().count() > 1 {
-                let mut components = path.components();
-                let a = components.next().unwrap();
+        Op::Prefix(path) if path.components().count() > 1 => {
+            let mut components = path.components();
+            let a = components.next().unwrap();
+            (
+                "split-prefix",
                 Op::Chain(
                     to_filter(Op::Prefix(components.as_path().to_owned())),
                     to_filter(Op::Prefix(std::path::PathBuf::from(&a))),
-                )
-            } else {
-                Op::Prefix(path)
-            }
+                ),
+            )
         }
-        Op::Rev(filters) => Op::Rev(filters.into_iter().map(|(i, f)| (i, step(f))).collect()),
-        Op::Compose(filters) if filters.is_empty() => Op::Empty,
-        Op::Compose(filters) if filters.len() == 1 => to_op(filters[0]),
-        Op::Compose(mut filters) => {
+        Op::Glob(pattern) => match glob_literal_dir(&pattern) {
+            // Only walk the literal directory instead of the whole tree.
+            // This can't become `::dir/` even for `dir/**`, because `**` does
+            // not match hidden files.
+            Some((dir, rest)) => (
+                "glob-literal-dir",
+                Op::Chain(
+                    to_filter(Op::Subdir(std::path::PathBuf::from(dir))),
+                    to_filter(Op::Chain(
+                        to_filter(Op::Glob(rest.to_string())),
+                        to_filter(Op::Prefix(std::path::PathBuf::from(dir))),
+                    )),
+                ),
+            ),
+            None => ("none", Op::Glob(pattern)),
+        },
+        Op::Rev(filters) => (
+            "descend",
+            Op::Rev(filters.into_iter().map(|(i, f)| (i, step(f))).collect()),
+        ),
+        Op::Compose(filters) if filters.is_empty() => ("empty-compose", Op::Empty),
+        Op::Compose(filters) if filters.len() == 1 => ("single-member-compose", to_op(filters[0])),
+        Op::Compose(filters)
+            if filters.windows(2).any(|w| w[0] == w[1])
+                || filters.contains(&to_filter(Op::Empty)) =>
+        {
+            let mut filters = filters;
             filters.dedup();
             filters.retain(|x| *x != to_filter(Op::Empty));
+            ("dedup-members", Op::Compose(filters))
+        }
+        // Sorted before anything else, so the other rules see the same members next to
+        // each other however the compose was written
+        Op::Compose(filters) if prefix_sort(&filters) != filters => {
+            ("sort-members", Op::Compose(prefix_sort(&filters)))
+        }
+        Op::Compose(filters) => {
             let mut grouped = group(&filters);
-            if let Some((common, rest)) = common_pre(&filters) {
-                Op::Chain(common, to_filter(Op::Compose(rest)))
+            let without_nops: Vec<_> = filters.iter().map(|f| without_nop(*f)).collect();
+            if without_nops != filters {
+                // Done before anything else, so rules that restructure the members
+                // don't leave chains with `:/` behind for the next round
+                ("drop-nop", Op::Compose(without_nops))
+            } else if let Some(kept) = drop_restricted_members(&filters) {
+                ("restricted-member", Op::Compose(kept))
+            } else if let Some(op) = common_exclusion(&filters) {
+                ("common-exclusion", op)
+            } else if let Some(merged) = merge_selects(&filters) {
+                ("merge-selects", Op::Compose(merged))
+            } else if let Some((common, rest)) = common_pre(&filters) {
+                (
+                    "common-pre",
+                    Op::Chain(common, to_filter(Op::Compose(rest))),
+                )
             } else if let Some((common, rest)) = common_post(&filters) {
-                Op::Chain(to_filter(Op::Compose(rest)), common)
+                (
+                    "common-post",
+                    Op::Chain(to_filter(Op::Compose(rest)), common),
+                )
+            } else if let Some((common, rest)) = common_subtract(&filters) {
+                (
+                    "common-subtract",
+                    Op::Subtract(to_filter(Op::Compose(rest)), common),
+                )
+            } else if let Some((common, rest)) = common_trailing_subtract(&filters) {
+                (
+                    "common-trailing-subtract",
+                    Op::Chain(to_filter(Op::Compose(rest)), common),
+                )
             } else if grouped.len() != 1 && grouped.len() != filters.len() {
-                Op::Compose(
-                    grouped
-                        .drain(..)
-                        .map(|x| to_filter(Op::Compose(x)))
-                        .collect(),
+                (
+                    "group-members",
+                    Op::Compose(
+                        grouped
+                            .drain(..)
+                            .map(|x| to_filter(Op::Compose(x)))
+                            .collect(),
+                    ),
                 )
             } else {
-                let mut filters = prefix_sort(&filters);
-                Op::Compose(filters.drain(..).map(step).collect())
+                (
+                    "descend",
+                    Op::Compose(filters.into_iter().map(step).collect()),
+                )
             }
         }
         Op::Chain(a, b) => match (to_op(a), to_op(b)) {
-            (Op::Chain(x, y), b) => Op::Chain(x, to_filter(Op::Chain(y, to_filter(b)))),
-            (Op::Prefix(a), Op::Subdir(b)) if a == b => Op::Nop,
-            (Op::Prefix(a), Op::Subdir(b))
-                if a != b && a.components().count() == b.components().count() =>
+            (Op::Chain(x, y), Op::Subdir(p)) if y == to_filter(Op::Prefix(p.clone())) => {
+                ("cancel-restriction-subdir", to_op(x))
+            }
+            (Op::Chain(_, _), Op::Compose(filters))
+                if is_dir_restriction(a) && filters.iter().any(|f| starts_with(*f, a)) =>
             {
-                Op::Empty
+                (
+                    "restriction-in-compose",
+                    Op::Chain(
+                        a,
+                        to_filter(Op::Compose(
+                            filters
+                                .into_iter()
+                                .map(|f| match to_op(f) {
+                                    _ if f == a => to_filter(Op::Nop),
+                                    Op::Chain(x, y) if x == a => y,
+                                    _ => f,
+                                })
+                                .collect(),
+                        )),
+                    ),
+                )
+            }
+            (Op::Chain(x, y), b) => (
+                "associate-right",
+                Op::Chain(x, to_filter(Op::Chain(y, to_filter(b)))),
+            ),
+            (Op::Prefix(_), Op::Compose(filters)) if distribute(a, &filters, true).is_some() => {
+                ("distribute-prefix", distribute(a, &filters, true).unwrap())
             }
-            (Op::Prefix(a), Op::Subdir(b)) if a != b => {
-                Op::Prefix(a.strip_prefix(&b).unwrap_or(&a).to_owned())
+            (Op::Compose(filters), Op::Prefix(_)) if distribute(b, &filters, false).is_some() => {
+                ("distribute-prefix", distribute(b, &filters, false).unwrap())
             }
-            (Op::Nop, b) => b,
-            (a, Op::Nop) => a,
-            (Op::Empty, _) => Op::Empty,
-            (_, Op::Empty) => Op::Empty,
-            (a, b) => Op::Chain(step(to_filter(a)), step(to_filter(b))),
+            // Moving everything into `a` and then selecting `b` only keeps something if one
+            // of the paths contains the other. The shared part of the paths cancels out.
+            (Op::Prefix(a), Op::Subdir(b)) if a == b => ("cancel-prefix-subdir", Op::Nop),
+            (Op::Prefix(a), Op::Subdir(b)) if a.starts_with(&b) => (
+                "shorten-prefix-subdir",
+                Op::Prefix(a.strip_prefix(&b).unwrap().to_owned()),
+            ),
+            (Op::Prefix(a), Op::Subdir(b)) if b.starts_with(&a) => (
+                "shorten-prefix-subdir",
+                Op::Subdir(b.strip_prefix(&a).unwrap().to_owned()),
+            ),
+            (Op::Prefix(_), Op::Subdir(_)) => ("contradicting-prefix-subdir", Op::Empty),
+            (Op::Prefix(p), Op::Chain(x, y)) if subdir_path(x).as_ref() == Some(&p) => {
+                ("cancel-prefix-restriction", to_op(y))
+            }
+            // Everything is moved below `p`, so removing `p` leaves nothing
+            (Op::Prefix(p), _) if removes_dir(b, &p) => ("excluded-prefix", Op::Empty),
+            // Linearising a linear history does not change it
+            (Op::Linear, Op::Linear) => ("linear-linear", Op::Linear),
+            (Op::Linear, Op::Chain(x, _)) if x == a => ("linear-linear", to_op(b)),
+            _ if contradicts(a, b) => ("contradiction", Op::Empty),
+            (x, Op::Exclude(y)) if glob_exclusion(&x, &to_op(y)).is_some() => {
+                ("glob-exclusion", glob_exclusion(&x, &to_op(y)).unwrap())
+            }
+            (Op::Glob(x), Op::Glob(y)) if glob_intersection(&x, &y).is_some() => {
+                ("glob-intersection", glob_intersection(&x, &y).unwrap())
+            }
+            (Op::Glob(x), Op::Chain(y, z)) if matches!(to_op(y), Op::Glob(y) if glob_intersection(&x, &y).is_some()) => {
+                (
+                    "glob-intersection",
+                    Op::Chain(step(to_filter(Op::Chain(a, y))), z),
+                )
+            }
+            (Op::Glob(g), Op::Subdir(s)) if glob_in_subdir(&g, &s).is_some() => (
+                "glob-into-subdir",
+                Op::Chain(b, to_filter(Op::Glob(glob_in_subdir(&g, &s).unwrap()))),
+            ),
+            (Op::Glob(g), Op::Chain(x, y))
+                if subdir_path(x).is_some_and(|s| glob_in_subdir(&g, &s).is_some()) =>
+            {
+                let g = glob_in_subdir(&g, &subdir_path(x).unwrap()).unwrap();
+                (
+                    "glob-into-subdir",
+                    Op::Chain(x, to_filter(Op::Chain(to_filter(Op::Glob(g)), y))),
+                )
+            }
+            (Op::Nop, b) => ("drop-nop", b),
+            (a, Op::Nop) => ("drop-nop", a),
+            (Op::Empty, _) => ("empty-chain", Op::Empty),
+            (_, Op::Empty) => ("empty-chain", Op::Empty),
+            (a, b) => ("descend", Op::Chain(step(to_filter(a)), step(to_filter(b)))),
         },
-        Op::Exclude(b) if b == to_filter(Op::Nop) => Op::Empty,
-        Op::Exclude(b) if b == to_filter(Op::Empty) => Op::Nop,
-        Op::Exclude(b) => Op::Exclude(step(b)),
-        Op::Subtract(a, b) if a == b => Op::Empty,
+        Op::Exclude(b) if b == to_filter(Op::Nop) => ("exclude-nop", Op::Empty),
+        Op::Exclude(b) if b == to_filter(Op::Empty) => ("exclude-empty", Op::Nop),
+        Op::Exclude(b) => ("descend", Op::Exclude(step(b))),
+        Op::IfExists(path, a, b) => ("descend", Op::IfExists(path, step(a), step(b))),
+        Op::Subtract(a, b) if a == b => ("subtract-self", Op::Empty),
         Op::Subtract(af, bf) => match (to_op(af), to_op(bf)) {
-            (Op::Empty, _) => Op::Empty,
-            (_, Op::Nop) => Op::Empty,
-            (a, Op::Empty) => a,
-            (Op::Chain(a, b), Op::Chain(c, d)) if a == c => {
-                Op::Chain(a, to_filter(Op::Subtract(b, d)))
-            }
-            (_, b) if prefix_of(b.clone()) != to_filter(Op::Nop) => {
-                Op::Subtract(af, last_chain(to_filter(Op::Nop), to_filter(b)).0)
+            (Op::Empty, _) => ("empty-subtract", Op::Empty),
+            // Walk the tree once instead of once for each side
+            (a, b) if glob_exclusion(&a, &b).is_some() => {
+                ("glob-exclusion", glob_exclusion(&a, &b).unwrap())
             }
-            (a, _) if prefix_of(a.clone()) != to_filter(Op::Nop) => Op::Chain(
-                to_filter(Op::Subtract(
-                    last_chain(to_filter(Op::Nop), to_filter(a.clone())).0,
-                    bf,
-                )),
-                prefix_of(a),
+            _ if is_subset(af, bf) => ("subtract-superset", Op::Empty),
+            // Nothing of `a` is seen by `b`
+            _ if disjoint(af, bf) => ("subtract-disjoint", to_op(af)),
+            (_, Op::Nop) => ("subtract-nop", Op::Empty),
+            (a, Op::Empty) => ("subtract-empty", a),
+            // Empty composes are only reduced to `Op::Empty` when they are stepped themselves,
+            // which would take an extra iteration.
+            (Op::Compose(av), _) if av.is_empty() => ("empty-subtract", Op::Empty),
+            (a, Op::Compose(bv)) if bv.is_empty() => ("subtract-empty", a),
+            (Op::Chain(a, b), Op::Chain(c, d)) if a == c => (
+                "subtract-common-pre",
+                Op::Chain(a, to_filter(Op::Subtract(b, d))),
             ),
-            (_, b) if is_prefix(b.clone()) => Op::Subtract(af, to_filter(Op::Nop)),
-            _ if common_post(&vec![af, bf]).is_some() => {
-                let (cp, rest) = common_post(&vec![af, bf]).unwrap();
-                Op::Chain(to_filter(Op::Subtract(rest[0], rest[1])), cp)
+            (_, b) if prefix_of(b.clone()) != to_filter(Op::Nop) => (
+                "subtract-strip-prefix",
+                Op::Subtract(af, last_chain(to_filter(Op::Nop), to_filter(b)).0),
+            ),
+            (a, _) if prefix_of(a.clone()) != to_filter(Op::Nop) => (
+                "subtract-hoist-prefix",
+                Op::Chain(
+                    to_filter(Op::Subtract(
+                        last_chain(to_filter(Op::Nop), to_filter(a.clone())).0,
+                        bf,
+                    )),
+                    prefix_of(a),
+                ),
+            ),
+            (_, b) if is_prefix(b.clone()) => {
+                ("subtract-prefix", Op::Subtract(af, to_filter(Op::Nop)))
+            }
+            _ if common_post(&[af, bf]).is_some() => {
+                let (cp, rest) = common_post(&[af, bf]).unwrap();
+                (
+                    "subtract-common-post",
+                    Op::Chain(to_filter(Op::Subtract(rest[0], rest[1])), cp),
+                )
             }
-            (Op::Compose(mut av), _) if av.contains(&bf) => {
+            // Only valid if the other members don't see or produce any of the paths of `bf`
+            (Op::Compose(mut av), _)
+                if av.contains(&bf) && av.iter().all(|x| *x == bf || disjoint(*x, bf)) =>
+            {
                 av.retain(|x| *x != bf);
-                to_op(step(to_filter(Op::Compose(av))))
+                ("subtract-member", to_op(step(to_filter(Op::Compose(av)))))
             }
-            (_, Op::Compose(bv)) if bv.contains(&af) => to_op(step(to_filter(Op::Empty))),
+            (_, Op::Compose(bv)) if produced_by_member(&bv, af) => {
+                ("subtract-superset", to_op(step(to_filter(Op::Empty))))
+            }
+            // Members on both sides can only be dropped if none of the remaining
+            // members on the left see or produce any of their paths
             (Op::Compose(mut av), Op::Compose(mut bv)) => {
-                let v = av.clone();
-                av.retain(|x| !bv.contains(x));
-                bv.retain(|x| !v.contains(x));
+                let common = av
+                    .iter()
+                    .filter(|x| bv.contains(x) && av.iter().all(|y| y == *x || disjoint(*y, **x)))
+                    .copied()
+                    .collect::<Vec<_>>();
+                av.retain(|x| !common.contains(x));
+                bv.retain(|x| !common.contains(x));
 
-                Op::Subtract(
-                    step(to_filter(Op::Compose(av))),
-                    step(to_filter(Op::Compose(bv))),
+                (
+                    "subtract-common-members",
+                    Op::Subtract(
+                        step(to_filter(Op::Compose(av))),
+                        step(to_filter(Op::Compose(bv))),
+                    ),
                 )
             }
-            (a, b) => Op::Subtract(step(to_filter(a)), step(to_filter(b))),
+            (a, b) => (
+                "descend",
+                Op::Subtract(step(to_filter(a)), step(to_filter(b))),
+            ),
         },
-        _ => to_op(filter),
-    });
+        Op::Intersect(a, b) if a == b => ("intersect-self", to_op(a)),
+        Op::Intersect(af, bf) => match (to_op(af), to_op(bf)) {
+            (Op::Empty, _) | (_, Op::Empty) => ("empty-intersect", Op::Empty),
+            (Op::Compose(av), _) | (_, Op::Compose(av)) if av.is_empty() => {
+                ("empty-intersect", Op::Empty)
+            }
+            (a, Op::Nop) => ("intersect-nop", a),
+            (Op::Chain(a, b), Op::Chain(c, d)) if a == c => (
+                "intersect-common-pre",
+                Op::Chain(a, to_filter(Op::Intersect(b, d))),
+            ),
+            (a, b) => (
+                "descend",
+                Op::Intersect(step(to_filter(a)), step(to_filter(b))),
+            ),
+        },
+        _ => ("none", to_op(filter)),
+    };
+    let result = to_filter(result);
+    let rule = if result == original { "none" } else { rule };
 
-    OPTIMIZED.lock().unwrap().insert(original, result);
-    result
+    memo_put(|m| &mut m.stepped, &STEPPED, original, (result, rule));
+    (result, rule)
 }
 
 pub fn invert(filter: Filter) -> JoshResult<Filter> {
     let result = match to_op(filter) {
         Op::Nop => Some(Op::Nop),
         Op::Linear => Some(Op::Nop),
+        Op::Take(_) => Some(Op::Nop),
+        Op::KeepVersions(_) => Some(Op::Nop),
         Op::Unsign => Some(Op::Unsign),
         Op::Empty => Some(Op::Empty),
         Op::Subdir(path) => Some(Op::Prefix(path)),
         Op::File(path) => Some(Op::File(path)),
         Op::Prefix(path) => Some(Op::Subdir(path)),
         Op::Glob(pattern) => Some(Op::Glob(pattern)),
+        Op::PathRegex(regex) => Some(Op::PathRegex(regex)),
+        Op::ContentGrep(regex) => Some(Op::ContentGrep(regex)),
+        Op::BinaryOnly => Some(Op::BinaryOnly),
+        Op::TextOnly => Some(Op::TextOnly),
+        Op::LfsOnly => Some(Op::LfsOnly),
+        Op::TouchedBy(id) => Some(Op::TouchedBy(id)),
+        Op::DiffRange(from, to) => Some(Op::DiffRange(from, to)),
+        Op::AddedSince(base) => Some(Op::AddedSince(base)),
+        Op::IntersectRefs(ids, content) => Some(Op::IntersectRefs(ids, content)),
+        Op::Select(include, exclude) => Some(Op::Select(include, exclude)),
+        Op::Docs => Some(Op::Docs),
         Op::Rev(_) => Some(Op::Nop),
         _ => None,
     };
@@ -458,6 +1722,17 @@ pub fn invert(filter: Filter) -> JoshResult<Filter> {
                 .collect::<JoshResult<Vec<_>>>()?,
         ),
         Op::Exclude(filter) => Op::Exclude(invert(filter)?),
+        // Map back and keep only what `b` selects from the result
+        Op::Intersect(a, b) => {
+            if invert(b).is_err() {
+                return Err(josh_error(&format!(
+                    "no invert: {} is not invertible, so {} can't be inverted",
+                    spec(b),
+                    spec(filter)
+                )));
+            }
+            Op::Chain(invert(a)?, to_filter(Op::Intersect(to_filter(Op::Nop), b)))
+        }
         _ => return Err(josh_error("no invert")),
     });
 
@@ -466,3 +1741,893 @@ pub fn invert(filter: Filter) -> JoshResult<Filter> {
     INVERTED.lock().unwrap().insert(original, result);
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_sort_test() {
+        let members = |specs: &[&str]| specs.iter().map(|s| parse(s).unwrap()).collect::<Vec<_>>();
+
+        // The glob reads and writes everything, so only members that keep paths where
+        // they are can be moved past it
+        let sorted = prefix_sort(&members(&["::c/", "p = :/x", "::a/", "::**/*.md", "::b/"]));
+        assert_eq!(
+            sorted,
+            members(&["::a/", "::b/", "::c/", "p = :/x", "::**/*.md"])
+        );
+        assert_eq!(
+            prefix_sort(&members(&["::c/", "::**/*.md", "::b/", "::a/"])),
+            members(&["::**/*.md", "::a/", "::b/", "::c/"])
+        );
+
+        // Members writing to overlapping paths keep their order
+        assert_eq!(
+            prefix_sort(&members(&["x/y = :/b", "::c/", "x = :/a"])),
+            members(&["x/y = :/b", "x = :/a", "::c/"])
+        );
+
+        assert_eq!(
+            parse(":[::c/,p = :/x,::a/,::b/,::d]").unwrap(),
+            parse(":[::b/,::d,::a/,p = :/x,::c/]").unwrap()
+        );
+    }
+
+    #[test]
+    fn reordered_workspace_test() {
+        let workspace = |members: &[&str]| parse(&format!(":[{}]", members.join(","))).unwrap();
+        let canonical = workspace(&[
+            "::**/*.md",
+            "::docs/",
+            "::README.md",
+            "lib = :/libs/core",
+            "tools = :/tools:exclude[::*.bin]",
+            "vendor/x = :/third_party/x",
+            "lib/extra = :/libs/extra",
+        ]);
+
+        assert_eq!(
+            workspace(&[
+                "::README.md",
+                "::**/*.md",
+                "vendor/x = :/third_party/x",
+                "::docs/",
+                "tools = :/tools:exclude[::*.bin]",
+                "lib = :/libs/core",
+                "lib/extra = :/libs/extra",
+            ]),
+            canonical
+        );
+
+        // Both write to `lib/extra`, so their order matters
+        assert_ne!(
+            workspace(&[
+                "::**/*.md",
+                "::docs/",
+                "::README.md",
+                "lib/extra = :/libs/extra",
+                "lib = :/libs/core",
+                "tools = :/tools:exclude[::*.bin]",
+                "vendor/x = :/third_party/x",
+            ]),
+            canonical
+        );
+    }
+
+    #[test]
+    fn single_member_compose_test() {
+        let nop = to_filter(Op::Nop);
+        let empty = to_filter(Op::Empty);
+
+        assert_eq!(step(to_filter(Op::Compose(vec![nop]))), nop);
+        assert_eq!(step(to_filter(Op::Compose(vec![empty]))), empty);
+        assert_eq!(optimize(to_filter(Op::Compose(vec![nop]))), nop);
+        assert_eq!(optimize(to_filter(Op::Compose(vec![empty]))), empty);
+        assert_eq!(optimize(to_filter(Op::Compose(vec![nop, empty]))), nop);
+        assert_eq!(optimize(to_filter(Op::Compose(vec![empty, empty]))), empty);
+    }
+
+    #[test]
+    fn distribute_prefix_test() {
+        // Pushing the prefix into the compose lets it cancel with the subdirs
+        assert_eq!(
+            spec(parse(":prefix=p:[:/p/x,:/p/y:prefix=out,::*.rs]").unwrap()),
+            ":[:/x,:/y:prefix=out,:prefix=p::*.rs]"
+        );
+
+        // Nothing cancels, so the prefix stays outside of the compose
+        assert_eq!(
+            spec(parse(":[::a,::b]:prefix=p").unwrap()),
+            ":[::a,::b]:prefix=p"
+        );
+        assert_eq!(
+            spec(parse(":prefix=p:[::*.a,::*.b]").unwrap()),
+            ":prefix=p:[::*.a,::*.b]"
+        );
+        // `::a` only adds paths that `:/` already has
+        assert_eq!(spec(parse(":[::a,:/]:prefix=p").unwrap()), ":prefix=p");
+    }
+
+    /// Held by tests that look at the contents of the shared caches or clear or shrink
+    /// them, so they don't remove entries while another one looks at them
+    static SHARED_CACHES: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn local_memo_test() {
+        let _shared = SHARED_CACHES.lock().unwrap_or_else(|e| e.into_inner());
+        let wide = |name: &str| {
+            let members = (0..40)
+                .map(|i| {
+                    parse(&format!(
+                        ":/{}/m{}:[:/src:prefix=src,::docs/,::{}.toml]:prefix=m{}",
+                        name,
+                        i % 8,
+                        name,
+                        i
+                    ))
+                    .unwrap()
+                })
+                .collect();
+            to_filter(Op::Compose(members))
+        };
+
+        // Different names for each run, so neither profits from the caches of the other
+        let (shared, _) = optimize_rounds(wide("shared"), MAX_ROUNDS);
+        let (local, stepped, simplified) = with_local_memo(|| {
+            let (local, _) = optimize_rounds(wide("local"), MAX_ROUNDS);
+            MEMO.with(|memo| {
+                let memo = memo.borrow();
+                let memo = memo.as_ref().unwrap();
+                (local, memo.stepped.clone(), memo.simplified.clone())
+            })
+        });
+
+        assert_eq!(spec(shared).replace("shared", "local"), spec(local));
+        assert!(MEMO.with(|memo| memo.borrow().is_none()));
+
+        assert!(!stepped.is_empty());
+        assert!(!simplified.is_empty());
+        let mut shared_stepped = STEPPED.lock().unwrap();
+        for (filter, result) in stepped {
+            assert_eq!(shared_stepped.get(&filter), Some(&result));
+        }
+        drop(shared_stepped);
+        let mut shared_simplified = SIMPLIFIED.lock().unwrap();
+        for (filter, result) in simplified {
+            assert_eq!(shared_simplified.get(&filter), Some(&result));
+        }
+    }
+
+    #[test]
+    fn opt_cache_capacity_test() {
+        let _shared = SHARED_CACHES.lock().unwrap_or_else(|e| e.into_inner());
+        set_opt_cache_capacity(64);
+        let filters: Vec<_> = (0..500)
+            .map(|i| {
+                to_filter(Op::Chain(
+                    parse(&format!(":/a{}", i)).unwrap(),
+                    parse(&format!(":[:/b,:/c{}]", i)).unwrap(),
+                ))
+            })
+            .collect();
+        let first: Vec<_> = filters.iter().map(|f| optimize(*f)).collect();
+
+        assert!(OPTIMIZED.lock().unwrap().len() <= 64);
+        assert!(STEPPED.lock().unwrap().len() <= 64);
+        assert!(SIMPLIFIED.lock().unwrap().len() <= 64);
+        for f in &filters {
+            spec(*f);
+        }
+        assert!(SPEC_CACHE.lock().unwrap().len() <= 64);
+
+        let second: Vec<_> = filters.iter().map(|f| optimize(*f)).collect();
+        assert_eq!(first, second);
+
+        set_opt_cache_capacity(DEFAULT_OPT_CACHE_CAPACITY);
+    }
+
+    #[test]
+    fn subtract_empty_compose_test() {
+        let a = parse(":/a").unwrap();
+        let empty_compose = to_filter(Op::Compose(vec![]));
+
+        assert_eq!(step(to_filter(Op::Subtract(a, empty_compose))), a);
+        assert_eq!(
+            step(to_filter(Op::Subtract(empty_compose, a))),
+            to_filter(Op::Empty)
+        );
+        assert_eq!(optimize(to_filter(Op::Subtract(a, empty_compose))), a);
+    }
+
+    #[test]
+    fn group_canonical_order_test() {
+        let chain = |a: Op, b: Op| to_filter(Op::Chain(to_filter(a), to_filter(b)));
+        let subdir = |p: &str| Op::Subdir(std::path::PathBuf::from(p));
+        let prefix = |p: &str| Op::Prefix(std::path::PathBuf::from(p));
+
+        let ax = chain(
+            subdir("a"),
+            Op::Chain(to_filter(subdir("x")), to_filter(prefix("p1"))),
+        );
+        let b = chain(subdir("b"), prefix("p2"));
+        let ay = chain(
+            subdir("a"),
+            Op::Chain(to_filter(subdir("y")), to_filter(prefix("p3"))),
+        );
+
+        // The members sharing ":/a" are not adjacent, but sorting them puts them next to
+        // each other so they get grouped
+        assert_eq!(
+            step(to_filter(Op::Compose(vec![ax, b, ay]))),
+            to_filter(Op::Compose(vec![ax, ay, b]))
+        );
+        assert_eq!(
+            step(to_filter(Op::Compose(vec![ax, ay, b]))),
+            to_filter(Op::Compose(vec![
+                to_filter(Op::Compose(vec![ax, ay])),
+                to_filter(Op::Compose(vec![b])),
+            ]))
+        );
+        assert_eq!(
+            step(to_filter(Op::Compose(vec![ax, ay]))),
+            chain(
+                subdir("a"),
+                Op::Compose(vec![
+                    chain(subdir("x"), prefix("p1")),
+                    chain(subdir("y"), prefix("p3")),
+                ])
+            )
+        );
+    }
+
+    #[test]
+    fn prefix_subdir_rename_test() {
+        let s = parse(":/a/b").unwrap();
+        let p = std::path::PathBuf::from("c");
+        let renamed = to_filter(Op::Chain(s, to_filter(Op::Prefix(p.clone()))));
+        let f = to_filter(Op::Chain(renamed, to_filter(Op::Subdir(p))));
+
+        assert_eq!(step(f), s);
+        assert_eq!(optimize(f), optimize(s));
+    }
+
+    #[test]
+    fn subdir_rewrap_test() {
+        for x in ["a", "a/b/c"] {
+            let path = std::path::PathBuf::from(x);
+            let subdir = to_filter(Op::Subdir(path.clone()));
+            let prefix = to_filter(Op::Prefix(path));
+            let split = parse(&format!(":/{}", x.replace('/', ":/"))).unwrap();
+
+            let rewrapped = to_filter(Op::Chain(to_filter(Op::Chain(subdir, prefix)), subdir));
+            assert_eq!(step(rewrapped), subdir);
+
+            for f in [
+                rewrapped,
+                to_filter(Op::Chain(subdir, to_filter(Op::Chain(prefix, subdir)))),
+                to_filter(Op::Chain(to_filter(Op::Chain(split, prefix)), subdir)),
+            ] {
+                assert_eq!(spec(optimize(f)), spec(subdir), "{}", spec(f));
+            }
+        }
+    }
+
+    #[test]
+    fn subtract_compose_member_test() {
+        let file = |p: &str| to_filter(Op::File(std::path::PathBuf::from(p)));
+        let subtract =
+            |av: Vec<Filter>, b: Filter| to_filter(Op::Subtract(to_filter(Op::Compose(av)), b));
+
+        let f = subtract(vec![file("a"), file("b"), file("c")], file("a"));
+        assert_eq!(
+            step(f),
+            step(to_filter(Op::Compose(vec![file("b"), file("c")])))
+        );
+
+        // `*.rs` also matches `a.rs`, so dropping the member would keep `a.rs`
+        let glob = to_filter(Op::Glob("*.rs".to_string()));
+        let f = subtract(vec![file("a.rs"), glob], file("a.rs"));
+        assert!(matches!(to_op(step(f)), Op::Subtract(_, _)));
+    }
+
+    #[test]
+    fn prefix_subdir_residual_test() {
+        let path = |p: &str| std::path::PathBuf::from(p);
+        let chain = |p: &str, s: &str| {
+            step(to_filter(Op::Chain(
+                to_filter(Op::Prefix(path(p))),
+                to_filter(Op::Subdir(path(s))),
+            )))
+        };
+
+        assert_eq!(chain("a/b", "a/b"), to_filter(Op::Nop));
+        assert_eq!(chain("a/b/c", "a"), to_filter(Op::Prefix(path("b/c"))));
+        assert_eq!(chain("a", "a/b/c"), to_filter(Op::Subdir(path("b/c"))));
+        assert_eq!(chain("a/b", "a/c"), to_filter(Op::Empty));
+        assert_eq!(chain("a/b", "a/c/d"), to_filter(Op::Empty));
+        assert_eq!(chain("x", "y/z"), to_filter(Op::Empty));
+        assert_eq!(chain("ab", "a"), to_filter(Op::Empty));
+    }
+
+    #[test]
+    fn nop_chain_in_compose_test() {
+        let nop = to_filter(Op::Nop);
+        let subdir = |p: &str| to_filter(Op::Subdir(p.into()));
+        let chain = |a, b| to_filter(Op::Chain(a, b));
+
+        assert_eq!(
+            step_rule(to_filter(Op::Compose(vec![
+                chain(subdir("a"), nop),
+                subdir("b")
+            ]))),
+            (
+                to_filter(Op::Compose(vec![subdir("a"), subdir("b")])),
+                "drop-nop"
+            )
+        );
+
+        // Without removing the `:/` first, factoring out the common `:/a` would leave
+        // `:/:/b` as a member
+        let f = to_filter(Op::Compose(vec![
+            chain(subdir("a"), chain(nop, subdir("b"))),
+            chain(subdir("a"), subdir("c")),
+        ]));
+        assert_eq!(
+            step(f),
+            to_filter(Op::Compose(vec![
+                chain(subdir("a"), subdir("b")),
+                chain(subdir("a"), subdir("c")),
+            ]))
+        );
+    }
+
+    #[test]
+    fn excluded_prefix_test() {
+        let prefix = |p: &str| to_filter(Op::Prefix(p.into()));
+        let select = |include: &[&str], exclude: &[&str]| {
+            to_filter(Op::Select(
+                include.iter().map(|p| p.to_string()).collect(),
+                exclude.iter().map(|p| p.to_string()).collect(),
+            ))
+        };
+        let chain = |a, b| to_filter(Op::Chain(a, b));
+        let empty = to_filter(Op::Empty);
+
+        for (p, then) in [
+            ("p", select(&["**"], &["p/**"])),
+            ("p/q", select(&["**"], &["p/**"])),
+            ("p", select(&["*.rs", "**/*.md"], &["x/**", "p/**"])),
+            ("p", chain(select(&["**"], &["p/**"]), prefix("x"))),
+            ("p", to_filter(Op::Exclude(parse("::p/").unwrap()))),
+            ("p/q", to_filter(Op::Exclude(to_filter(Op::Nop)))),
+        ] {
+            let f = chain(prefix(p), then);
+            assert_eq!(step_rule(f), (empty, "excluded-prefix"), "{}", spec(f));
+        }
+
+        for (p, then) in [
+            // Hidden files are not matched by `p/**`
+            ("p", select(&["**", "**/.*"], &["p/**"])),
+            ("p", select(&["**"], &["p/q/**"])),
+            ("p", to_filter(Op::Exclude(parse("::p/q/").unwrap()))),
+        ] {
+            let f = chain(prefix(p), then);
+            assert_ne!(step_rule(f).1, "excluded-prefix", "{}", spec(f));
+        }
+    }
+
+    #[test]
+    fn restricted_member_test() {
+        let a = parse(":/a").unwrap();
+        let b = parse(":/b").unwrap();
+        let restricted = to_filter(Op::Chain(
+            a,
+            to_filter(Op::Chain(
+                to_filter(Op::Glob("*.rs".to_string())),
+                to_filter(Op::Subtract(
+                    to_filter(Op::Nop),
+                    to_filter(Op::File("x.rs".into())),
+                )),
+            )),
+        ));
+        assert_eq!(
+            step_rule(to_filter(Op::Compose(vec![a, b, restricted]))),
+            (to_filter(Op::Compose(vec![a, b])), "restricted-member")
+        );
+
+        // Everything in place is a restriction of the input itself
+        let glob = to_filter(Op::Glob("*.md".to_string()));
+        assert_eq!(
+            step_rule(to_filter(Op::Compose(vec![to_filter(Op::Nop), glob]))),
+            (
+                to_filter(Op::Compose(vec![to_filter(Op::Nop)])),
+                "restricted-member"
+            )
+        );
+
+        // A restriction before the member it restricts takes inputs away from the
+        // members between them, so it is kept
+        assert_ne!(
+            step_rule(to_filter(Op::Compose(vec![restricted, b, a]))).1,
+            "restricted-member"
+        );
+
+        // Moving files is not a restriction
+        let moved = to_filter(Op::Chain(a, parse(":/c").unwrap()));
+        assert_ne!(
+            step_rule(to_filter(Op::Compose(vec![a, moved]))).1,
+            "restricted-member"
+        );
+    }
+
+    #[test]
+    fn redundant_dir_restriction_test() {
+        let a = parse("::a/").unwrap();
+        let x = parse("::a/b/").unwrap();
+        let y = parse("::c").unwrap();
+        let f = to_filter(Op::Chain(
+            a,
+            to_filter(Op::Compose(vec![
+                to_filter(Op::Chain(a, x)),
+                to_filter(Op::Chain(a, y)),
+                a,
+            ])),
+        ));
+
+        assert_eq!(
+            step(f),
+            to_filter(Op::Chain(
+                a,
+                to_filter(Op::Compose(vec![x, y, to_filter(Op::Nop)]))
+            ))
+        );
+    }
+
+    #[test]
+    fn prefix_subdir_prefix_test() {
+        let path = |p: &str| std::path::PathBuf::from(p);
+        let rewrap = |a: &str, b: &str| {
+            to_filter(Op::Chain(
+                to_filter(Op::Prefix(path(a))),
+                to_filter(Op::Chain(
+                    to_filter(Op::Subdir(path(a))),
+                    to_filter(Op::Prefix(path(b))),
+                )),
+            ))
+        };
+
+        assert_eq!(step(rewrap("a", "b")), to_filter(Op::Prefix(path("b"))));
+        assert_eq!(
+            optimize(rewrap("a/x", "b")),
+            to_filter(Op::Prefix(path("b")))
+        );
+    }
+
+    #[test]
+    fn prefix_subdir_cancel_test() {
+        let nop = to_filter(Op::Nop);
+        for spec in [":prefix=x:/x", ":prefix=a/b/c:/a/b/c", ":prefix=a/b:/a:/b"] {
+            assert_eq!(parse(spec).unwrap(), nop, "{}", spec);
+        }
+
+        // Selecting a subdir and moving it back keeps only that subdir, so it's not a nop
+        let dir = parse(":/x:prefix=x").unwrap();
+        assert_eq!(spec(dir), "::x/");
+        assert_ne!(parse(":/a/b:prefix=a/b").unwrap(), nop);
+
+        // Taking another subdir after the prefix can't find anything
+        assert_eq!(parse(":prefix=x:/y").unwrap(), to_filter(Op::Empty));
+        assert_eq!(spec(parse(":prefix=a/b:/a").unwrap()), ":prefix=b");
+    }
+
+    #[test]
+    fn common_post_prefix_test() {
+        for (input, expected) in [
+            // Bare prefix members
+            (":[:prefix=a,:/x:prefix=a]", ":[:/,:/x]:prefix=a"),
+            (":[:prefix=a/b,:/y:prefix=a]", ":[:prefix=b,:/y]:prefix=a"),
+            // Files below the prefix end with it implicitly
+            (":[::a/f,:/x:prefix=a]", ":[:/a::f,:/x]:prefix=a"),
+            (":[::a/b/f,:prefix=a/c]", ":[:/a::b/f,:prefix=c]:prefix=a"),
+            // Nothing to take out
+            (":[::a/f,::a/g]", ":[::a/f,::a/g]"),
+            (":[::f,:/x:prefix=a]", ":[::f,:/x:prefix=a]"),
+            (":[::b/f,:/x:prefix=a]", ":[::b/f,:/x:prefix=a]"),
+        ] {
+            assert_eq!(spec(parse(input).unwrap()), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn common_trailing_subtract_test() {
+        let trailing = |spec: &str| {
+            to_filter(Op::Chain(
+                parse(spec).unwrap(),
+                to_filter(Op::Subtract(
+                    to_filter(Op::Nop),
+                    to_filter(Op::Glob("*.md".to_string())),
+                )),
+            ))
+        };
+        let compose =
+            |specs: &[&str]| to_filter(Op::Compose(specs.iter().map(|s| trailing(s)).collect()));
+
+        let f = compose(&["::a/", "x = :/b"]);
+        assert_eq!(
+            step_rule(f),
+            (
+                to_filter(Op::Chain(
+                    to_filter(Op::Compose(vec![
+                        parse("::a/").unwrap(),
+                        parse("x = :/b").unwrap()
+                    ])),
+                    to_filter(Op::Subtract(
+                        to_filter(Op::Nop),
+                        to_filter(Op::Glob("*.md".to_string()))
+                    )),
+                )),
+                "common-trailing-subtract"
+            )
+        );
+
+        // Grouped with the other members that end with the same subtract
+        let f = to_filter(Op::Compose(vec![
+            trailing("::a/"),
+            trailing("x = :/b"),
+            parse("::c/").unwrap(),
+        ]));
+        let (_, steps) = optimize_traced(f);
+        assert!(steps.iter().any(|s| s.rule == "group-members"));
+        assert_eq!(
+            spec(optimize(f)),
+            ":[:[::a/,:/b:prefix=x]:subtract[:/,::*.md],::c/]"
+        );
+
+        // `::a/b/` would get the `.md` files that `::a/` doesn't keep
+        let f = compose(&["::a/", "::a/b/"]);
+        assert_ne!(step_rule(f).1, "common-trailing-subtract");
+    }
+
+    #[test]
+    fn contradicting_subdir_test() {
+        let p = |s: &str| std::path::PathBuf::from(s);
+        let file = |s: &str| to_filter(Op::File(p(s)));
+        let subdir = |s: &str| to_filter(Op::Subdir(p(s)));
+        let prefix = |s: &str| to_filter(Op::Prefix(p(s)));
+        let chain = |a: Filter, b: Filter| to_filter(Op::Chain(a, b));
+        let compose = |a: Filter, b: Filter| to_filter(Op::Compose(vec![a, b]));
+        let empty = to_filter(Op::Empty);
+
+        assert_eq!(step(chain(prefix("a"), file("b"))), empty);
+        assert_eq!(step(chain(file("a"), file("b"))), empty);
+
+        // Nothing else than `a` is written
+        assert_eq!(step(chain(file("a"), subdir("b"))), empty);
+        assert_eq!(
+            step(chain(file("a"), chain(subdir("b"), prefix("c")))),
+            empty
+        );
+        // Only `x/a` and `y` are written
+        let moved = compose(chain(subdir("a"), prefix("x/a")), prefix("y"));
+        assert_eq!(step(chain(moved, subdir("z"))), empty);
+        assert_eq!(step(chain(moved, subdir("x/b"))), empty);
+
+        // The subdir can exist in the output, `::a` also takes `a` if it is a directory
+        for f in [
+            chain(file("a"), subdir("a")),
+            chain(file("a"), subdir("a/b")),
+            chain(file("a/b"), subdir("a")),
+            chain(moved, subdir("x")),
+            chain(moved, subdir("x/a/b")),
+            chain(moved, subdir("y/b")),
+            chain(moved, file("x/a/c")),
+            chain(prefix("a"), file("a/b")),
+            chain(subdir("a"), subdir("b")),
+            chain(to_filter(Op::Glob("*.rs".to_string())), subdir("b")),
+        ] {
+            assert_ne!(optimize(f), empty, "{}", spec(f));
+        }
+    }
+
+    #[test]
+    fn glob_intersection_test() {
+        let glob = |g: &str| to_filter(Op::Glob(g.to_string()));
+        let chain = |a: &str, b: &str| to_filter(Op::Chain(glob(a), glob(b)));
+
+        assert_eq!(step(chain("a/**", "a/b/c/**")), glob("a/b/c/**"));
+        assert_eq!(step(chain("a/b/c/**", "a/**")), glob("a/b/c/**"));
+        assert_eq!(step(chain("a/b/**", "a/b/**")), glob("a/b/**"));
+        assert_eq!(step(chain("a/**", "ab/**")), to_filter(Op::Empty));
+        let nested = to_filter(Op::Chain(
+            glob("a/**"),
+            to_filter(Op::Chain(glob("a/b/**"), glob("a/b/c/**"))),
+        ));
+        assert_eq!(step(step(nested)), glob("a/b/c/**"));
+
+        // No single glob, or `a/**` doesn't match what is in `a/.b`
+        for f in [
+            chain("a/**", "a/.b/**"),
+            chain("a/**", "a/*.rs"),
+            chain("*/**", "a/b/**"),
+            chain("a/**/*.rs", "a/b/**"),
+        ] {
+            assert!(matches!(to_op(step(f)), Op::Chain(_, _)), "{}", spec(f));
+        }
+    }
+
+    #[test]
+    fn glob_subdir_commute_test() {
+        let glob = |g: &str| to_filter(Op::Glob(g.to_string()));
+        let subdir = to_filter(Op::Subdir(std::path::PathBuf::from("s")));
+        let hidden = to_filter(Op::Subdir(std::path::PathBuf::from(".s")));
+
+        assert_eq!(
+            step(to_filter(Op::Chain(glob("s/*.rs"), subdir))),
+            to_filter(Op::Chain(subdir, glob("*.rs")))
+        );
+        assert_eq!(
+            step(to_filter(Op::Chain(glob("**/*.rs"), subdir))),
+            to_filter(Op::Chain(subdir, glob("**/*.rs")))
+        );
+        let rest = to_filter(Op::Prefix(std::path::PathBuf::from("p")));
+        assert_eq!(
+            step(to_filter(Op::Chain(
+                glob("**/*.rs"),
+                to_filter(Op::Chain(subdir, rest))
+            ))),
+            to_filter(Op::Chain(
+                subdir,
+                to_filter(Op::Chain(glob("**/*.rs"), rest))
+            ))
+        );
+
+        // Not equivalent, so left alone
+        for f in [
+            to_filter(Op::Chain(glob("**/*.rs"), hidden)),
+            to_filter(Op::Chain(glob("**/s/*.rs"), subdir)),
+            to_filter(Op::Chain(glob("t/*.rs"), subdir)),
+        ] {
+            let subdir = *as_chain_vec(f).last().unwrap();
+            assert_ne!(as_chain_vec(step(f))[0], subdir, "{}", spec(f));
+        }
+    }
+
+    #[test]
+    fn linear_linear_test() {
+        let linear = to_filter(Op::Linear);
+        let subdir = parse(":/a").unwrap();
+        let twice = to_filter(Op::Chain(linear, linear));
+
+        assert_eq!(step(twice), linear);
+        assert_eq!(
+            step(to_filter(Op::Chain(
+                linear,
+                to_filter(Op::Chain(linear, subdir))
+            ))),
+            to_filter(Op::Chain(linear, subdir))
+        );
+        assert_eq!(
+            optimize(to_filter(Op::Chain(
+                to_filter(Op::Chain(subdir, linear)),
+                linear
+            ))),
+            optimize(to_filter(Op::Chain(subdir, linear)))
+        );
+        assert_eq!(
+            spec(optimize(parse(":/a:linear:linear").unwrap())),
+            ":/a:linear"
+        );
+    }
+
+    #[test]
+    fn interleaved_test() {
+        for spec in [
+            ":/a:[::b/,::c/]",
+            ":[a = :/x, ::b/**/*.rs, :subtract[::c/,::c/d], :exclude[::e]]",
+            ":subtract[:[::a/,::b/],:[::a/,::c/]]:prefix=p",
+            ":[x/y = :/b,::c/,x = :/a,::src/**/*.md]",
+            ":/a/b:prefix=c:/c:prefix=d",
+        ] {
+            let filter = parse(spec).unwrap();
+            let interleaved = optimize_interleaved(filter);
+            assert!(size(interleaved) <= size(optimize(filter)), "{}", spec);
+            assert_eq!(step(interleaved), interleaved, "{}", spec);
+        }
+
+        // `step` splits the literal directory of the glob and `simplify` joins it again,
+        // so the second fixpoint is the first one again
+        let glob = to_filter(Op::Glob("src/**".to_string()));
+        let (result, converged) = iterate(glob, MAX_ROUNDS, true);
+        assert!(converged);
+        assert_eq!(result, iterate(glob, MAX_ROUNDS, false).0);
+        assert_ne!(simplify(result), result);
+    }
+
+    #[test]
+    fn optimize_for_size_test() {
+        let deep = to_filter(Op::Prefix("a/b/c".into()));
+        assert_eq!(size(optimize(deep)), 5);
+        assert_eq!(optimize_for_size(deep), deep);
+
+        for (input, expected) in [
+            (":/a/b/c:prefix=x/y", ":/a/b/c:prefix=x/y"),
+            (":[x/y = :/a/b,::c/d/]", ":[:/a/b:prefix=x/y,::c/d/]"),
+            (":exclude[:/a/b/c]", ":exclude[:/a/b/c]"),
+        ] {
+            let filter = parse(input).unwrap();
+            let small = optimize_for_size(filter);
+            assert_eq!(spec(small), expected, "{}", input);
+            assert!(size(small) < size(filter), "{}", input);
+            assert_eq!(optimize(small), filter, "{}", input);
+        }
+    }
+
+    #[test]
+    fn register_fixpoints_test() {
+        // On a cache of its own, so other tests neither see the registration nor evict it
+        let cache = opt_cache();
+        let optimize = |f| optimize_in(&cache, f, MAX_ROUNDS);
+        let registered = to_filter(Op::Chain(
+            to_filter(Op::Prefix("fixpoint".into())),
+            to_filter(Op::Subdir("fixpoint".into())),
+        ));
+        let other = to_filter(Op::Chain(
+            to_filter(Op::Prefix("not_registered".into())),
+            to_filter(Op::Subdir("not_registered".into())),
+        ));
+
+        register_fixpoints_in(&cache, &[registered]);
+        // Without the registration this would optimize to `:/`
+        assert_eq!(optimize(registered), registered);
+        assert_eq!(optimize(other), to_filter(Op::Nop));
+        assert_eq!(
+            optimize_in(&opt_cache(), registered, MAX_ROUNDS),
+            to_filter(Op::Nop)
+        );
+    }
+
+    #[test]
+    fn common_pre_bare_tails_test() {
+        let prefix = |p: &str| to_filter(Op::Prefix(p.into()));
+        let chain = |a, b| to_filter(Op::Chain(a, b));
+        let composed = |common: Filter| {
+            to_filter(Op::Compose(vec![
+                chain(common, prefix("x")),
+                chain(common, prefix("y")),
+                chain(common, prefix("z")),
+            ]))
+        };
+        let prefixes = to_filter(Op::Compose(vec![prefix("x"), prefix("y"), prefix("z")]));
+
+        let subdir = to_filter(Op::Subdir("a".into()));
+        let glob = to_filter(Op::Glob("*.rs".into()));
+        for common in [subdir, glob, chain(subdir, glob)] {
+            assert_eq!(
+                step_rule(composed(common)),
+                (chain(common, prefixes), "common-pre"),
+                "{}",
+                spec(common)
+            );
+        }
+    }
+
+    #[test]
+    fn structurally_equal_filters_share_cache_test() {
+        let _shared = SHARED_CACHES.lock().unwrap_or_else(|e| e.into_inner());
+        let built = |dir: std::path::PathBuf| {
+            to_filter(Op::Chain(
+                to_filter(Op::Subdir(dir)),
+                to_filter(Op::Prefix("share_cache".into())),
+            ))
+        };
+        let a = built("shared".into());
+        let b = built(std::path::PathBuf::from(String::from("shar") + "ed"));
+        assert_eq!(a, b);
+
+        // Optimizing `a` also adds the entry that `b` is looked up with
+        let optimized = optimize(a);
+        assert_eq!(OPTIMIZED.lock().unwrap().get(&b), Some(&optimized));
+        assert_eq!(optimize(b), optimized);
+    }
+
+    #[test]
+    fn optimization_cache_stats_test() {
+        let _shared = SHARED_CACHES.lock().unwrap_or_else(|e| e.into_inner());
+        clear_optimization_caches();
+        let filters: Vec<_> = (0..20)
+            .map(|i| {
+                to_filter(Op::Chain(
+                    to_filter(Op::Subdir(format!("stats{}", i).into())),
+                    to_filter(Op::Prefix("p".into())),
+                ))
+            })
+            .collect();
+
+        // Other tests optimize filters at the same time, so the counters can only be
+        // checked for lower bounds
+        let before = optimization_cache_stats();
+        let first: Vec<_> = filters.iter().map(|f| optimize(*f)).collect();
+        let computed = optimization_cache_stats();
+        assert!(computed.misses >= before.misses + filters.len() as u64);
+        assert!(computed.entries >= filters.len());
+
+        let second: Vec<_> = filters.iter().map(|f| optimize(*f)).collect();
+        let hit = optimization_cache_stats();
+        assert_eq!(first, second);
+        assert!(hit.hits >= computed.hits + filters.len() as u64);
+    }
+
+    #[test]
+    fn optimize_with_stats_test() {
+        let (optimized, stats) = optimize_with_stats(to_filter(Op::Subdir("a/b/c".into())));
+        assert_eq!(optimized, optimize(to_filter(Op::Subdir("a/b/c".into()))));
+        // Split again after each round of `simplify` joining the parts
+        let split = stats["split-subdir"];
+        assert!(split.applied >= 2, "{:?}", split);
+        assert!(split.size_delta > 0, "{:?}", split);
+
+        let x = to_filter(Op::Chain(
+            to_filter(Op::Subdir("x".into())),
+            to_filter(Op::Prefix("y".into())),
+        ));
+        let (_, stats) = optimize_with_stats(to_filter(Op::Compose(vec![
+            x,
+            x,
+            to_filter(Op::Glob("*.md".into())),
+        ])));
+        let dedup = stats["dedup-members"];
+        assert_eq!(dedup.applied, 1);
+        assert!(dedup.size_delta < 0, "{:?}", dedup);
+
+        // Members are rewritten by `descend`, which is counted for the rules applied to them
+        let (_, stats) = optimize_with_stats(to_filter(Op::Compose(vec![
+            to_filter(Op::Subdir("p/q".into())),
+            to_filter(Op::Glob("*.md".into())),
+        ])));
+        assert!(!stats.contains_key("descend"), "{:?}", stats);
+        assert!(stats.contains_key("split-subdir"), "{:?}", stats);
+    }
+
+    #[test]
+    fn rule_names_test() {
+        let names = rule_names();
+        let unique: std::collections::HashSet<_> = names.iter().collect();
+        assert_eq!(unique.len(), names.len());
+
+        let filter = |op| to_filter(op);
+        let seen: std::collections::HashSet<_> = [
+            filter(Op::Subdir("a/b".into())),
+            filter(Op::Chain(filter(Op::Linear), filter(Op::Linear))),
+            filter(Op::Subtract(
+                parse("::src/core/").unwrap(),
+                parse("::src/").unwrap(),
+            )),
+            filter(Op::Compose(vec![
+                parse(":/a:prefix=x").unwrap(),
+                parse(":/a:prefix=y").unwrap(),
+            ])),
+            parse(":[a = :/x, ::b/**/*.rs, :subtract[::c/,::c/d], :exclude[::e]]").unwrap(),
+        ]
+        .into_iter()
+        .flat_map(|f| {
+            let (optimized, steps) = optimize_traced(f);
+            assert_eq!(optimized, optimize(f), "{}", spec(f));
+            for s in &steps {
+                assert!(names.contains(&s.rule), "{}", s.rule);
+                assert_eq!(step(s.before), s.after);
+            }
+            steps.into_iter().map(|s| s.rule)
+        })
+        .collect();
+
+        for rule in [
+            "split-subdir",
+            "linear-linear",
+            "subtract-superset",
+            "common-pre",
+        ] {
+            assert!(seen.contains(rule), "{} in {:?}", rule, seen);
+        }
+    }
+}
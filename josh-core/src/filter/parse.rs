@@ -2,6 +2,10 @@ use super::*;
 use indoc::{formatdoc, indoc};
 use itertools::Itertools;
 
+/// Upper bound for the compiled size of path regexes. Matching is always linear in the
+/// length of the path, but the regex itself could otherwise become arbitrarily large.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
 fn make_op(args: &[&str]) -> JoshResult<Op> {
     match args {
         ["nop"] => Ok(Op::Nop),
@@ -10,6 +14,18 @@ fn make_op(args: &[&str]) -> JoshResult<Op> {
         ["author", author, email] => Ok(Op::Author(author.to_string(), email.to_string())),
         ["committer", author, email] => Ok(Op::Committer(author.to_string(), email.to_string())),
         ["workspace", arg] => Ok(Op::Workspace(Path::new(arg).to_owned())),
+        ["regex", arg] => Ok(Op::PathRegex(
+            regex::RegexBuilder::new(arg)
+                .size_limit(REGEX_SIZE_LIMIT)
+                .build()
+                .map_err(|e| josh_error(&format!("Invalid regex in \":regex\": {}", e)))?,
+        )),
+        ["grep", arg] => Ok(Op::ContentGrep(
+            regex::RegexBuilder::new(arg)
+                .size_limit(REGEX_SIZE_LIMIT)
+                .build()
+                .map_err(|e| josh_error(&format!("Invalid regex in \":grep\": {}", e)))?,
+        )),
         ["prefix"] => Err(josh_error(indoc!(
             r#"
             Filter ":prefix" requires an argument.
@@ -35,7 +51,128 @@ fn make_op(args: &[&str]) -> JoshResult<Op> {
         ["SQUASH"] => Ok(Op::Squash(None)),
         ["SQUASH", _ids @ ..] => Err(josh_error("SQUASH with ids can't be parsed")),
         ["linear"] => Ok(Op::Linear),
+        ["take", n] => match n.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Op::Take(n)),
+            _ => Err(josh_error(&format!(
+                "Filter \":take\" requires a positive number of commits, got: {:?}",
+                n
+            ))),
+        },
+        ["keep-versions", k] => match k.parse::<usize>() {
+            Ok(k) if k > 0 => Ok(Op::KeepVersions(k)),
+            _ => Err(josh_error(&format!(
+                "Filter \":keep-versions\" requires a positive number of versions, got: {:?}",
+                k
+            ))),
+        },
         ["unsign"] => Ok(Op::Unsign),
+        ["origin-trailer"] => Ok(Op::OriginTrailer),
+        ["notes", notes_ref] => Ok(Op::Notes(notes_ref.to_string())),
+        ["last-author", pattern] => {
+            glob::Pattern::new(pattern)?;
+            Ok(Op::LastAuthor(pattern.to_string()))
+        }
+        ["touched", id] => match git2::Oid::from_str(id) {
+            Ok(oid) if oid.to_string() == id.to_lowercase() => Ok(Op::TouchedBy(oid)),
+            _ => Err(josh_error(&format!(
+                "Filter \":touched\" requires a full commit id, got: {:?}",
+                id
+            ))),
+        },
+        ["added-since", id] => match git2::Oid::from_str(id) {
+            Ok(oid) if oid.to_string() == id.to_lowercase() => Ok(Op::AddedSince(oid)),
+            _ => Err(josh_error(&format!(
+                "Filter \":added-since\" requires a full commit id, got: {:?}",
+                id
+            ))),
+        },
+        ["diff", range] => {
+            let full_id = |id: &str| {
+                git2::Oid::from_str(id)
+                    .ok()
+                    .filter(|oid| oid.to_string() == id.to_lowercase())
+            };
+            match range
+                .split_once("..")
+                .map(|(from, to)| (full_id(from), full_id(to)))
+            {
+                Some((Some(from), Some(to))) => Ok(Op::DiffRange(from, to)),
+                _ => Err(josh_error(&format!(
+                    "Filter \":diff\" requires a range of full commit ids, got: {:?}",
+                    range
+                ))),
+            }
+        }
+        ["intersect", ids @ ..] | ["intersect-content", ids @ ..] if !ids.is_empty() => {
+            let ids = ids
+                .iter()
+                .map(|id| match git2::Oid::from_str(id) {
+                    Ok(oid) if oid.to_string() == id.to_lowercase() => Ok(oid),
+                    _ => Err(josh_error(&format!(
+                        "Filter \":{}\" requires full commit ids, got: {:?}",
+                        args[0], id
+                    ))),
+                })
+                .collect::<JoshResult<_>>()?;
+            Ok(Op::IntersectRefs(ids, args[0] == "intersect-content"))
+        }
+        ["self"] => Ok(Op::SelfView),
+        ["lowercase"] => Ok(Op::LowercasePaths),
+        ["collapse"] => Ok(Op::FlattenSingleChild),
+        ["explode", child] => {
+            if child.is_empty() || child.contains('/') || *child == "." || *child == ".." {
+                return Err(josh_error(&format!(
+                    "Filter \":explode\" requires a file name, got: {:?}",
+                    child
+                )));
+            }
+            Ok(Op::Explode(child.to_string()))
+        }
+        ["strip-ext", ext] => {
+            if ext.is_empty() || ext.contains('/') || ext.starts_with('.') {
+                return Err(josh_error(&format!(
+                    "Filter \":strip-ext\" requires an extension without leading dot, got: {:?}",
+                    ext
+                )));
+            }
+            Ok(Op::StripExtension(ext.to_string()))
+        }
+        ["flatten", sep] => {
+            if sep.is_empty() || sep.contains('/') {
+                return Err(josh_error(&format!(
+                    "Filter \":flatten\" requires a separator without \"/\", got: {:?}",
+                    sep
+                )));
+            }
+            Ok(Op::FlattenWithPathNames(sep.to_string()))
+        }
+        ["dedup"] => Ok(Op::DedupBlobs),
+        ["docs"] => Ok(Op::Docs),
+        ["binary"] => Ok(Op::BinaryOnly),
+        ["text"] => Ok(Op::TextOnly),
+        ["lfs"] => Ok(Op::LfsOnly),
+        ["owned", owner] | ["owned", owner, _] if owner.is_empty() => {
+            Err(josh_error("Filter \":owned\" requires an owner"))
+        }
+        ["owned", owner] => Ok(Op::Owned(
+            owner.to_string(),
+            Path::new(codeowners::DEFAULT_PATH).to_owned(),
+        )),
+        ["owned", owner, path] => Ok(Op::Owned(owner.to_string(), Path::new(path).to_owned())),
+        ["archive", format, path] => {
+            if !archive::FORMATS.contains(format) {
+                return Err(josh_error(&format!(
+                    "Unsupported archive format in \":archive\": {}, expected one of: {}",
+                    format,
+                    archive::FORMATS.join(", ")
+                )));
+            }
+            if path.is_empty() {
+                return Err(josh_error("Filter \":archive\" requires an output path"));
+            }
+            Ok(Op::Archive(format.to_string(), Path::new(path).to_owned()))
+        }
+        ["content-hash"] => Ok(Op::ContentToHash),
         ["PATHS"] => Ok(Op::Paths),
         ["INDEX"] => Ok(Op::Index),
         ["INVERT"] => Ok(Op::Invert),
@@ -100,12 +237,29 @@ fn parse_item(pair: pest::iterators::Pair<Rule>) -> JoshResult<Op> {
                     match *cmd {
                         "exclude" => Ok(Op::Exclude(to_filter(Op::Compose(g)))),
                         "subtract" if g.len() == 2 => Ok(Op::Subtract(g[0], g[1])),
+                        "intersect" if g.len() == 2 => Ok(Op::Intersect(g[0], g[1])),
                         _ => Err(josh_error(&format!("parse_item: no match {:?}", cmd))),
                     }
                 }
                 _ => Err(josh_error("parse_item: no match {:?}")),
             }
         }
+        Rule::filter_select => {
+            let mut include = vec![];
+            let mut exclude = vec![];
+            for pattern in pair.into_inner() {
+                let rule = pattern.as_rule();
+                let pattern = unquote(pattern.into_inner().next().unwrap().as_str());
+                glob::Pattern::new(&pattern)
+                    .map_err(|e| josh_error(&format!("Invalid pattern in \":select\": {}", e)))?;
+                if rule == Rule::select_include {
+                    include.push(pattern);
+                } else {
+                    exclude.push(pattern);
+                }
+            }
+            Ok(Op::Select(include, exclude))
+        }
         Rule::filter_join => {
             let v: Vec<_> = pair.into_inner().map(|x| x.as_str()).collect();
 
@@ -138,6 +292,16 @@ fn parse_item(pair: pest::iterators::Pair<Rule>) -> JoshResult<Op> {
 
             Ok(Op::RegexReplace(replacements))
         }
+        Rule::filter_rewrite_message => {
+            let rules = pair
+                .into_inner()
+                .map(|x| unquote(x.as_str()))
+                .tuples()
+                .map(|(regex, replacement)| Ok((regex::Regex::new(&regex)?, replacement)))
+                .collect::<JoshResult<_>>()?;
+
+            Ok(Op::RewriteMessage(rules))
+        }
         Rule::filter_squash => {
             let ids = pair
                 .into_inner()
@@ -147,6 +311,17 @@ fn parse_item(pair: pest::iterators::Pair<Rule>) -> JoshResult<Op> {
 
             Ok(Op::Squash(Some(ids)))
         }
+        Rule::filter_if_exists => {
+            let mut inner = pair.into_inner();
+            let path = unquote(inner.next().unwrap().as_str());
+            let then = parse(inner.next().unwrap().as_str())?;
+            let otherwise = parse(inner.next().unwrap().as_str())?;
+            Ok(Op::IfExists(
+                Path::new(path.trim_end_matches('/')).to_owned(),
+                then,
+                otherwise,
+            ))
+        }
 
         _ => Err(josh_error("parse_item: no match")),
     }
@@ -253,7 +428,9 @@ pub fn quote_if(s: &str) -> String {
 }
 
 pub fn quote(s: &str) -> String {
+    // Single quotes are escaped, because `unquote` turns them into double quotes
     serde_json::to_string(&serde_json::Value::String(s.to_string()))
+        .map(|s| s.replace('\'', "\\u0027"))
         .unwrap_or("<invalid string>".to_string())
 }
 
@@ -282,6 +459,105 @@ pub fn parse(filter_spec: &str) -> JoshResult<Filter> {
     )?))))
 }
 
+/// Like `parse`, but with `delimiter` starting filters instead of `:`, so with `|`
+/// the spec `|/src|prefix=x` is the same as `:/src:prefix=x`. This is for embedding
+/// specs where `:` has another meaning. A `:` that is not quoted is rejected.
+pub fn parse_with_delimiter(filter_spec: &str, delimiter: char) -> JoshResult<Filter> {
+    parse(&swap_delimiter(filter_spec, delimiter, ':')?)
+}
+
+/// Characters that have a meaning in the filter language and can't be used as delimiter
+fn check_delimiter(delimiter: char) -> JoshResult<()> {
+    let reserved = delimiter.is_ascii_alphanumeric()
+        || delimiter.is_whitespace()
+        || "_-+.*~@/=,;[]()\"'\\#".contains(delimiter);
+    if reserved {
+        return Err(josh_error(&format!(
+            "{:?} can't be used as filter delimiter",
+            delimiter
+        )));
+    }
+    Ok(())
+}
+
+/// Replace every `from` that is not inside of a quoted string by `to`. Fails if `to`
+/// already occurs outside of quotes, as it could not be told apart afterwards.
+pub(crate) fn swap_delimiter(filter_spec: &str, from: char, to: char) -> JoshResult<String> {
+    check_delimiter(from)?;
+    check_delimiter(to)?;
+    let mut result = String::with_capacity(filter_spec.len());
+    let mut quote = None;
+    let mut escaped = false;
+    for c in filter_spec.chars() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+                result.push(c);
+            }
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                result.push(c);
+            }
+            None if c == from => result.push(to),
+            None if c == to && from != to => {
+                return Err(josh_error(&format!(
+                    "Unexpected {:?} in filter using {:?} as delimiter: {}",
+                    to, from, filter_spec
+                )));
+            }
+            None => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+/// Parse the named views of a TOML workspace manifest.
+/// Views are expected in a `[views]` table, mapping the name of each view
+/// to its filter spec:
+///
+/// ```toml
+/// [views]
+/// docs = ":/docs"
+/// api = ":[proto = :/api/proto, ::api/README.md]"
+/// ```
+///
+/// Other top level keys are ignored, so the views can live in a larger config file.
+pub fn parse_workspace_toml(
+    content: &str,
+) -> JoshResult<std::collections::HashMap<String, Filter>> {
+    let manifest: toml::Table = toml::from_str(content)
+        .map_err(|e| josh_error(&format!("invalid workspace manifest: {}", e)))?;
+
+    let views = match manifest.get("views") {
+        Some(toml::Value::Table(views)) => views,
+        Some(_) => {
+            return Err(josh_error(
+                "invalid workspace manifest: `views` must be a table",
+            ))
+        }
+        None => return Ok(std::collections::HashMap::new()),
+    };
+
+    views
+        .iter()
+        .map(|(name, value)| match value {
+            toml::Value::String(spec) => parse(spec)
+                .map(|filter| (name.clone(), filter))
+                .map_err(|e| josh_error(&format!("invalid filter for view `{}`: {}", name, e.0))),
+            _ => Err(josh_error(&format!(
+                "invalid workspace manifest: view `{}` must be a filter string",
+                name
+            ))),
+        })
+        .collect()
+}
+
 /// Get the potential leading comments from a workspace.josh as a string
 pub fn get_comments(filter_spec: &str) -> JoshResult<String> {
     if let Ok(r) = Grammar::parse(Rule::workspace_file, filter_spec) {
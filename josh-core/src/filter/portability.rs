@@ -0,0 +1,135 @@
+/*
+ * Checks of filtered trees against the path restrictions of the platforms they get
+ * checked out on.
+ */
+
+use super::*;
+
+/// Platform whose path restrictions `validate_output_portability` checks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Windows,
+}
+
+/// Reason why a path can't be checked out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortabilityProblem {
+    /// A component is a reserved device name like `CON` or `lpt1.txt`
+    ReservedName,
+    /// A component contains a character the platform does not allow in file names
+    InvalidCharacter(char),
+    /// A component ends with a dot or a space, which the platform strips
+    TrailingDotOrSpace,
+    /// The path is longer than the platform supports
+    TooLong { length: usize, max: usize },
+    /// The path differs only in case from the given other path in the same directory
+    CaseCollision(std::path::PathBuf),
+}
+
+/// A path of a filtered tree that can't be checked out on the target platform
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortabilityIssue {
+    pub path: std::path::PathBuf,
+    pub problem: PortabilityProblem,
+}
+
+impl std::fmt::Display for PortabilityIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = self.path.display();
+        match &self.problem {
+            PortabilityProblem::ReservedName => write!(f, "{}: reserved name", path),
+            PortabilityProblem::InvalidCharacter(c) => {
+                write!(f, "{}: invalid character {:?}", path, c)
+            }
+            PortabilityProblem::TrailingDotOrSpace => {
+                write!(f, "{}: ends with a dot or space", path)
+            }
+            PortabilityProblem::TooLong { length, max } => {
+                write!(
+                    f,
+                    "{}: {} characters, at most {} are supported",
+                    path, length, max
+                )
+            }
+            PortabilityProblem::CaseCollision(other) => {
+                write!(f, "{}: differs only in case from {}", path, other.display())
+            }
+        }
+    }
+}
+
+/// Longest path that can be checked out without long path support, not counting
+/// the directory of the checkout
+const WINDOWS_MAX_PATH: usize = 260;
+
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Apply `filter` to `tree` and list the paths of the result that can't be checked
+/// out on `target`. Issues are sorted by path, a path can have several of them.
+pub fn validate_output_portability(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree,
+    target: Platform,
+) -> JoshResult<Vec<PortabilityIssue>> {
+    let filtered = apply(transaction, filter, tree)?;
+
+    let mut issues = vec![];
+    let mut seen = std::collections::HashMap::new();
+    filtered.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+        let name = entry.name().unwrap_or_default();
+        let path = Path::new(root).join(name);
+        match target {
+            Platform::Windows => {
+                let folded = Path::new(&root.to_lowercase()).join(name.to_lowercase());
+                if let Some(other) = seen.insert(folded, path.clone()) {
+                    issues.push(PortabilityIssue {
+                        path: path.clone(),
+                        problem: PortabilityProblem::CaseCollision(other),
+                    });
+                }
+                for problem in windows_problems(name, &path) {
+                    issues.push(PortabilityIssue {
+                        path: path.clone(),
+                        problem,
+                    });
+                }
+            }
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+    issues.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(issues)
+}
+
+fn windows_problems(name: &str, path: &Path) -> Vec<PortabilityProblem> {
+    let mut problems = vec![];
+    // `CON.txt` refers to the device as well
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if WINDOWS_RESERVED
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(stem))
+    {
+        problems.push(PortabilityProblem::ReservedName);
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| c.is_ascii_control() || "<>:\"\\|?*".contains(*c))
+    {
+        problems.push(PortabilityProblem::InvalidCharacter(c));
+    }
+    if name.ends_with('.') || name.ends_with(' ') {
+        problems.push(PortabilityProblem::TrailingDotOrSpace);
+    }
+    let length = path.to_string_lossy().chars().count();
+    if length > WINDOWS_MAX_PATH {
+        problems.push(PortabilityProblem::TooLong {
+            length,
+            max: WINDOWS_MAX_PATH,
+        });
+    }
+    problems
+}
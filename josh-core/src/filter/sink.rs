@@ -0,0 +1,103 @@
+/*
+ * Applying filters without writing the result to the repository.
+ */
+
+use super::*;
+
+/// Receives the objects created by `apply_with_sink`.
+/// Trees are written after all the objects they contain.
+pub trait ObjectSink {
+    fn write_blob(&mut self, oid: git2::Oid, content: &[u8]) -> JoshResult<()>;
+    fn write_tree(&mut self, oid: git2::Oid, content: &[u8]) -> JoshResult<()>;
+}
+
+/// Discards all objects, for computing only the oid of a result
+pub struct NullSink;
+
+impl ObjectSink for NullSink {
+    fn write_blob(&mut self, _oid: git2::Oid, _content: &[u8]) -> JoshResult<()> {
+        Ok(())
+    }
+    fn write_tree(&mut self, _oid: git2::Oid, _content: &[u8]) -> JoshResult<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the raw content of all objects in memory
+#[derive(Default)]
+pub struct MemorySink {
+    pub objects: std::collections::HashMap<git2::Oid, (git2::ObjectType, Vec<u8>)>,
+}
+
+impl ObjectSink for MemorySink {
+    fn write_blob(&mut self, oid: git2::Oid, content: &[u8]) -> JoshResult<()> {
+        self.objects
+            .insert(oid, (git2::ObjectType::Blob, content.to_vec()));
+        Ok(())
+    }
+    fn write_tree(&mut self, oid: git2::Oid, content: &[u8]) -> JoshResult<()> {
+        self.objects
+            .insert(oid, (git2::ObjectType::Tree, content.to_vec()));
+        Ok(())
+    }
+}
+
+/// Writes all objects to the odb of a repository
+pub struct OdbSink<'a>(pub &'a git2::Repository);
+
+impl ObjectSink for OdbSink<'_> {
+    fn write_blob(&mut self, _oid: git2::Oid, content: &[u8]) -> JoshResult<()> {
+        self.0.odb()?.write(git2::ObjectType::Blob, content)?;
+        Ok(())
+    }
+    fn write_tree(&mut self, _oid: git2::Oid, content: &[u8]) -> JoshResult<()> {
+        self.0.odb()?.write(git2::ObjectType::Tree, content)?;
+        Ok(())
+    }
+}
+
+/// Filter a single tree like `apply`, but pass the objects of the result that are not
+/// already in the repository to `sink` instead of writing them to its odb.
+/// Intermediate objects are kept in memory and dropped when the call returns.
+/// Returns the oid of the filtered tree.
+pub fn apply_with_sink(
+    transaction: &cache::Transaction,
+    filter: Filter,
+    tree: git2::Tree,
+    sink: &mut dyn ObjectSink,
+) -> JoshResult<git2::Oid> {
+    let private = transaction.try_clone_private()?;
+    let odb = private.repo().odb()?;
+    let _mempack = odb.add_new_mempack_backend(1000)?;
+    private.set_in_memory(true);
+
+    let input = private.repo().find_tree(tree.id())?;
+    let result = apply(&private, filter, input)?.id();
+
+    let existing = transaction.repo().odb()?;
+    export(private.repo(), &existing, result, sink)?;
+    Ok(result)
+}
+
+fn export(
+    repo: &git2::Repository,
+    existing: &git2::Odb,
+    tree: git2::Oid,
+    sink: &mut dyn ObjectSink,
+) -> JoshResult<()> {
+    if existing.exists(tree) {
+        return Ok(());
+    }
+    let odb = repo.odb()?;
+    for entry in repo.find_tree(tree)?.iter() {
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => export(repo, existing, entry.id(), sink)?,
+            Some(git2::ObjectType::Blob) if !existing.exists(entry.id()) => {
+                sink.write_blob(entry.id(), odb.read(entry.id())?.data())?
+            }
+            _ => {}
+        }
+    }
+    let object = odb.read(tree)?;
+    sink.write_tree(tree, object.data())
+}
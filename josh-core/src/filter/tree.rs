@@ -1,7 +1,5 @@
 use super::*;
 
-use rayon::prelude::*;
-
 pub fn pathstree<'a>(
     root: &str,
     input: git2::Oid,
@@ -13,7 +11,7 @@ pub fn pathstree<'a>(
     }
 
     let tree = repo.find_tree(input)?;
-    let mut result = empty(repo);
+    let mut result = DirBuilder::new(repo, None)?;
 
     for entry in tree.iter() {
         let name = entry.name().ok_or_else(|| josh_error("no name"))?;
@@ -29,13 +27,11 @@ pub fn pathstree<'a>(
             } else {
                 path_string.to_string()
             };
-            result = replace_child(
-                repo,
+            result.set(
                 Path::new(name),
-                repo.blob(file_contents.as_bytes())?,
+                write_blob(repo, file_contents.as_bytes())?,
                 0o0100644,
-                &result,
-            )?;
+            );
         }
 
         if entry.kind() == Some(git2::ObjectType::Tree) {
@@ -47,10 +43,11 @@ pub fn pathstree<'a>(
             .id();
 
             if s != empty_id() {
-                result = replace_child(repo, Path::new(name), s, 0o0040000, &result)?;
+                result.set(Path::new(name), s, 0o0040000);
             }
         }
     }
+    let result = result.write()?;
     transaction.insert_paths((input, root.to_string()), result.id());
     Ok(result)
 }
@@ -64,7 +61,7 @@ pub fn regex_replace<'a>(
     let repo = transaction.repo();
 
     let tree = repo.find_tree(input)?;
-    let mut result = tree::empty(repo);
+    let mut result = DirBuilder::new(repo, None)?;
 
     for entry in tree.iter() {
         let name = entry.name().ok_or(super::josh_error("no name"))?;
@@ -72,26 +69,526 @@ pub fn regex_replace<'a>(
             let file_contents = get_blob(repo, &tree, std::path::Path::new(&name));
             let replaced = regex.replacen(&file_contents, 0, replacement);
 
-            result = replace_child(
-                repo,
+            result.set(
                 std::path::Path::new(name),
-                repo.blob(replaced.as_bytes())?,
+                write_blob(repo, replaced.as_bytes())?,
                 entry.filemode(),
-                &result,
-            )?;
+            );
         }
 
         if entry.kind() == Some(git2::ObjectType::Tree) {
             let s = regex_replace(entry.id(), regex, replacement, transaction)?.id();
 
             if s != tree::empty_id() {
-                result = replace_child(repo, std::path::Path::new(name), s, 0o0040000, &result)?;
+                result.set(std::path::Path::new(name), s, 0o0040000);
+            }
+        }
+    }
+    result.write()
+}
+
+/// Replace the content of every regular file with `<oid> <size>\n` of the original
+/// content. Symlinks and submodules are kept as they are.
+pub fn content_hash<'a>(
+    transaction: &'a cache::Transaction,
+    input: git2::Oid,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    if let Some(cached) = transaction.get_glob((input, key)) {
+        return Ok(repo.find_tree(cached)?);
+    }
+
+    let tree = repo.find_tree(input)?;
+    let mut result = DirBuilder::new(repo, Some(&tree))?;
+
+    for entry in tree.iter() {
+        let name = entry.name().ok_or_else(|| josh_error("INVALID_FILENAME"))?;
+        let mode = entry.filemode();
+        let id = if entry.kind() == Some(git2::ObjectType::Tree) {
+            content_hash(transaction, entry.id(), key)?.id()
+        } else if mode == 0o0100644 || mode == 0o0100755 {
+            let (size, _) = repo.odb()?.read_header(entry.id())?;
+            write_blob(repo, format!("{} {}\n", entry.id(), size).as_bytes())?
+        } else {
+            continue;
+        };
+        result.set(Path::new(name), id, mode);
+    }
+
+    let result = result.write()?;
+    transaction.insert_glob((input, key), result.id());
+    Ok(result)
+}
+
+/// Keep only the blobs for which `pred` returns true, dropping directories that end up empty.
+/// Every blob has to be read, so this is a lot more expensive than filtering by path.
+pub fn keep_blobs<'a>(
+    transaction: &'a cache::Transaction,
+    input: git2::Oid,
+    pred: &dyn Fn(&git2::Blob) -> bool,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    if let Some(cached) = transaction.get_glob((input, key)) {
+        return Ok(repo.find_tree(cached)?);
+    }
+
+    let tree = repo.find_tree(input)?;
+    let mut result = DirBuilder::new(repo, None)?;
+
+    for entry in tree.iter() {
+        let name = entry.name().ok_or_else(|| josh_error("INVALID_FILENAME"))?;
+        let id = match entry.kind() {
+            Some(git2::ObjectType::Tree) => keep_blobs(transaction, entry.id(), pred, key)?.id(),
+            Some(git2::ObjectType::Blob) if pred(&repo.find_blob(entry.id())?) => entry.id(),
+            _ => continue,
+        };
+        if id != empty_id() {
+            result.set(Path::new(name), id, entry.filemode());
+        }
+    }
+
+    let result = result.write()?;
+    transaction.insert_glob((input, key), result.id());
+    Ok(result)
+}
+
+/// Pointer files are small, git-lfs does not consider larger files even if they
+/// start like a pointer
+const LFS_POINTER_MAX_SIZE: usize = 1024;
+
+/// Whether `content` is a git-lfs pointer file, standing in for a file stored outside
+/// of the repository. Pointers start with a `version` line naming the spec, followed
+/// by `oid` and `size` of the actual file.
+pub fn is_lfs_pointer(content: &[u8]) -> bool {
+    if content.len() > LFS_POINTER_MAX_SIZE {
+        return false;
+    }
+    let content = match std::str::from_utf8(content) {
+        Ok(content) => content,
+        Err(_) => return false,
+    };
+    let mut lines = content.lines();
+    let version = lines.next().unwrap_or_default();
+    if !(version.starts_with("version https://git-lfs.github.com/spec/")
+        || version == "version https://hawser.github.com/spec/v1")
+    {
+        return false;
+    }
+    let keys: Vec<_> = lines
+        .filter_map(|line| line.split_once(' ').map(|(key, _)| key))
+        .collect();
+    keys.contains(&"oid") && keys.contains(&"size")
+}
+
+/// Replace files that have the same content and mode as a file that comes earlier
+/// in sorted path order with relative symlinks to that file
+pub fn dedup_blobs<'a>(
+    transaction: &'a cache::Transaction,
+    input: git2::Oid,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    if let Some(cached) = transaction.get_glob((input, key)) {
+        return Ok(repo.find_tree(cached)?);
+    }
+
+    let tree = repo.find_tree(input)?;
+    let mut files = vec![];
+    tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+        let mode = entry.filemode();
+        if entry.kind() == Some(git2::ObjectType::Blob) && (mode == 0o0100644 || mode == 0o0100755)
+        {
+            if let Some(name) = entry.name() {
+                files.push((format!("{}{}", root, name), entry.id(), mode));
+            }
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+    files.sort();
+
+    let mut first = std::collections::HashMap::<(git2::Oid, i32), String>::new();
+    let mut result = tree;
+    for (path, id, mode) in files {
+        if let Some(target) = first.get(&(id, mode)) {
+            let link = write_blob(repo, relative_link(&path, target).as_bytes())?;
+            result = insert(repo, &result, Path::new(&path), link, 0o0120000)?;
+        } else {
+            first.insert((id, mode), path);
+        }
+    }
+
+    transaction.insert_glob((input, key), result.id());
+    Ok(result)
+}
+
+/// Target of a symlink at `from` pointing to `to`, both relative to the same root
+fn relative_link(from: &str, to: &str) -> String {
+    let from: Vec<_> = from.split('/').collect();
+    let to: Vec<_> = to.split('/').collect();
+    let from_dir = &from[..from.len() - 1];
+    let common = from_dir
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(to.len() - 1);
+    let mut link = vec![".."; from_dir.len() - common];
+    link.extend_from_slice(&to[common..]);
+    link.join("/")
+}
+
+pub fn lowercase<'a>(
+    transaction: &'a cache::Transaction,
+    root: &str,
+    input: git2::Oid,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    if let Some(cached) = transaction.get_glob((input, key)) {
+        return Ok(repo.find_tree(cached)?);
+    }
+
+    let tree = repo.find_tree(input)?;
+    let mut result = DirBuilder::new(repo, None)?;
+    let mut names = std::collections::HashMap::<String, String>::new();
+
+    for entry in tree.iter() {
+        let name = entry.name().ok_or_else(|| josh_error("INVALID_FILENAME"))?;
+        let lower = name.to_lowercase();
+        if let Some(other) = names.insert(lower.clone(), name.to_string()) {
+            let path = |name| format!("{}{}{}", root, if root.is_empty() { "" } else { "/" }, name);
+            return Err(josh_error(&format!(
+                "Path collision in \":lowercase\": {:?} and {:?}",
+                path(other.as_str()),
+                path(name)
+            )));
+        }
+
+        let id = if entry.kind() == Some(git2::ObjectType::Tree) {
+            let root = format!("{}{}{}", root, if root.is_empty() { "" } else { "/" }, name);
+            lowercase(transaction, &root, entry.id(), key)?.id()
+        } else {
+            entry.id()
+        };
+        result.set(Path::new(&lower), id, entry.filemode());
+    }
+
+    let result = result.write()?;
+    transaction.insert_glob((input, key), result.id());
+    Ok(result)
+}
+
+/// Remove the extension `.ext` from the names of all files that have it, so with `tmpl`
+/// `foo.tmpl` becomes `foo`. Files named just `.ext` are kept as they are.
+pub fn strip_extension<'a>(
+    transaction: &'a cache::Transaction,
+    root: &str,
+    ext: &str,
+    input: git2::Oid,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    if let Some(cached) = transaction.get_glob((input, key)) {
+        return Ok(repo.find_tree(cached)?);
+    }
+
+    let tree = repo.find_tree(input)?;
+    let mut result = DirBuilder::new(repo, None)?;
+    let mut names = std::collections::HashMap::<String, String>::new();
+    let suffix = format!(".{}", ext);
+
+    for entry in tree.iter() {
+        let name = entry.name().ok_or_else(|| josh_error("INVALID_FILENAME"))?;
+        let path = |name| format!("{}{}{}", root, if root.is_empty() { "" } else { "/" }, name);
+        let (stripped, id) = match entry.kind() {
+            Some(git2::ObjectType::Tree) => (
+                name,
+                strip_extension(transaction, &path(name), ext, entry.id(), key)?.id(),
+            ),
+            Some(git2::ObjectType::Blob) => (
+                name.strip_suffix(&suffix)
+                    .filter(|stem| !stem.is_empty())
+                    .unwrap_or(name),
+                entry.id(),
+            ),
+            _ => (name, entry.id()),
+        };
+        if let Some(other) = names.insert(stripped.to_string(), name.to_string()) {
+            return Err(josh_error(&format!(
+                "Path collision in \":strip-ext\": {:?} and {:?}",
+                path(other.as_str()),
+                path(name)
+            )));
+        }
+        result.set(Path::new(stripped), id, entry.filemode());
+    }
+
+    let result = result.write()?;
+    transaction.insert_glob((input, key), result.id());
+    Ok(result)
+}
+
+/// Move all files to the top level of the tree, naming each of them by its full path
+/// with the slashes replaced by `sep`, so `a/b/c` becomes `a__b__c` with `__`.
+/// Fails if a name already contains `sep`, as the resulting names would not be unique.
+pub fn flatten<'a>(
+    transaction: &'a cache::Transaction,
+    sep: &str,
+    input: git2::Oid,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    if let Some(cached) = transaction.get_glob((input, key)) {
+        return Ok(repo.find_tree(cached)?);
+    }
+
+    let tree = repo.find_tree(input)?;
+    let mut result = repo.treebuilder(None)?;
+    let mut error = None;
+
+    tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+        let Some(name) = entry.name() else {
+            error = Some(josh_error("INVALID_FILENAME"));
+            return git2::TreeWalkResult::Abort;
+        };
+        if name.contains(sep) {
+            error = Some(josh_error(&format!(
+                "Path collision in \":flatten\": {:?} contains the separator {:?}",
+                format!("{}{}", root, name),
+                sep
+            )));
+            return git2::TreeWalkResult::Abort;
+        }
+        if entry.kind() == Some(git2::ObjectType::Tree) {
+            return git2::TreeWalkResult::Ok;
+        }
+        let flat = format!("{}{}", root.replace('/', sep), name);
+        if let Err(e) = result.insert(&flat, entry.id(), entry.filemode()) {
+            error = Some(e.into());
+            return git2::TreeWalkResult::Abort;
+        }
+        git2::TreeWalkResult::Ok
+    })
+    .or_else(|e| if error.is_some() { Ok(()) } else { Err(e) })?;
+
+    if let Some(error) = error {
+        return Err(error);
+    }
+
+    let result = repo.find_tree(result.write()?)?;
+    transaction.insert_glob((input, key), result.id());
+    Ok(result)
+}
+
+/// Replace each chain of directories that only contain a single directory by one
+/// directory, named by joining the names of the chain with `_`
+pub fn collapse<'a>(
+    transaction: &'a cache::Transaction,
+    root: &str,
+    input: git2::Oid,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    if let Some(cached) = transaction.get_glob((input, key)) {
+        return Ok(repo.find_tree(cached)?);
+    }
+
+    let tree = repo.find_tree(input)?;
+    let mut result = DirBuilder::new(repo, None)?;
+    let mut names = std::collections::HashSet::<String>::new();
+
+    for entry in tree.iter() {
+        let mut name = entry
+            .name()
+            .ok_or_else(|| josh_error("INVALID_FILENAME"))?
+            .to_string();
+        let mut id = entry.id();
+        if entry.kind() == Some(git2::ObjectType::Tree) {
+            loop {
+                let subtree = repo.find_tree(id)?;
+                let child = match subtree.iter().next() {
+                    Some(child)
+                        if subtree.len() == 1 && child.kind() == Some(git2::ObjectType::Tree) =>
+                    {
+                        child
+                    }
+                    _ => break,
+                };
+                name = format!("{}_{}", name, child.name().unwrap_or_default());
+                id = child.id();
             }
+            let root = format!("{}{}{}", root, if root.is_empty() { "" } else { "/" }, name);
+            id = collapse(transaction, &root, id, key)?.id();
+        }
+        if !names.insert(name.clone()) {
+            return Err(josh_error(&format!(
+                "Path collision in \":collapse\": {:?}",
+                format!("{}{}{}", root, if root.is_empty() { "" } else { "/" }, name)
+            )));
+        }
+        result.set(Path::new(&name), id, entry.filemode());
+    }
+
+    let result = result.write()?;
+    transaction.insert_glob((input, key), result.id());
+    Ok(result)
+}
+
+/// Wrap every file at the top level of `input` into a directory of the same name,
+/// containing it as `child`. Directories and submodules are kept as they are.
+pub fn explode<'a>(
+    transaction: &'a cache::Transaction,
+    child: &str,
+    input: git2::Oid,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    if let Some(cached) = transaction.get_glob((input, key)) {
+        return Ok(repo.find_tree(cached)?);
+    }
+
+    let tree = repo.find_tree(input)?;
+    let mut result = DirBuilder::new(repo, None)?;
+
+    for entry in tree.iter() {
+        let name = entry.name().ok_or_else(|| josh_error("INVALID_FILENAME"))?;
+        if entry.kind() == Some(git2::ObjectType::Blob) {
+            let mut dir = DirBuilder::new(repo, None)?;
+            dir.set(Path::new(child), entry.id(), entry.filemode());
+            result.set(Path::new(name), dir.write()?.id(), 0o0040000);
+        } else {
+            result.set(Path::new(name), entry.id(), entry.filemode());
         }
     }
+
+    let result = result.write()?;
+    transaction.insert_glob((input, key), result.id());
     Ok(result)
 }
 
+/// Keep only the files of `input` that have no file at the same path in `base`.
+/// Files that exist in `base` are removed, whether their content changed or not.
+pub fn added_since<'a>(
+    transaction: &'a cache::Transaction,
+    input: git2::Oid,
+    base: git2::Oid,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    if let Some(cached) = transaction.get_glob((input, key)) {
+        return Ok(repo.find_tree(cached)?);
+    }
+
+    let result = added_below(transaction, input, Some(repo.find_tree(base)?))?;
+    let result = repo.find_tree(result)?;
+    transaction.insert_glob((input, key), result.id());
+    Ok(result)
+}
+
+fn added_below(
+    transaction: &cache::Transaction,
+    input: git2::Oid,
+    base: Option<git2::Tree>,
+) -> JoshResult<git2::Oid> {
+    let Some(base) = base else {
+        return Ok(input);
+    };
+    if base.id() == input {
+        return Ok(empty_id());
+    }
+    transaction.check_deadline()?;
+    let repo = transaction.repo();
+    let tree = repo.find_tree(input)?;
+    let mut result = DirBuilder::new(repo, None)?;
+
+    for entry in tree.iter() {
+        let name = entry.name().ok_or_else(|| josh_error("INVALID_FILENAME"))?;
+        let same = base.get_name(name);
+        let in_base = |kind| same.as_ref().and_then(|e| e.kind()) == Some(kind);
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => {
+                let base = match &same {
+                    Some(e) if in_base(git2::ObjectType::Tree) => Some(repo.find_tree(e.id())?),
+                    _ => None,
+                };
+                result.set(
+                    Path::new(name),
+                    added_below(transaction, entry.id(), base)?,
+                    0o0040000,
+                );
+            }
+            Some(kind) if !in_base(kind) => {
+                result.set(Path::new(name), entry.id(), entry.filemode())
+            }
+            _ => {}
+        }
+    }
+
+    Ok(result.write()?.id())
+}
+
+/// Keep only the files for whose full path `pred` returns true. Unlike `remove_pred`
+/// only the result for the whole tree is cached, so `pred` may depend on more than the
+/// content of the directory a file is in.
+pub fn keep_paths<'a>(
+    transaction: &'a cache::Transaction,
+    input: git2::Oid,
+    pred: &dyn Fn(&Path) -> bool,
+    key: git2::Oid,
+) -> JoshResult<git2::Tree<'a>> {
+    let repo = transaction.repo();
+    if let Some(cached) = transaction.get_glob((input, key)) {
+        return Ok(repo.find_tree(cached)?);
+    }
+
+    let result = keep_paths_below(transaction, Path::new(""), input, &|p, _| pred(p))?;
+    let result = repo.find_tree(result)?;
+    transaction.insert_glob((input, key), result.id());
+    Ok(result)
+}
+
+/// Keep only the files for whose full path and blob id `pred` returns true, without
+/// caching anything
+pub fn keep_files(
+    transaction: &cache::Transaction,
+    input: git2::Oid,
+    pred: &dyn Fn(&Path, git2::Oid) -> bool,
+) -> JoshResult<git2::Oid> {
+    keep_paths_below(transaction, Path::new(""), input, pred)
+}
+
+fn keep_paths_below(
+    transaction: &cache::Transaction,
+    root: &Path,
+    input: git2::Oid,
+    pred: &dyn Fn(&Path, git2::Oid) -> bool,
+) -> JoshResult<git2::Oid> {
+    transaction.check_deadline()?;
+    let repo = transaction.repo();
+    let tree = repo.find_tree(input)?;
+    let mut result = DirBuilder::new(repo, None)?;
+
+    for entry in tree.iter() {
+        let name = entry.name().ok_or_else(|| josh_error("INVALID_FILENAME"))?;
+        let path = root.join(name);
+        match entry.kind() {
+            Some(git2::ObjectType::Blob) if pred(&path, entry.id()) => {
+                result.set(Path::new(name), entry.id(), entry.filemode())
+            }
+            Some(git2::ObjectType::Tree) => result.set(
+                Path::new(name),
+                keep_paths_below(transaction, &path, entry.id(), pred)?,
+                0o0040000,
+            ),
+            _ => {}
+        }
+    }
+
+    Ok(result.write()?.id())
+}
+
 pub fn remove_pred<'a>(
     transaction: &'a cache::Transaction,
     root: &str,
@@ -100,26 +597,31 @@ pub fn remove_pred<'a>(
     key: git2::Oid,
 ) -> JoshResult<git2::Tree<'a>> {
     let repo = transaction.repo();
-    if let Some(cached) = transaction.get_glob((input, key)) {
+    // `pred` sees the full path, so the same subtree at another path can have a
+    // different result
+    let cache_key = if root.is_empty() {
+        key
+    } else {
+        git2::Oid::hash_object(
+            git2::ObjectType::Blob,
+            format!("{}:{}", key, root).as_bytes(),
+        )?
+    };
+    if let Some(cached) = transaction.get_glob((input, cache_key)) {
         return Ok(repo.find_tree(cached)?);
     }
+    transaction.check_deadline()?;
     rs_tracing::trace_scoped!("remove_pred X", "root": root);
 
     let tree = repo.find_tree(input)?;
-    let mut result = empty(repo);
+    let mut result = DirBuilder::new(repo, None)?;
 
     for entry in tree.iter() {
         let name = entry.name().ok_or_else(|| josh_error("INVALID_FILENAME"))?;
         let path = std::path::PathBuf::from(root).join(name);
 
         if entry.kind() == Some(git2::ObjectType::Blob) && pred(&path, true) {
-            result = replace_child(
-                repo,
-                Path::new(entry.name().ok_or_else(|| josh_error("no name"))?),
-                entry.id(),
-                entry.filemode(),
-                &result,
-            )?;
+            result.set(Path::new(name), entry.id(), entry.filemode());
         }
 
         if entry.kind() == Some(git2::ObjectType::Tree) {
@@ -142,18 +644,13 @@ pub fn remove_pred<'a>(
             };
 
             if s != empty_id() {
-                result = replace_child(
-                    repo,
-                    Path::new(entry.name().ok_or_else(|| josh_error("no name"))?),
-                    s,
-                    0o0040000,
-                    &result,
-                )?;
+                result.set(Path::new(name), s, 0o0040000);
             }
         }
     }
 
-    transaction.insert_glob((input, key), result.id());
+    let result = result.write()?;
+    transaction.insert_glob((input, cache_key), result.id());
     Ok(result)
 }
 
@@ -179,19 +676,18 @@ pub fn subtract(
             return Ok(input1);
         }
         rs_tracing::trace_scoped!("subtract fast");
-        let mut result_tree = tree1.clone();
+        let mut result_tree = DirBuilder::new(repo, Some(&tree1))?;
 
         for entry in tree2.iter() {
             if let Some(e) = tree1.get_name(entry.name().ok_or_else(|| josh_error("no name"))?) {
-                result_tree = replace_child(
-                    repo,
+                result_tree.set(
                     Path::new(entry.name().ok_or_else(|| josh_error("no name"))?),
                     subtract(transaction, e.id(), entry.id())?,
                     e.filemode(),
-                    &result_tree,
-                )?;
+                );
             }
         }
+        let result_tree = result_tree.write()?;
 
         transaction.insert_subtract((input1, input2), result_tree.id());
 
@@ -210,16 +706,39 @@ fn replace_child<'a>(
     mode: i32,
     full_tree: &git2::Tree,
 ) -> JoshResult<git2::Tree<'a>> {
-    let full_tree_id = {
-        let mut builder = repo.treebuilder(Some(full_tree))?;
+    let mut builder = DirBuilder::new(repo, Some(full_tree))?;
+    builder.set(child, oid, mode);
+    builder.write()
+}
+
+/// Collects the entries of a single directory. Unlike repeated calls to `replace_child`
+/// the tree object is only written once, when all entries are known, so no intermediate
+/// trees end up in the object database.
+struct DirBuilder<'a> {
+    repo: &'a git2::Repository,
+    builder: git2::TreeBuilder<'a>,
+}
+
+impl<'a> DirBuilder<'a> {
+    fn new(repo: &'a git2::Repository, base: Option<&git2::Tree>) -> JoshResult<Self> {
+        Ok(DirBuilder {
+            repo,
+            builder: repo.treebuilder(base)?,
+        })
+    }
+
+    /// Insert or replace an entry, a zero or empty tree `oid` removes it
+    fn set(&mut self, child: &Path, oid: git2::Oid, mode: i32) {
         if oid == git2::Oid::zero() || oid == empty_id() {
-            builder.remove(child).ok();
+            self.builder.remove(child).ok();
         } else {
-            builder.insert(child, oid, mode).ok();
+            self.builder.insert(child, oid, mode).ok();
         }
-        builder.write()?
-    };
-    return Ok(repo.find_tree(full_tree_id)?);
+    }
+
+    fn write(self) -> JoshResult<git2::Tree<'a>> {
+        Ok(self.repo.find_tree(self.builder.write()?)?)
+    }
 }
 
 pub fn insert<'a>(
@@ -457,7 +976,7 @@ pub fn trigram_index<'a>(
 
     let mut files = vec![vec![]; 8];
 
-    let mut result = empty(repo);
+    let mut result = DirBuilder::new(repo, None)?;
 
     /* 'entry: */
     for entry in tree.iter() {
@@ -561,16 +1080,14 @@ pub fn trigram_index<'a>(
             }
 
             if s.id() != empty_id() {
-                result = replace_child(repo, Path::new(name), s.id(), 0o0040000, &result)?;
+                result.set(Path::new(name), s.id(), 0o0040000);
             }
         }
     }
 
     for a in 0..arrs_sub.len() {
         if arrs_own[a].iter().any(|x| *x != 0) {
-            result = insert(
-                repo,
-                &result,
+            result.set(
                 Path::new(&format!("OWN{}", a)),
                 repo.blob(
                     arrs_own[a]
@@ -581,13 +1098,10 @@ pub fn trigram_index<'a>(
                         .as_bytes(),
                 )?,
                 0o0100644,
-            )
-            .unwrap();
+            );
         }
         if arrs_sub[a].iter().any(|x| *x != 0) {
-            result = insert(
-                repo,
-                &result,
+            result.set(
                 Path::new(&format!("SUB{}", a)),
                 repo.blob(
                     arrs_sub[a]
@@ -598,20 +1112,17 @@ pub fn trigram_index<'a>(
                         .as_bytes(),
                 )?,
                 0o0100644,
-            )
-            .unwrap();
+            );
         }
         if !files[a].is_empty() {
-            result = insert(
-                repo,
-                &result,
+            result.set(
                 Path::new(&format!("BLOBS{}", a)),
                 repo.blob(files[a].join("\n").as_bytes())?,
                 0o0100644,
-            )
-            .unwrap();
+            );
         }
     }
+    let result = result.write()?;
     transaction.insert_trigram_index(tree.id(), result.id());
     Ok(result)
 }
@@ -834,7 +1345,7 @@ pub fn invert_paths<'a>(
                 repo,
                 &result,
                 Path::new(&opath),
-                repo.blob(mpath.as_bytes())?,
+                write_blob(repo, mpath.as_bytes())?,
                 0o0100644,
             )
             .unwrap();
@@ -933,9 +1444,52 @@ pub fn compose_fast(
     Ok(repo.find_tree(result)?)
 }
 
+/// Paths that exist in both trees with different content. When overlaying `winner` onto
+/// `loser` the content of `winner` is kept at those paths.
+pub fn conflicts(
+    repo: &git2::Repository,
+    winner: git2::Oid,
+    loser: git2::Oid,
+    root: &str,
+) -> JoshResult<Vec<String>> {
+    let mut r = vec![];
+    if winner == loser {
+        return Ok(r);
+    }
+    let (winner, loser) = match (repo.find_tree(winner), repo.find_tree(loser)) {
+        (Ok(winner), Ok(loser)) => (winner, loser),
+        _ => return Ok(vec![root.to_string()]),
+    };
+    for entry in winner.iter() {
+        let name = entry.name().ok_or_else(|| josh_error("no name"))?;
+        if let Some(e) = loser.get_name(name) {
+            r.append(&mut conflicts(
+                repo,
+                entry.id(),
+                e.id(),
+                &format!("{}{}{}", root, if root.is_empty() { "" } else { "/" }, name),
+            )?);
+        }
+    }
+    Ok(r)
+}
+
 pub fn compose<'a>(
     transaction: &'a cache::Transaction,
     trees: Vec<(&Filter, git2::Tree<'a>)>,
+) -> JoshResult<git2::Tree<'a>> {
+    compose_with(transaction, trees, &|member, result| {
+        overlay(transaction, member, result)
+    })
+}
+
+/// Like `compose`, but combine the part of each member's output that it contributes
+/// with the result of the members before it using `merge`, instead of letting the
+/// member win at the paths both have
+pub fn compose_with<'a>(
+    transaction: &'a cache::Transaction,
+    trees: Vec<(&Filter, git2::Tree<'a>)>,
+    merge: &dyn Fn(git2::Oid, git2::Oid) -> JoshResult<git2::Oid>,
 ) -> JoshResult<git2::Tree<'a>> {
     rs_tracing::trace_scoped!("compose");
     let repo = transaction.repo();
@@ -960,7 +1514,16 @@ pub fn compose<'a>(
         };
         transaction.insert_unapply(*f, aid, unapplied);
         taken = repo.find_tree(overlay(transaction, taken.id(), unapplied)?)?;
-        result = repo.find_tree(overlay(transaction, subtracted.id(), result.id())?)?;
+        if transaction.log_conflicts() {
+            for path in conflicts(repo, subtracted.id(), result.id(), "")? {
+                tracing::warn!(
+                    "compose conflict at {:?}, using output of {}",
+                    path,
+                    spec(*f)
+                );
+            }
+        }
+        result = repo.find_tree(merge(subtracted.id(), result.id())?)?;
     }
 
     Ok(result)
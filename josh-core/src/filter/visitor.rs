@@ -0,0 +1,85 @@
+/*
+ * Traversal of the filter AST for analyses implemented outside of josh.
+ */
+
+use super::*;
+
+/// Callbacks for the nodes of a filter, driven by `walk`.
+/// The callbacks for filters containing other filters visit those by calling `walk`
+/// on them by default. Implementations that override them can track state around
+/// the call to `walk`, or skip parts of the tree by not calling it.
+/// Filters without a dedicated callback are passed to `visit_other`.
+pub trait FilterVisitor {
+    fn visit_nop(&mut self) {}
+    fn visit_empty(&mut self) {}
+    fn visit_subdir(&mut self, _path: &Path) {}
+    fn visit_prefix(&mut self, _path: &Path) {}
+    fn visit_file(&mut self, _path: &Path) {}
+    fn visit_glob(&mut self, _pattern: &str) {}
+    fn visit_workspace(&mut self, _path: &Path) {}
+
+    fn visit_compose(&mut self, members: &[Filter]) {
+        for f in members {
+            walk(*f, self);
+        }
+    }
+    fn visit_chain(&mut self, first: Filter, second: Filter) {
+        walk(first, self);
+        walk(second, self);
+    }
+    fn visit_subtract(&mut self, a: Filter, b: Filter) {
+        walk(a, self);
+        walk(b, self);
+    }
+    fn visit_intersect(&mut self, a: Filter, b: Filter) {
+        walk(a, self);
+        walk(b, self);
+    }
+    fn visit_exclude(&mut self, filter: Filter) {
+        walk(filter, self);
+    }
+    fn visit_rev(&mut self, filters: &[Filter]) {
+        for f in filters {
+            walk(*f, self);
+        }
+    }
+    fn visit_join(&mut self, filters: &[Filter]) {
+        for f in filters {
+            walk(*f, self);
+        }
+    }
+    fn visit_if_exists(&mut self, _path: &Path, then: Filter, otherwise: Filter) {
+        walk(then, self);
+        walk(otherwise, self);
+    }
+    fn visit_squash(&mut self, filters: &[Filter]) {
+        for f in filters {
+            walk(*f, self);
+        }
+    }
+
+    fn visit_other(&mut self, _filter: Filter) {}
+}
+
+/// Call the callback of `visitor` matching `filter`
+pub fn walk<V: FilterVisitor + ?Sized>(filter: Filter, visitor: &mut V) {
+    match to_op(filter) {
+        Op::Nop => visitor.visit_nop(),
+        Op::Empty => visitor.visit_empty(),
+        Op::Subdir(path) => visitor.visit_subdir(&path),
+        Op::Prefix(path) => visitor.visit_prefix(&path),
+        Op::File(path) => visitor.visit_file(&path),
+        Op::Glob(pattern) => visitor.visit_glob(&pattern),
+        Op::Workspace(path) => visitor.visit_workspace(&path),
+        Op::Compose(filters) => visitor.visit_compose(&filters),
+        Op::Chain(a, b) => visitor.visit_chain(a, b),
+        Op::Subtract(a, b) => visitor.visit_subtract(a, b),
+        Op::Intersect(a, b) => visitor.visit_intersect(a, b),
+        Op::Exclude(b) => visitor.visit_exclude(b),
+        Op::Rev(filters) => visitor.visit_rev(&filters.into_values().collect::<Vec<_>>()),
+        Op::Join(filters) => visitor.visit_join(&filters.into_values().collect::<Vec<_>>()),
+        Op::IfExists(path, a, b) => visitor.visit_if_exists(&path, a, b),
+        Op::Squash(Some(ids)) => visitor.visit_squash(&ids.into_values().collect::<Vec<_>>()),
+        _ => visitor.visit_other(filter),
+    }
+}
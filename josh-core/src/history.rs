@@ -1,6 +1,20 @@
 use super::*;
 use std::collections::HashMap;
 
+/// State of a history walk, reported through `Transaction::set_progress`.
+/// Nested walks, for example for the history of workspaces, report their own progress.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    pub filter: filter::Filter,
+    /// Number of commits filtered so far
+    pub walked: usize,
+    /// Number of new commits reachable from the input. For linear filters only the
+    /// first parents are walked, so the walk can end before reaching this.
+    pub total: usize,
+    /// Nesting depth of the walk
+    pub depth: usize,
+}
+
 pub fn walk2(
     filter: filter::Filter,
     input: git2::Oid,
@@ -8,7 +22,7 @@ pub fn walk2(
 ) -> JoshResult<()> {
     rs_tracing::trace_scoped!("walk2","spec":filter::spec(filter), "id": input.to_string());
 
-    ok_or!(transaction.repo().find_commit(input), {
+    let commit = ok_or!(transaction.repo().find_commit(input), {
         return Ok(());
     });
 
@@ -16,6 +30,12 @@ pub fn walk2(
         return Ok(());
     }
 
+    // Filtering the ancestors first would only compute results that are not used
+    if filter::filters_from_tip(filter) {
+        filter::apply_to_commit(filter, &commit, transaction)?;
+        return Ok(());
+    }
+
     let (known, n_new) = find_known(filter, input, transaction)?;
 
     let walk = {
@@ -41,25 +61,40 @@ pub fn walk2(
 
     let walks = transaction.new_walk();
 
-    for original_commit_id in walk {
-        if !filter::apply_to_commit3(
-            filter,
-            &transaction.repo().find_commit(original_commit_id?)?,
-            transaction,
-        )? {
-            break;
-        }
+    // The walk is ended also when filtering a commit fails, for example because the
+    // deadline of the transaction has passed, so the transaction can be used again
+    let filter_commits = || -> JoshResult<()> {
+        for original_commit_id in walk {
+            if !filter::apply_to_commit3(
+                filter,
+                &transaction.repo().find_commit(original_commit_id?)?,
+                transaction,
+            )? {
+                break;
+            }
 
-        n_commits += 1;
-        if n_commits % 1000 == 0 {
-            log::debug!(
-                "{} {} commits filtered, {} misses",
-                " ->".repeat(walks),
-                n_commits,
-                transaction.misses() - n_misses,
-            );
-            n_misses = transaction.misses();
+            n_commits += 1;
+            transaction.report_progress(&Progress {
+                filter,
+                walked: n_commits,
+                total: n_new,
+                depth: walks,
+            });
+            if n_commits % 1000 == 0 {
+                log::debug!(
+                    "{} {} commits filtered, {} misses",
+                    " ->".repeat(walks),
+                    n_commits,
+                    transaction.misses() - n_misses,
+                );
+                n_misses = transaction.misses();
+            }
         }
+        Ok(())
+    };
+    if let Err(e) = filter_commits() {
+        transaction.end_walk();
+        return Err(e);
     }
 
     log::info!(
@@ -476,13 +511,16 @@ pub fn unapply_filter(
 
         let new_trees = match new_trees {
             Ok(new_trees) => new_trees,
-            Err(JoshError(msg)) => {
-                return Err(josh_error(&format!(
-                    "\nCan't apply {:?} ({:?})\n{}",
-                    commit_message,
-                    module_commit.id(),
-                    msg
-                )))
+            Err(e) => {
+                return Err(josh_error_kind(
+                    e.kind(),
+                    &format!(
+                        "\nCan't apply {:?} ({:?})\n{}",
+                        commit_message,
+                        module_commit.id(),
+                        e.0
+                    ),
+                ))
             }
         };
 
@@ -636,21 +674,34 @@ fn select_parent_commits<'a>(
     filtered_tree_id: git2::Oid,
     filtered_parent_commits: Vec<&'a git2::Commit>,
 ) -> Vec<&'a git2::Commit<'a>> {
-    let affects_filtered = filtered_parent_commits
-        .iter()
-        .any(|x| filtered_tree_id != x.tree_id());
-
-    let all_diffs_empty = original_commit
-        .parents()
-        .all(|x| x.tree_id() == original_commit.tree_id());
+    let keep = keeps_parents(
+        original_commit.tree_id(),
+        original_commit.parents().map(|x| x.tree_id()),
+        filtered_tree_id,
+        filtered_parent_commits.iter().map(|x| x.tree_id()),
+    );
 
-    if affects_filtered || all_diffs_empty {
+    if keep {
         filtered_parent_commits
     } else {
         vec![]
     }
 }
 
+/// Whether a filtered commit gets its own commit on top of its filtered parents, instead
+/// of being dropped. This is the case if filtering changed the tree compared to one of the
+/// parents, or if the original commit did not change anything either.
+fn keeps_parents(
+    original_tree: git2::Oid,
+    mut original_parent_trees: impl Iterator<Item = git2::Oid>,
+    filtered_tree: git2::Oid,
+    mut filtered_parent_trees: impl Iterator<Item = git2::Oid>,
+) -> bool {
+    let affects_filtered = filtered_parent_trees.any(|x| filtered_tree != x);
+    let all_diffs_empty = original_parent_trees.all(|x| x == original_tree);
+    affects_filtered || all_diffs_empty
+}
+
 pub fn remove_commit_signature<'a>(
     original_commit: &'a git2::Commit,
     filtered_parent_ids: Vec<git2::Oid>,
@@ -789,3 +840,255 @@ fn is_empty_root(repo: &git2::Repository, tree: &git2::Tree) -> bool {
     }
     all_empty
 }
+
+/// Commit of a history that is simulated instead of written, see `filtered_commit_count`
+struct SimulatedCommit {
+    tree: git2::Oid,
+    parents: Vec<usize>,
+}
+
+/// Number of commits in the history produced by filtering `commit` with `filter`.
+/// For filters that only change trees, the history is simulated with the same decisions
+/// about dropping commits as the actual filtering, without writing any commits. Other
+/// filters are applied normally and the resulting commits counted.
+pub fn filtered_commit_count(
+    transaction: &cache::Transaction,
+    filter: filter::Filter,
+    commit: git2::Oid,
+) -> JoshResult<usize> {
+    let repo = transaction.repo();
+    let filter = filter::optimize(filter);
+    if filter == filter::empty() {
+        return Ok(0);
+    }
+    let parts = filter::as_chain_vec(filter);
+    if !parts.iter().all(|f| filter::is_tree_filter(*f)) {
+        let filtered = filter::apply_to_commit(filter, &repo.find_commit(commit)?, transaction)?;
+        if filtered == git2::Oid::zero() {
+            return Ok(0);
+        }
+        let mut walk = repo.revwalk()?;
+        walk.push(filtered)?;
+        return Ok(walk.count());
+    }
+
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    walk.push(commit)?;
+    let mut index = HashMap::new();
+    let mut commits = vec![];
+    for id in walk {
+        let c = repo.find_commit(id?)?;
+        index.insert(c.id(), commits.len());
+        commits.push(SimulatedCommit {
+            tree: c.tree_id(),
+            parents: c.parent_ids().map(|p| index[&p]).collect(),
+        });
+    }
+    let mut tip = commits.len().checked_sub(1);
+
+    // Chains are filtered one part after the other, each filtering the history
+    // produced by the previous part
+    for part in parts {
+        let (filtered, mapping) = simulate_filter(transaction, part, &commits)?;
+        tip = tip.and_then(|t| mapping[t]);
+        commits = filtered;
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    let mut todo: Vec<_> = tip.into_iter().collect();
+    while let Some(c) = todo.pop() {
+        if seen.insert(c) {
+            todo.extend(&commits[c].parents);
+        }
+    }
+    Ok(seen.len())
+}
+
+/// Filter a simulated history with a tree filter, following `create_filtered_commit2`.
+/// Returns the filtered history together with the filtered commit of each input commit,
+/// `None` standing for the zero commit.
+fn simulate_filter(
+    transaction: &cache::Transaction,
+    filter: filter::Filter,
+    commits: &[SimulatedCommit],
+) -> JoshResult<(Vec<SimulatedCommit>, Vec<Option<usize>>)> {
+    let repo = transaction.repo();
+    let empty = filter::tree::empty_id();
+    let mut filtered: Vec<SimulatedCommit> = vec![];
+    let mut mapping: Vec<Option<usize>> = vec![];
+
+    for commit in commits {
+        let tree = filter::apply(transaction, filter, repo.find_tree(commit.tree)?)?.id();
+        let filtered_parent_ids: Vec<_> = commit.parents.iter().map(|p| mapping[*p]).collect();
+        let mut parents: Vec<usize> = filtered_parent_ids.iter().flatten().copied().collect();
+
+        if parents.iter().any(|p| filtered[*p].tree == empty) {
+            let is_initial_merge = filtered_parent_ids.len() > 1
+                && (filtered_parent_ids.contains(&None)
+                    || !have_common_ancestor(&filtered, &parents));
+            if is_initial_merge {
+                parents.retain(|p| filtered[*p].tree != empty);
+            }
+        }
+
+        let keep = keeps_parents(
+            commit.tree,
+            commit.parents.iter().map(|p| commits[*p].tree),
+            tree,
+            parents.iter().map(|p| filtered[*p].tree),
+        );
+        let selected = if keep { parents.clone() } else { vec![] };
+
+        if selected.is_empty()
+            && !(commit.parents.is_empty() && is_empty_root(repo, &repo.find_tree(commit.tree)?))
+        {
+            if let Some(first) = parents.first() {
+                mapping.push(Some(*first));
+                continue;
+            }
+            if tree == empty {
+                mapping.push(None);
+                continue;
+            }
+        }
+
+        mapping.push(Some(filtered.len()));
+        filtered.push(SimulatedCommit {
+            tree,
+            parents: selected,
+        });
+    }
+    Ok((filtered, mapping))
+}
+
+/// Whether all of `heads` share an ancestor, like `merge_base_many` finding a base
+fn have_common_ancestor(commits: &[SimulatedCommit], heads: &[usize]) -> bool {
+    let ancestors = |head: usize| {
+        let mut seen = std::collections::HashSet::new();
+        let mut todo = vec![head];
+        while let Some(c) = todo.pop() {
+            if seen.insert(c) {
+                todo.extend(&commits[c].parents);
+            }
+        }
+        seen
+    };
+    let mut common = ancestors(heads[0]);
+    for head in &heads[1..] {
+        let other = ancestors(*head);
+        common.retain(|c| other.contains(c));
+    }
+    !common.is_empty()
+}
+
+/// Rewrite the history of `commit` so that of every path only the `k` most recent
+/// versions are kept, for `:keep-versions`. Versions are the distinct contents a path
+/// had in any commit reachable from `commit`, most recent in the order of a topological
+/// walk from `commit`. Older versions are removed from the trees, and commits that are
+/// left without a change of their own are dropped.
+pub fn keep_versions(
+    transaction: &cache::Transaction,
+    commit: &git2::Commit,
+    k: usize,
+) -> JoshResult<git2::Oid> {
+    let repo = transaction.repo();
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+    walk.push(commit.id())?;
+    let commits = walk.collect::<Result<Vec<_>, _>>()?;
+
+    let mut kept = HashMap::new();
+    let mut seen = std::collections::HashSet::new();
+    for id in &commits {
+        let tree = repo.find_commit(*id)?.tree_id();
+        collect_versions(
+            transaction,
+            tree,
+            std::path::Path::new(""),
+            k,
+            &mut kept,
+            &mut seen,
+        )?;
+    }
+
+    let mut rewritten: HashMap<git2::Oid, Option<git2::Oid>> = HashMap::new();
+    for id in commits.iter().rev() {
+        let original = repo.find_commit(*id)?;
+        let tree = filter::tree::keep_files(transaction, original.tree_id(), &|path, id| {
+            kept.get(path).is_some_and(|v: &Vec<_>| v.contains(&id))
+        })?;
+
+        let mut parents = vec![];
+        for p in original.parent_ids() {
+            if let Some(Some(p)) = rewritten.get(&p) {
+                if !parents.contains(p) {
+                    parents.push(*p);
+                }
+            }
+        }
+        let parents = parents
+            .iter()
+            .map(|p| repo.find_commit(*p))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let unchanged = match parents.as_slice() {
+            [] => tree == filter::tree::empty_id(),
+            [parent] => parent.tree_id() == tree,
+            _ => false,
+        };
+        let filtered = if unchanged {
+            parents.first().map(|p| p.id())
+        } else {
+            Some(rewrite_commit(
+                repo,
+                &original,
+                &parents.iter().collect::<Vec<_>>(),
+                RewriteData {
+                    tree: repo.find_tree(tree)?,
+                    author: None,
+                    committer: None,
+                    message: None,
+                },
+                false,
+            )?)
+        };
+        rewritten.insert(*id, filtered);
+    }
+
+    Ok(rewritten
+        .get(&commit.id())
+        .copied()
+        .flatten()
+        .unwrap_or_else(git2::Oid::zero))
+}
+
+/// Add the contents of the files in `tree` to the versions of their paths, until a path
+/// has `k` of them
+fn collect_versions(
+    transaction: &cache::Transaction,
+    tree: git2::Oid,
+    root: &std::path::Path,
+    k: usize,
+    kept: &mut HashMap<std::path::PathBuf, Vec<git2::Oid>>,
+    seen: &mut std::collections::HashSet<(std::path::PathBuf, git2::Oid)>,
+) -> JoshResult<()> {
+    // The same directory at the same path can't contribute new versions
+    if !seen.insert((root.to_owned(), tree)) {
+        return Ok(());
+    }
+    transaction.check_deadline()?;
+    for entry in transaction.repo().find_tree(tree)?.iter() {
+        let name = entry.name().ok_or_else(|| josh_error("INVALID_FILENAME"))?;
+        let path = root.join(name);
+        if entry.kind() == Some(git2::ObjectType::Tree) {
+            collect_versions(transaction, entry.id(), &path, k, kept, seen)?;
+        } else {
+            let versions = kept.entry(path).or_default();
+            if versions.len() < k && !versions.contains(&entry.id()) {
+                versions.push(entry.id());
+            }
+        }
+    }
+    Ok(())
+}
@@ -135,11 +135,56 @@ pub fn to_filtered_ref(upstream_repo: &str, filter_spec: &str) -> String {
     )
 }
 
+/// The failures callers may want to handle differently from others
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Other,
+    /// The deadline of a transaction has passed
+    Timeout,
+    /// Filters refer to each other in a cycle
+    FilterCycle,
+}
+
+/// The message is public, the kind is only set by `josh_error_kind`.
 #[derive(Debug, Clone)]
-pub struct JoshError(pub String);
+pub struct JoshError(pub String, ErrorKind);
 
 pub fn josh_error(s: &str) -> JoshError {
-    JoshError(s.to_owned())
+    josh_error_kind(ErrorKind::Other, s)
+}
+
+pub fn josh_error_kind(kind: ErrorKind, s: &str) -> JoshError {
+    JoshError(s.to_owned(), kind)
+}
+
+/// Error returned when the deadline of a transaction has passed
+pub fn timeout_error() -> JoshError {
+    josh_error_kind(ErrorKind::Timeout, "deadline exceeded")
+}
+
+/// Error returned when filters refer to each other in a cycle, listing the specs of
+/// the filters on the cycle with the first one repeated at the end
+pub fn filter_cycle_error(filters: &[String]) -> JoshError {
+    josh_error_kind(
+        ErrorKind::FilterCycle,
+        &format!("filter cycle: {}", filters.join(" -> ")),
+    )
+}
+
+impl JoshError {
+    pub fn kind(&self) -> ErrorKind {
+        self.1
+    }
+
+    /// Whether this error was returned because the deadline of a transaction passed
+    pub fn is_timeout(&self) -> bool {
+        self.kind() == ErrorKind::Timeout
+    }
+
+    /// Whether this error was returned because filters refer to each other in a cycle
+    pub fn is_filter_cycle(&self) -> bool {
+        self.kind() == ErrorKind::FilterCycle
+    }
 }
 
 impl std::fmt::Display for JoshError {
@@ -396,10 +441,10 @@ pub fn get_acl(
                     })
                 })?;
                 if let Err(e) = lists.0 {
-                    return Some(Err(JoshError(format!("Error parsing whitelist: {}", e))));
+                    return Some(Err(josh_error(&format!("Error parsing whitelist: {}", e))));
                 }
                 if let Err(e) = lists.1 {
-                    return Some(Err(JoshError(format!("Error parsing blacklist: {}", e))));
+                    return Some(Err(josh_error(&format!("Error parsing blacklist: {}", e))));
                 }
                 if let Ok(w) = lists.0 {
                     whitelist = filter::compose(whitelist, w);
@@ -3,7 +3,6 @@
 #[macro_use]
 extern crate rs_tracing;
 
-use josh::JoshError;
 use std::fs::read_to_string;
 use std::io::Write;
 
@@ -80,6 +79,12 @@ fn make_app() -> clap::Command {
                 .help("Don't load cache")
                 .short('n'),
         )
+        .arg(
+            clap::Arg::new("progress")
+                .action(clap::ArgAction::SetTrue)
+                .help("Show the number of filtered commits on stderr")
+                .long("progress"),
+        )
         .arg(
             clap::Arg::new("pack")
                 .action(clap::ArgAction::SetTrue)
@@ -146,7 +151,22 @@ fn run_filter(args: Vec<String>) -> josh::JoshResult<i32> {
 
     let mut filterobj = josh::filter::parse(&specstr)?;
 
-    let transaction = josh::cache::Transaction::open_from_env(!args.get_flag("no-cache"))?;
+    let mut transaction = josh::cache::Transaction::open_from_env(!args.get_flag("no-cache"))?;
+    if args.get_flag("progress") {
+        transaction.set_progress(|p| {
+            if p.walked % 100 == 0 || p.walked == p.total {
+                eprint!(
+                    "\r{}{}/{} commits filtered",
+                    " ->".repeat(p.depth),
+                    p.walked,
+                    p.total
+                );
+            }
+            if p.walked == p.total {
+                eprintln!();
+            }
+        });
+    }
 
     let repo = transaction.repo();
     let input_ref = args.get_one::<String>("input").unwrap();
@@ -206,6 +226,7 @@ fn run_filter(args: Vec<String>) -> josh::JoshResult<i32> {
     let odb = repo.odb()?;
     let mp = if args.get_flag("pack") {
         let mempack = odb.add_new_mempack_backend(1000)?;
+        transaction.set_in_memory(true);
         Some(mempack)
     } else {
         None
@@ -218,6 +239,7 @@ fn run_filter(args: Vec<String>) -> josh::JoshResult<i32> {
         if args.get_flag("cache-stats") {
             josh::cache::print_stats();
         }
+        josh::cache::flush().ok();
         if let Some(mempack) = mp {
             let mut buf = git2::Buf::new();
             mempack.dump(repo, &mut buf).unwrap();
@@ -380,8 +402,8 @@ fn run_filter(args: Vec<String>) -> josh::JoshResult<i32> {
             Ok(rewritten) => {
                 repo.reference(&input_ref, rewritten, true, "unapply_filter")?;
             }
-            Err(JoshError(msg)) => {
-                println!("{}", msg);
+            Err(e) => {
+                println!("{}", e.0);
                 return Ok(1);
             }
         }
@@ -440,12 +462,7 @@ fn main() {
     };
 
     std::process::exit(if let Err(e) = run_filter(args) {
-        println!(
-            "ERROR: {}",
-            match e {
-                JoshError(s) => s,
-            }
-        );
+        println!("ERROR: {}", e.0);
         1
     } else {
         0
@@ -396,9 +396,9 @@ async fn repo_update_fn(req: Request<hyper::Body>) -> josh::JoshResult<Response<
         Ok(stderr) => Response::builder()
             .status(hyper::StatusCode::OK)
             .body(hyper::Body::from(stderr)),
-        Err(josh::JoshError(stderr)) => Response::builder()
+        Err(e) => Response::builder()
             .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-            .body(hyper::Body::from(stderr)),
+            .body(hyper::Body::from(e.0)),
     }?)
 }
 
@@ -1598,9 +1598,7 @@ async fn handle_http_request(
                 .await
                 .unwrap_or_else(|e| {
                     make_response(
-                        hyper::Body::from(match e {
-                            JoshError(s) => s,
-                        }),
+                        hyper::Body::from(e.0),
                         hyper::StatusCode::INTERNAL_SERVER_ERROR,
                     )
                 })
@@ -1637,6 +1635,7 @@ async fn run_proxy() -> josh::JoshResult<i32> {
 
     josh_proxy::create_repo(&local)?;
     josh::cache::load(&local)?;
+    josh::cache::load_apply_cache(&local)?;
 
     let proxy_service = Arc::new(JoshProxyService {
         port: ARGS.port.to_string(),
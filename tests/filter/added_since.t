@@ -0,0 +1,37 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir sub
+  $ echo contents1 > unchanged
+  $ echo contents2 > modified
+  $ echo contents3 > sub/file3
+  $ git add .
+  $ git commit -q -m "add files"
+  $ BASE=$(git rev-parse HEAD)
+
+  $ mkdir -p sub/new
+  $ echo contents4 > added
+  $ echo contents5 > sub/new/file5
+  $ git add .
+  $ git commit -q -m "add files"
+  $ echo changed > modified
+  $ echo contents6 > sub/file6
+  $ git add .
+  $ git commit -q -m "change modified, add sub/file6"
+
+Only files that don't exist in the base are kept, modified files are removed
+
+  $ josh-filter :added-since=$BASE refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  added
+  sub/file6
+  sub/new/file5
+  $ git ls-tree --name-only -r refs/heads/filtered~1
+  added
+  sub/new/file5
+  $ git log --oneline refs/heads/filtered | wc -l
+  2
+
+  $ josh-filter :added-since=HEAD refs/heads/master --update refs/heads/filtered
+  ERROR: Filter ":added-since" requires a full commit id, got: "HEAD"
+  [1]
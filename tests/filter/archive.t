@@ -0,0 +1,39 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p dist/bin dist/share/doc
+  $ echo "#!/bin/sh" > dist/bin/tool
+  $ chmod +x dist/bin/tool
+  $ echo readme > dist/share/doc/README
+  $ ln -s share/doc/README dist/README
+  $ echo other > other
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter -s ':/dist:archive=tar;dist.tar' refs/heads/master --update refs/heads/filtered
+  [1] :/dist
+  [1] :archive=tar;dist.tar
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  dist.tar
+  $ git show refs/heads/filtered:dist.tar | tar -tvf -
+  lrwxrwxrwx 0/0               0 1970-01-01 00:00 README -> share/doc/README
+  drwxr-xr-x 0/0               0 1970-01-01 00:00 bin/
+  -rwxr-xr-x 0/0              10 1970-01-01 00:00 bin/tool
+  drwxr-xr-x 0/0               0 1970-01-01 00:00 share/
+  drwxr-xr-x 0/0               0 1970-01-01 00:00 share/doc/
+  -rw-r--r-- 0/0               7 1970-01-01 00:00 share/doc/README
+  $ git show refs/heads/filtered:dist.tar | tar -xOf - share/doc/README
+  readme
+
+Unrelated changes don't change the archive
+
+  $ echo changed > other
+  $ git commit -q -a -m "change other"
+  $ josh-filter ':/dist:archive=tar;dist.tar' refs/heads/master --update refs/heads/filtered2
+  $ git rev-parse refs/heads/filtered:dist.tar refs/heads/filtered2:dist.tar | uniq | wc -l
+  1
+
+  $ josh-filter ':/dist:archive=zip;dist.zip' refs/heads/master --update refs/heads/filtered
+  ERROR: Unsupported archive format in ":archive": zip, expected one of: tar
+  [1]
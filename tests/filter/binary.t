@@ -0,0 +1,33 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p src assets
+  $ echo "fn main() {}" > src/main.rs
+  $ echo "# Assets" > assets/README.md
+  $ printf "\x89PNG\r\n\x1a\n\0\0\0\rIHDR" > assets/logo.png
+  $ printf "data\0more" > src/blob.bin
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter -s :binary refs/heads/master --update refs/heads/filtered
+  [1] :binary
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  assets/logo.png
+  src/blob.bin
+
+  $ josh-filter -s :text refs/heads/master --update refs/heads/filtered
+  [1] :binary
+  [1] :text
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  assets/README.md
+  src/main.rs
+
+  $ josh-filter -s :/assets:binary refs/heads/master --update refs/heads/filtered
+  [1] :/assets
+  [1] :text
+  [2] :binary
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  logo.png
@@ -0,0 +1,44 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p src/main/java/org/example lib/a/b docs/x
+  $ echo contents1 > src/main/java/org/example/App.java
+  $ echo contents2 > src/main/java/org/example/Util.java
+  $ echo contents3 > lib/a/b/file
+  $ echo contents4 > lib/c
+  $ echo contents5 > docs/x/y
+  $ echo contents6 > README.md
+  $ git add .
+  $ git commit -q -m "add files"
+
+Chains of directories with a single subdirectory are joined, a directory with a single
+file or several entries ends the chain
+
+  $ josh-filter -s :collapse refs/heads/master --update refs/heads/filtered
+  [1] :collapse
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  README.md
+  docs_x/y
+  lib/a_b/file
+  lib/c
+  src_main_java_org_example/App.java
+  src_main_java_org_example/Util.java
+  $ git show refs/heads/filtered:lib/a_b/file
+  contents3
+
+  $ josh-filter -s :/src:collapse refs/heads/master --update refs/heads/filtered
+  [1] :/src
+  [2] :collapse
+  $ git ls-tree --name-only -r refs/heads/filtered
+  main_java_org_example/App.java
+  main_java_org_example/Util.java
+
+  $ mkdir -p lib/a_b
+  $ echo contents7 > lib/a_b/other
+  $ git add .
+  $ git commit -q -m "add colliding directory"
+
+  $ josh-filter :collapse refs/heads/master --update refs/heads/filtered
+  ERROR: Path collision in ":collapse": "lib/a_b"
+  [1]
@@ -0,0 +1,30 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p sub/dir
+  $ echo contents1 > sub/dir/file1
+  $ echo longer contents2 > sub/file2
+  $ echo contents3 > file3
+  $ chmod +x file3
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter -s :/sub:content-hash refs/heads/master --update refs/heads/filtered
+  [1] :/sub
+  [1] :content-hash
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  dir/file1
+  file2
+  $ git show refs/heads/filtered:file2
+  7df11f40034d99e88399cfd32ec5e9ce4696b6e2 17
+  $ git rev-parse master:sub/file2
+  7df11f40034d99e88399cfd32ec5e9ce4696b6e2
+
+  $ josh-filter :content-hash refs/heads/master --update refs/heads/filtered
+  $ git ls-tree -r refs/heads/filtered | awk '{print $1, $4}'
+  100755 file3
+  100644 sub/dir/file1
+  100644 sub/file2
+  $ git show refs/heads/filtered:file3
+  1cb5d64cdb55e3db2a8d6f00d596572b4cfa9d5c 10
@@ -0,0 +1,26 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p a b/c
+  $ echo same > a/x
+  $ echo same > b/c/y
+  $ echo same > a/z
+  $ echo same > top
+  $ echo other > b/other
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter -s :dedup refs/heads/master --update refs/heads/filtered
+  [1] :dedup
+
+  $ git checkout -q filtered
+  $ find . -path ./.git -prune -o -type f -print | sort
+  ./a/x
+  ./b/other
+  $ for l in $(find . -type l | sort); do echo "$l -> $(readlink $l)"; done
+  ./a/z -> x
+  ./b/c/y -> ../../a/x
+  ./top -> a/x
+  $ cat b/c/y top
+  same
+  same
@@ -0,0 +1,61 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir sub
+  $ echo contents1 > file1
+  $ echo contents2 > file2
+  $ echo contents3 > sub/file3
+  $ git add .
+  $ git commit -q -m "add files"
+  $ FROM=$(git rev-parse HEAD)
+
+  $ echo contents4 > file4
+  $ git add .
+  $ git commit -q -m "add file4"
+  $ echo changed > sub/file3
+  $ git rm -q file2
+  $ git commit -q -am "change file3, remove file2"
+  $ TO=$(git rev-parse HEAD)
+
+Added and modified files are kept with their content in the filtered commit,
+removed files are dropped
+
+  $ josh-filter :diff=$FROM..$TO refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file4
+  sub/file3
+  $ git show refs/heads/filtered:sub/file3
+  changed
+
+The same set of paths is selected in every commit of the history
+
+  $ git ls-tree --name-only -r refs/heads/filtered~1
+  file4
+  sub/file3
+  $ git show refs/heads/filtered~1:sub/file3
+  contents3
+
+Identical directories at different paths are matched by their own path
+
+  $ git init -q dup 1> /dev/null
+  $ cd dup
+  $ mkdir a b
+  $ echo a > a/f
+  $ echo b > b/f
+  $ git add .
+  $ git commit -q -m "add a and b"
+  $ FROM=$(git rev-parse HEAD)
+  $ echo changed > a/f
+  $ git commit -q -am "change a"
+  $ TO=$(git rev-parse HEAD)
+  $ echo changed > b/f
+  $ git commit -q -am "change b"
+
+  $ josh-filter :diff=$FROM..$TO refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  a/f
+  $ cd ..
+
+  $ josh-filter :diff=$FROM refs/heads/master --update refs/heads/filtered
+  ERROR: Filter ":diff" requires a range of full commit ids, got: "*" (glob)
+  [1]
@@ -0,0 +1,37 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p src/lib/docs doc tools .github
+  $ echo a > README
+  $ echo b > src/lib/README.txt
+  $ echo c > src/lib/lib.rs
+  $ echo d > src/lib/docs/design.svg
+  $ echo e > doc/index.rst
+  $ echo f > tools/CHANGELOG.md
+  $ echo g > tools/build.sh
+  $ echo h > src/main.rs
+  $ echo i > .github/CONTRIBUTING.md
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter -s :docs refs/heads/master --update refs/heads/filtered
+  [1] :docs
+
+  $ git ls-tree -r --name-only refs/heads/filtered
+  README
+  doc/index.rst
+  src/lib/README.txt
+  src/lib/docs/design.svg
+  tools/CHANGELOG.md
+
+  $ josh-filter ':subtract[:docs,::**/CHANGELOG.md]' refs/heads/master --update refs/heads/filtered
+  $ git ls-tree -r --name-only refs/heads/filtered
+  README
+  doc/index.rst
+  src/lib/README.txt
+  src/lib/docs/design.svg
+
+  $ josh-filter :/src:docs refs/heads/master --update refs/heads/filtered
+  $ git ls-tree -r --name-only refs/heads/filtered
+  lib/README.txt
+  lib/docs/design.svg
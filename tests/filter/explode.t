@@ -0,0 +1,32 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p docs
+  $ echo contents1 > a.txt
+  $ echo contents2 > b.txt
+  $ echo contents3 > docs/guide.md
+  $ git add .
+  $ git commit -q -m "add files"
+
+Every file at the top level becomes a directory of the same name holding it,
+directories are kept as they are
+
+  $ josh-filter -s :explode=content refs/heads/master --update refs/heads/filtered
+  [1] :explode=content
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  a.txt/content
+  b.txt/content
+  docs/guide.md
+  $ git show refs/heads/filtered:a.txt/content
+  contents1
+
+  $ josh-filter -s :/docs:explode=content refs/heads/master --update refs/heads/filtered
+  [1] :/docs
+  [2] :explode=content
+  $ git ls-tree --name-only -r refs/heads/filtered
+  guide.md/content
+
+  $ josh-filter :explode=a/b refs/heads/master --update refs/heads/filtered
+  ERROR: Filter ":explode" requires a file name, got: "a/b"
+  [1]
@@ -0,0 +1,33 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p src/lib docs
+  $ echo contents1 > src/lib/util.rs
+  $ echo contents2 > src/main.rs
+  $ echo contents3 > docs/README.md
+  $ echo contents4 > Cargo.toml
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter -s :flatten=__ refs/heads/master --update refs/heads/filtered
+  [1] :flatten=__
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  Cargo.toml
+  docs__README.md
+  src__lib__util.rs
+  src__main.rs
+  $ git show refs/heads/filtered:src__lib__util.rs
+  contents1
+
+  $ echo contents5 > src/my__file
+  $ git add .
+  $ git commit -q -m "add file containing the separator"
+
+  $ josh-filter :flatten=__ refs/heads/master --update refs/heads/filtered
+  ERROR: Path collision in ":flatten": "src/my__file" contains the separator "__"
+  [1]
+
+  $ josh-filter :flatten=a/b refs/heads/master --update refs/heads/filtered
+  ERROR: Filter ":flatten" requires a separator without "/", got: "a/b"
+  [1]
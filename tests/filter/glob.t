@@ -0,0 +1,53 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p api/v1 api/v2/internal lib/src docs
+  $ echo a > api/v1/service.proto
+  $ echo b > api/v2/service.proto
+  $ echo c > api/v2/internal/types.proto
+  $ echo d > api/v2/README.md
+  $ echo e > lib/src/main.rs
+  $ echo f > docs/index.md
+  $ echo g > top.proto
+  $ git add .
+  $ git commit -q -m "add files"
+
+Matching files are kept at their paths, in all directories
+
+  $ josh-filter ::**/*.proto refs/heads/master --update refs/heads/filtered
+  $ git ls-tree -r --name-only refs/heads/filtered
+  api/v1/service.proto
+  api/v2/internal/types.proto
+  api/v2/service.proto
+  top.proto
+
+Directories without matching files are not part of the result
+
+  $ git ls-tree -r -t --name-only refs/heads/filtered
+  api
+  api/v1
+  api/v1/service.proto
+  api/v2
+  api/v2/internal
+  api/v2/internal/types.proto
+  api/v2/service.proto
+  top.proto
+
+Nothing matching gives an empty tree, so there is no commit to point the ref to
+
+  $ josh-filter ::**/*.java refs/heads/master --update refs/heads/nothing
+  Warning: reference refs/heads/nothing wasn't updated
+  $ git rev-parse -q --verify refs/heads/nothing
+  [1]
+
+After a subdir the pattern is matched against the paths in the subdir
+
+  $ josh-filter :/api/v2::**/*.proto refs/heads/master --update refs/heads/filtered
+  $ git ls-tree -r --name-only refs/heads/filtered
+  internal/types.proto
+  service.proto
+
+  $ josh-filter -p :/api/v2::**/*.proto
+  :/api/v2::**/*.proto
+  $ josh-filter -p :/api::*/*.md
+  :/api::*/*.md
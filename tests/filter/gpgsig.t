@@ -37,12 +37,17 @@ If 0b4cf6c9efbbda1eada39fa9c1d21d2525b027bb shows up then the signature was lost
   cb22ebb8e47b109f7add68b1043e561e0db09802
 
 Remove the signature, the shas are different.
+The stats list all filters in the cache, so they include those of the runs above.
   $ josh-filter :unsign refs/heads/master --update refs/heads/filtered -s
+  [1] :/extra
+  [1] :prefix=extra
   [1] :unsign
   $ git rev-parse master filtered
   cb22ebb8e47b109f7add68b1043e561e0db09802
   0b4cf6c9efbbda1eada39fa9c1d21d2525b027bb
   $ josh-filter --reverse :unsign refs/heads/double-filtered --update refs/heads/filtered -s
+  [1] :/extra
+  [1] :prefix=extra
   [1] :unsign
   $ git rev-parse master double-filtered
   cb22ebb8e47b109f7add68b1043e561e0db09802
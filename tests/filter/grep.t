@@ -0,0 +1,34 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p src/a docs
+  $ printf "fn main() {}\n// TODO: error handling\n" > src/main.rs
+  $ echo "fn lib() {}" > src/lib.rs
+  $ echo "TODO write docs" > docs/README.md
+  $ echo "nothing here" > src/a/done.rs
+  $ printf "TODO\0binary" > src/a/data.bin
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter -s :grep=TODO refs/heads/master --update refs/heads/filtered
+  [1] :grep=TODO
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  docs/README.md
+  src/main.rs
+
+  $ josh-filter -s ':/src:grep="^fn"' refs/heads/master --update refs/heads/filtered
+  [1] :/src
+  [1] :grep="^fn"
+  [1] :grep=TODO
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  lib.rs
+  main.rs
+
+  $ josh-filter -s ':grep="("' refs/heads/master --update refs/heads/filtered
+  ERROR: Invalid regex in ":grep": regex parse error:
+      (
+      ^
+  error: unclosed group
+  [1]
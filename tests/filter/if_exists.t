@@ -0,0 +1,30 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p old/layout/src
+  $ echo contents1 > old/layout/src/file1
+  $ git add .
+  $ git commit -q -m "old layout"
+
+  $ josh-filter -s ':if-exists=old/layout/?(:/old/layout/src):(:/src)' refs/heads/master --update refs/heads/filtered
+  [1] :if-exists=old/layout?(:/old/layout/src):(:/src)
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file1
+
+  $ git mv old/layout/src src
+  $ git commit -q -m "new layout"
+  $ echo contents2 > src/file2
+  $ git add .
+  $ git commit -q -m "add file"
+
+  $ josh-filter -s ':if-exists=old/layout/?(:/old/layout/src):(:/src)' refs/heads/master --update refs/heads/filtered
+  [2] :if-exists=old/layout?(:/old/layout/src):(:/src)
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file1
+  file2
+  $ git log --oneline refs/heads/filtered
+  * (glob)
+  * (glob)
+
@@ -0,0 +1,79 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir sub
+  $ echo contents1 > file1
+  $ echo contents2 > file2
+  $ echo contents3 > sub/file3
+  $ git add .
+  $ git commit -q -m "add files"
+  $ BASE=$(git rev-parse HEAD)
+
+  $ git checkout -q -b a
+  $ echo changed > file2
+  $ echo contents4 > file4
+  $ git rm -q sub/file3
+  $ git add .
+  $ git commit -q -m "change a"
+  $ A=$(git rev-parse HEAD)
+
+  $ git checkout -q -b b $BASE
+  $ echo changed > file2
+  $ echo contents4 > file4
+  $ echo contents5 > file5
+  $ git add .
+  $ git commit -q -m "change b"
+  $ B=$(git rev-parse HEAD)
+
+  $ git checkout -q master
+
+Paths present in all trees, whatever their content
+
+  $ josh-filter ":intersect=$A;$B" refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file1
+  file2
+
+  $ josh-filter ":intersect=$A;$B" refs/heads/b --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file1
+  file2
+  file4
+
+Only paths that also have the same content
+
+  $ josh-filter ":intersect-content=$A;$B" refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file1
+
+  $ josh-filter ":intersect-content=$A;$B" refs/heads/b --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file1
+  file2
+  file4
+
+  $ josh-filter -p ":intersect-content=$A;$B" | sed "s/$A/A/;s/$B/B/"
+  :intersect-content=A;B
+
+Identical directories at different paths are matched by their own path
+
+  $ git init -q dup 1> /dev/null
+  $ cd dup
+  $ mkdir a
+  $ echo contents > a/f
+  $ git add .
+  $ git commit -q -m "add a"
+  $ ONLY_A=$(git rev-parse HEAD)
+  $ mkdir b
+  $ echo contents > b/f
+  $ git add .
+  $ git commit -q -m "add b"
+
+  $ josh-filter ":intersect=$ONLY_A" refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  a/f
+  $ cd ..
+
+  $ josh-filter ":intersect=$(git rev-parse --short=8 HEAD)" refs/heads/master --update refs/heads/filtered
+  ERROR: Filter ":intersect" requires full commit ids, got: "*" (glob)
+  [1]
@@ -0,0 +1,44 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ echo other > other
+  $ git add other
+  $ git commit -q -m "add other"
+  $ for i in 1 2 3 4 5; do
+  >   echo version$i > file
+  >   git add file
+  >   git commit -q -m "change file $i"
+  > done
+  $ echo other2 > other
+  $ git add other
+  $ git commit -q -m "change other"
+
+  $ josh-filter -s :keep-versions=2 refs/heads/master --update refs/heads/filtered
+  [1] :keep-versions=2
+
+Only the last two versions of each file are kept, and the commits that only
+added older versions are dropped
+
+  $ git log --graph --pretty=%s refs/heads/filtered
+  * change other
+  * change file 5
+  * change file 4
+  * add other
+  $ git log --pretty=%s refs/heads/filtered -- file
+  change file 5
+  change file 4
+  $ git log --format=%H refs/heads/filtered -- file | while read c; do git show $c:file; done
+  version5
+  version4
+  $ git show refs/heads/filtered~3:other
+  other
+  $ test $(git rev-parse refs/heads/filtered^{tree}) = $(git rev-parse master^{tree})
+
+Keeping more versions than there are returns the original history
+
+  $ josh-filter :keep-versions=10 refs/heads/master --update refs/heads/filtered
+  $ test $(git rev-parse refs/heads/filtered) = $(git rev-parse master)
+
+  $ josh-filter :keep-versions=0 refs/heads/master --update refs/heads/filtered
+  ERROR: Filter ":keep-versions" requires a positive number of versions, got: "0"
+  [1]
@@ -0,0 +1,71 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir sub
+  $ echo contents1 > file1
+  $ echo contents2 > file2
+  $ echo contents3 > sub/file3
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ echo alice > file1
+  $ echo alice > file4
+  $ git add .
+  $ git commit -q --author="Alice <alice@example.com>" -m "change file1, add file4"
+
+  $ echo bob > file4
+  $ git commit -q -a --author="Bob <bob@example.com>" -m "change file4"
+
+Only files whose last change was made by a matching author are kept
+
+  $ josh-filter :last-author=alice@example.com refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file1
+  $ git log --pretty=%s refs/heads/filtered
+  change file4
+  change file1, add file4
+  $ git ls-tree --name-only -r refs/heads/filtered~1
+  file1
+  file4
+
+  $ josh-filter :last-author="bob@*" refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file4
+
+Files of a merge that are the same as in one of the parents keep the author from there
+
+  $ git checkout -q -b branch HEAD~1
+  $ echo alice > file2
+  $ echo alice > sub/file5
+  $ git add .
+  $ git commit -q -a --author="Alice <alice@example.com>" -m "change file2"
+  $ git checkout -q master
+  $ echo master > sub/file3
+  $ git commit -q -a -m "change file3"
+  $ git merge -q --no-ff branch -m "merge"
+
+  $ josh-filter :last-author=alice@example.com refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file1
+  file2
+  sub/file5
+
+  $ josh-filter :/sub:last-author=alice@example.com refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file5
+
+Identical directories at different paths are matched by their own path
+
+  $ git init -q dup 1> /dev/null
+  $ cd dup
+  $ mkdir a b
+  $ echo contents > a/f
+  $ git add .
+  $ git commit -q --author="Alice <alice@example.com>" -m "add a"
+  $ echo contents > b/f
+  $ git add .
+  $ git commit -q --author="Bob <bob@example.com>" -m "add b"
+
+  $ josh-filter :last-author=alice@example.com refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  a/f
@@ -0,0 +1,23 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p assets src
+  $ cat > assets/logo.png <<EOF
+  > version https://git-lfs.github.com/spec/v1
+  > oid sha256:4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393
+  > size 12345
+  > EOF
+  $ cat > assets/notes.txt <<EOF
+  > version https://git-lfs.github.com/spec/v1
+  > this only looks like a pointer
+  > EOF
+  $ echo "*.png filter=lfs diff=lfs merge=lfs -text" > .gitattributes
+  $ echo "fn main() {}" > src/main.rs
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter -s :lfs refs/heads/master --update refs/heads/filtered
+  [1] :lfs
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  assets/logo.png
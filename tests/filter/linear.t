@@ -72,4 +72,18 @@
   * mod file2
   * Merge branch 'branch2'
   * add file2
-  * add file1
\ No newline at end of file
+  * add file1
+Linearising twice is the same as linearising once
+
+  $ josh-filter :linear:linear refs/heads/master --update refs/heads/filtered2
+  $ git log --pretty=%s refs/heads/filtered2
+  mod file2
+  Merge branch 'branch2'
+  add file2
+  add file1
+  $ git rev-list --count refs/heads/filtered2
+  4
+  $ git rev-list --merges --count refs/heads/filtered2
+  0
+  $ git rev-list --count refs/heads/master
+  5
@@ -0,0 +1,27 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p Sub/Dir
+  $ echo contents1 > Sub/Dir/File1
+  $ echo contents2 > Sub/file2
+  $ echo contents3 > README.md
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter -s :lowercase refs/heads/master --update refs/heads/filtered
+  [1] :lowercase
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  readme.md
+  sub/dir/file1
+  sub/file2
+  $ git show refs/heads/filtered:sub/dir/file1
+  contents1
+
+  $ echo contents4 > Sub/FILE2
+  $ git add .
+  $ git commit -q -m "add colliding file"
+
+  $ josh-filter :lowercase refs/heads/master --update refs/heads/filtered
+  ERROR: Path collision in ":lowercase": "Sub/FILE2" and "Sub/file2"
+  [1]
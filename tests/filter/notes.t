@@ -0,0 +1,39 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ echo contents1 > file1
+  $ git add .
+  $ git commit -q -m "add file1"
+  $ git notes --ref review add -m "looks good" HEAD
+
+  $ echo contents2 > file2
+  $ git add .
+  $ git commit -q -m "add file2"
+
+  $ echo contents3 > file3
+  $ git add .
+  $ git commit -q -m "add file3"
+  $ git notes --ref review add -m "needs work" HEAD
+
+  $ josh-filter -s :notes=refs/notes/review refs/heads/master --update refs/heads/filtered
+  [3] :notes=refs/notes/review
+
+  $ git ls-tree --name-only -r refs/heads/filtered~2
+  .notes/* (glob)
+  file1
+  $ git ls-tree --name-only -r refs/heads/filtered~1
+  .notes/* (glob)
+  file1
+  file2
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  .notes/* (glob)
+  .notes/* (glob)
+  file1
+  file2
+  file3
+
+  $ git show refs/heads/filtered:.notes/$(git rev-parse master~2)
+  looks good
+  $ git show refs/heads/filtered:.notes/$(git rev-parse master)
+  needs work
@@ -0,0 +1,32 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir sub
+  $ echo contents1 > sub/file1
+  $ git add .
+  $ git commit -q -m "add file1"
+  $ echo contents2 > sub/file2
+  $ git add .
+  $ git commit -q -m "add file2" -m "Signed-off-by: Josh <josh@example.com>"
+
+  $ josh-filter -s :origin-trailer:/sub refs/heads/master --update refs/heads/filtered
+  [2] :/sub
+  [2] :origin-trailer
+
+  $ git log --format=%B refs/heads/filtered
+  add file2
+  
+  Signed-off-by: Josh <josh@example.com>
+  Josh-Original: * (glob)
+  
+  add file1
+  
+  Josh-Original: * (glob)
+  
+
+  $ git log --format=%H master > original
+  $ git log --format=%B refs/heads/filtered | sed -n 's/^Josh-Original: //p' > traced
+  $ diff original traced
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file1
+  file2
@@ -0,0 +1,41 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p .github web/components api docs
+  $ cat > .github/CODEOWNERS <<EOF
+  > *            @team-core
+  > /web/        @team-frontend
+  > *.css        @team-frontend @team-design
+  > /docs/       @team-core @team-frontend
+  > EOF
+  $ echo contents1 > web/index.html
+  $ echo contents2 > web/components/button.js
+  $ echo contents3 > api/server.rs
+  $ echo contents4 > api/admin.css
+  $ echo contents5 > docs/README.md
+  $ echo contents6 > Cargo.toml
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter -s :owned=@team-frontend refs/heads/master --update refs/heads/filtered
+  [1] :owned=@team-frontend
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  api/admin.css
+  docs/README.md
+  web/components/button.js
+  web/index.html
+
+  $ josh-filter :owned=@team-core refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  .github/CODEOWNERS
+  Cargo.toml
+  api/server.rs
+  docs/README.md
+
+  $ josh-filter -s ':owned=@team-design;.github/CODEOWNERS' refs/heads/master --update refs/heads/filtered
+  [1] :owned=@team-core
+  [1] :owned=@team-design
+  [1] :owned=@team-frontend
+  $ git ls-tree --name-only -r refs/heads/filtered
+  api/admin.css
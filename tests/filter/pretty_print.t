@@ -56,15 +56,9 @@
       ::b/
   ]
   $ josh-filter -p :[::a,::b]:/c
-  :[
-      ::a:/c
-      ::b:/c
-  ]
+  :empty
   $ josh-filter -p :[::a,::b]::c
-  :[
-      ::a
-      ::b
-  ]::c
+  :empty
 Exclude of compose should not be split out
   $ josh-filter -p :[:/a:prefix=a,:/b:prefix=b]:exclude[::a/a,::b/b]
   :[
@@ -148,10 +142,7 @@ Exclude of compose should not be split out
   > ]]
   > EOF
   $ josh-filter -p --file f
-  b = :subtract[
-      :/b
-      :/c
-  ]
+  ::b/
 
   $ cat > f <<EOF
   > :subtract[
@@ -250,10 +241,7 @@ Exclude of compose should not be split out
   > EOF
 
   $ josh-filter -p --file f
-  x/g = :subtract[
-      :/a/x/g
-      :/m/bs/m2/i/tc/i1
-  ]
+  x/g = :/a/x/g
 
   $ cat > f <<EOF
   > a/subsub1 = :/sub1/subsub1
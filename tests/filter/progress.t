@@ -0,0 +1,19 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir sub
+  $ echo contents1 > sub/file1
+  $ git add .
+  $ git commit -q -m "add file1"
+  $ echo contents2 > sub/file2
+  $ git add .
+  $ git commit -q -m "add file2"
+
+  $ josh-filter --progress :/sub refs/heads/master --update refs/heads/filtered
+  \r2/2 commits filtered (esc)
+  $ josh-filter --progress :prefix=x refs/heads/master --update refs/heads/filtered
+  \r2/2 commits filtered (esc)
+
+Nothing is reported if all commits were filtered before
+
+  $ josh-filter --progress :/sub refs/heads/master --update refs/heads/filtered
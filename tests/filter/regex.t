@@ -0,0 +1,54 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p src/a target
+  $ echo contents > src/lib.rs
+  $ echo contents > src/lib_test.rs
+  $ echo contents > src/a/mod_test.rs
+  $ echo contents > src/a/mod_test.rs.orig
+  $ echo contents > target/build_test.rs
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter ':regex="^src/.*_test\\.rs$"' refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  src/a/mod_test.rs
+  src/lib_test.rs
+
+Paths are matched relative to the input of the filter
+
+  $ josh-filter ':/src:regex="^[^/]*_test\\.rs$"' refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  lib_test.rs
+
+  $ josh-filter -p ':regex="^src/.*_test\\.rs$"'
+  :regex="^src/.*_test\\.rs$"
+
+  $ josh-filter ':regex="^nothing$"' refs/heads/master --update refs/heads/nothing
+  Warning: reference refs/heads/nothing wasn't updated
+  $ git rev-parse -q --verify refs/heads/nothing
+  [1]
+
+  $ josh-filter ':regex="(unclosed"' refs/heads/master --update refs/heads/filtered
+  ERROR: Invalid regex in ":regex": regex parse error:
+      (unclosed
+      ^
+  error: unclosed group
+  [1]
+
+  $ josh-filter ':regex="\\w{1000}{1000}"' refs/heads/master --update refs/heads/filtered
+  ERROR: Invalid regex in ":regex": Compiled regex exceeds size limit of 1048576 bytes.
+  [1]
+
+Identical directories at different paths are matched by their own path
+
+  $ git init -q dup 1> /dev/null
+  $ cd dup
+  $ mkdir a b
+  $ echo contents > a/f
+  $ echo contents > b/f
+  $ git add .
+  $ git commit -q -m "add a and b"
+  $ josh-filter ':regex="^a/"' refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  a/f
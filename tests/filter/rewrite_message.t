@@ -0,0 +1,34 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir sub
+  $ echo contents1 > sub/file1
+  $ git add .
+  $ git commit -q -m "[INTERNAL-123] add file1" -m "See INTERNAL-123 for details"
+  $ echo contents2 > sub/file2
+  $ git add .
+  $ git commit -q -m "add file2"
+
+  $ josh-filter -s ':/sub:rewrite-message("\\[INTERNAL-[0-9]+\\] ":"","INTERNAL-([0-9]+)":"ticket $1")' refs/heads/master --update refs/heads/filtered
+  [2] :/sub
+  [2] :rewrite-message(
+      "\\[INTERNAL-[0-9]+\\] ":""
+      "INTERNAL-([0-9]+)":"ticket $1"
+  )
+
+  $ git log --format=%B refs/heads/filtered
+  add file2
+  
+  add file1
+  
+  See ticket 123 for details
+  
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file1
+  file2
+
+  $ josh-filter -p ':rewrite-message("a":"b","c":"d")'
+  :rewrite-message(
+      "a":"b"
+      "c":"d"
+  )
@@ -0,0 +1,55 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p src/a target/debug doc
+  $ echo contents > src/lib.rs
+  $ echo contents > src/a/mod.rs
+  $ echo contents > src/a/README.md
+  $ echo contents > target/debug/build.rs
+  $ echo contents > target/notes.md
+  $ echo contents > doc/index.md
+  $ echo contents > Cargo.toml
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter ':select[+**/*.rs, +**/*.md, -**/target/**]' refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  doc/index.md
+  src/a/README.md
+  src/a/mod.rs
+  src/lib.rs
+
+It is equivalent to subtracting the excluded from the included patterns
+
+  $ josh-filter ':subtract[:[::**/*.rs,::**/*.md],::**/target/**]' refs/heads/master --update refs/heads/subtracted
+  $ test $(git rev-parse filtered^{tree}) = $(git rev-parse subtracted^{tree})
+
+  $ josh-filter ':/src:select[-**/*.md,+**]' refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  a/mod.rs
+  lib.rs
+
+  $ josh-filter -p ':select[+**/*.rs,-**/target/**]'
+  :select[+**/*.rs,-**/target/**]
+  $ josh-filter -p ':select[
+  >     +**/*.rs
+  >     -"**/target[1]/**"
+  > ]'
+  :select[+**/*.rs,-"**/target[1]/**"]
+
+  $ josh-filter ':select[+"[a"]' refs/heads/master --update refs/heads/filtered
+  ERROR: Invalid pattern in ":select": Pattern syntax error near position 0: invalid range pattern
+  [1]
+
+Identical directories at different paths are matched by their own path
+
+  $ git init -q dup 1> /dev/null
+  $ cd dup
+  $ mkdir a b
+  $ echo contents > a/f
+  $ echo contents > b/f
+  $ git add .
+  $ git commit -q -m "add a and b"
+  $ josh-filter ':select[+a/**]' refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  a/f
@@ -0,0 +1,78 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir sub
+  $ echo contents1 > sub/file1
+  $ echo contents2 > file2
+  $ git add .
+  $ git commit -q -m "add files"
+
+Without a .josh-view file the tree is unchanged
+
+  $ josh-filter :self refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file2
+  sub/file1
+
+  $ echo ":/sub" > .josh-view
+  $ git add .
+  $ git commit -q -m "add view"
+
+  $ josh-filter -s :self refs/heads/master --update refs/heads/filtered
+  [2] :self
+
+  $ git log --graph --pretty=%s refs/heads/filtered
+  * add view
+  * add files
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file1
+  $ git ls-tree --name-only -r refs/heads/filtered~1
+  file2
+  sub/file1
+
+  $ echo ":[a=:/sub,::file2]" > .josh-view
+  $ git add .
+  $ git commit -q -m "change view"
+
+  $ josh-filter :self refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  a/file1
+  file2
+
+  $ echo ":/sub:[" > .josh-view
+  $ git add .
+  $ git commit -q -m "invalid view"
+
+  $ josh-filter :self refs/heads/master --update refs/heads/filtered
+  ERROR: Invalid filter in .josh-view: Invalid workspace:
+  ----
+   --> 2:1
+    |
+  2 | 
+    | ^---
+    |
+    = expected filter_spec or dst_path
+  
+  :/sub:[
+  
+  ----
+  [1]
+
+  $ echo ":/sub:self" > .josh-view
+  $ git add .
+  $ git commit -q -m "recursive view"
+
+  $ josh-filter :self refs/heads/master --update refs/heads/filtered
+  ERROR: Filter in .josh-view must not use ":self"
+  [1]
+
+Also when it is nested in other filters
+
+  $ echo ":intersect[:/sub:self,:/sub]" > .josh-view
+  $ git add .
+  $ git commit -q -m "nested recursive view"
+
+  $ josh-filter :self refs/heads/master --update refs/heads/filtered
+  ERROR: Filter in .josh-view must not use ":self"
+  [1]
@@ -0,0 +1,35 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir -p config templates.tmpl
+  $ echo contents1 > config/app.yml.tmpl
+  $ echo contents2 > foo.tmpl
+  $ echo contents3 > .tmpl
+  $ echo contents4 > templates.tmpl/file
+  $ echo contents5 > README.md
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ josh-filter -s :strip-ext=tmpl refs/heads/master --update refs/heads/filtered
+  [1] :strip-ext=tmpl
+
+  $ git ls-tree --name-only -r refs/heads/filtered
+  .tmpl
+  README.md
+  config/app.yml
+  foo
+  templates.tmpl/file
+  $ git show refs/heads/filtered:foo
+  contents2
+
+  $ echo contents6 > foo
+  $ git add .
+  $ git commit -q -m "add colliding file"
+
+  $ josh-filter :strip-ext=tmpl refs/heads/master --update refs/heads/filtered
+  ERROR: Path collision in ":strip-ext": "foo" and "foo.tmpl"
+  [1]
+
+  $ josh-filter :strip-ext=.tmpl refs/heads/master --update refs/heads/filtered
+  ERROR: Filter ":strip-ext" requires an extension without leading dot, got: ".tmpl"
+  [1]
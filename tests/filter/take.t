@@ -0,0 +1,56 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ for i in 1 2 3 4 5; do
+  >   mkdir -p sub
+  >   echo contents$i > sub/file$i
+  >   echo contents$i > file$i
+  >   git add .
+  >   git commit -q -m "add file$i"
+  > done
+
+  $ josh-filter -s :take=3 refs/heads/master --update refs/heads/filtered
+  [1] :take=3
+
+  $ git log --graph --pretty=%s refs/heads/filtered
+  * add file5
+  * add file4
+  * add file3
+
+  $ git rev-list --count refs/heads/filtered
+  3
+  $ git rev-list --max-parents=0 refs/heads/filtered | xargs git log -1 --pretty=%s
+  add file3
+
+The kept commits have the unmodified trees
+
+  $ git ls-tree --name-only refs/heads/filtered
+  file1
+  file2
+  file3
+  file4
+  file5
+  sub
+  $ test $(git rev-parse refs/heads/filtered^{tree}) = $(git rev-parse master^{tree})
+
+Taking more commits than the history has returns the original history
+
+  $ josh-filter :take=10 refs/heads/master --update refs/heads/filtered
+  $ test $(git rev-parse refs/heads/filtered) = $(git rev-parse master)
+
+It can be combined with path filters
+
+  $ josh-filter :/sub:take=2 refs/heads/master --update refs/heads/filtered
+  $ git log --graph --pretty=%s refs/heads/filtered
+  * add file5
+  * add file4
+  $ git ls-tree --name-only refs/heads/filtered
+  file1
+  file2
+  file3
+  file4
+  file5
+
+  $ josh-filter :take=0 refs/heads/master --update refs/heads/filtered
+  ERROR: Filter ":take" requires a positive number of commits, got: "0"
+  [1]
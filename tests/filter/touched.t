@@ -0,0 +1,58 @@
+  $ export RUST_BACKTRACE=1
+  $ git init -q 1> /dev/null
+
+  $ mkdir sub
+  $ echo contents1 > file1
+  $ echo contents2 > file2
+  $ echo contents3 > sub/file3
+  $ git add .
+  $ git commit -q -m "add files"
+
+  $ echo contents1 > file4
+  $ echo changed > sub/file3
+  $ git add .
+  $ git commit -q -m "add file4, change file3"
+  $ TOUCHED=$(git rev-parse HEAD)
+
+  $ josh-filter :touched=$TOUCHED refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file4
+  sub/file3
+
+Merges are compared to their first parent
+
+  $ git checkout -q -b branch HEAD~1
+  $ echo branch > file2
+  $ git commit -q -am "change file2"
+  $ git checkout -q master
+  $ echo master > file1
+  $ git commit -q -am "change file1"
+  $ git merge -q --no-ff branch -m "merge"
+  $ MERGE=$(git rev-parse HEAD)
+
+  $ josh-filter :touched=$MERGE refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  file2
+
+  $ josh-filter :touched=$(git rev-parse --short=8 HEAD) refs/heads/master --update refs/heads/filtered
+  ERROR: Filter ":touched" requires a full commit id, got: "*" (glob)
+  [1]
+
+Identical directories at different paths are matched by their own path
+
+  $ git init -q dup 1> /dev/null
+  $ cd dup
+  $ mkdir a b
+  $ echo a > a/f
+  $ echo b > b/f
+  $ git add .
+  $ git commit -q -m "add a and b"
+  $ echo changed > a/f
+  $ git commit -q -am "change a"
+  $ TOUCHED=$(git rev-parse HEAD)
+  $ echo changed > b/f
+  $ git commit -q -am "change b"
+
+  $ josh-filter :touched=$TOUCHED refs/heads/master --update refs/heads/filtered
+  $ git ls-tree --name-only -r refs/heads/filtered
+  a/f
@@ -85,11 +85,11 @@
   [2] :workspace=ws
   [2] :workspace=ws2
   [3] :[
+      blub = :/sub1
       a = :[
           ::sub2/subsub/
           ::sub3/
       ]
-      blub = :/sub1
   ]
   [3] :prefix=xyz
 